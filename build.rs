@@ -0,0 +1,7 @@
+fn main() {
+    // Lets self-update pick the release asset built for the same target.
+    println!(
+        "cargo:rustc-env=TARGET={}",
+        std::env::var("TARGET").unwrap()
+    );
+}
@@ -0,0 +1,104 @@
+use serde::Deserialize;
+
+/// Routes and parameter names of the Nautica API, for self-hosted forks that
+/// differ from the stock deployment. A path prefix belongs in the base URL.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Routes {
+    /// Path of the song listing.
+    pub songs: String,
+
+    /// Path of a song's archive, where `{id}` stands for the song ID.
+    pub download: String,
+
+    /// Query parameter selecting a listing page, used when the server does
+    /// not link to the next page itself.
+    pub page_param: String,
+
+    /// Query parameter setting the number of songs per page.
+    pub per_page_param: String,
+
+    /// Query parameter setting the order of the listing.
+    pub sort_param: String,
+
+    /// Query parameter searching the listing.
+    pub query_param: String,
+
+    /// Path that sets the CSRF cookie required to log in.
+    pub csrf_cookie: String,
+
+    /// Path of the login form.
+    pub login: String,
+
+    /// Path of the listing of the songs the logged-in account liked.
+    pub favorites: String,
+
+    /// Path of the listing of the logged-in account's playlists.
+    pub playlists: String,
+
+    /// Path of a playlist and its songs, where `{id}` stands for the
+    /// playlist ID.
+    pub playlist: String,
+
+    /// Path that songs are published to.
+    pub publish: String,
+
+    /// Path of a user's profile, where `{id}` stands for the user ID.
+    pub user: String,
+}
+
+impl Routes {
+    /// Returns the path of the archive of the song `id`.
+    pub fn download_path(&self, id: &str) -> String {
+        self.download.replace("{id}", id)
+    }
+
+    /// Returns the path of the playlist `id`.
+    pub fn playlist_path(&self, id: &str) -> String {
+        self.playlist.replace("{id}", id)
+    }
+
+    /// Returns the path of the profile of the user `id`.
+    pub fn user_path(&self, id: &str) -> String {
+        self.user.replace("{id}", id)
+    }
+}
+
+impl Default for Routes {
+    fn default() -> Self {
+        Self {
+            songs: String::from("/app/songs"),
+            download: String::from("/songs/{id}/download"),
+            page_param: String::from("page"),
+            per_page_param: String::from("per_page"),
+            sort_param: String::from("sort"),
+            query_param: String::from("q"),
+            csrf_cookie: String::from("/sanctum/csrf-cookie"),
+            login: String::from("/login"),
+            favorites: String::from("/app/user/songs/liked"),
+            playlists: String::from("/app/user/playlists"),
+            playlist: String::from("/app/playlists/{id}"),
+            publish: String::from("/app/user/songs"),
+            user: String::from("/app/users/{id}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_partial_routes() {
+        let routes: Routes = toml::from_str(
+            r#"
+            download = "/api/charts/{id}/zip"
+            page_param = "p"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(routes.songs, "/app/songs");
+        assert_eq!(routes.download_path("5441d590"), "/api/charts/5441d590/zip");
+        assert_eq!(routes.page_param, "p");
+    }
+}
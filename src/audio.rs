@@ -0,0 +1,287 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+
+/// Number of bytes at the end of an Ogg file searched for the last page.
+const OGG_TAIL_LEN: usize = 64 * 1024;
+
+/// Bitrates of MPEG-1 Layer III frames in kbit/s, by bitrate index.
+const MPEG1_BITRATES: [u32; 15] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+
+/// Bitrates of MPEG-2 and MPEG-2.5 Layer III frames in kbit/s, by bitrate
+/// index.
+const MPEG2_BITRATES: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+/// What the headers of an audio file tell about it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioInfo {
+    /// Sample rate in Hz.
+    pub sample_rate: u32,
+
+    /// Length in seconds.
+    pub duration: f64,
+}
+
+/// Reads the headers of the audio file at `path` to check that it can be
+/// played. Only Ogg (Vorbis or Opus), WAV, and MP3 files are checked; `None`
+/// is returned for other formats. Fails if the headers are broken or the
+/// audio is empty.
+pub fn probe(path: &Path) -> anyhow::Result<Option<AudioInfo>> {
+    let Some(ext) = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase)
+    else {
+        return Ok(None);
+    };
+    let info = match ext.as_str() {
+        "ogg" | "opus" => ogg_info,
+        "wav" => wav_info,
+        "mp3" => mp3_info,
+        _ => return Ok(None),
+    };
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let Some(info) = info(&bytes) else {
+        bail!("No valid {ext} header");
+    };
+    ensure!(info.sample_rate > 0, "No sample rate in the {ext} header");
+    ensure!(info.duration > 0.0, "The {ext} audio is empty");
+    Ok(Some(info))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Divides the granule position of the last page by the sample rate from the
+/// identification header.
+fn ogg_info(bytes: &[u8]) -> Option<AudioInfo> {
+    let head = &bytes[..bytes.len().min(OGG_TAIL_LEN)];
+    let (rate, pre_skip) = if let Some(i) = find(head, b"\x01vorbis") {
+        (u32_at(head, i + 12)?, 0)
+    } else if let Some(i) = find(head, b"OpusHead") {
+        // Opus granule positions always count 48 kHz samples.
+        let pre_skip = u16::from_le_bytes(head.get(i + 10..i + 12)?.try_into().ok()?);
+        (48_000, u64::from(pre_skip))
+    } else {
+        return None;
+    };
+
+    let tail_start = bytes.len().saturating_sub(OGG_TAIL_LEN);
+    let last_page = tail_start + rfind(&bytes[tail_start..], b"OggS")?;
+    let granule = u64::from_le_bytes(bytes.get(last_page + 6..last_page + 14)?.try_into().ok()?);
+    if rate == 0 {
+        return Some(AudioInfo {
+            sample_rate: 0,
+            duration: 0.0,
+        });
+    }
+    Some(AudioInfo {
+        sample_rate: rate,
+        duration: granule.saturating_sub(pre_skip) as f64 / f64::from(rate),
+    })
+}
+
+/// Divides the size of the `data` chunk by the byte rate of the `fmt ` chunk.
+fn wav_info(bytes: &[u8]) -> Option<AudioInfo> {
+    if bytes.get(..4)? != b"RIFF" || bytes.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let mut format = None;
+    let mut offset = 12;
+    while let (Some(id), Some(size)) = (bytes.get(offset..offset + 4), u32_at(bytes, offset + 4)) {
+        let body = offset + 8;
+        match id {
+            b"fmt " => format = Some((u32_at(bytes, body + 4)?, u32_at(bytes, body + 8)?)),
+            b"data" => {
+                let (sample_rate, byte_rate) = format?;
+                if byte_rate == 0 {
+                    return Some(AudioInfo {
+                        sample_rate,
+                        duration: 0.0,
+                    });
+                }
+                // The size of a stream still being written may be unset.
+                let size = size.min((bytes.len() - body) as u32);
+                return Some(AudioInfo {
+                    sample_rate,
+                    duration: f64::from(size) / f64::from(byte_rate),
+                });
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even size.
+        offset = body + size as usize + (size as usize & 1);
+    }
+    None
+}
+
+/// Reads the first MPEG Layer III frame, after any ID3v2 tag. The length
+/// comes from the frame count of a Xing or Info header if there is one, and
+/// from the bitrate of the first frame otherwise.
+fn mp3_info(bytes: &[u8]) -> Option<AudioInfo> {
+    let mut offset = 0;
+    if bytes.starts_with(b"ID3") {
+        // The tag size is a 28-bit syncsafe integer.
+        let size = bytes
+            .get(6..10)?
+            .iter()
+            .fold(0, |size, &b| (size << 7) | usize::from(b & 0x7f));
+        let footer = if bytes.get(5)? & 0x10 != 0 { 10 } else { 0 };
+        offset = 10 + size + footer;
+    }
+    let start = offset
+        + bytes
+            .get(offset..)?
+            .windows(2)
+            .position(|w| w[0] == 0xff && w[1] & 0xe0 == 0xe0)?;
+    let header = bytes.get(start..start + 4)?;
+    let version = (header[1] >> 3) & 0x03;
+    let layer = (header[1] >> 1) & 0x03;
+    // Only Layer III, i.e. MP3, with a known version.
+    if version == 1 || layer != 1 {
+        return None;
+    }
+    let mpeg1 = version == 3;
+    let rate = [44_100, 48_000, 32_000].get(usize::from((header[2] >> 2) & 0x03))?;
+    let sample_rate = match version {
+        3 => *rate,
+        2 => rate / 2,
+        _ => rate / 4,
+    };
+    let samples_per_frame = if mpeg1 { 1152 } else { 576 };
+
+    let frame = bytes.get(start..bytes.len().min(start + 200))?;
+    if let Some(i) = find(frame, b"Xing").or_else(|| find(frame, b"Info")) {
+        let flags = u32::from_be_bytes(frame.get(i + 4..i + 8)?.try_into().ok()?);
+        if flags & 1 != 0 {
+            let frames = u32::from_be_bytes(frame.get(i + 8..i + 12)?.try_into().ok()?);
+            return Some(AudioInfo {
+                sample_rate,
+                duration: f64::from(frames) * f64::from(samples_per_frame) / f64::from(sample_rate),
+            });
+        }
+    }
+    let bitrates = if mpeg1 {
+        MPEG1_BITRATES
+    } else {
+        MPEG2_BITRATES
+    };
+    let bitrate = *bitrates.get(usize::from(header[2] >> 4))?;
+    if bitrate == 0 {
+        return None;
+    }
+    Some(AudioInfo {
+        sample_rate,
+        duration: (bytes.len() - start) as f64 * 8.0 / f64::from(bitrate * 1000),
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|w| w == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wav() {
+        let mut wav = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0".to_vec();
+        // PCM, 2 channels, 44100 Hz, 176400 bytes per second.
+        wav.extend([1, 0, 2, 0]);
+        wav.extend(44100u32.to_le_bytes());
+        wav.extend(176_400u32.to_le_bytes());
+        wav.extend([4, 0, 16, 0]);
+        wav.extend(b"data");
+        wav.extend(88_200u32.to_le_bytes());
+        wav.extend(vec![0; 88_200]);
+        assert_eq!(
+            wav_info(&wav),
+            Some(AudioInfo {
+                sample_rate: 44_100,
+                duration: 0.5
+            })
+        );
+        assert_eq!(wav_info(b"RIFF\0\0\0\0WAVE"), None);
+    }
+
+    #[test]
+    fn ogg() {
+        let mut ogg = b"OggS\0\x02".to_vec();
+        ogg.extend([0; 22]);
+        ogg.extend(b"\x01vorbis\0\0\0\0\x02");
+        ogg.extend(44100u32.to_le_bytes());
+        ogg.extend([0; 100]);
+        ogg.extend(b"OggS\0\x04");
+        ogg.extend(88_200u64.to_le_bytes());
+        ogg.extend([0; 20]);
+        assert_eq!(
+            ogg_info(&ogg),
+            Some(AudioInfo {
+                sample_rate: 44_100,
+                duration: 2.0
+            })
+        );
+        assert_eq!(ogg_info(b"OggS"), None);
+    }
+
+    #[test]
+    fn mp3() {
+        // ID3v2 tag of 4 bytes, then an MPEG-1 Layer III frame header at
+        // 128 kbit/s and 44100 Hz.
+        let mut mp3 = b"ID3\x03\0\0\0\0\0\x04\0\0\0\0".to_vec();
+        mp3.extend([0xff, 0xfb, 0x90, 0x00]);
+        mp3.extend(vec![0; 16_000 - 4]);
+        assert_eq!(
+            mp3_info(&mp3),
+            Some(AudioInfo {
+                sample_rate: 44_100,
+                duration: 1.0
+            })
+        );
+
+        // A Xing header counting 100 frames of 1152 samples.
+        let mut vbr = vec![0xff, 0xfb, 0x90, 0x00];
+        vbr.extend([0; 32]);
+        vbr.extend(b"Xing\0\0\0\x01");
+        vbr.extend(100u32.to_be_bytes());
+        let info = mp3_info(&vbr).unwrap();
+        assert!((info.duration - 100.0 * 1152.0 / 44_100.0).abs() < 1e-9);
+
+        assert_eq!(mp3_info(b"not an mp3"), None);
+    }
+
+    #[test]
+    fn probe_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty.wav");
+        let mut wav = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x02\0".to_vec();
+        wav.extend(44100u32.to_le_bytes());
+        wav.extend(176_400u32.to_le_bytes());
+        wav.extend([4, 0, 16, 0]);
+        wav.extend(b"data\0\0\0\0");
+        fs::write(&empty, &wav).unwrap();
+        let err = probe(&empty).unwrap_err();
+        assert_eq!(err.to_string(), "The wav audio is empty");
+
+        let broken = dir.path().join("broken.ogg");
+        fs::write(&broken, "<html>Not found</html>").unwrap();
+        assert!(probe(&broken).is_err());
+
+        let flac = dir.path().join("music.flac");
+        fs::write(&flac, "").unwrap();
+        assert_eq!(probe(&flac).unwrap(), None);
+    }
+}
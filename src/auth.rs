@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use attohttpc::header;
+use attohttpc::header::HeaderMap;
+use attohttpc::header::HeaderValue;
+use attohttpc::StatusCode;
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+
+use crate::api::Routes;
+use crate::http::Client;
+use crate::http::Response;
+
+/// File in the library holding the cookies of the logged-in account.
+const SESSION_FILENAME: &str = ".session";
+
+/// Cookie that carries the CSRF token the login form has to echo back.
+const XSRF_COOKIE: &str = "XSRF-TOKEN";
+
+/// Logs in to the Nautica account `email` on the server at `base_url`,
+/// returning the session cookies as the value of a `Cookie` header.
+///
+/// Nautica authenticates its own frontend with Laravel Sanctum: a CSRF
+/// cookie is fetched first and sent back with the login form.
+pub fn login(
+    client: &Client,
+    base_url: &str,
+    routes: &Routes,
+    email: &str,
+    password: &str,
+) -> anyhow::Result<String> {
+    let mut cookies = BTreeMap::new();
+    let mut headers = HeaderMap::new();
+    headers.insert(header::REFERER, HeaderValue::from_str(base_url)?);
+    let resp = client.get_with(&format!("{base_url}{}", routes.csrf_cookie), &headers)?;
+    ensure!(
+        resp.is_success(),
+        "Failed to fetch the CSRF cookie: {}",
+        resp.status()
+    );
+    store_cookies(&mut cookies, &resp);
+    let xsrf_token =
+        xsrf_token(&cookie_header(&cookies)).context("The server did not set a CSRF cookie")?;
+
+    headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    headers.insert(
+        header::COOKIE,
+        HeaderValue::from_str(&cookie_header(&cookies))?,
+    );
+    headers.insert("X-XSRF-TOKEN", HeaderValue::from_str(&xsrf_token)?);
+    let body = json!({ "email": email, "password": password, "remember": true });
+    let resp = client.post(
+        &format!("{base_url}{}", routes.login),
+        &headers,
+        &serde_json::to_vec(&body)?,
+    )?;
+    match resp.status() {
+        status if status.is_success() => {}
+        StatusCode::UNAUTHORIZED | StatusCode::UNPROCESSABLE_ENTITY => {
+            bail!("Invalid email or password")
+        }
+        status => bail!("Login failed: {status}"),
+    }
+    store_cookies(&mut cookies, &resp);
+    Ok(cookie_header(&cookies))
+}
+
+/// Records the cookies set by `resp`, replacing earlier ones of the same name.
+fn store_cookies(cookies: &mut BTreeMap<String, String>, resp: &Response) {
+    for set_cookie in resp.headers().get_all(header::SET_COOKIE) {
+        let Ok(set_cookie) = set_cookie.to_str() else {
+            continue;
+        };
+        let pair = set_cookie.split(';').next().unwrap_or_default();
+        if let Some((name, value)) = pair.split_once('=') {
+            cookies.insert(name.trim().to_owned(), value.trim().to_owned());
+        }
+    }
+}
+
+/// Returns the CSRF token that requests changing data must send in the
+/// `X-XSRF-TOKEN` header, taken from the session cookies.
+pub(crate) fn xsrf_token(cookie: &str) -> Option<String> {
+    cookie
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == XSRF_COOKIE)
+        .map(|(_, token)| percent_decode_str(token).decode_utf8_lossy().into_owned())
+}
+
+fn cookie_header(cookies: &BTreeMap<String, String>) -> String {
+    cookies
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Returns the session saved in the library `dest` by [`save_session`], if
+/// logged in.
+pub fn load_session(dest: &Path) -> anyhow::Result<Option<String>> {
+    match fs::read_to_string(dest.join(SESSION_FILENAME)) {
+        Ok(cookie) => Ok(Some(cookie.trim().to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Saves the session cookies in the library `dest`, readable only by the
+/// current user since they grant access to the account.
+pub fn save_session(dest: &Path, cookie: &str) -> anyhow::Result<()> {
+    let path = dest.join(SESSION_FILENAME);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(&path)?, cookie.as_bytes())?;
+    Ok(())
+}
+
+/// Forgets the saved session. Returns `false` if not logged in.
+pub fn remove_session(dest: &Path) -> anyhow::Result<bool> {
+    match fs::remove_file(dest.join(SESSION_FILENAME)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use httpmock::prelude::*;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::http::HttpConfig;
+
+    #[test]
+    fn login_with_csrf_cookie() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/sanctum/csrf-cookie");
+            then.status(204)
+                .header("set-cookie", "XSRF-TOKEN=abc%3D; path=/")
+                .header("set-cookie", "nautica_session=first; path=/; httponly");
+        });
+        let login = server.mock(|when, then| {
+            when.method(POST)
+                .path("/login")
+                .header("x-xsrf-token", "abc=")
+                .header("cookie", "XSRF-TOKEN=abc%3D; nautica_session=first")
+                .json_body_partial(r#"{"email": "me@example.com", "password": "hunter2"}"#);
+            then.status(200)
+                .header("set-cookie", "nautica_session=second; path=/; httponly")
+                .header("set-cookie", "remember_web=token; path=/");
+        });
+
+        let client = Client::new(&HttpConfig::default());
+        let routes = Routes::default();
+        let cookie = login_to(&server, &client, &routes, "hunter2").unwrap();
+        login.assert();
+        assert_eq!(
+            cookie,
+            "XSRF-TOKEN=abc%3D; nautica_session=second; remember_web=token"
+        );
+
+        let dest = tempdir().unwrap();
+        assert_eq!(load_session(dest.path()).unwrap(), None);
+        save_session(dest.path(), &cookie).unwrap();
+        assert_eq!(load_session(dest.path()).unwrap(), Some(cookie));
+        assert!(remove_session(dest.path()).unwrap());
+        assert!(!remove_session(dest.path()).unwrap());
+    }
+
+    #[test]
+    fn reject_wrong_password() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/sanctum/csrf-cookie");
+            then.status(204).header("set-cookie", "XSRF-TOKEN=abc");
+        });
+        server.mock(|when, then| {
+            when.path("/login");
+            then.status(422);
+        });
+        let client = Client::new(&HttpConfig::default());
+        let error = login_to(&server, &client, &Routes::default(), "wrong").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid email or password");
+    }
+
+    fn login_to(
+        server: &MockServer,
+        client: &Client,
+        routes: &Routes,
+        password: &str,
+    ) -> anyhow::Result<String> {
+        login(
+            client,
+            &server.base_url(),
+            routes,
+            "me@example.com",
+            password,
+        )
+    }
+}
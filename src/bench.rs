@@ -0,0 +1,256 @@
+use std::fmt;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::ensure;
+use anyhow::Context;
+use tracing::info;
+
+use crate::extract;
+use crate::http::HttpConfig;
+use crate::Downloader;
+
+/// Songs listed per page when none is set.
+pub const DEFAULT_PER_PAGE: u32 = 20;
+
+/// Measures how fast the sync pipeline lists, downloads, and extracts songs
+/// from a server, typically a [`crate::mirror::Mirror`] of a local library,
+/// once for each connection setting.
+///
+/// Songs are downloaded into a scratch directory that is removed again, and
+/// nothing is recorded in a library.
+#[derive(Debug)]
+pub struct Bench {
+    base_url: String,
+    per_page: u32,
+    songs: Option<usize>,
+    settings: Vec<HttpConfig>,
+    scratch: PathBuf,
+}
+
+/// Measurements of one connection setting.
+#[derive(Debug)]
+pub struct BenchResult {
+    pub setting: String,
+    pub pages: u64,
+    pub songs: usize,
+
+    /// Size of the downloaded archives.
+    pub bytes: u64,
+
+    pub listing: Duration,
+    pub download: Duration,
+    pub extraction: Duration,
+}
+
+impl Bench {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into(),
+            per_page: DEFAULT_PER_PAGE,
+            songs: None,
+            settings: Vec::new(),
+            scratch: std::env::temp_dir().join(format!("nautica-bench-{}", std::process::id())),
+        }
+    }
+
+    pub fn per_page(mut self, per_page: u32) -> Self {
+        self.per_page = per_page;
+        self
+    }
+
+    /// Downloads only the first `songs` songs of the listing in each run.
+    pub fn songs(mut self, songs: usize) -> Self {
+        self.songs = Some(songs);
+        self
+    }
+
+    /// Adds a run with the connection setting `http`. Without any, the
+    /// default setting is measured.
+    pub fn setting(mut self, http: HttpConfig) -> Self {
+        self.settings.push(http);
+        self
+    }
+
+    /// Sets the directory songs are downloaded into, which must not exist.
+    pub fn scratch<P: Into<PathBuf>>(mut self, scratch: P) -> Self {
+        self.scratch = scratch.into();
+        self
+    }
+
+    pub fn run(&self) -> anyhow::Result<Vec<BenchResult>> {
+        let settings = if self.settings.is_empty() {
+            vec![HttpConfig::default()]
+        } else {
+            self.settings.clone()
+        };
+        ensure!(
+            !self.scratch.exists(),
+            "Scratch directory {} already exists",
+            self.scratch.display()
+        );
+        let results = settings
+            .iter()
+            .map(|http| self.measure(http))
+            .collect::<anyhow::Result<Vec<_>>>();
+        let _ = fs::remove_dir_all(&self.scratch);
+        results
+    }
+
+    fn measure(&self, http: &HttpConfig) -> anyhow::Result<BenchResult> {
+        let setting = describe(http);
+        let _span = tracing::info_span!("bench", setting).entered();
+        let downloader = Downloader::builder()
+            .dest(&self.scratch)
+            .base_url(self.base_url.clone())
+            .per_page(self.per_page)
+            .http(http.clone())
+            .build();
+
+        let start = Instant::now();
+        let mut listing = downloader.listing();
+        let songs = listing.by_ref().collect::<anyhow::Result<Vec<_>>>()?;
+        let listing_time = start.elapsed();
+        let pages = listing.pages;
+        info!(pages, songs = songs.len(), "Listed");
+
+        let mut result = BenchResult {
+            setting,
+            pages,
+            songs: 0,
+            bytes: 0,
+            listing: listing_time,
+            download: Duration::ZERO,
+            extraction: Duration::ZERO,
+        };
+        for song in songs.iter().take(self.songs.unwrap_or(usize::MAX)) {
+            let start = Instant::now();
+            let bytes = downloader
+                .mirrors
+                .send(&downloader.routes.download_path(&song.id), |url| {
+                    downloader.http.get(url)
+                })?
+                .bytes();
+            result.download += start.elapsed();
+
+            let dest = self.scratch.join(&song.id);
+            fs::create_dir_all(&dest)?;
+            let start = Instant::now();
+            extract(Cursor::new(&bytes), None, &dest)
+                .with_context(|| format!("Failed to extract {}", song.id))?;
+            result.extraction += start.elapsed();
+            fs::remove_dir_all(&dest)?;
+
+            result.songs += 1;
+            result.bytes += bytes.len() as u64;
+        }
+        Ok(result)
+    }
+}
+
+impl BenchResult {
+    pub fn pages_per_sec(&self) -> f64 {
+        per_sec(self.pages as f64, self.listing)
+    }
+
+    /// Songs downloaded and extracted per second.
+    pub fn songs_per_sec(&self) -> f64 {
+        per_sec(self.songs as f64, self.download + self.extraction)
+    }
+
+    /// Megabytes of archives extracted per second.
+    pub fn extraction_mb_per_sec(&self) -> f64 {
+        per_sec(self.bytes as f64 / 1e6, self.extraction)
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<16} {:>10.1} {:>10.1} {:>14.1}",
+            self.setting,
+            self.pages_per_sec(),
+            self.songs_per_sec(),
+            self.extraction_mb_per_sec()
+        )
+    }
+}
+
+fn per_sec(count: f64, time: Duration) -> f64 {
+    if time.is_zero() {
+        return 0.0;
+    }
+    count / time.as_secs_f64()
+}
+
+fn describe(http: &HttpConfig) -> String {
+    if http.keep_alive {
+        format!("pool of {}", http.pool_size)
+    } else {
+        String::from("no keep-alive")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::mirror::Mirror;
+    use crate::store::Entry;
+    use crate::store::Store;
+    use crate::Song;
+
+    #[test]
+    fn measure_settings() {
+        let library = tempdir().unwrap();
+        let mut store = Store::open(library.path());
+        for id in ["a", "b", "c"] {
+            let song: Song = serde_json::from_value(json!({
+                "id": id,
+                "user_id": "user",
+                "title": id,
+                "artist": "RG+Ice",
+                "uploaded_at": "2023-09-01 00:00:00",
+                "updated_at": "2023-09-01 00:00:00",
+            }))
+            .unwrap();
+            store.insert(id, &Entry::new(&song, id)).unwrap();
+            fs::create_dir(library.path().join(id)).unwrap();
+            fs::write(library.path().join(id).join("chart.ksh"), id).unwrap();
+        }
+        drop(store);
+        let mirror = Mirror::bind(library.path(), "127.0.0.1:0").unwrap();
+        let addr = mirror.local_addr().unwrap();
+        thread::spawn(move || mirror.run());
+
+        let scratch = tempdir().unwrap();
+        let results = Bench::new(format!("http://{addr}"))
+            .per_page(2)
+            .songs(2)
+            .setting(HttpConfig {
+                keep_alive: false,
+                ..Default::default()
+            })
+            .setting(HttpConfig::default())
+            .scratch(scratch.path().join("bench"))
+            .run()
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].setting, "no keep-alive");
+        assert_eq!(results[1].setting, "pool of 4");
+        for result in &results {
+            assert_eq!(result.pages, 2);
+            assert_eq!(result.songs, 2);
+            assert!(result.bytes > 0);
+        }
+        assert!(!scratch.path().join("bench").exists());
+    }
+}
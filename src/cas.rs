@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::warn;
+
+use crate::notify::Notifier;
+use crate::store::Store;
+use crate::trash::Disposal;
+use crate::Song;
+
+/// Directory of the library holding the file blobs, as
+/// `.objects/<first 2 hex digits>/<rest of the SHA-256>`.
+const OBJECTS_DIRNAME: &str = ".objects";
+
+/// Name of the file in each song directory mapping its files to blobs.
+pub const MANIFEST_FILENAME: &str = ".files.json";
+
+/// Outcome of checking the blobs and song directories of a library.
+#[derive(Debug, Default)]
+pub struct Verification {
+    /// Number of blobs checked.
+    pub blobs: usize,
+
+    /// Blobs whose content no longer matches their hash.
+    pub corrupt: Vec<PathBuf>,
+
+    /// Files of song directories that are missing or differ from their blob.
+    pub mismatched: Vec<PathBuf>,
+}
+
+/// Moves the files of the song directory `dir` into the blob store of the
+/// library `dest`, leaving hard links to the blobs in their place. Files
+/// already in the store are deduplicated. Returns the number of files that
+/// were.
+///
+/// Blobs are written under a temporary name and renamed into place, so
+/// concurrent writers storing the same content never see a partial blob.
+/// They are made read-only since every song sharing them would see a change.
+pub fn store_dir(dest: &Path, dir: &Path) -> anyhow::Result<usize> {
+    let objects = dest.join(OBJECTS_DIRNAME);
+    let mut manifest = BTreeMap::new();
+    let mut deduplicated = 0;
+    for path in files(dir)? {
+        let hash = hash_file(&path)?;
+        let blob = blob_path(&objects, &hash);
+        if blob.exists() {
+            if !same_file(&path, &blob)? {
+                replace_with_link(&blob, &path)?;
+                deduplicated += 1;
+            }
+        } else {
+            fs::create_dir_all(blob.parent().unwrap_or(&objects))?;
+            let tmp = blob.with_extension(format!("tmp-{}", std::process::id()));
+            link_or_copy(&path, &tmp)?;
+            set_read_only(&tmp)?;
+            fs::rename(&tmp, &blob)?;
+        }
+        let name = path.strip_prefix(dir).unwrap_or(&path);
+        manifest.insert(name.to_string_lossy().replace('\\', "/"), hash);
+    }
+    fs::write(
+        dir.join(MANIFEST_FILENAME),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+    Ok(deduplicated)
+}
+
+/// Moves the files of every song in the library `dest` into the blob store.
+/// Returns the number of songs stored and of files deduplicated.
+pub fn store_library(dest: &Path) -> anyhow::Result<(usize, usize)> {
+    let mut songs = 0;
+    let mut deduplicated = 0;
+    for (id, entry) in Store::open_read_only(dest).entries() {
+        let dir = dest.join(entry.dir(&id));
+        if !dir.is_dir() {
+            continue;
+        }
+        deduplicated += store_dir(dest, &dir)?;
+        songs += 1;
+    }
+    Ok((songs, deduplicated))
+}
+
+/// Checks that every blob still matches its hash, and that the files listed
+/// in the manifests of the songs are the blobs. Files are compared by size
+/// only, which is cheap since the content is checked through the blobs.
+pub fn verify(dest: &Path) -> anyhow::Result<Verification> {
+    let objects = dest.join(OBJECTS_DIRNAME);
+    let mut verification = Verification::default();
+    if objects.is_dir() {
+        for blob in files(&objects)? {
+            verification.blobs += 1;
+            let name: String = blob
+                .strip_prefix(&objects)
+                .unwrap_or(&blob)
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect();
+            if hash_file(&blob)? != name {
+                verification.corrupt.push(blob);
+            }
+        }
+    }
+    for (dir, manifest) in manifests(dest)? {
+        for (name, hash) in manifest {
+            let path = dir.join(&name);
+            let blob = blob_path(&objects, &hash);
+            let matches = match (fs::metadata(&path), fs::metadata(&blob)) {
+                (Ok(file), Ok(blob)) => file.len() == blob.len(),
+                _ => false,
+            };
+            if !matches {
+                verification.mismatched.push(path);
+            }
+        }
+    }
+    Ok(verification)
+}
+
+/// Removes the blobs no song refers to any more, e.g. after songs were
+/// removed, disposing of them by `disposal`. Returns the number of blobs
+/// removed.
+pub fn prune(dest: &Path, disposal: Disposal) -> anyhow::Result<usize> {
+    let objects = dest.join(OBJECTS_DIRNAME);
+    if !objects.is_dir() {
+        return Ok(0);
+    }
+    let referenced: HashSet<_> = manifests(dest)?
+        .into_iter()
+        .flat_map(|(_, manifest)| manifest.into_values())
+        .map(|hash| blob_path(&objects, &hash))
+        .collect();
+    let mut removed = 0;
+    for blob in files(&objects)? {
+        if !referenced.contains(&blob) {
+            let name = blob.strip_prefix(dest).unwrap_or(&blob);
+            disposal.dispose(dest, &blob, name)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Returns the manifests of the songs in the library `dest` by song
+/// directory.
+fn manifests(dest: &Path) -> anyhow::Result<Vec<(PathBuf, BTreeMap<String, String>)>> {
+    let mut manifests = Vec::new();
+    for (id, entry) in Store::open_read_only(dest).entries() {
+        let dir = dest.join(entry.dir(&id));
+        let path = dir.join(MANIFEST_FILENAME);
+        if !path.exists() {
+            continue;
+        }
+        let content = fs::read_to_string(&path)?;
+        let manifest = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid manifest {}", path.display()))?;
+        manifests.push((dir, manifest));
+    }
+    Ok(manifests)
+}
+
+fn blob_path(objects: &Path, hash: &str) -> PathBuf {
+    let (prefix, rest) = hash.split_at(2.min(hash.len()));
+    objects.join(prefix).join(rest)
+}
+
+/// Returns the files under `dir`, recursively, leaving out the manifest.
+fn files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(self::files(&path)?);
+        } else if path
+            .file_name()
+            .is_some_and(|name| name != MANIFEST_FILENAME)
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Replaces `path` with a hard link to `blob`, atomically.
+fn replace_with_link(blob: &Path, path: &Path) -> anyhow::Result<()> {
+    let tmp = path.with_file_name(format!(
+        ".{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    link_or_copy(blob, &tmp)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Hard links `to` to `from`, or copies it where hard links are not
+/// supported, e.g. across file systems.
+fn link_or_copy(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if let Err(e) = fs::hard_link(from, to) {
+        warn!(error = %e, path = %from.display(), "Failed to hard link; copying instead");
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+// Stable Rust cannot tell hard links apart elsewhere, so files are linked
+// again, which is harmless.
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+// Read-only files cannot be removed on Windows, which would get in the way of
+// removing songs, so blobs are only protected elsewhere.
+#[cfg(unix)]
+fn set_read_only(path: &Path) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn set_read_only(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Moves each downloaded song into the blob store.
+#[derive(Debug)]
+pub struct CasNotifier {
+    dest: PathBuf,
+}
+
+impl CasNotifier {
+    pub fn new(dest: PathBuf) -> Self {
+        Self { dest }
+    }
+}
+
+impl Notifier for CasNotifier {
+    fn song_downloaded(&self, _song: &Song, path: &Path) -> anyhow::Result<()> {
+        store_dir(&self.dest, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::store::Entry;
+
+    fn add_song(dest: &Path, store: &mut Store, id: &str, chart: &str) -> PathBuf {
+        let song: Song = serde_json::from_value(json!({
+            "id": id,
+            "user_id": "user",
+            "title": id,
+            "artist": "RG+Ice",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+        }))
+        .unwrap();
+        store.insert(id, &Entry::new(&song, id)).unwrap();
+        let dir = dest.join(id);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("chart.ksh"), chart).unwrap();
+        fs::write(dir.join("sub").join("music.ogg"), "shared music").unwrap();
+        dir
+    }
+
+    #[test]
+    fn store_and_verify() {
+        let dest = tempdir().unwrap();
+        let mut store = Store::open(dest.path());
+        let a = add_song(dest.path(), &mut store, "a", "chart a");
+        let b = add_song(dest.path(), &mut store, "b", "chart b");
+        drop(store);
+
+        assert_eq!(store_dir(dest.path(), &a).unwrap(), 0);
+        assert_eq!(store_dir(dest.path(), &b).unwrap(), 1);
+        // Storing again changes nothing.
+        assert_eq!(store_library(dest.path()).unwrap(), (2, 0));
+
+        let manifest: BTreeMap<String, String> =
+            serde_json::from_str(&fs::read_to_string(a.join(MANIFEST_FILENAME)).unwrap()).unwrap();
+        assert_eq!(
+            manifest.keys().collect::<Vec<_>>(),
+            ["chart.ksh", "sub/music.ogg"]
+        );
+        assert_eq!(
+            fs::read_to_string(b.join("sub").join("music.ogg")).unwrap(),
+            "shared music"
+        );
+        #[cfg(unix)]
+        assert!(same_file(&a.join("sub/music.ogg"), &b.join("sub/music.ogg")).unwrap());
+
+        let verification = verify(dest.path()).unwrap();
+        assert_eq!(verification.blobs, 3);
+        assert!(verification.corrupt.is_empty());
+        assert!(verification.mismatched.is_empty());
+
+        fs::remove_file(b.join("chart.ksh")).unwrap();
+        assert_eq!(
+            verify(dest.path()).unwrap().mismatched,
+            [b.join("chart.ksh")]
+        );
+
+        fs::remove_dir_all(&b).unwrap();
+        assert_eq!(prune(dest.path(), Disposal::Delete).unwrap(), 1);
+        assert_eq!(verify(dest.path()).unwrap().blobs, 2);
+    }
+}
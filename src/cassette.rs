@@ -0,0 +1,171 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use attohttpc::header::HeaderMap;
+use attohttpc::header::HeaderName;
+use attohttpc::header::HeaderValue;
+use attohttpc::StatusCode;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::http::Response;
+
+/// Responses of the servers kept in a directory, to test against a
+/// [`crate::Downloader`] without a network or a mock of each endpoint.
+///
+/// In [`Cassette::record`] mode, requests go to the servers as usual and
+/// each response is saved as `<key>.json` with its body in `<key>.body`,
+/// where the key is a hash of the method and URL. In [`Cassette::replay`]
+/// mode, the saved responses are returned instead and requests that were not
+/// recorded fail.
+#[derive(Debug, Clone)]
+pub struct Cassette {
+    dir: PathBuf,
+    mode: Mode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Record,
+    Replay,
+}
+
+/// A recorded response without its body.
+#[derive(Debug, Serialize, Deserialize)]
+struct Recording {
+    method: String,
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl Cassette {
+    /// Saves the responses of the servers in `dir`, replacing earlier
+    /// recordings of the same requests.
+    pub fn record<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            mode: Mode::Record,
+        }
+    }
+
+    /// Answers requests with the responses saved in `dir`.
+    pub fn replay<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            mode: Mode::Replay,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Sends the request with `send` and records the response, or returns
+    /// the recorded response without sending anything.
+    pub(crate) fn play(
+        &self,
+        method: &str,
+        url: &str,
+        send: impl FnOnce() -> anyhow::Result<Response>,
+    ) -> anyhow::Result<Response> {
+        let key = key(method, url);
+        let meta = self.dir.join(format!("{key}.json"));
+        let body = self.dir.join(format!("{key}.body"));
+        match self.mode {
+            Mode::Replay => {
+                let recording: Recording = fs::read(&meta)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|content| Ok(serde_json::from_slice(&content)?))
+                    .with_context(|| format!("No recording of {method} {url}"))?;
+                let mut headers = HeaderMap::new();
+                for (name, value) in &recording.headers {
+                    headers.append(
+                        HeaderName::from_bytes(name.as_bytes())?,
+                        HeaderValue::from_str(value)?,
+                    );
+                }
+                Ok(Response {
+                    status: StatusCode::from_u16(recording.status)?,
+                    headers,
+                    body: fs::read(&body)
+                        .with_context(|| format!("Failed to read {}", body.display()))?,
+                })
+            }
+            Mode::Record => {
+                let resp = send()?;
+                let headers = resp
+                    .headers
+                    .iter()
+                    .map(|(name, value)| {
+                        let value = value
+                            .to_str()
+                            .map_err(|_| anyhow!("Header {name} of {url} is not text"))?;
+                        Ok((name.to_string(), value.to_owned()))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                let recording = Recording {
+                    method: method.to_owned(),
+                    url: url.to_owned(),
+                    status: resp.status.as_u16(),
+                    headers,
+                };
+                fs::create_dir_all(&self.dir)?;
+                fs::write(&body, &resp.body)
+                    .with_context(|| format!("Failed to write {}", body.display()))?;
+                fs::write(&meta, serde_json::to_string_pretty(&recording)?)
+                    .with_context(|| format!("Failed to write {}", meta.display()))?;
+                Ok(resp)
+            }
+        }
+    }
+}
+
+fn key(method: &str, url: &str) -> String {
+    let hash = Sha256::digest(format!("{method} {url}"));
+    hex::encode(&hash[..8])
+}
+
+#[cfg(test)]
+mod test {
+    use httpmock::MockServer;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::http::Client;
+    use crate::http::HttpConfig;
+
+    #[test]
+    fn record_and_replay() {
+        let dir = tempdir().unwrap();
+        let server = MockServer::start();
+        let mut songs = server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data":[]}"#);
+        });
+        let url = server.url("/app/songs");
+
+        let mut recorder = Client::new(&HttpConfig::default());
+        recorder.cassette(Cassette::record(dir.path()));
+        assert_eq!(recorder.get(&url).unwrap().bytes(), br#"{"data":[]}"#);
+        songs.assert_hits(1);
+        songs.delete();
+
+        let mut player = Client::new(&HttpConfig::default());
+        player.cassette(Cassette::replay(dir.path()));
+        let resp = player.get(&url).unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        assert_eq!(resp.bytes(), br#"{"data":[]}"#);
+
+        let error = player.head(&url).unwrap_err();
+        assert_eq!(error.to_string(), format!("No recording of HEAD {url}"));
+    }
+}
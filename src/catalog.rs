@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::Song;
+
+/// Snapshot of the whole remote song index, written by the catalog command
+/// for offline planning, diffing between dates, and other tools.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Catalog {
+    pub fetched_at: DateTime<Utc>,
+
+    /// Server the songs were listed from.
+    pub base_url: String,
+
+    /// Songs in listing order.
+    pub songs: Vec<Song>,
+}
+
+impl Catalog {
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Invalid catalog {}", path.display()))
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, self.to_json()?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::mirror::Mirror;
+    use crate::store::Entry;
+    use crate::store::Store;
+    use crate::Downloader;
+
+    #[test]
+    fn snapshot_catalog() {
+        let library = tempdir().unwrap();
+        let mut store = Store::open(library.path());
+        for (id, uploaded_at) in [
+            ("a", "2023-09-01 00:00:00"),
+            ("b", "2023-09-02 00:00:00"),
+            ("c", "2023-09-03 00:00:00"),
+        ] {
+            let song: Song = serde_json::from_value(json!({
+                "id": id,
+                "user_id": "user",
+                "title": id,
+                "artist": "RG+Ice",
+                "uploaded_at": uploaded_at,
+                "updated_at": uploaded_at,
+            }))
+            .unwrap();
+            store.insert(id, &Entry::new(&song, id)).unwrap();
+        }
+        drop(store);
+        let mirror = Mirror::bind(library.path(), "127.0.0.1:0").unwrap();
+        let addr = mirror.local_addr().unwrap();
+        thread::spawn(move || mirror.run());
+
+        let dest = tempdir().unwrap();
+        let catalog = Downloader::builder()
+            .dest(dest.path())
+            .base_url(format!("http://{addr}"))
+            .per_page(2)
+            .build()
+            .snapshot()
+            .unwrap();
+        assert_eq!(catalog.base_url, format!("http://{addr}"));
+        let ids: Vec<_> = catalog.songs.iter().map(|song| song.id.as_str()).collect();
+        assert_eq!(ids, ["c", "b", "a"]);
+        // Nothing is downloaded.
+        assert_eq!(fs::read_dir(dest.path()).unwrap().count(), 0);
+
+        let path = dest.path().join("catalog.json");
+        catalog.write(&path).unwrap();
+        let read = Catalog::read(&path).unwrap();
+        assert_eq!(read.songs.len(), 3);
+        assert_eq!(read.songs[0].uploaded_at, catalog.songs[0].uploaded_at);
+    }
+}
@@ -0,0 +1,252 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::filter::Filter;
+use crate::notify::Notifier;
+use crate::playlist::Playlist;
+use crate::store::Store;
+use crate::usc::MapDatabase;
+use crate::DownloadReport;
+use crate::Song;
+
+const COLLECTIONS_FILENAME: &str = "collections.toml";
+
+/// A USC collection of the local songs that match some criteria.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Collection {
+    pub name: String,
+
+    /// Uploader IDs or names; see [`Filter::users`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_level: Option<u8>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_level: Option<u8>,
+
+    /// Local tags that songs must all have; see [`Filter::tags`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+
+    /// Whether only songs starred locally belong to the collection.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub starred: bool,
+
+    /// ID of the Nautica playlist the collection mirrors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub playlist: Option<String>,
+
+    /// IDs of the songs in the collection, if it is limited to certain songs.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub songs: Vec<String>,
+}
+
+impl Collection {
+    /// Creates a collection of the songs in `playlist`, named after it.
+    pub fn from_playlist(playlist: &Playlist) -> Self {
+        Self {
+            name: playlist.name.clone(),
+            playlist: Some(playlist.id.clone()),
+            songs: playlist.songs.iter().map(|song| song.id.clone()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether the songs in the collection depend on their local tags
+    /// or star, which change without a sync.
+    pub fn uses_local_marks(&self) -> bool {
+        !self.tags.is_empty() || self.starred
+    }
+
+    fn filter(&self) -> Filter {
+        Filter {
+            users: self.users.clone(),
+            query: self.query.clone(),
+            min_level: self.min_level,
+            max_level: self.max_level,
+            tags: self.tags.clone(),
+            starred: self.starred,
+            allowlist: (!self.songs.is_empty())
+                .then(|| self.songs.iter().map(String::as_str).collect()),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the directories of the songs in `dest` that belong to the
+    /// collection.
+    fn song_dirs(&self, dest: &Path, store: &Store) -> Vec<PathBuf> {
+        let filter = self.filter();
+        store
+            .entries()
+            .into_iter()
+            .filter(|(id, entry)| filter.matches_entry(id, entry))
+            .map(|(id, entry)| dest.join(entry.dir(&id)))
+            .filter(|dir| dir.is_dir())
+            .collect()
+    }
+}
+
+/// Collection definitions, kept in `collections.toml` in the library so that
+/// collections can be updated after each sync.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Collections {
+    #[serde(default, rename = "collection")]
+    collections: Vec<Collection>,
+}
+
+impl Collections {
+    pub fn load(dest: &Path) -> anyhow::Result<Self> {
+        let path = dest.join(COLLECTIONS_FILENAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Invalid {}", path.display()))
+    }
+
+    pub fn save(&self, dest: &Path) -> anyhow::Result<()> {
+        fs::write(dest.join(COLLECTIONS_FILENAME), toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Collection> {
+        self.collections.iter()
+    }
+
+    /// Adds `collection`, replacing any collection with the same name.
+    pub fn insert(&mut self, collection: Collection) {
+        self.remove(&collection.name);
+        self.collections.push(collection);
+    }
+
+    /// Removes the collection named `name`, returning whether it existed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.collections.len();
+        self.collections.retain(|c| c.name != name);
+        self.collections.len() != len
+    }
+}
+
+/// Writes `collection` to the USC database, adding matching songs that USC
+/// does not know yet. Returns the number of songs in the collection.
+pub fn sync(dest: &Path, db: &mut MapDatabase, collection: &Collection) -> anyhow::Result<usize> {
+    let store = Store::open_read_only(dest);
+    let dirs = collection.song_dirs(dest, &store);
+    db.set_collection(&collection.name, &dirs)
+}
+
+/// Brings all collections up to date after each sync that downloaded songs.
+#[derive(Debug)]
+pub struct CollectionsNotifier {
+    dest: PathBuf,
+    db: PathBuf,
+}
+
+impl CollectionsNotifier {
+    pub fn new(dest: PathBuf, db: PathBuf) -> Self {
+        Self { dest, db }
+    }
+}
+
+impl Notifier for CollectionsNotifier {
+    fn song_downloaded(&self, _song: &Song, _path: &Path) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn run_finished(&self, report: &DownloadReport) -> anyhow::Result<()> {
+        let collections = Collections::load(&self.dest)?;
+        if report.downloaded.is_empty() || collections.collections.is_empty() {
+            return Ok(());
+        }
+        let mut db = MapDatabase::open(&self.db)?;
+        for collection in collections.iter() {
+            sync(&self.dest, &mut db, collection)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::store::Entry;
+
+    #[test]
+    fn save_and_load() {
+        let dest = tempdir().unwrap();
+        let mut collections = Collections::default();
+        collections.insert(Collection {
+            name: String::from("Lv18+"),
+            min_level: Some(18),
+            ..Default::default()
+        });
+        collections.insert(Collection {
+            name: String::from("Ixiot"),
+            users: vec![String::from("Ixiot")],
+            ..Default::default()
+        });
+        collections.insert(Collection {
+            name: String::from("Lv18+"),
+            min_level: Some(19),
+            ..Default::default()
+        });
+        collections.save(dest.path()).unwrap();
+
+        let mut loaded = Collections::load(dest.path()).unwrap();
+        assert_eq!(loaded.collections.len(), 2);
+        assert_eq!(loaded.collections[1].min_level, Some(19));
+        assert!(loaded.remove("Ixiot"));
+        assert!(!loaded.remove("Ixiot"));
+    }
+
+    #[test]
+    fn collection_of_playlist() {
+        let playlist: Playlist = serde_json::from_value(serde_json::json!({
+            "id": "p1",
+            "name": "Practice",
+            "songs": [{
+                "id": "a",
+                "user_id": "user",
+                "title": "title",
+                "artist": "artist",
+                "uploaded_at": "2023-09-01 00:00:00",
+                "updated_at": "2023-09-01 00:00:00",
+            }],
+        }))
+        .unwrap();
+        let collection = Collection::from_playlist(&playlist);
+        assert_eq!(collection.name, "Practice");
+
+        let dest = tempdir().unwrap();
+        let mut store = Store::open(dest.path());
+        for id in ["a", "b"] {
+            let entry = Entry::new(&playlist.songs[0], id);
+            store.insert(id, &entry).unwrap();
+            fs::create_dir(dest.path().join(id)).unwrap();
+        }
+        assert_eq!(
+            collection.song_dirs(dest.path(), &store),
+            [dest.path().join("a")]
+        );
+
+        let mut collections = Collections::default();
+        collections.insert(collection.clone());
+        collections.save(dest.path()).unwrap();
+        let loaded = Collections::load(dest.path()).unwrap();
+        assert_eq!(loaded.iter().next(), Some(&collection));
+    }
+}
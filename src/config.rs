@@ -0,0 +1,239 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::api::Routes;
+use crate::copies::CopyConfig;
+use crate::dedup::Preference;
+use crate::email::EmailConfig;
+use crate::http::HttpConfig;
+use crate::layout::Layout;
+use crate::notify::Event;
+use crate::queue::QueueConfig;
+use crate::remote::RemoteConfig;
+use crate::s3::S3Config;
+use crate::transcode::TranscodeConfig;
+use crate::trash;
+use crate::trash::Disposal;
+use crate::OnError;
+
+/// Settings loaded from a TOML configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Servers to sync from in order of preference, e.g. the official one
+    /// followed by community mirrors.
+    pub base_urls: Vec<String>,
+
+    /// Routes of the API, for self-hosted forks of Nautica.
+    pub api: Routes,
+
+    /// Shell command to run after each downloaded song.
+    pub hook: Option<String>,
+
+    /// Naming of song directories.
+    pub layout: Option<Layout>,
+
+    /// Directory to download songs into before moving them to the library.
+    pub temp_dir: Option<PathBuf>,
+
+    /// Whether a run stops at the first song that fails to download.
+    pub on_error: Option<OnError>,
+
+    /// Songs failing in a row after which a run stops as an outage; 0 never
+    /// stops.
+    pub max_consecutive_failures: Option<u32>,
+
+    /// Seconds a song may take to download before it is given up on.
+    pub max_duration_per_song: Option<u64>,
+
+    /// Regenerate the symlink views after each sync.
+    pub views: bool,
+
+    /// Store the files of downloaded songs as content-addressed blobs in
+    /// `.objects`, hard linked into the song directories.
+    pub cas: bool,
+
+    /// Check the jackets of downloaded songs and make thumbnails of them in
+    /// `.thumbnails`.
+    pub thumbnails: bool,
+
+    /// Record the background video links of downloaded songs.
+    pub videos: bool,
+
+    /// Shell command to fetch each background video link; implies `videos`.
+    pub video_fetcher: Option<String>,
+
+    /// Transcode WAV audio to OGG after download when present.
+    pub transcode: Option<TranscodeConfig>,
+
+    /// Move what `remove` and `cas prune` delete into `.trash` instead, as
+    /// with `--use-trash`.
+    pub use_trash: bool,
+
+    /// Days trashed files are kept for before they are deleted for good
+    /// [default: 30].
+    pub trash_retention_days: Option<u64>,
+
+    /// Remove downloaded songs whose content is already in the library.
+    pub skip_duplicates: bool,
+
+    /// Which of the uploads of the same song by different users to download.
+    pub prefer: Option<Preference>,
+
+    /// Download songs updated on the server again if their archive changed.
+    pub refresh_updated: bool,
+
+    /// Measure the loudness of downloaded songs.
+    pub loudness: bool,
+
+    /// Write ReplayGain tags into the music of downloaded songs; implies
+    /// `loudness`.
+    pub replaygain: bool,
+
+    /// Upload downloaded songs to an S3-compatible bucket.
+    pub s3: Option<S3Config>,
+
+    /// Upload downloaded songs to a WebDAV or SFTP destination.
+    pub remote: Option<RemoteConfig>,
+
+    /// USC song database to add downloaded songs to.
+    pub usc_db: Option<PathBuf>,
+
+    /// Connection reuse for the requests of a sync.
+    pub http: HttpConfig,
+
+    /// Order of the songs in the download queue.
+    pub queue: QueueConfig,
+
+    /// Other destinations each downloaded song is written to.
+    pub copies: Vec<CopyConfig>,
+
+    pub notifications: NotificationConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// Show a desktop notification for each new song in watch mode.
+    pub desktop: bool,
+
+    pub webhooks: Vec<WebhookConfig>,
+
+    pub discord: Vec<DiscordConfig>,
+
+    /// Email a summary of each run that downloaded or failed songs.
+    pub email: Option<EmailConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+
+    /// Events to send; all events when omitted.
+    #[serde(default = "all_events")]
+    pub events: Vec<Event>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordConfig {
+    pub url: String,
+
+    /// Songs per message, from 1 to 10.
+    #[serde(default = "default_discord_batch")]
+    pub batch: usize,
+}
+
+fn default_discord_batch() -> usize {
+    10
+}
+
+fn all_events() -> Vec<Event> {
+    vec![Event::SongDownloaded, Event::RunFinished]
+}
+
+impl Config {
+    /// Loads the configuration from `path`, falling back to the defaults if
+    /// the file does not exist.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Returns how `remove` and `cas prune` dispose of files, moving them into
+    /// the trash if `use_trash` is set here or on the command line.
+    pub fn disposal(&self, use_trash: bool) -> Disposal {
+        if use_trash || self.use_trash {
+            Disposal::Trash
+        } else {
+            Disposal::Delete
+        }
+    }
+
+    /// Returns the time trashed files are kept for.
+    pub fn trash_retention(&self) -> Duration {
+        self.trash_retention_days
+            .map_or(trash::DEFAULT_RETENTION, |days| {
+                Duration::from_secs(days * 24 * 60 * 60)
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config: Config = toml::from_str("[notifications]\ndesktop = true\n").unwrap();
+        assert!(config.notifications.desktop);
+
+        let config: Config = toml::from_str("").unwrap();
+        assert!(!config.notifications.desktop);
+        assert_eq!(config.layout, None);
+        assert!(config.http.keep_alive);
+        assert!(config.http.http2);
+
+        let config: Config = toml::from_str("[http]\npool_size = 8\nhttp2 = false\n").unwrap();
+        assert_eq!(config.http.pool_size, 8);
+        assert_eq!(config.http.idle_timeout, 30);
+        assert!(!config.http.http2);
+
+        let pins = "[http.pins]\n\"ksm.dev\" = [\"sha256//glT6Wo+YNUVltq0ZsQmaWXWsz2Q6gjaOXnC1zOGqOVA=\"]\n";
+        let config: Config = toml::from_str(pins).unwrap();
+        assert_eq!(config.http.pins["ksm.dev"].len(), 1);
+        assert!(toml::from_str::<Config>("[http.pins]\n\"ksm.dev\" = [\"ksm\"]\n").is_err());
+
+        let config: Config = toml::from_str("layout = \"readable\"\n").unwrap();
+        assert_eq!(config.layout, Some(Layout::Readable));
+
+        assert!(toml::from_str::<Config>("[notifications]\ndesktp = true\n").is_err());
+
+        let config: Config = toml::from_str(
+            r#"
+            [[notifications.webhooks]]
+            url = "http://localhost/a"
+
+            [[notifications.webhooks]]
+            url = "http://localhost/b"
+            events = ["run_finished"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.notifications.webhooks[0].events, all_events());
+        assert_eq!(
+            config.notifications.webhooks[1].events,
+            [Event::RunFinished]
+        );
+    }
+}
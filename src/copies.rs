@@ -0,0 +1,98 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::copy_dir;
+
+/// Settings of a `[[copies]]` entry of the configuration file: another
+/// destination each downloaded song is written to in the same pass, e.g. an
+/// archive on a NAS next to the library on a fast disk.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CopyConfig {
+    /// Directory to write songs into.
+    pub path: PathBuf,
+
+    /// Keep the archive of each song as downloaded, as `<dir>.zip`, instead
+    /// of its extracted files.
+    #[serde(default)]
+    pub zip: bool,
+}
+
+impl CopyConfig {
+    /// Writes the song in the directory `dir` of the library, extracted into
+    /// `extracted` from `archive`, replacing an earlier copy of it. The copy
+    /// only appears once complete.
+    pub fn write(&self, dir: &str, archive: &[u8], extracted: &Path) -> anyhow::Result<()> {
+        let target = if self.zip {
+            self.path.join(format!("{dir}.zip"))
+        } else {
+            self.path.join(dir)
+        };
+        let mut partial = OsString::from(target.as_os_str());
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let result = if self.zip {
+            fs::write(&partial, archive)
+        } else {
+            copy_dir(extracted, &partial)
+        };
+        result
+            .and_then(|()| {
+                if target.is_dir() {
+                    fs::remove_dir_all(&target)?;
+                }
+                fs::rename(&partial, &target)
+            })
+            .with_context(|| format!("Failed to write {}", target.display()))
+            .inspect_err(|_| {
+                let _ = fs::remove_dir_all(&partial).or_else(|_| fs::remove_file(&partial));
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn write_copies() {
+        let extracted = tempdir().unwrap();
+        fs::write(extracted.path().join("chart.ksh"), "title=Outbreak").unwrap();
+        let nas = tempdir().unwrap();
+        let ssd = tempdir().unwrap();
+        let zipped = CopyConfig {
+            path: nas.path().to_owned(),
+            zip: true,
+        };
+        let copy = CopyConfig {
+            path: ssd.path().to_owned(),
+            zip: false,
+        };
+        for _ in 0..2 {
+            zipped
+                .write("Ixiot/Outbreak", b"zip", extracted.path())
+                .unwrap();
+            copy.write("Ixiot/Outbreak", b"zip", extracted.path())
+                .unwrap();
+        }
+        assert_eq!(
+            fs::read(nas.path().join("Ixiot/Outbreak.zip")).unwrap(),
+            b"zip"
+        );
+        assert_eq!(
+            fs::read_to_string(ssd.path().join("Ixiot/Outbreak/chart.ksh")).unwrap(),
+            "title=Outbreak"
+        );
+        assert_eq!(fs::read_dir(ssd.path().join("Ixiot")).unwrap().count(), 1);
+    }
+}
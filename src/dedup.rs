@@ -0,0 +1,332 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::bail;
+use serde::Deserialize;
+use sha1_smol::Sha1;
+
+use crate::ksh;
+use crate::search::normalize;
+use crate::store::Entry;
+use crate::store::Store;
+use crate::Song;
+
+pub(crate) const AUDIO_EXTENSIONS: &[&str] = &["ogg", "mp3", "wav", "flac", "opus"];
+
+/// Returns a hash of the content of the song in `dir`, or `None` if it has no
+/// charts.
+///
+/// Only chart bodies are hashed, not their headers, since re-uploads often
+/// change the title or credits but not the notes. Audio files are hashed as
+/// they are. The result does not depend on file names.
+pub fn fingerprint(dir: &Path) -> anyhow::Result<Option<String>> {
+    let charts = ksh::charts(dir)?;
+    if charts.is_empty() {
+        return Ok(None);
+    }
+    let mut hashes = Vec::new();
+    for chart in charts {
+        let content = ksh::decode(&fs::read(&chart)?).into_owned();
+        let body: Vec<_> = content
+            .lines()
+            .map(str::trim_end)
+            .skip_while(|line| *line != "--")
+            .collect();
+        hashes.push(format!("chart {}", Sha1::from(body.join("\n")).digest()));
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_audio = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()));
+        if is_audio {
+            hashes.push(format!("audio {}", Sha1::from(fs::read(&path)?).digest()));
+        }
+    }
+    hashes.sort();
+    Ok(Some(Sha1::from(hashes.join("\n")).digest().to_string()))
+}
+
+/// Returns the key under which uploads count as the same song: the title and
+/// artist, normalized and with runs of whitespace folded.
+pub fn song_key(title: &str, artist: &str) -> String {
+    let fold = |text: &str| {
+        normalize(text)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    format!("{}\t{}", fold(title), fold(artist))
+}
+
+/// Groups the songs in the library `dest` that have the same content, or the
+/// same title and artist but different uploaders, oldest upload first.
+/// Fingerprints missing from the store are computed and saved.
+pub fn find_duplicates(dest: &Path) -> anyhow::Result<Vec<Vec<(String, Entry)>>> {
+    let mut store = Store::open(dest);
+    let mut entries = BTreeMap::new();
+    let mut by_fingerprint: HashMap<String, Vec<String>> = HashMap::new();
+    let mut by_key: HashMap<String, Vec<String>> = HashMap::new();
+    for (id, mut entry) in store.entries() {
+        if entry.fingerprint.is_none() {
+            entry.fingerprint = fingerprint(&dest.join(entry.dir(&id)))?;
+            store.insert(&id, &entry)?;
+        }
+        if let Some(fingerprint) = entry.fingerprint.clone() {
+            by_fingerprint
+                .entry(fingerprint)
+                .or_default()
+                .push(id.clone());
+        }
+        by_key
+            .entry(song_key(&entry.title, &entry.artist))
+            .or_default()
+            .push(id.clone());
+        entries.insert(id, entry);
+    }
+    let cross_user = by_key.into_values().filter(|ids| {
+        ids.iter()
+            .map(|id| &entries[id].user_id)
+            .collect::<HashSet<_>>()
+            .len()
+            > 1
+    });
+
+    // Groups sharing a song are merged.
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    for ids in by_fingerprint.into_values().chain(cross_user) {
+        let mut merged = ids;
+        groups.retain(|group| {
+            let overlaps = group.iter().any(|id| merged.contains(id));
+            if overlaps {
+                merged.extend(group.iter().cloned());
+            }
+            !overlaps
+        });
+        merged.sort();
+        merged.dedup();
+        groups.push(merged);
+    }
+    let mut groups: Vec<Vec<_>> = groups
+        .into_iter()
+        .filter(|group| group.len() > 1)
+        .map(|group| {
+            let mut group: Vec<_> = group
+                .into_iter()
+                .map(|id| {
+                    let entry = entries[&id].clone();
+                    (id, entry)
+                })
+                .collect();
+            group.sort_by_key(|(_, entry)| entry.uploaded_at);
+            group
+        })
+        .collect();
+    groups.sort_by(|a, b| a[0].0.cmp(&b[0].0));
+    Ok(groups)
+}
+
+/// Which of the uploads of the same song by different users a sync keeps.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Preference {
+    /// The one uploaded first.
+    Earliest,
+
+    /// The one downloaded most often from the server.
+    MostDownloaded,
+
+    /// The one by this uploader ID or name (case-insensitive), or else the
+    /// one uploaded first.
+    Uploader(String),
+}
+
+impl Preference {
+    /// Picks the song to keep among `songs`, uploads of the same song.
+    fn pick<'a>(&self, songs: &[&'a Song]) -> Option<&'a Song> {
+        let earliest = songs.iter().copied().min_by_key(|song| song.uploaded_at);
+        match self {
+            Self::Earliest => earliest,
+            Self::MostDownloaded => songs
+                .iter()
+                .copied()
+                .max_by_key(|song| (song.downloads, Reverse(song.uploaded_at))),
+            Self::Uploader(name) => songs
+                .iter()
+                .copied()
+                .find(|song| {
+                    song.user_id.eq_ignore_ascii_case(name)
+                        || song
+                            .user
+                            .as_ref()
+                            .is_some_and(|user| user.name.eq_ignore_ascii_case(name))
+                })
+                .or(earliest),
+        }
+    }
+}
+
+impl FromStr for Preference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "earliest" => Ok(Self::Earliest),
+            "most-downloaded" => Ok(Self::MostDownloaded),
+            _ => match s.strip_prefix("uploader:") {
+                Some(name) if !name.is_empty() => Ok(Self::Uploader(name.to_owned())),
+                _ => bail!(
+                    "Unknown preference {s:?}, expected \"earliest\", \"most-downloaded\", \
+                     or \"uploader:<ID or name>\""
+                ),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for Preference {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+/// Returns the songs among `songs` that are the same song as an upload by a
+/// different user, mapped to the ID of the one kept instead: the one in
+/// `store` if there is one, or else the one `preference` picks. Songs
+/// already in `store` are never left out.
+pub fn same_songs(
+    songs: &[Song],
+    store: &Store,
+    preference: &Preference,
+) -> HashMap<String, String> {
+    let mut local = HashMap::new();
+    for (id, entry) in store.entries() {
+        local
+            .entry(song_key(&entry.title, &entry.artist))
+            .or_insert((id, entry.user_id));
+    }
+    let mut groups: HashMap<String, Vec<&Song>> = HashMap::new();
+    for song in songs.iter().filter(|song| !store.contains(&song.id)) {
+        groups
+            .entry(song_key(&song.title, &song.artist))
+            .or_default()
+            .push(song);
+    }
+    let mut same = HashMap::new();
+    for (key, group) in groups {
+        let (kept_id, kept_user) = match local.get(&key) {
+            Some((id, user_id)) => (id.clone(), user_id.clone()),
+            None => {
+                let Some(song) = preference.pick(&group) else {
+                    continue;
+                };
+                (song.id.clone(), song.user_id.clone())
+            }
+        };
+        for song in group {
+            if song.user_id != kept_user {
+                same.insert(song.id.clone(), kept_id.clone());
+            }
+        }
+    }
+    same
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn song(id: &str, user_id: &str, title: &str, uploaded_at: &str, downloads: u64) -> Song {
+        serde_json::from_value(json!({
+            "id": id,
+            "user_id": user_id,
+            "title": title,
+            "artist": "RG+Ice",
+            "uploaded_at": uploaded_at,
+            "updated_at": uploaded_at,
+            "downloads": downloads,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn fingerprint_ignores_header_and_names() {
+        let dest = tempdir().unwrap();
+        let a = dest.path().join("a");
+        let b = dest.path().join("b");
+        fs::create_dir(&a).unwrap();
+        fs::create_dir(&b).unwrap();
+        fs::write(
+            a.join("chart.ksh"),
+            "title=Song\r\n--\r\n1000|00|--\r\n--\r\n",
+        )
+        .unwrap();
+        fs::write(a.join("song.ogg"), "audio").unwrap();
+        fs::write(
+            b.join("exh.ksh"),
+            "title=Song (fixed)\n--\n1000|00|--\n--\n",
+        )
+        .unwrap();
+        fs::write(b.join("music.ogg"), "audio").unwrap();
+
+        let fingerprint_a = fingerprint(&a).unwrap();
+        assert!(fingerprint_a.is_some());
+        assert_eq!(fingerprint_a, fingerprint(&b).unwrap());
+
+        fs::write(b.join("music.ogg"), "other audio").unwrap();
+        assert_ne!(fingerprint_a, fingerprint(&b).unwrap());
+        assert_eq!(fingerprint(dest.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn prefer_one_upload_of_the_same_song() {
+        let dest = tempdir().unwrap();
+        let songs = [
+            song("a", "u1", "Outbreak", "2023-09-01 00:00:00", 5),
+            song("b", "u2", "ＯＵＴＢＲＥＡＫ ", "2023-09-02 00:00:00", 50),
+            song("c", "u1", "Outbreak (long)", "2023-09-03 00:00:00", 1),
+            song("d", "u1", "outbreak", "2023-09-04 00:00:00", 1),
+        ];
+        let store = Store::open(dest.path());
+        let same = |preference: &str| {
+            let mut same: Vec<_> = same_songs(&songs, &store, &preference.parse().unwrap())
+                .into_iter()
+                .collect();
+            same.sort();
+            same
+        };
+        let pair = |id: &str, kept: &str| (id.to_owned(), kept.to_owned());
+        assert_eq!(same("earliest"), [pair("b", "a")]);
+        assert_eq!(same("most-downloaded"), [pair("a", "b"), pair("d", "b")]);
+        assert_eq!(same("uploader:U2"), [pair("a", "b"), pair("d", "b")]);
+        assert!("uploader:".parse::<Preference>().is_err());
+
+        // The song in the library is kept.
+        let mut store = store;
+        store
+            .insert(
+                "old",
+                &Entry::new(
+                    &song("old", "u3", "Outbreak", "2023-08-01 00:00:00", 0),
+                    "old",
+                ),
+            )
+            .unwrap();
+        let mut same: Vec<_> = same_songs(&songs, &store, &Preference::MostDownloaded)
+            .into_iter()
+            .collect();
+        same.sort();
+        assert_eq!(same, [pair("a", "old"), pair("b", "old"), pair("d", "old")]);
+    }
+}
@@ -0,0 +1,42 @@
+use std::io;
+use std::path::Path;
+
+/// Returns the number of bytes available to unprivileged users on the
+/// filesystem containing `path`, or `None` where this is not supported.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> io::Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid C string and `stat` is only read after
+    // statvfs reports success.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn available_space_of_temp_dir() {
+        let dir = tempdir().unwrap();
+        assert!(available_space(dir.path()).unwrap().unwrap() > 0);
+        assert!(available_space(&dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,359 @@
+use std::fmt;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use attohttpc::StatusCode;
+use tracing::info;
+use tracing::warn;
+use url::Url;
+
+use crate::http::DeadlineExceeded;
+use crate::http::Response;
+
+/// Attempts of a request on one server before moving on to the next.
+const ATTEMPTS_PER_MIRROR: u32 = 3;
+
+/// Wait before the second attempt on a server, doubled for each later one.
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Failed requests in a row after which a server is paused when none is set.
+pub const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+
+/// Time a server is paused for when none is set.
+pub const DEFAULT_BREAKER_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Error of a request the server answered with an unsuccessful status, so
+/// that callers can tell a missing song from a server in trouble.
+#[derive(Debug)]
+pub struct HttpStatus {
+    pub url: String,
+    pub status: StatusCode,
+}
+
+impl fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} returned {}", self.url, self.status)
+    }
+}
+
+impl std::error::Error for HttpStatus {}
+
+/// Ordered list of servers with the same API, e.g. the official one followed
+/// by community mirrors.
+///
+/// A request that keeps failing on one server is sent to the next one with
+/// the same path. The server that last answered is tried first, so a dead
+/// server costs its retries only once per run.
+///
+/// After a burst of failed requests, a server is paused for a cooldown
+/// period: requests go to the other servers, or wait for the cooldown to end
+/// if all are paused, so that a struggling server is not hammered. The next
+/// request then tries the server again, which pauses it again if it fails.
+#[derive(Debug)]
+pub struct Mirrors {
+    base_urls: Vec<String>,
+    current: AtomicUsize,
+    breakers: Vec<Mutex<Breaker>>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+/// Circuit breaker of one server.
+#[derive(Debug, Default)]
+struct Breaker {
+    /// Failed requests in a row.
+    failures: u32,
+
+    /// End of the cooldown once the server was paused. Kept after it ends
+    /// until a request succeeds, so that a single failure pauses it again.
+    paused_until: Option<Instant>,
+}
+
+impl Mirrors {
+    /// Uses `base_urls` in order. There must be at least one.
+    pub fn new(base_urls: Vec<String>) -> Self {
+        assert!(!base_urls.is_empty(), "at least one base URL is required");
+        let base_urls: Vec<_> = base_urls
+            .into_iter()
+            .map(|url| url.trim_end_matches('/').to_owned())
+            .collect();
+        Self {
+            breakers: base_urls.iter().map(|_| Mutex::default()).collect(),
+            base_urls,
+            current: AtomicUsize::new(0),
+            threshold: DEFAULT_BREAKER_THRESHOLD,
+            cooldown: DEFAULT_BREAKER_COOLDOWN,
+        }
+    }
+
+    /// Pauses a server for `cooldown` once `threshold` requests in a row
+    /// failed on it. A threshold of 0 never pauses.
+    pub fn breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.threshold = threshold;
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Returns the base URL requests are currently sent to.
+    pub fn current(&self) -> &str {
+        &self.base_urls[self.current.load(Ordering::Relaxed)]
+    }
+
+    /// Converts a link returned by one of the servers, such as the next page
+    /// of a listing, to a path that can be requested from any of them.
+    pub fn path_of(&self, link: &str) -> String {
+        if let Some(path) = self
+            .base_urls
+            .iter()
+            .find_map(|base_url| link.strip_prefix(base_url.as_str()))
+        {
+            return path.to_owned();
+        }
+        match Url::parse(link) {
+            Ok(url) => match url.query() {
+                Some(query) => format!("{}?{query}", url.path()),
+                None => url.path().to_owned(),
+            },
+            Err(_) => link.to_owned(),
+        }
+    }
+
+    /// Sends the request made by `request` for the URL of `path` on each
+    /// server in turn until one answers successfully, returning the last
+    /// error if none does. Server errors and timeouts are retried with
+    /// backoff; an unsuccessful status ends in an [`HttpStatus`].
+    pub fn send<F>(&self, path: &str, request: F) -> anyhow::Result<Response>
+    where
+        F: Fn(&str) -> anyhow::Result<Response>,
+    {
+        loop {
+            match self.send_once(path, &request) {
+                Ok(resp) => return resp,
+                Err(resume_at) => {
+                    let wait = resume_at.saturating_duration_since(Instant::now());
+                    info!(
+                        wait = %humantime::format_duration(Duration::from_secs(wait.as_secs())),
+                        "All servers are paused, waiting"
+                    );
+                    thread::sleep(wait);
+                }
+            }
+        }
+    }
+
+    /// Sends the request to each server that is not paused in turn. Fails
+    /// with the end of the earliest cooldown if all are paused.
+    fn send_once<F>(&self, path: &str, request: &F) -> Result<anyhow::Result<Response>, Instant>
+    where
+        F: Fn(&str) -> anyhow::Result<Response>,
+    {
+        let start = self.current.load(Ordering::Relaxed);
+        let mut last_error = None;
+        let mut resume_at: Option<Instant> = None;
+        for i in 0..self.base_urls.len() {
+            let index = (start + i) % self.base_urls.len();
+            if let Some(until) = self.paused_until(index) {
+                resume_at = Some(resume_at.map_or(until, |at| at.min(until)));
+                continue;
+            }
+            let url = format!("{}{path}", self.base_urls[index]);
+            for attempt in 0..ATTEMPTS_PER_MIRROR {
+                if attempt > 0 {
+                    thread::sleep(RETRY_DELAY * 2u32.pow(attempt - 1));
+                }
+                let (error, retry) = match request(&url) {
+                    // Not Modified answers a conditional request.
+                    Ok(resp) if resp.is_success() || resp.is_not_modified() => {
+                        self.current.store(index, Ordering::Relaxed);
+                        self.succeeded(index);
+                        return Ok(Ok(resp));
+                    }
+                    // Asking the same server again will not help, but another
+                    // one may have the song.
+                    Ok(resp) => (
+                        anyhow!(HttpStatus {
+                            url: url.clone(),
+                            status: resp.status(),
+                        }),
+                        !resp.status().is_client_error(),
+                    ),
+                    // No time is left to try again, here or elsewhere.
+                    Err(e) if e.is::<DeadlineExceeded>() => {
+                        return Ok(Err(e.context(format!("Request to {url} failed"))))
+                    }
+                    Err(e) => (e.context(format!("Request to {url} failed")), true),
+                };
+                warn!(error = %error, attempt = attempt + 1, "Request failed");
+                last_error = Some(error);
+                if !retry {
+                    // The server is up, just without what was asked for.
+                    self.succeeded(index);
+                    break;
+                }
+                if self.failed(index) {
+                    break;
+                }
+            }
+            if self.base_urls.len() > 1 {
+                warn!(base_url = self.base_urls[index], "Giving up on server");
+            }
+        }
+        match (last_error, resume_at) {
+            (Some(error), _) => Ok(Err(error)),
+            (None, Some(resume_at)) => Err(resume_at),
+            (None, None) => unreachable!("there is at least one server"),
+        }
+    }
+
+    /// Returns the end of the cooldown of the server `index` if it is paused.
+    fn paused_until(&self, index: usize) -> Option<Instant> {
+        let breaker = self.breakers[index].lock().unwrap();
+        breaker.paused_until.filter(|until| *until > Instant::now())
+    }
+
+    fn succeeded(&self, index: usize) {
+        let mut breaker = self.breakers[index].lock().unwrap();
+        if breaker.paused_until.take().is_some() {
+            info!(
+                base_url = self.base_urls[index],
+                "Resuming requests to server"
+            );
+        }
+        breaker.failures = 0;
+    }
+
+    /// Records a failed request on the server `index`, returning whether
+    /// the server is paused now.
+    fn failed(&self, index: usize) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        let mut breaker = self.breakers[index].lock().unwrap();
+        breaker.failures += 1;
+        if breaker.paused_until.is_none() && breaker.failures < self.threshold {
+            return false;
+        }
+        breaker.paused_until = Some(Instant::now() + self.cooldown);
+        warn!(
+            base_url = self.base_urls[index],
+            failures = breaker.failures,
+            cooldown = %humantime::format_duration(self.cooldown),
+            "Pausing requests to server"
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use httpmock::MockServer;
+
+    use super::*;
+    use crate::http::Client;
+    use crate::http::HttpConfig;
+
+    #[test]
+    fn fails_over_to_next_mirror() {
+        let down = MockServer::start();
+        let down_mock = down.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(503);
+        });
+        let up = MockServer::start();
+        let up_mock = up.mock(|when, then| {
+            when.path("/app/songs").query_param("page", "2");
+            then.status(200).body("ok");
+        });
+
+        let mirrors = Mirrors::new(vec![down.base_url(), format!("{}/", up.base_url())]);
+        let path = mirrors.path_of(&format!("{}/app/songs?page=2", down.base_url()));
+        assert_eq!(path, "/app/songs?page=2");
+
+        let client = Client::new(&HttpConfig::default());
+        let resp = mirrors.send(&path, |url| client.get(url)).unwrap();
+        assert_eq!(resp.bytes(), b"ok");
+        down_mock.assert_hits(ATTEMPTS_PER_MIRROR as usize);
+        assert_eq!(mirrors.current(), up.base_url());
+
+        // The working mirror is now tried first.
+        mirrors.send(&path, |url| client.get(url)).unwrap();
+        down_mock.assert_hits(ATTEMPTS_PER_MIRROR as usize);
+        up_mock.assert_hits(2);
+    }
+
+    #[test]
+    fn pauses_failing_server() {
+        let server = MockServer::start();
+        let mut down = server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(503);
+        });
+        let client = Client::new(&HttpConfig::default());
+        let cooldown = Duration::from_millis(300);
+        let mirrors = Mirrors::new(vec![server.base_url()]).breaker(2, cooldown);
+
+        // The second failure pauses the server before its last attempt.
+        assert!(mirrors.send("/app/songs", |url| client.get(url)).is_err());
+        down.assert_hits(2);
+
+        // The next request waits for the cooldown, and a single failure
+        // pauses the server again.
+        let start = Instant::now();
+        assert!(mirrors.send("/app/songs", |url| client.get(url)).is_err());
+        assert!(start.elapsed() >= cooldown);
+        down.assert_hits(3);
+
+        down.delete();
+        let up = server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).body("ok");
+        });
+        let start = Instant::now();
+        mirrors.send("/app/songs", |url| client.get(url)).unwrap();
+        assert!(start.elapsed() >= cooldown);
+        // Resumed servers are not waited for.
+        let start = Instant::now();
+        mirrors.send("/app/songs", |url| client.get(url)).unwrap();
+        assert!(start.elapsed() < cooldown);
+        up.assert_hits(2);
+    }
+
+    #[test]
+    fn returns_last_error() {
+        let down = MockServer::start();
+        down.mock(|when, then| {
+            when.any_request();
+            then.status(500);
+        });
+        let client = Client::new(&HttpConfig::default());
+        let mirrors = Mirrors::new(vec![down.base_url()]);
+        let error = mirrors
+            .send("/songs/a/download", |url| client.get(url))
+            .unwrap_err();
+        assert!(error.to_string().contains("500"));
+
+        let missing = MockServer::start();
+        let missing_mock = missing.mock(|when, then| {
+            when.any_request();
+            then.status(404);
+        });
+        let mirrors = Mirrors::new(vec![missing.base_url()]);
+        let error = mirrors
+            .send("/songs/a/download", |url| client.get(url))
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<HttpStatus>().unwrap().status,
+            StatusCode::NOT_FOUND
+        );
+        missing_mock.assert_hits(1);
+        assert_eq!(
+            mirrors.path_of("https://ksm.dev/songs/a/download"),
+            "/songs/a/download"
+        );
+    }
+}
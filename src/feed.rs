@@ -0,0 +1,257 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::SecondsFormat;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::api::Routes;
+use crate::notify::Notifier;
+use crate::Song;
+use crate::NAUTICA_BASE_URL;
+
+/// Number of songs a feed lists by default.
+const DEFAULT_LIMIT: usize = 50;
+
+/// Maintains an Atom feed of the songs downloaded most recently, e.g. for a
+/// community site to show the latest charts on a cabinet.
+///
+/// The songs in the feed are also kept as JSON next to it, e.g. in
+/// `feed.json` for `feed.atom`, so that the feed survives restarts.
+#[derive(Debug)]
+pub struct FeedNotifier {
+    path: PathBuf,
+    title: String,
+    base_url: String,
+    routes: Routes,
+    limit: usize,
+    items: Mutex<Vec<FeedItem>>,
+}
+
+/// A song listed in a feed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct FeedItem {
+    id: String,
+    title: String,
+    artist: String,
+    uploader: String,
+    levels: Vec<u8>,
+    jacket_url: Option<String>,
+    downloaded_at: DateTime<Utc>,
+}
+
+impl FeedNotifier {
+    /// Opens the feed at `path`, picking up the songs it listed before.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let state = state_path(path);
+        let items = if state.exists() {
+            let content = fs::read_to_string(&state)
+                .with_context(|| format!("Failed to read {}", state.display()))?;
+            serde_json::from_str(&content)
+                .with_context(|| format!("Invalid feed state {}", state.display()))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            title: String::from("Latest charts"),
+            base_url: String::from(NAUTICA_BASE_URL),
+            routes: Routes::default(),
+            limit: DEFAULT_LIMIT,
+            items: Mutex::new(items),
+        })
+    }
+
+    /// Sets the title of the feed.
+    pub fn title(mut self, title: &str) -> Self {
+        self.title = title.to_owned();
+        self
+    }
+
+    /// Sets the server the entries link the songs to.
+    pub fn base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Sets the routes of the API, for servers that differ from Nautica.
+    pub fn routes(mut self, routes: Routes) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// Sets the number of songs the feed lists.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    fn render(&self, items: &[FeedItem]) -> String {
+        let updated = items
+            .first()
+            .map_or_else(Utc::now, |item| item.downloaded_at);
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        let _ = writeln!(xml, "<feed xmlns=\"http://www.w3.org/2005/Atom\">");
+        let _ = writeln!(xml, "  <title>{}</title>", escape(&self.title));
+        let _ = writeln!(
+            xml,
+            "  <id>urn:nautica-downloader:feed:{}</id>",
+            escape(&self.title)
+        );
+        let _ = writeln!(xml, "  <updated>{}</updated>", timestamp(updated));
+        for item in items {
+            let url = format!("{}{}", self.base_url, self.routes.download_path(&item.id));
+            let levels: Vec<_> = item.levels.iter().map(u8::to_string).collect();
+            let title = format!("{} / {}", item.title, item.artist);
+            let _ = writeln!(xml, "  <entry>");
+            let _ = writeln!(xml, "    <title>{}</title>", escape(&title));
+            let _ = writeln!(xml, "    <id>urn:nautica:song:{}</id>", escape(&item.id));
+            let _ = writeln!(
+                xml,
+                "    <updated>{}</updated>",
+                timestamp(item.downloaded_at)
+            );
+            let _ = writeln!(
+                xml,
+                "    <author><name>{}</name></author>",
+                escape(&item.uploader)
+            );
+            let _ = writeln!(xml, "    <link href=\"{}\"/>", escape(&url));
+            if let Some(jacket_url) = &item.jacket_url {
+                let _ = writeln!(
+                    xml,
+                    "    <link rel=\"enclosure\" type=\"{}\" href=\"{}\"/>",
+                    image_type(jacket_url),
+                    escape(jacket_url)
+                );
+            }
+            let _ = writeln!(xml, "    <summary>Levels {}</summary>", levels.join(" "));
+            let _ = writeln!(xml, "  </entry>");
+        }
+        let _ = writeln!(xml, "</feed>");
+        xml
+    }
+
+    fn save(&self, items: &[FeedItem]) -> anyhow::Result<()> {
+        let state = state_path(&self.path);
+        fs::write(&state, serde_json::to_string_pretty(items)?)
+            .with_context(|| format!("Failed to write {}", state.display()))?;
+        fs::write(&self.path, self.render(items))
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+impl Notifier for FeedNotifier {
+    fn song_downloaded(&self, song: &Song, _path: &Path) -> anyhow::Result<()> {
+        let mut items = self.items.lock().unwrap();
+        items.retain(|item| item.id != song.id);
+        items.insert(
+            0,
+            FeedItem {
+                id: song.id.clone(),
+                title: song.title.clone(),
+                artist: song.artist.clone(),
+                uploader: song
+                    .user
+                    .as_ref()
+                    .map_or(&song.user_id, |user| &user.name)
+                    .clone(),
+                levels: song.charts.iter().map(|chart| chart.level).collect(),
+                jacket_url: song.jacket_url.clone(),
+                downloaded_at: Utc::now(),
+            },
+        );
+        items.truncate(self.limit);
+        self.save(&items)
+    }
+}
+
+/// Returns the path of the file keeping the songs of the feed at `path`.
+fn state_path(path: &Path) -> PathBuf {
+    path.with_extension("json")
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn image_type(url: &str) -> &'static str {
+    let url = url.to_ascii_lowercase();
+    if url.ends_with(".png") {
+        "image/png"
+    } else if url.ends_with(".gif") {
+        "image/gif"
+    } else {
+        "image/jpeg"
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn song(id: &str, title: &str) -> Song {
+        serde_json::from_value(json!({
+            "id": id,
+            "user_id": "user",
+            "title": title,
+            "artist": "RG+Ice",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+            "user": { "id": "user", "name": "Ixiot" },
+            "jacket_url": format!("https://ksm.dev/jackets/{id}.png"),
+            "charts": [{ "difficulty": 3, "level": 16 }, { "difficulty": 4, "level": 18 }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn maintain_feed() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("feed.atom");
+        let feed = FeedNotifier::open(&path)
+            .unwrap()
+            .base_url("http://mirror/")
+            .limit(2);
+        feed.song_downloaded(&song("5441d590", "Outbreak"), dir.path())
+            .unwrap();
+        feed.song_downloaded(&song("89b54d80", "Tom & Jerry"), dir.path())
+            .unwrap();
+
+        let xml = fs::read_to_string(&path).unwrap();
+        assert!(xml.contains("<title>Tom &amp; Jerry / RG+Ice</title>"));
+        assert!(xml.contains("<link href=\"http://mirror/songs/5441d590/download\"/>"));
+        assert!(xml.contains(
+            "<link rel=\"enclosure\" type=\"image/png\" href=\"https://ksm.dev/jackets/5441d590.png\"/>"
+        ));
+        assert!(xml.contains("<author><name>Ixiot</name></author>"));
+        assert!(xml.contains("<summary>Levels 16 18</summary>"));
+        // The newest song comes first.
+        assert!(xml.find("89b54d80").unwrap() < xml.find("5441d590").unwrap());
+
+        // The songs are picked up again, and the oldest drop out at the limit.
+        let feed = FeedNotifier::open(&path).unwrap().limit(2);
+        feed.song_downloaded(&song("9e523640", "Easy"), dir.path())
+            .unwrap();
+        let xml = fs::read_to_string(&path).unwrap();
+        assert!(xml.contains("9e523640"));
+        assert!(xml.contains("89b54d80"));
+        assert!(!xml.contains("5441d590"));
+    }
+}
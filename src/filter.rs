@@ -0,0 +1,475 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::Context;
+
+use crate::store::Entry;
+use crate::Chart;
+use crate::Song;
+
+/// Criteria restricting which songs a sync considers.
+///
+/// An empty filter matches every song.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Uploader IDs or names (case-insensitive); any of them may match.
+    pub users: Vec<String>,
+
+    /// Words that must all appear in the title, artist, or tags
+    /// (case-insensitive). Also sent to the server's search.
+    pub query: Option<String>,
+
+    /// Lowest chart level; a song matches if any of its charts is in range.
+    pub min_level: Option<u8>,
+
+    /// Highest chart level; a song matches if any of its charts is in range.
+    pub max_level: Option<u8>,
+
+    /// Chart effectors (case-insensitive); a song matches if any of its
+    /// charts is by one of them.
+    pub effectors: Vec<String>,
+
+    /// Jacket illustrators (case-insensitive). Only known for downloaded
+    /// songs, from their chart files, so remote songs never match.
+    pub illustrators: Vec<String>,
+
+    /// Local tags (case-insensitive) that must all be set on the song. Only
+    /// downloaded songs have them, so remote songs never match.
+    pub tags: Vec<String>,
+
+    /// Only match songs starred locally, which remote songs never are.
+    pub starred: bool,
+
+    /// Song or user IDs that never match.
+    pub blocklist: IdList,
+
+    /// Song or user IDs; if given, only songs listed here or by a listed
+    /// uploader match.
+    pub allowlist: Option<IdList>,
+}
+
+/// A set of song and user IDs, read from a file with one ID per line.
+#[derive(Debug, Clone, Default)]
+pub struct IdList {
+    ids: HashSet<String>,
+}
+
+impl IdList {
+    /// Loads a list from `path`. Blank lines and lines starting with `#` are
+    /// ignored.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(content.lines().collect())
+    }
+
+    /// Adds `ids` to the list in `path`, creating it if needed. IDs already
+    /// listed are not added again.
+    pub fn append<'a>(path: &Path, ids: impl IntoIterator<Item = &'a str>) -> anyhow::Result<()> {
+        let mut content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let mut list: IdList = content.lines().collect();
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        for id in ids {
+            if list.ids.insert(id.to_owned()) {
+                content.push_str(id);
+                content.push('\n');
+            }
+        }
+        fs::write(path, content).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn contains(&self, fields: &Fields) -> bool {
+        self.ids.contains(fields.id) || self.ids.contains(fields.user_id)
+    }
+}
+
+impl<'a> FromIterator<&'a str> for IdList {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+        let ids = iter
+            .into_iter()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect();
+        Self { ids }
+    }
+}
+
+/// An inclusive range of values, parsed from `MIN..MAX`, `MIN..`, `..MAX`, or
+/// a single value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds<T> {
+    pub min: Option<T>,
+    pub max: Option<T>,
+}
+
+impl<T: PartialOrd> Bounds<T> {
+    pub fn contains(&self, value: &T) -> bool {
+        self.min.as_ref().is_none_or(|min| value >= min)
+            && self.max.as_ref().is_none_or(|max| value <= max)
+    }
+}
+
+impl<T> Bounds<T> {
+    pub fn map<U>(self, f: impl Fn(T) -> U) -> Bounds<U> {
+        Bounds {
+            min: self.min.map(&f),
+            max: self.max.map(&f),
+        }
+    }
+}
+
+impl<T> FromStr for Bounds<T>
+where
+    T: FromStr + Clone,
+    T::Err: fmt::Display,
+{
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let parse = |value: &str| -> anyhow::Result<Option<T>> {
+            let value = value.trim();
+            if value.is_empty() {
+                return Ok(None);
+            }
+            let value = value
+                .parse()
+                .map_err(|e| anyhow!("Invalid value {value:?}: {e}"))?;
+            Ok(Some(value))
+        };
+        match s.split_once("..") {
+            Some((min, max)) => Ok(Self {
+                min: parse(min)?,
+                max: parse(max)?,
+            }),
+            None => {
+                let value = parse(s)?.ok_or_else(|| anyhow!("Empty range"))?;
+                Ok(Self {
+                    min: Some(value.clone()),
+                    max: Some(value),
+                })
+            }
+        }
+    }
+}
+
+/// The fields of a song that filters look at, shared by remote songs and
+/// local entries.
+struct Fields<'a> {
+    id: &'a str,
+    user_id: &'a str,
+    user_name: Option<&'a str>,
+    title: &'a str,
+    artist: &'a str,
+    tags: Vec<&'a str>,
+    levels: Vec<u8>,
+    effectors: Vec<&'a str>,
+    illustrator: Option<&'a str>,
+    local_tags: &'a [String],
+    starred: bool,
+}
+
+impl Filter {
+    pub fn matches(&self, song: &Song) -> bool {
+        self.matches_fields(&Fields {
+            id: &song.id,
+            user_id: &song.user_id,
+            user_name: song.user.as_ref().map(|user| user.name.as_str()),
+            title: &song.title,
+            artist: &song.artist,
+            tags: song.tags.iter().map(|tag| tag.value.as_str()).collect(),
+            levels: song.charts.iter().map(|chart| chart.level).collect(),
+            effectors: effectors(&song.charts),
+            illustrator: None,
+            local_tags: &[],
+            starred: false,
+        })
+    }
+
+    /// Matches a song in the local library using its stored metadata.
+    pub fn matches_entry(&self, id: &str, entry: &Entry) -> bool {
+        self.matches_fields(&Fields {
+            id,
+            user_id: &entry.user_id,
+            user_name: entry.user_name.as_deref(),
+            title: &entry.title,
+            artist: &entry.artist,
+            tags: Vec::new(),
+            levels: entry.levels.clone(),
+            effectors: effectors(&entry.charts),
+            illustrator: entry.illustrator.as_deref(),
+            local_tags: &entry.tags,
+            starred: entry.starred,
+        })
+    }
+
+    fn matches_fields(&self, fields: &Fields) -> bool {
+        self.matches_lists(fields)
+            && self.matches_user(fields)
+            && self.matches_query(fields)
+            && self.matches_level(fields)
+            && self.matches_effector(fields)
+            && self.matches_illustrator(fields)
+            && self.matches_tags(fields)
+            && (!self.starred || fields.starred)
+    }
+
+    fn matches_lists(&self, fields: &Fields) -> bool {
+        !self.blocklist.contains(fields)
+            && self
+                .allowlist
+                .as_ref()
+                .is_none_or(|allowlist| allowlist.contains(fields))
+    }
+
+    fn matches_user(&self, fields: &Fields) -> bool {
+        self.users.is_empty()
+            || self.users.iter().any(|user| {
+                user == fields.user_id
+                    || fields
+                        .user_name
+                        .is_some_and(|name| name.eq_ignore_ascii_case(user))
+            })
+    }
+
+    fn matches_level(&self, fields: &Fields) -> bool {
+        if self.min_level.is_none() && self.max_level.is_none() {
+            return true;
+        }
+        let range = self.min_level.unwrap_or(u8::MIN)..=self.max_level.unwrap_or(u8::MAX);
+        fields.levels.iter().any(|level| range.contains(level))
+    }
+
+    fn matches_effector(&self, fields: &Fields) -> bool {
+        self.effectors.is_empty()
+            || fields.effectors.iter().any(|name| {
+                self.effectors
+                    .iter()
+                    .any(|effector| name.eq_ignore_ascii_case(effector))
+            })
+    }
+
+    fn matches_illustrator(&self, fields: &Fields) -> bool {
+        self.illustrators.is_empty()
+            || fields.illustrator.is_some_and(|name| {
+                self.illustrators
+                    .iter()
+                    .any(|illustrator| name.eq_ignore_ascii_case(illustrator))
+            })
+    }
+
+    fn matches_tags(&self, fields: &Fields) -> bool {
+        self.tags.iter().all(|tag| {
+            fields
+                .local_tags
+                .iter()
+                .any(|local_tag| local_tag.eq_ignore_ascii_case(tag))
+        })
+    }
+
+    fn matches_query(&self, fields: &Fields) -> bool {
+        let Some(query) = &self.query else {
+            return true;
+        };
+        let haystack = [fields.title, fields.artist]
+            .into_iter()
+            .chain(fields.tags.iter().copied())
+            .collect::<Vec<_>>()
+            .join("\n")
+            .to_lowercase();
+        query
+            .to_lowercase()
+            .split_whitespace()
+            .all(|word| haystack.contains(word))
+    }
+}
+
+fn effectors(charts: &[Chart]) -> Vec<&str> {
+    charts
+        .iter()
+        .filter_map(|chart| chart.effector.as_deref())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn song(user_id: &str, user_name: &str) -> Song {
+        serde_json::from_value(json!({
+            "id": format!("song-of-{user_id}"),
+            "user_id": user_id,
+            "title": "Outbreak",
+            "artist": "RG+Ice",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+            "user": { "id": user_id, "name": user_name },
+            "tags": [{ "value": "BOF2023" }],
+            "charts": [
+                { "difficulty": 1, "level": 5 },
+                { "difficulty": 4, "level": 18, "effector": "Ixiot" },
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_bounds() {
+        let bpm: Bounds<f64> = "170..200".parse().unwrap();
+        assert!(bpm.contains(&170.0) && bpm.contains(&200.0));
+        assert!(!bpm.contains(&200.5));
+
+        let bpm: Bounds<f64> = "..150".parse().unwrap();
+        assert_eq!(bpm.min, None);
+        assert!(bpm.contains(&0.0));
+
+        let bpm: Bounds<f64> = "180".parse().unwrap();
+        assert!(bpm.contains(&180.0) && !bpm.contains(&181.0));
+
+        assert!("fast..".parse::<Bounds<f64>>().is_err());
+        assert!("".parse::<Bounds<f64>>().is_err());
+    }
+
+    #[test]
+    fn filter_by_user() {
+        let filter = Filter::default();
+        assert!(filter.matches(&song("u1", "Ixiot")));
+
+        let filter = Filter {
+            users: vec![String::from("ixiot"), String::from("u2")],
+            ..Default::default()
+        };
+        assert!(filter.matches(&song("u1", "Ixiot")));
+        assert!(filter.matches(&song("u2", "someone")));
+        assert!(!filter.matches(&song("u3", "someone")));
+    }
+
+    #[test]
+    fn filter_by_query() {
+        let query = |q: &str| Filter {
+            query: Some(q.to_owned()),
+            ..Default::default()
+        };
+        assert!(query("outbreak").matches(&song("u1", "Ixiot")));
+        assert!(query("rg+ice OUTBREAK").matches(&song("u1", "Ixiot")));
+        assert!(query("bof").matches(&song("u1", "Ixiot")));
+        assert!(!query("outbreak kid").matches(&song("u1", "Ixiot")));
+    }
+
+    #[test]
+    fn filter_by_level() {
+        let levels = |min_level, max_level| Filter {
+            min_level,
+            max_level,
+            ..Default::default()
+        };
+        assert!(levels(Some(14), Some(18)).matches(&song("u1", "Ixiot")));
+        assert!(levels(Some(18), None).matches(&song("u1", "Ixiot")));
+        assert!(levels(None, Some(5)).matches(&song("u1", "Ixiot")));
+        assert!(!levels(Some(14), Some(17)).matches(&song("u1", "Ixiot")));
+        assert!(!levels(Some(19), None).matches(&song("u1", "Ixiot")));
+    }
+
+    #[test]
+    fn filter_by_effector() {
+        let effectors = |names: &[&str]| Filter {
+            effectors: names.iter().map(|&name| name.to_owned()).collect(),
+            ..Default::default()
+        };
+        assert!(effectors(&["ixiot"]).matches(&song("u1", "someone")));
+        assert!(effectors(&["someone", "Ixiot"]).matches(&song("u1", "someone")));
+        assert!(!effectors(&["someone"]).matches(&song("u1", "someone")));
+    }
+
+    #[test]
+    fn filter_by_illustrator() {
+        let filter = Filter {
+            illustrators: vec![String::from("yoshimo")],
+            ..Default::default()
+        };
+        let song = song("u1", "Ixiot");
+        assert!(!filter.matches(&song));
+
+        let mut entry = Entry::new(&song, "dir");
+        assert!(!filter.matches_entry(&song.id, &entry));
+        entry.illustrator = Some(String::from("Yoshimo"));
+        assert!(filter.matches_entry(&song.id, &entry));
+    }
+
+    #[test]
+    fn filter_by_local_marks() {
+        let filter = Filter {
+            tags: vec![String::from("stamina"), String::from("favorite")],
+            ..Default::default()
+        };
+        let song = song("u1", "Ixiot");
+        assert!(!filter.matches(&song));
+
+        let mut entry = Entry::new(&song, "dir");
+        entry.tags = vec![String::from("Stamina")];
+        assert!(!filter.matches_entry(&song.id, &entry));
+        entry.tags.push(String::from("favorite"));
+        assert!(filter.matches_entry(&song.id, &entry));
+
+        let filter = Filter {
+            starred: true,
+            ..Default::default()
+        };
+        assert!(!filter.matches_entry(&song.id, &entry));
+        entry.starred = true;
+        assert!(filter.matches_entry(&song.id, &entry));
+    }
+
+    #[test]
+    fn filter_by_lists() {
+        let list = |ids: &[&str]| ids.iter().copied().collect::<IdList>();
+        let filter = Filter {
+            blocklist: list(&["u2", "song-of-u3"]),
+            ..Default::default()
+        };
+        assert!(filter.matches(&song("u1", "Ixiot")));
+        assert!(!filter.matches(&song("u2", "someone")));
+        assert!(!filter.matches(&song("u3", "someone")));
+
+        let filter = Filter {
+            blocklist: list(&["u2"]),
+            allowlist: Some(list(&["# comment", "u1", "", "song-of-u2", "song-of-u3"])),
+            ..Default::default()
+        };
+        assert!(filter.matches(&song("u1", "Ixiot")));
+        assert!(!filter.matches(&song("u2", "someone")));
+        assert!(filter.matches(&song("u3", "someone")));
+        assert!(!filter.matches(&song("u4", "someone")));
+    }
+
+    #[test]
+    fn append_to_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocklist.txt");
+        IdList::append(&path, ["song-of-u1"]).unwrap();
+        fs::write(&path, "# blocked\nsong-of-u1").unwrap();
+        IdList::append(&path, ["song-of-u1", "song-of-u2"]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# blocked\nsong-of-u1\nsong-of-u2\n"
+        );
+        let filter = Filter {
+            blocklist: IdList::load(&path).unwrap(),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&song("u2", "someone")));
+    }
+}
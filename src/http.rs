@@ -0,0 +1,942 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use attohttpc::header;
+use attohttpc::header::HeaderMap;
+use attohttpc::header::HeaderName;
+use attohttpc::header::HeaderValue;
+use attohttpc::StatusCode;
+use curl::easy::Easy2;
+use curl::easy::Handler;
+use curl::easy::HttpVersion;
+use curl::easy::List;
+use curl::easy::WriteError;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use url::Url;
+
+#[cfg(feature = "cassette")]
+use crate::cassette::Cassette;
+use crate::failover::DEFAULT_BREAKER_COOLDOWN;
+use crate::failover::DEFAULT_BREAKER_THRESHOLD;
+use crate::pin::Pin;
+use crate::systemd::Watchdog;
+
+/// Idle connections kept open per server when none is set.
+pub const DEFAULT_POOL_SIZE: usize = 4;
+
+/// Seconds an idle connection is kept open when none is set.
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 30;
+
+/// Returns the compressions asked for in API requests, which curl decodes:
+/// gzip and deflate, and Brotli if curl was built with it. The curl built
+/// with the static-curl feature is not.
+fn accept_encoding() -> &'static str {
+    if curl::Version::get().feature_brotli() {
+        "br, gzip, deflate"
+    } else {
+        "gzip, deflate"
+    }
+}
+
+/// Redirects followed before a request fails.
+const MAX_REDIRECTS: u32 = 10;
+
+/// Connection settings of the `[http]` table of the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Reuse connections to a server between requests instead of opening
+    /// one per request.
+    pub keep_alive: bool,
+
+    /// Idle connections kept open per server.
+    pub pool_size: usize,
+
+    /// Seconds an idle connection is kept open.
+    pub idle_timeout: u64,
+
+    /// Negotiate HTTP/2 with servers over HTTPS that support it, falling
+    /// back to HTTP/1.1.
+    pub http2: bool,
+
+    /// Failed requests in a row after which a server is paused; 0 never
+    /// pauses. See [`crate::failover::Mirrors`].
+    pub breaker_threshold: u32,
+
+    /// Seconds a server is paused for.
+    pub breaker_cooldown: u64,
+
+    /// Public keys that servers must present, by host name. Connections to a
+    /// host with pins fail unless one of them matches, even if the
+    /// certificate is otherwise trusted; other hosts are not pinned.
+    pub pins: BTreeMap<String, Vec<Pin>>,
+
+    /// Addresses to connect to instead of looking up host names, e.g. to
+    /// reach a staging or LAN mirror under the official name.
+    pub resolve: Vec<HostMapping>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive: true,
+            pool_size: DEFAULT_POOL_SIZE,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            http2: true,
+            breaker_threshold: DEFAULT_BREAKER_THRESHOLD,
+            breaker_cooldown: DEFAULT_BREAKER_COOLDOWN.as_secs(),
+            pins: BTreeMap::new(),
+            resolve: Vec::new(),
+        }
+    }
+}
+
+/// Addresses to connect to for a host and port, parsed from
+/// `<host>:<port>:<address>[,<address>...]` as in curl's `--resolve`, e.g.
+/// `ksm.dev:443:192.168.1.10`. IPv6 addresses may be put in brackets.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HostMapping {
+    pub host: String,
+    pub port: u16,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl FromStr for HostMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let invalid =
+            || anyhow!("Invalid host mapping {s:?}, expected \"<host>:<port>:<address>\"");
+        let mut parts = s.trim().splitn(3, ':');
+        let (Some(host), Some(port), Some(addrs)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        ensure!(!host.is_empty(), invalid());
+        let port = port.parse().map_err(|_| invalid())?;
+        let addrs = addrs
+            .split(',')
+            .map(|addr| {
+                let addr = addr.trim();
+                let addr = addr
+                    .strip_prefix('[')
+                    .and_then(|addr| addr.strip_suffix(']'))
+                    .unwrap_or(addr);
+                addr.parse()
+                    .map_err(|_| anyhow!("Invalid address {addr:?} in host mapping {s:?}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            port,
+            addrs,
+        })
+    }
+}
+
+/// Writes the mapping as curl's `--resolve` takes it.
+impl fmt::Display for HostMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:", self.host, self.port)?;
+        for (i, addr) in self.addrs.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match addr {
+                IpAddr::V4(addr) => write!(f, "{addr}")?,
+                IpAddr::V6(addr) => write!(f, "[{addr}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for HostMapping {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+/// A response whose body was read in full.
+#[derive(Debug)]
+pub struct Response {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Response {
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status.is_success()
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Returns whether the server answered a conditional request saying the
+    /// resource did not change.
+    pub fn is_not_modified(&self) -> bool {
+        self.status == StatusCode::NOT_MODIFIED
+    }
+
+    pub fn bytes(self) -> Vec<u8> {
+        self.body
+    }
+
+    /// Parses the body as JSON, which is always UTF-8.
+    pub fn json<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// Validators of a downloaded resource, sent back with a later request for
+/// it so that the server only sends it again if it changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validators {
+    /// `ETag` of the resource.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+
+    /// `Last-Modified` date of the resource.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Returns the validators of the resource `resp` holds.
+    pub fn of(resp: &Response) -> Self {
+        let get = |name| {
+            resp.headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        Self {
+            etag: get(header::ETAG),
+            last_modified: get(header::LAST_MODIFIED),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Returns the headers making a request conditional on the resource
+    /// having changed.
+    fn headers(&self) -> anyhow::Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = &self.etag {
+            headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(etag)?);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.insert(
+                header::IF_MODIFIED_SINCE,
+                HeaderValue::from_str(last_modified)?,
+            );
+        }
+        Ok(headers)
+    }
+}
+
+/// Error of a request whose response was not read in full by its deadline.
+#[derive(Debug)]
+pub struct DeadlineExceeded;
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Deadline exceeded")
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// HTTP client for every request to the servers, on libcurl, so that the
+/// pins and host mappings apply to logging in and publishing as well as to
+/// syncs. Connections to each server are kept open between requests, so that
+/// a bulk sync pays for the TCP and TLS handshakes once per server rather
+/// than once per song.
+///
+/// Each request takes a curl handle from a pool, whose connections it reuses,
+/// and puts it back when done. Proxies set in the environment are used.
+pub struct Client {
+    keep_alive: bool,
+    pool_size: usize,
+    idle_timeout: Duration,
+    http2: bool,
+    pins: HashMap<String, Vec<Pin>>,
+    resolve: Vec<HostMapping>,
+    handles: Mutex<Vec<Easy2<Collector>>>,
+    headers: HeaderMap,
+    timeout: Duration,
+    watchdog: Option<Arc<Watchdog>>,
+
+    #[cfg(feature = "cassette")]
+    cassette: Option<Cassette>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Leave the session cookie out of logs.
+        f.debug_struct("Client")
+            .field("keep_alive", &self.keep_alive)
+            .field("pool_size", &self.pool_size)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("http2", &self.http2)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Client {
+    pub fn new(config: &HttpConfig) -> Self {
+        Self {
+            keep_alive: config.keep_alive,
+            pool_size: config.pool_size,
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            http2: config.http2,
+            pins: config
+                .pins
+                .iter()
+                .map(|(host, pins)| (host.to_ascii_lowercase(), pins.clone()))
+                .collect(),
+            resolve: config.resolve.clone(),
+            handles: Mutex::default(),
+            headers: HeaderMap::new(),
+            timeout: Duration::from_secs(60),
+            watchdog: None,
+            #[cfg(feature = "cassette")]
+            cassette: None,
+        }
+    }
+
+    /// Sends `value` as the header `name` with every request to the servers
+    /// of the sync. It is left out when a redirect leads elsewhere.
+    pub fn header(&mut self, name: HeaderName, value: &str) -> anyhow::Result<()> {
+        self.headers.insert(name, HeaderValue::from_str(value)?);
+        Ok(())
+    }
+
+    /// Sets how long to wait for a server to connect or send data.
+    pub fn timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Pings `watchdog` whenever data of a response arrives, so that a long
+    /// download keeps it from firing while a stalled one does not.
+    pub fn watchdog(&mut self, watchdog: Arc<Watchdog>) {
+        self.watchdog = Some(watchdog);
+    }
+
+    pub fn get(&self, url: &str) -> anyhow::Result<Response> {
+        self.send(Request::new("GET", url))
+    }
+
+    /// Sends a GET request with `headers` besides those of every request,
+    /// replacing any of the same name. They are left out when a redirect
+    /// leads elsewhere.
+    pub fn get_with(&self, url: &str, headers: &HeaderMap) -> anyhow::Result<Response> {
+        self.send(Request {
+            headers: headers.clone(),
+            ..Request::new("GET", url)
+        })
+    }
+
+    /// Sends a POST request with `body` and `headers` as with
+    /// [`Client::get_with`]. Redirects are not followed, so that the body
+    /// only goes where it was sent.
+    pub fn post(&self, url: &str, headers: &HeaderMap, body: &[u8]) -> anyhow::Result<Response> {
+        self.send(Request {
+            headers: headers.clone(),
+            body: Some(body),
+            ..Request::new("POST", url)
+        })
+    }
+
+    /// Sends a GET request that the server answers with Not Modified, and no
+    /// body, if the resource still has the given `validators`, and that is
+    /// given up on at `deadline` as with [`Client::get_until`].
+    pub fn get_if(
+        &self,
+        url: &str,
+        validators: &Validators,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<Response> {
+        self.send(Request {
+            validators: Some(validators),
+            deadline,
+            ..Request::new("GET", url)
+        })
+    }
+
+    /// Sends a GET request that fails with [`DeadlineExceeded`] unless the
+    /// response is read in full by `deadline`, however slowly the server
+    /// keeps sending it.
+    pub fn get_until(&self, url: &str, deadline: Instant) -> anyhow::Result<Response> {
+        self.send(Request {
+            deadline: Some(deadline),
+            ..Request::new("GET", url)
+        })
+    }
+
+    /// Sends a GET request for a response of the API, which is compressed
+    /// if the server supports it. Archives are not asked for compressed,
+    /// since they already are.
+    pub fn get_api(&self, url: &str) -> anyhow::Result<Response> {
+        self.send(Request {
+            compressed: true,
+            ..Request::new("GET", url)
+        })
+    }
+
+    pub fn head(&self, url: &str) -> anyhow::Result<Response> {
+        self.send(Request::new("HEAD", url))
+    }
+
+    /// Records the responses of the servers in `cassette`, or answers
+    /// requests from it.
+    #[cfg(feature = "cassette")]
+    pub fn cassette(&mut self, cassette: Cassette) {
+        self.cassette = Some(cassette);
+    }
+
+    fn send(&self, request: Request) -> anyhow::Result<Response> {
+        let deadline = request.deadline;
+        time_left(self.timeout, deadline)?;
+        let transfer = || self.transfer(&request);
+        #[cfg(feature = "cassette")]
+        let result = match &self.cassette {
+            Some(cassette) => cassette.play(request.method, request.url, transfer),
+            None => transfer(),
+        };
+        #[cfg(not(feature = "cassette"))]
+        let result = transfer();
+        // Whatever failed, it was cut short by the deadline.
+        result.map_err(|e| match deadline {
+            Some(deadline) if Instant::now() >= deadline => e.context(DeadlineExceeded),
+            _ => e,
+        })
+    }
+
+    /// Sends a request, following redirects here rather than in curl so that
+    /// the headers of the sync only go to its servers.
+    fn transfer(&self, request: &Request) -> anyhow::Result<Response> {
+        let first =
+            Url::parse(request.url).with_context(|| format!("Invalid URL {}", request.url))?;
+        let mut url = first.clone();
+        let conditions = match request.validators {
+            Some(validators) => validators.headers()?,
+            None => HeaderMap::new(),
+        };
+        let mut own = self.headers.clone();
+        own.extend(request.headers.clone());
+        own.extend(conditions.clone());
+        let other = conditions;
+        for _ in 0..=MAX_REDIRECTS {
+            // The session cookie is only for the servers of the sync.
+            let headers = if url.origin() == first.origin() {
+                &own
+            } else {
+                &other
+            };
+            let resp = self.perform(request, &url, headers)?;
+            let location = resp
+                .headers
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .filter(|_| request.body.is_none());
+            match location {
+                Some(location) if resp.status.is_redirection() => url = url.join(location)?,
+                _ => return Ok(resp),
+            }
+        }
+        bail!("Too many redirects for {first}")
+    }
+
+    fn perform(
+        &self,
+        request: &Request,
+        url: &Url,
+        headers: &HeaderMap,
+    ) -> anyhow::Result<Response> {
+        ensure!(
+            matches!(url.scheme(), "http" | "https"),
+            "Unsupported URL scheme in {url}"
+        );
+        let mut easy = self
+            .handles
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Easy2::new(Collector::default()));
+        // A reset keeps the connections of the handle.
+        easy.reset();
+        easy.get_mut().watchdog = self.watchdog.clone();
+        self.configure(&mut easy, request, url, headers)?;
+        if let Err(e) = easy.perform() {
+            // curl gives up at the deadline by its own clock, which may be a
+            // moment ahead.
+            let cut_short = request.deadline.is_some_and(|deadline| {
+                deadline.saturating_duration_since(Instant::now()) < self.timeout
+            });
+            if e.is_operation_timedout() && cut_short {
+                return Err(anyhow!(e).context(DeadlineExceeded));
+            }
+            return Err(e.into());
+        }
+        let status = StatusCode::from_u16(u16::try_from(easy.response_code()?)?)?;
+        let Collector {
+            mut headers, body, ..
+        } = mem::take(easy.get_mut());
+        if request.compressed {
+            // curl decoded the body.
+            headers.remove(header::CONTENT_ENCODING);
+            headers.remove(header::CONTENT_LENGTH);
+        }
+        if self.keep_alive {
+            let mut handles = self.handles.lock().unwrap();
+            if handles.len() < self.pool_size {
+                handles.push(easy);
+            }
+        }
+        Ok(Response {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    fn configure(
+        &self,
+        easy: &mut Easy2<Collector>,
+        request: &Request,
+        url: &Url,
+        headers: &HeaderMap,
+    ) -> anyhow::Result<()> {
+        easy.url(url.as_str())?;
+        easy.nobody(request.method == "HEAD")?;
+        if let Some(body) = request.body {
+            easy.post(true)?;
+            easy.post_fields_copy(body)?;
+        }
+        easy.useragent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION")
+        ))?;
+        // HTTP/2 is offered in the TLS handshake (ALPN), so that requests
+        // to a server share one connection.
+        easy.http_version(if self.http2 {
+            HttpVersion::V2TLS
+        } else {
+            HttpVersion::V11
+        })?;
+        easy.connect_timeout(self.timeout)?;
+        // The transfer fails once the server sent nothing for the timeout.
+        easy.low_speed_limit(1)?;
+        easy.low_speed_time(self.timeout)?;
+        if let Some(deadline) = request.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            ensure!(!left.is_zero(), DeadlineExceeded);
+            easy.timeout(left)?;
+        }
+        if self.keep_alive {
+            easy.tcp_keepalive(true)?;
+            easy.max_connects(u32::try_from(self.pool_size.max(1))?)?;
+            easy.maxage_conn(self.idle_timeout)?;
+        } else {
+            easy.forbid_reuse(true)?;
+        }
+        if request.compressed {
+            easy.accept_encoding(accept_encoding())?;
+        }
+        if !self.resolve.is_empty() {
+            let mut list = List::new();
+            for mapping in &self.resolve {
+                list.append(&mapping.to_string())?;
+            }
+            easy.resolve(list)?;
+        }
+        let pins = url
+            .host_str()
+            .and_then(|host| self.pins.get(&host.to_ascii_lowercase()));
+        if let Some(pins) = pins {
+            let pins: Vec<_> = pins.iter().map(Pin::to_string).collect();
+            easy.pinned_public_key(&pins.join(";"))?;
+        }
+        let mut list = List::new();
+        for (name, value) in headers {
+            list.append(&format!("{name}: {}", value.to_str()?))?;
+        }
+        easy.http_headers(list)?;
+        Ok(())
+    }
+}
+
+/// A request to send with a [`Client`].
+struct Request<'a> {
+    method: &'static str,
+    url: &'a str,
+
+    /// Headers besides those of every request.
+    headers: HeaderMap,
+
+    /// Body of a POST request.
+    body: Option<&'a [u8]>,
+
+    /// Whether to ask for the response compressed.
+    compressed: bool,
+
+    /// Validators making the request conditional.
+    validators: Option<&'a Validators>,
+
+    /// Time the response must be read in full by.
+    deadline: Option<Instant>,
+}
+
+impl<'a> Request<'a> {
+    fn new(method: &'static str, url: &'a str) -> Self {
+        Self {
+            method,
+            url,
+            headers: HeaderMap::new(),
+            body: None,
+            compressed: false,
+            validators: None,
+            deadline: None,
+        }
+    }
+}
+
+/// Collects the headers and body of a response from curl.
+#[derive(Default)]
+struct Collector {
+    headers: HeaderMap,
+    body: Vec<u8>,
+    watchdog: Option<Arc<Watchdog>>,
+}
+
+impl Handler for Collector {
+    fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.ping();
+        }
+        self.body.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn header(&mut self, line: &[u8]) -> bool {
+        // Informational responses precede the actual one, each starting with
+        // its status line.
+        if line.starts_with(b"HTTP/") {
+            self.headers.clear();
+            return true;
+        }
+        // Header values may have bytes outside ASCII; lines that are not
+        // headers at all are skipped.
+        let Some(colon) = line.iter().position(|&b| b == b':') else {
+            return true;
+        };
+        let name = HeaderName::from_bytes(line[..colon].trim_ascii());
+        let value = HeaderValue::from_bytes(line[colon + 1..].trim_ascii());
+        if let (Ok(name), Ok(value)) = (name, value) {
+            self.headers.append(name, value);
+        }
+        true
+    }
+}
+
+/// Returns how long to wait for a server: `timeout`, or less if `deadline`
+/// comes first. Fails with [`DeadlineExceeded`] once it has passed.
+fn time_left(timeout: Duration, deadline: Option<Instant>) -> anyhow::Result<Duration> {
+    let Some(deadline) = deadline else {
+        return Ok(timeout);
+    };
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(anyhow!(DeadlineExceeded));
+    }
+    Ok(left.min(timeout))
+}
+
+/// Returns TLS settings trusting the platform's certificates.
+pub(crate) fn tls_config() -> anyhow::Result<rustls::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs()? {
+        // Certificates the platform has but rustls cannot parse are skipped.
+        let _ = roots.add(&rustls::Certificate(cert.0));
+    }
+    Ok(rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use httpmock::MockServer;
+
+    use super::*;
+
+    /// Reads a request up to its empty line, returning whether there was one
+    /// before the connection closed.
+    fn read_request(reader: &mut impl BufRead) -> bool {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                return false;
+            }
+            if line == "\r\n" {
+                return true;
+            }
+        }
+    }
+
+    /// Serves the `responses` in order on as few connections as the client
+    /// uses, returning the number of connections accepted.
+    fn serve(responses: Vec<&'static str>) -> (String, thread::JoinHandle<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut responses = responses.into_iter();
+            let mut connections = 0;
+            'accept: for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                connections += 1;
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    if !read_request(&mut reader) {
+                        continue 'accept;
+                    }
+                    let Some(response) = responses.next() else {
+                        break 'accept;
+                    };
+                    stream.write_all(response.as_bytes()).unwrap();
+                    if responses.len() == 0 {
+                        break 'accept;
+                    }
+                    if response.contains("Connection: close") {
+                        continue 'accept;
+                    }
+                }
+            }
+            connections
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn reuse_connections() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nsec\r\n3;x=y\r\nond\r\n0\r\n\r\n",
+            "HTTP/1.1 302 Found\r\nLocation: /third\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\nthird",
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        ]);
+        let client = Client::new(&HttpConfig::default());
+        assert_eq!(client.get(&format!("{url}/a")).unwrap().bytes(), b"first");
+        assert_eq!(client.get(&format!("{url}/b")).unwrap().bytes(), b"second");
+        assert_eq!(client.get(&format!("{url}/c")).unwrap().bytes(), b"third");
+        let resp = client.head(&format!("{url}/d")).unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        // The connection closed by the server is replaced once.
+        assert_eq!(server.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn accept_headers_outside_ascii() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Disposition: attachment; filename=\"曲.zip\"\r\nContent-Length: 2\r\n\r\nok",
+        ]);
+        let resp = Client::new(&HttpConfig::default()).get(&url).unwrap();
+        assert_eq!(
+            resp.headers()[header::CONTENT_DISPOSITION].as_bytes(),
+            "attachment; filename=\"曲.zip\"".as_bytes()
+        );
+        assert_eq!(resp.bytes(), b"ok");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn negotiate_http2() {
+        // The HTTP/2 offered to servers needs a curl built with it.
+        assert!(curl::Version::get().feature_http2());
+        let (url, server) = serve(vec!["HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"]);
+        // Servers over plain HTTP are still spoken to in HTTP/1.1.
+        let resp = Client::new(&HttpConfig::default()).get(&url).unwrap();
+        assert_eq!(resp.bytes(), b"ok");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn open_connection_per_request_without_keep_alive() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}",
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n[]",
+        ]);
+        let client = Client::new(&HttpConfig {
+            keep_alive: false,
+            ..Default::default()
+        });
+        let resp = client.get(&url).unwrap();
+        assert!(resp.json::<HashMap<String, String>>().unwrap().is_empty());
+        assert!(client
+            .get(&url)
+            .unwrap()
+            .json::<Vec<u8>>()
+            .unwrap()
+            .is_empty());
+        assert_eq!(server.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn give_up_at_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/songs/a/download", listener.local_addr().unwrap());
+        // Sends the body a byte at a time, fast enough never to time out.
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                read_request(&mut reader);
+                thread::spawn(move || {
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n")
+                        .unwrap();
+                    for _ in 0..1000 {
+                        if stream.write_all(b"x").is_err() {
+                            break;
+                        }
+                        thread::sleep(Duration::from_millis(20));
+                    }
+                });
+            }
+        });
+        for keep_alive in [true, false] {
+            let client = Client::new(&HttpConfig {
+                keep_alive,
+                ..Default::default()
+            });
+            let start = Instant::now();
+            let error = client
+                .get_until(&url, start + Duration::from_millis(300))
+                .unwrap_err();
+            assert!(error.is::<DeadlineExceeded>(), "{error:?}");
+            assert!(start.elapsed() < Duration::from_secs(5));
+            let error = client.get_until(&url, start).unwrap_err();
+            assert!(error.is::<DeadlineExceeded>());
+        }
+    }
+
+    #[test]
+    fn decompress_api_responses() {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(br#"{"data":[]}"#).unwrap();
+        let server = MockServer::start();
+        let api_mock = server.mock(|when, then| {
+            when.path("/api")
+                .header("accept-encoding", accept_encoding());
+            then.status(200)
+                .header("content-encoding", "gzip")
+                .body(gzip.finish().unwrap());
+        });
+        for keep_alive in [true, false] {
+            let client = Client::new(&HttpConfig {
+                keep_alive,
+                ..Default::default()
+            });
+            let resp = client.get_api(&server.url("/api")).unwrap();
+            assert_eq!(resp.headers().get(header::CONTENT_ENCODING), None);
+            assert_eq!(resp.bytes(), br#"{"data":[]}"#);
+        }
+        api_mock.assert_hits(2);
+
+        // Archives are not asked for compressed.
+        assert_eq!(
+            Client::new(&HttpConfig::default())
+                .get(&server.url("/api"))
+                .unwrap()
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn post_without_following_redirects() {
+        let server = MockServer::start();
+        let post = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/login")
+                .header("cookie", "session=new")
+                .body("form");
+            then.status(302).header("location", "/home");
+        });
+        let mut client = Client::new(&HttpConfig::default());
+        client.header(header::COOKIE, "session=old").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_static("session=new"));
+        let resp = client
+            .post(&server.url("/login"), &headers, b"form")
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        post.assert();
+    }
+
+    #[test]
+    fn map_hosts() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.path("/songs")
+                .header("host", format!("ksm.test:{}", server.port()));
+            then.status(200).body("ok");
+        });
+        for keep_alive in [true, false] {
+            let client = Client::new(&HttpConfig {
+                keep_alive,
+                resolve: vec![format!("KSM.test:{}:[::1],127.0.0.1", server.port())
+                    .parse()
+                    .unwrap()],
+                ..Default::default()
+            });
+            let url = format!("http://ksm.test:{}/songs", server.port());
+            assert_eq!(client.get(&url).unwrap().bytes(), b"ok");
+        }
+        mock.assert_hits(2);
+
+        let mapping: HostMapping = "ksm.dev:443:1.2.3.4".parse().unwrap();
+        assert_eq!(mapping.addrs, [IpAddr::from([1, 2, 3, 4])]);
+        assert!("ksm.dev:1.2.3.4".parse::<HostMapping>().is_err());
+        assert!("ksm.dev:443:ksm.test".parse::<HostMapping>().is_err());
+    }
+}
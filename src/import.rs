@@ -0,0 +1,248 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::ensure;
+use anyhow::Context;
+use chrono::Utc;
+use serde_json::json;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+
+use crate::dedup;
+use crate::ksh;
+use crate::ksh::Header;
+use crate::layout::Layout;
+use crate::store::Entry;
+use crate::store::Store;
+use crate::Song;
+
+/// Suffix of the IDs of songs imported from local archives, which no server
+/// knows. It comes last so that ID prefixes in directory names stay unique.
+pub const LOCAL_ID_SUFFIX: &str = "-local";
+
+/// Name of the file in an imported song directory that records where the
+/// song came from.
+pub const SIDECAR_FILENAME: &str = "import.json";
+
+/// Returns whether `id` is the ID of a song imported from a local archive.
+pub fn is_local(id: &str) -> bool {
+    id.ends_with(LOCAL_ID_SUFFIX)
+}
+
+/// Outcome of an import.
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// IDs of the songs imported.
+    pub imported: Vec<String>,
+
+    /// Archives that could not be imported.
+    pub failed: Vec<PathBuf>,
+}
+
+/// Imports song archives obtained outside Nautica into the library `dest`.
+#[derive(Debug, Default)]
+pub struct Importer {
+    layout: Layout,
+}
+
+impl Importer {
+    /// Sets the naming of the song directories.
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Imports the zip archive at `path`, or every zip archive in the
+    /// directory `path`. Archives imported before are skipped, and archives
+    /// that fail to import are logged and reported without stopping the
+    /// others.
+    pub fn import(&self, dest: &Path, path: &Path) -> anyhow::Result<ImportReport> {
+        let archives = if path.is_dir() {
+            let mut archives: Vec<_> = fs::read_dir(path)?
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| {
+                    path.extension()
+                        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+                })
+                .collect();
+            archives.sort();
+            archives
+        } else {
+            vec![path.to_owned()]
+        };
+        let mut store = Store::open(dest);
+        let mut report = ImportReport::default();
+        for archive in archives {
+            let _span = info_span!("import_song", archive = %archive.display()).entered();
+            match self.import_archive(dest, &mut store, &archive) {
+                Ok(Some(id)) => report.imported.push(id),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!(error = format!("{e:#}"), "Failed to import");
+                    report.failed.push(archive);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    fn import_archive(
+        &self,
+        dest: &Path,
+        store: &mut Store,
+        archive: &Path,
+    ) -> anyhow::Result<Option<String>> {
+        let bytes =
+            fs::read(archive).with_context(|| format!("Failed to read {}", archive.display()))?;
+        let sha256 = hex::encode(Sha256::digest(&bytes));
+        // The same archive always gets the same ID, so importing is repeatable.
+        let id = format!("{}{LOCAL_ID_SUFFIX}", &sha256[..16]);
+        if store.contains(&id) {
+            info!(id, "Already imported");
+            return Ok(None);
+        }
+
+        let staging = dest.join(format!(".nautica-{id}"));
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(&staging)?;
+        let result = crate::extract(Cursor::new(bytes), None, &staging)
+            .and_then(|()| self.register(dest, store, &staging, &id, archive, &sha256));
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&staging);
+        }
+        result.map(Some)
+    }
+
+    /// Moves the extracted song in `staging` into the library and records it.
+    fn register(
+        &self,
+        dest: &Path,
+        store: &mut Store,
+        staging: &Path,
+        id: &str,
+        archive: &Path,
+        sha256: &str,
+    ) -> anyhow::Result<String> {
+        let charts = ksh::charts(staging)?;
+        ensure!(!charts.is_empty(), "No charts in {}", archive.display());
+        let header = Header::read(&charts[0])?;
+        let stem = archive
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let now = Utc::now();
+        let song = Song {
+            id: id.to_owned(),
+            user_id: String::new(),
+            title: header.get("title").unwrap_or(stem.as_str()).to_owned(),
+            artist: header.get("artist").unwrap_or_default().to_owned(),
+            uploaded_at: now,
+            updated_at: now,
+            user: None,
+            jacket_url: None,
+            preview_url: None,
+            charts: Vec::new(),
+            tags: Vec::new(),
+            encoding: None,
+            mojibake: false,
+            downloads: 0,
+        };
+
+        let sidecar = json!({
+            "id": id,
+            "source": archive.file_name().map(|name| name.to_string_lossy()),
+            "sha256": sha256,
+            "imported_at": now,
+        });
+        fs::write(
+            staging.join(SIDECAR_FILENAME),
+            serde_json::to_string_pretty(&sidecar)?,
+        )?;
+
+        let dir = self
+            .layout
+            .unique_dir_name(&(&song).into(), dest, &store.taken_dirs());
+        let mut entry = Entry::new(&song, &dir);
+        entry.read_song_info(staging);
+        entry.fingerprint = dedup::fingerprint(staging).unwrap_or_default();
+        let song_dest: PathBuf = dest.join(&dir);
+        if let Some(parent) = song_dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(staging, &song_dest)?;
+        if let Err(e) = store.insert(id, &entry) {
+            // Moved back to be removed with the rest of the failed import, so
+            // that no song is left in the library without an entry.
+            let _ = fs::rename(&song_dest, staging);
+            return Err(e);
+        }
+        info!(id, title = entry.title, artist = entry.artist, "Imported");
+        Ok(id.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn import_zip() {
+        let dest = tempdir().unwrap();
+        let archives = tempdir().unwrap();
+        fs::copy(
+            "tests/fixtures/89b54d80-4e6d-11ee-83d4-2ffdf82667a6.zip",
+            archives.path().join("turing.zip"),
+        )
+        .unwrap();
+        fs::write(archives.path().join("notes.txt"), "").unwrap();
+
+        fs::write(archives.path().join("broken.zip"), "not a zip").unwrap();
+
+        let importer = Importer::default().layout(Layout::Readable);
+        let report = importer.import(dest.path(), archives.path()).unwrap();
+        assert_eq!(report.failed, [archives.path().join("broken.zip")]);
+        let ids = report.imported;
+        assert_eq!(ids.len(), 1);
+        assert!(is_local(&ids[0]));
+
+        let entry = Store::open_read_only(dest.path()).get(&ids[0]).unwrap();
+        assert_eq!(entry.title, "チューリングラブ feat.Sou");
+        assert!(!entry.charts.is_empty());
+        let song_dest = dest.path().join(entry.dir(&ids[0]));
+        assert!(song_dest.join("チューリングラブ feat.Sou.ogg").exists());
+        assert!(song_dest.join(SIDECAR_FILENAME).exists());
+
+        // Importing again is a no-op.
+        let report = importer
+            .import(dest.path(), &archives.path().join("turing.zip"))
+            .unwrap();
+        assert!(report.imported.is_empty());
+        assert!(report.failed.is_empty());
+    }
+
+    #[test]
+    fn leave_no_song_behind_when_recording_fails() {
+        let dest = tempdir().unwrap();
+        // The store cannot be written over a directory.
+        fs::create_dir(dest.path().join("meta.json")).unwrap();
+        let archive = Path::new("tests/fixtures/89b54d80-4e6d-11ee-83d4-2ffdf82667a6.zip");
+
+        let report = Importer::default().import(dest.path(), archive).unwrap();
+        assert_eq!(report.failed, [archive]);
+        // Only the store and what its failed write left.
+        for entry in fs::read_dir(dest.path()).unwrap() {
+            let name = entry.unwrap().file_name();
+            assert!(name.to_string_lossy().starts_with("meta.json"), "{name:?}");
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::ensure;
+
+const JACKETS_DIRNAME: &str = ".jackets";
+
+/// Image extensions a cached jacket may have. Jackets with any other
+/// extension are stored as `png`.
+pub(crate) const EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp", "bmp"];
+
+/// Jacket images of remote songs, stored as `<dest>/.jackets/<id>.<ext>` so
+/// that songs can be browsed without downloading them.
+#[derive(Debug, Clone)]
+pub struct JacketCache {
+    dir: PathBuf,
+}
+
+impl JacketCache {
+    pub fn new(dest: &Path) -> Self {
+        Self {
+            dir: dest.join(JACKETS_DIRNAME),
+        }
+    }
+
+    /// Returns the cached jacket of the song `id`, if any.
+    pub fn get(&self, id: &str) -> Option<PathBuf> {
+        EXTENSIONS
+            .iter()
+            .map(|ext| self.dir.join(format!("{id}.{ext}")))
+            .find(|path| path.is_file())
+    }
+
+    /// Stores the jacket of the song `id`, returning its path.
+    pub fn insert(&self, id: &str, ext: &str, bytes: &[u8]) -> io::Result<PathBuf> {
+        let ext = ext.to_ascii_lowercase();
+        let ext = if EXTENSIONS.contains(&ext.as_str()) {
+            ext.as_str()
+        } else {
+            EXTENSIONS[0]
+        };
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("{id}.{ext}"));
+        fs::write(&path, bytes)?;
+        Ok(path)
+    }
+}
+
+/// What the headers of a jacket image tell about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// Format of the image, e.g. `png`.
+    pub format: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Checks that `bytes` hold a whole PNG, JPEG, GIF, WebP, or BMP image with a
+/// size, so that broken jackets, e.g. error pages or cut off downloads, are
+/// caught before anything tries to show them.
+pub fn check(bytes: &[u8]) -> anyhow::Result<ImageInfo> {
+    let (format, info) = if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        ("png", png_size(bytes))
+    } else if bytes.starts_with(b"\xff\xd8") {
+        ("jpeg", jpeg_size(bytes))
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        ("gif", gif_size(bytes))
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        ("webp", webp_size(bytes))
+    } else if bytes.starts_with(b"BM") {
+        ("bmp", bmp_size(bytes))
+    } else {
+        bail!("Not a known image format");
+    };
+    let Some((width, height)) = info else {
+        bail!("Broken or truncated {format} image");
+    };
+    ensure!(width > 0 && height > 0, "The {format} image is empty");
+    Ok(ImageInfo {
+        format,
+        width,
+        height,
+    })
+}
+
+/// Reads the size from the `IHDR` chunk, requiring the `IEND` chunk last.
+fn png_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    if bytes.get(12..16)? != b"IHDR" || !bytes.ends_with(b"IEND\xae\x42\x60\x82") {
+        return None;
+    }
+    Some((u32_be(bytes, 16)?, u32_be(bytes, 20)?))
+}
+
+/// Reads the size from the first start of frame segment, requiring the end of
+/// image marker last.
+fn jpeg_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    // Some encoders pad the file after the end of image marker.
+    let end = bytes.iter().rposition(|&b| b != 0)?;
+    if end < 1 || bytes[end - 1..=end] != [0xff, 0xd9] {
+        return None;
+    }
+    let mut offset = 2;
+    loop {
+        if *bytes.get(offset)? != 0xff {
+            return None;
+        }
+        let marker = *bytes.get(offset + 1)?;
+        let len = usize::from(u16::from_be_bytes([
+            *bytes.get(offset + 2)?,
+            *bytes.get(offset + 3)?,
+        ]));
+        // SOF0 to SOF15, except DHT, JPG, and DAC, which share the range.
+        if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+            let height = u16::from_be_bytes([*bytes.get(offset + 5)?, *bytes.get(offset + 6)?]);
+            let width = u16::from_be_bytes([*bytes.get(offset + 7)?, *bytes.get(offset + 8)?]);
+            return Some((u32::from(width), u32::from(height)));
+        }
+        offset += 2 + len;
+    }
+}
+
+/// Reads the size from the logical screen descriptor, requiring the trailer
+/// last.
+fn gif_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    if bytes.last() != Some(&0x3b) {
+        return None;
+    }
+    let width = u16::from_le_bytes([*bytes.get(6)?, *bytes.get(7)?]);
+    let height = u16::from_le_bytes([*bytes.get(8)?, *bytes.get(9)?]);
+    Some((u32::from(width), u32::from(height)))
+}
+
+/// Reads the size from the first chunk, requiring the file to be as long as
+/// its RIFF header says.
+fn webp_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let riff_len = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?) as usize;
+    if bytes.len() < riff_len + 8 {
+        return None;
+    }
+    let u24 = |i: usize| {
+        Some(u32::from_le_bytes([
+            *bytes.get(i)?,
+            *bytes.get(i + 1)?,
+            *bytes.get(i + 2)?,
+            0,
+        ]))
+    };
+    match bytes.get(12..16)? {
+        b"VP8 " => {
+            let width = u16::from_le_bytes([*bytes.get(26)?, *bytes.get(27)?]) & 0x3fff;
+            let height = u16::from_le_bytes([*bytes.get(28)?, *bytes.get(29)?]) & 0x3fff;
+            Some((u32::from(width), u32::from(height)))
+        }
+        b"VP8L" => {
+            let bits = u32::from_le_bytes(bytes.get(21..25)?.try_into().ok()?);
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        b"VP8X" => Some((u24(24)? + 1, u24(27)? + 1)),
+        _ => None,
+    }
+}
+
+/// Reads the size from the info header, requiring the pixel data to be in
+/// the file.
+fn bmp_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let data = u32::from_le_bytes(bytes.get(10..14)?.try_into().ok()?) as usize;
+    if bytes.len() <= data {
+        return None;
+    }
+    let width = i32::from_le_bytes(bytes.get(18..22)?.try_into().ok()?);
+    // Top-down bitmaps have a negative height.
+    let height = i32::from_le_bytes(bytes.get(22..26)?.try_into().ok()?);
+    Some((width.unsigned_abs(), height.unsigned_abs()))
+}
+
+fn u32_be(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Outcome of filling the jacket cache.
+#[derive(Debug, Default)]
+pub struct JacketReport {
+    /// Number of jackets downloaded in this run.
+    pub fetched: usize,
+
+    /// Number of songs whose jacket was already cached.
+    pub cached: usize,
+
+    /// Number of songs without a jacket on the server.
+    pub missing: usize,
+
+    /// IDs of the songs whose jacket failed to download.
+    pub failed: Vec<String>,
+
+    /// IDs of the songs whose jacket on the server is not a valid image.
+    pub invalid: Vec<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn insert_and_get() {
+        let dest = tempdir().unwrap();
+        let cache = JacketCache::new(dest.path());
+        assert_eq!(cache.get("5441d590"), None);
+
+        let path = cache.insert("5441d590", "JPG", b"jpg").unwrap();
+        assert_eq!(path, dest.path().join(".jackets/5441d590.jpg"));
+        assert_eq!(cache.get("5441d590"), Some(path));
+
+        let path = cache.insert("89b54d80", "php", b"png").unwrap();
+        assert_eq!(path, dest.path().join(".jackets/89b54d80.png"));
+    }
+
+    #[test]
+    fn check_images() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend(300u32.to_be_bytes());
+        png.extend(200u32.to_be_bytes());
+        png.extend(b"\x08\x06\0\0\0\0\0\0\0\0\0\0\0IEND\xae\x42\x60\x82");
+        assert_eq!(
+            check(&png).unwrap(),
+            ImageInfo {
+                format: "png",
+                width: 300,
+                height: 200
+            }
+        );
+        let err = check(&png[..png.len() - 4]).unwrap_err();
+        assert_eq!(err.to_string(), "Broken or truncated png image");
+
+        // APP0 segment, then a baseline start of frame of 640x480.
+        let mut jpeg = b"\xff\xd8\xff\xe0\0\x04\0\0\xff\xc0\0\x0b\x08".to_vec();
+        jpeg.extend([0x01, 0xe0, 0x02, 0x80, 0x01, 0, 0, 0]);
+        jpeg.extend(b"\xff\xd9\0\0");
+        assert_eq!(check(&jpeg).unwrap().width, 640);
+        assert_eq!(check(&jpeg).unwrap().height, 480);
+
+        let gif = b"GIF89a\x10\0\x20\0\0\0\0\x3b";
+        assert_eq!(check(gif).unwrap().height, 32);
+
+        let mut webp = b"RIFF\x16\0\0\0WEBPVP8X\x0a\0\0\0\0\0\0\0".to_vec();
+        webp.extend([0xff, 0, 0, 0x7f, 0, 0]);
+        assert_eq!(check(&webp).unwrap().width, 256);
+        assert_eq!(check(&webp).unwrap().height, 128);
+
+        let gif = b"GIF89a\0\0\0\0\0\0\0\x3b";
+        assert_eq!(
+            check(gif).unwrap_err().to_string(),
+            "The gif image is empty"
+        );
+        assert!(check(b"<html>Not found</html>").is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use encoding_rs::SHIFT_JIS;
+use encoding_rs::UTF_8;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+const EXTENSION: &str = "ksh";
+
+/// Extension of charts in KSON, the JSON-based successor of the KSH format.
+const KSON_EXTENSION: &str = "kson";
+
+/// Names of the difficulty slots in KSH headers, by index.
+const DIFFICULTY_NAMES: [&str; 4] = ["light", "challenge", "extended", "infinite"];
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Metadata lines of a K-Shoot Mania chart, i.e. the `key=value` lines
+/// before the first `--` separator.
+///
+/// The metadata of KSON charts is mapped to the same keys, so callers need
+/// not care about the format.
+#[derive(Debug, Clone, Default)]
+pub struct Header {
+    fields: Vec<(String, String)>,
+}
+
+impl Header {
+    /// Reads the header of the chart at `path`.
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        if is_kson(path) {
+            return Self::parse_kson(&decode(&bytes))
+                .with_context(|| format!("Invalid KSON chart {}", path.display()));
+        }
+        Ok(Self::parse(&decode(&bytes)))
+    }
+
+    /// Parses the metadata of a KSON chart into the keys of a KSH header.
+    pub fn parse_kson(content: &str) -> anyhow::Result<Self> {
+        let kson: Value =
+            serde_json::from_str(content.strip_prefix('\u{feff}').unwrap_or(content))?;
+        let meta = &kson["meta"];
+        let mut fields = Vec::new();
+        let mut push = |key: &str, value: &Value| {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                Value::Number(n) => n.to_string(),
+                _ => return,
+            };
+            fields.push((key.to_owned(), value));
+        };
+        for (key, kson_key) in [
+            ("title", "title"),
+            ("artist", "artist"),
+            ("effect", "chart_author"),
+            ("illustrator", "jacket_author"),
+            ("jacket", "jacket_filename"),
+            ("icon", "icon_filename"),
+            ("level", "level"),
+            ("t", "disp_bpm"),
+        ] {
+            push(key, &meta[kson_key]);
+        }
+        // Older versions of the format wrap the index in an object.
+        let difficulty = meta["difficulty"]
+            .as_u64()
+            .or_else(|| meta["difficulty"]["idx"].as_u64());
+        if let Some(name) = difficulty.and_then(|i| DIFFICULTY_NAMES.get(i as usize)) {
+            push("difficulty", &Value::from(*name));
+        }
+        push("m", &kson["audio"]["bgm"]["filename"]);
+        Ok(Self { fields })
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let fields = content
+            .strip_prefix('\u{feff}')
+            .unwrap_or(content)
+            .lines()
+            .take_while(|line| line.trim() != "--")
+            .filter_map(|line| {
+                let (key, value) = line.split_once('=')?;
+                Some((key.trim().to_owned(), value.trim().to_owned()))
+            })
+            .collect();
+        Self { fields }
+    }
+
+    /// Returns the value of the first line with `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the chart level, 1 to 20.
+    pub fn level(&self) -> Option<u8> {
+        self.get("level")?.parse().ok()
+    }
+
+    /// Returns the BPM shown in song select, e.g. `11.875-475` for a chart
+    /// with tempo changes.
+    pub fn bpm(&self) -> Option<Bpm> {
+        let value = self.get("t")?;
+        let (min, max) = value.split_once('-').unwrap_or((value, value));
+        Some(Bpm {
+            min: min.trim().parse().ok()?,
+            max: max.trim().parse().ok()?,
+        })
+    }
+
+    /// Returns the file name of the music, without the alternative tracks
+    /// that may follow it.
+    pub fn music(&self) -> Option<&str> {
+        self.get("m")?.split(';').next().filter(|m| !m.is_empty())
+    }
+
+    /// Returns the difficulty slot, 0 (light) to 3 (infinite).
+    pub fn difficulty_index(&self) -> Option<u8> {
+        let difficulty = self.get("difficulty")?;
+        let index = DIFFICULTY_NAMES
+            .iter()
+            .position(|name| *name == difficulty)?;
+        Some(index as u8)
+    }
+}
+
+/// Tempo range of a song.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Bpm {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl fmt::Display for Bpm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}-{}", self.min, self.max)
+        }
+    }
+}
+
+/// Decodes a chart file, which is UTF-8 (usually with a byte order mark) in
+/// newer charts and Shift_JIS in older ones.
+pub fn decode(bytes: &[u8]) -> Cow<'_, str> {
+    if let Some(content) = UTF_8.decode_without_bom_handling_and_without_replacement(
+        bytes.strip_prefix(UTF8_BOM).unwrap_or(bytes),
+    ) {
+        return content;
+    }
+    SHIFT_JIS.decode(bytes).0
+}
+
+/// Rewrites the chart at `path` with `f`, keeping its encoding and byte order
+/// mark. Returns whether the chart changed.
+pub fn rewrite(path: &Path, f: impl FnOnce(&str) -> String) -> anyhow::Result<bool> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let (bom, body) = match bytes.strip_prefix(UTF8_BOM) {
+        Some(body) => (UTF8_BOM, body),
+        None => (&[][..], &bytes[..]),
+    };
+    let rewritten = match std::str::from_utf8(body) {
+        Ok(content) => [bom, f(content).as_bytes()].concat(),
+        Err(_) => {
+            let content = f(&SHIFT_JIS.decode(&bytes).0);
+            let (encoded, _, had_errors) = SHIFT_JIS.encode(&content);
+            ensure!(!had_errors, "Cannot encode {} as Shift_JIS", path.display());
+            encoded.into_owned()
+        }
+    };
+    if rewritten == bytes {
+        return Ok(false);
+    }
+    fs::write(path, rewritten)?;
+    Ok(true)
+}
+
+/// Returns whether the chart at `path` is in the KSON format.
+pub fn is_kson(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(KSON_EXTENSION))
+}
+
+/// Returns the chart files in `dir`, KSH or KSON, sorted by file name.
+pub fn charts(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut charts: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.extension().is_some_and(|ext| {
+                ext.eq_ignore_ascii_case(EXTENSION) || ext.eq_ignore_ascii_case(KSON_EXTENSION)
+            })
+        })
+        .collect();
+    charts.sort();
+    Ok(charts)
+}
+
+/// Returns the music file that the first chart in `dir` refers to.
+pub fn music_file(dir: &Path) -> anyhow::Result<PathBuf> {
+    for chart in charts(dir)? {
+        if let Some(music) = Header::read(&chart)?.music() {
+            let path = dir.join(music);
+            if path.is_file() {
+                return Ok(path);
+            }
+        }
+    }
+    bail!("No music file found in {}", dir.display())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_header() {
+        let header = Header::parse(
+            "\u{feff}title=Outbreak\r\nartist=RG+Ice\r\ndifficulty=extended\r\nlevel=16\r\n--\r\nt=190\r\n",
+        );
+        assert_eq!(header.get("artist"), Some("RG+Ice"));
+        assert_eq!(header.level(), Some(16));
+        assert_eq!(header.difficulty_index(), Some(2));
+        assert_eq!(header.get("t"), None);
+        assert_eq!(header.music(), None);
+        assert_eq!(header.bpm(), None);
+
+        let header = Header::parse("t=11.875-475\nm=a.ogg;a_f.ogg\n");
+        assert_eq!(header.music(), Some("a.ogg"));
+        let bpm = header.bpm().unwrap();
+        assert_eq!((bpm.min, bpm.max), (11.875, 475.0));
+        assert_eq!(bpm.to_string(), "11.875-475");
+        assert_eq!(Header::parse("t=190").bpm().unwrap().to_string(), "190");
+    }
+
+    #[test]
+    fn parse_kson_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("exh.kson");
+        fs::write(
+            &path,
+            r#"{
+                "format_version": 1,
+                "meta": {
+                    "title": "Outbreak",
+                    "artist": "RG+Ice",
+                    "chart_author": "Ixiot",
+                    "jacket_filename": "jacket.png",
+                    "difficulty": 2,
+                    "level": 16,
+                    "disp_bpm": "95-190"
+                },
+                "audio": { "bgm": { "filename": "outbreak.ogg" } }
+            }"#,
+        )
+        .unwrap();
+        fs::write(dir.path().join("nov.ksh"), "title=Outbreak\n--\n").unwrap();
+        fs::write(dir.path().join("outbreak.ogg"), "").unwrap();
+
+        let header = Header::read(&path).unwrap();
+        assert_eq!(header.get("title"), Some("Outbreak"));
+        assert_eq!(header.get("effect"), Some("Ixiot"));
+        assert_eq!(header.get("jacket"), Some("jacket.png"));
+        assert_eq!(header.level(), Some(16));
+        assert_eq!(header.difficulty_index(), Some(2));
+        assert_eq!(header.bpm().unwrap().to_string(), "95-190");
+        assert_eq!(header.music(), Some("outbreak.ogg"));
+
+        let header = Header::parse_kson(r#"{ "meta": { "difficulty": { "idx": 3 } } }"#).unwrap();
+        assert_eq!(header.difficulty_index(), Some(3));
+        assert!(Header::parse_kson("title=Outbreak").is_err());
+
+        assert_eq!(
+            charts(dir.path()).unwrap(),
+            [path, dir.path().join("nov.ksh")]
+        );
+        assert_eq!(
+            music_file(dir.path()).unwrap(),
+            dir.path().join("outbreak.ogg")
+        );
+    }
+
+    #[test]
+    fn decode_encodings() {
+        assert_eq!(decode(b"\xef\xbb\xbftitle=a"), "title=a");
+        let (sjis, _, _) = SHIFT_JIS.encode("title=チューリングラブ");
+        assert_eq!(decode(&sjis), "title=チューリングラブ");
+    }
+
+    #[test]
+    fn rewrite_keeps_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let utf8 = dir.path().join("utf8.ksh");
+        fs::write(&utf8, "\u{feff}title=チューリングラブ\nm=a.wav\n").unwrap();
+        let sjis = dir.path().join("sjis.ksh");
+        fs::write(
+            &sjis,
+            SHIFT_JIS.encode("title=チューリングラブ\nm=a.wav\n").0,
+        )
+        .unwrap();
+
+        for path in [&utf8, &sjis] {
+            assert!(rewrite(path, |c| c.replace("a.wav", "a.ogg")).unwrap());
+            assert!(!rewrite(path, |c| c.replace("a.wav", "a.ogg")).unwrap());
+        }
+        assert_eq!(
+            fs::read_to_string(&utf8).unwrap(),
+            "\u{feff}title=チューリングラブ\nm=a.ogg\n"
+        );
+        assert_eq!(
+            fs::read(&sjis).unwrap(),
+            &*SHIFT_JIS.encode("title=チューリングラブ\nm=a.ogg\n").0
+        );
+    }
+}
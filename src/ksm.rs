@@ -0,0 +1,124 @@
+use std::fs;
+use std::path::Path;
+
+use chrono::DateTime;
+use chrono::Local;
+use chrono::Utc;
+use encoding_rs::SHIFT_JIS;
+
+use crate::ksh;
+use crate::layout::sanitize;
+use crate::notify::Notifier;
+use crate::Song;
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// How songs are grouped into folders inside K-Shoot Mania's `songs`
+/// directory, which only looks at `songs/<group>/<song>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KsmGroup {
+    /// One folder per uploader.
+    Uploader,
+
+    /// One folder per day of syncing, e.g. `Nautica 2023-09-10`.
+    Sync,
+}
+
+impl KsmGroup {
+    pub(crate) fn folder_name(self, uploader: &str, downloaded_at: DateTime<Utc>) -> String {
+        match self {
+            Self::Uploader => sanitize_name(uploader),
+            Self::Sync => format!(
+                "Nautica {}",
+                downloaded_at.with_timezone(&Local).format("%Y-%m-%d")
+            ),
+        }
+    }
+}
+
+/// Like [`sanitize`], but also replaces characters that cannot be encoded in
+/// Shift_JIS, since K-Shoot Mania cannot open such paths.
+pub fn sanitize_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            let mut buf = [0; 4];
+            let (_, _, had_errors) = SHIFT_JIS.encode(c.encode_utf8(&mut buf));
+            if had_errors {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    sanitize(&name)
+}
+
+/// Adds a byte order mark to the UTF-8 charts in `dir` that lack one, since
+/// K-Shoot Mania reads charts without it as Shift_JIS. Returns the number of
+/// charts changed. KSON charts are JSON, which must not start with a BOM, so
+/// they are left alone.
+pub fn add_bom_to_charts(dir: &Path) -> anyhow::Result<usize> {
+    let mut changed = 0;
+    for chart in ksh::charts(dir)? {
+        if ksh::is_kson(&chart) {
+            continue;
+        }
+        let bytes = fs::read(&chart)?;
+        if bytes.starts_with(UTF8_BOM) || bytes.is_ascii() || std::str::from_utf8(&bytes).is_err() {
+            continue;
+        }
+        fs::write(&chart, [UTF8_BOM, &bytes].concat())?;
+        changed += 1;
+    }
+    Ok(changed)
+}
+
+/// Prepares each downloaded song for K-Shoot Mania.
+#[derive(Debug, Default)]
+pub struct KsmNotifier;
+
+impl Notifier for KsmNotifier {
+    fn song_downloaded(&self, _song: &Song, path: &Path) -> anyhow::Result<()> {
+        add_bom_to_charts(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn sanitize_for_shift_jis() {
+        assert_eq!(sanitize_name("チューリングラブ"), "チューリングラブ");
+        assert_eq!(sanitize_name("Café ♪ 🎵"), "Caf_ ♪ _");
+    }
+
+    #[test]
+    fn add_bom() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("utf8.ksh"), "title=チューリングラブ").unwrap();
+        fs::write(dir.path().join("bom.ksh"), "\u{feff}title=チューリングラブ").unwrap();
+        fs::write(dir.path().join("ascii.ksh"), "title=Outbreak").unwrap();
+        let (sjis, _, _) = SHIFT_JIS.encode("title=チューリングラブ");
+        fs::write(dir.path().join("sjis.ksh"), &sjis).unwrap();
+        fs::write(
+            dir.path().join("utf8.kson"),
+            r#"{"meta":{"title":"チューリングラブ"}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(add_bom_to_charts(dir.path()).unwrap(), 1);
+        assert!(fs::read(dir.path().join("utf8.ksh"))
+            .unwrap()
+            .starts_with(UTF8_BOM));
+        assert_eq!(fs::read(dir.path().join("sjis.ksh")).unwrap(), &*sjis);
+        assert!(fs::read_to_string(dir.path().join("utf8.kson"))
+            .unwrap()
+            .starts_with('{'));
+        assert_eq!(add_bom_to_charts(dir.path()).unwrap(), 0);
+    }
+}
@@ -0,0 +1,501 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::ksm;
+use crate::ksm::KsmGroup;
+use crate::store::Entry;
+use crate::Song;
+
+/// Length of the song ID prefix that keeps readable names unique.
+const ID_PREFIX_LEN: usize = 8;
+
+/// Maximum length in characters of a sanitized path component.
+const MAX_NAME_LEN: usize = 120;
+
+/// How song directories are named inside the library.
+///
+/// Parsed from `id`, `readable`, `uploader`, `ksm-uploader`, `ksm-sync`, or
+/// a [`Template`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Layout {
+    /// The song ID, e.g. `5441d590-4d43-11ee-a602-d95b1bfc2e6d`.
+    #[default]
+    Id,
+
+    /// `Artist - Title [5441d590]`.
+    Readable,
+
+    /// `Uploader/Artist - Title`, for browsing songs by charter.
+    Uploader,
+
+    /// A user-defined template such as `{artist}/{title} [{level_max}]`.
+    Template(Template),
+
+    /// `<group>/Artist - Title`, for use as a K-Shoot Mania `songs`
+    /// directory.
+    Ksm(KsmGroup),
+}
+
+/// The fields of a song that directory names are built from, shared by
+/// remote songs and local entries.
+pub struct Fields<'a> {
+    id: &'a str,
+    title: &'a str,
+    artist: &'a str,
+    uploader: &'a str,
+    levels: Vec<u8>,
+    uploaded_at: Option<DateTime<Utc>>,
+    downloaded_at: DateTime<Utc>,
+}
+
+impl<'a> From<&'a Song> for Fields<'a> {
+    fn from(song: &'a Song) -> Self {
+        Self {
+            id: &song.id,
+            title: &song.title,
+            artist: &song.artist,
+            uploader: match &song.user {
+                Some(user) => &user.name,
+                None => &song.user_id,
+            },
+            levels: song.charts.iter().map(|chart| chart.level).collect(),
+            uploaded_at: Some(song.uploaded_at),
+            downloaded_at: Utc::now(),
+        }
+    }
+}
+
+impl<'a> Fields<'a> {
+    /// Uses the stored metadata of the local song with ID `id`.
+    pub fn entry(id: &'a str, entry: &'a Entry) -> Self {
+        Self {
+            id,
+            title: &entry.title,
+            artist: &entry.artist,
+            uploader: entry.uploader(),
+            levels: entry.levels.clone(),
+            uploaded_at: entry.uploaded_at,
+            downloaded_at: entry.downloaded_at,
+        }
+    }
+}
+
+impl Layout {
+    /// Returns the directory of a song, relative to the library. Nested
+    /// directories are separated by `/`.
+    pub fn dir_name(&self, song: &Fields) -> String {
+        match self {
+            Self::Id => song.id.to_owned(),
+            Self::Readable => {
+                let name = sanitize(&format!("{} - {}", song.artist, song.title));
+                format!("{name} [{}]", id_prefix(song))
+            }
+            Self::Uploader => format!(
+                "{}/{}",
+                sanitize(song.uploader),
+                sanitize(&format!("{} - {}", song.artist, song.title))
+            ),
+            Self::Template(template) => template.render(song),
+            Self::Ksm(group) => format!(
+                "{}/{}",
+                group.folder_name(song.uploader, song.downloaded_at),
+                ksm::sanitize_name(&format!("{} - {}", song.artist, song.title))
+            ),
+        }
+    }
+
+    /// Returns whether directory names include the uploader's name, which
+    /// the server may need to be asked for.
+    pub fn uses_uploader(&self) -> bool {
+        match self {
+            Self::Id | Self::Readable | Self::Ksm(KsmGroup::Sync) => false,
+            Self::Uploader | Self::Ksm(KsmGroup::Uploader) => true,
+            Self::Template(template) => template.parts.contains(&Part::Field(Field::Uploader)),
+        }
+    }
+
+    /// Like [`Layout::dir_name`], but avoids directories that already exist in
+    /// `dest` or are recorded for other songs in `taken` by appending the
+    /// song ID prefix, or the full song ID if that is taken too, to the last
+    /// path component. Different songs with the same artist and title thus
+    /// never share a directory.
+    pub fn unique_dir_name(&self, song: &Fields, dest: &Path, taken: &TakenDirs) -> String {
+        let dir = self.dir_name(song);
+        let unique = [
+            dir.clone(),
+            format!("{dir} [{}]", id_prefix(song)),
+            format!("{dir} [{}]", song.id),
+        ]
+        .into_iter()
+        .find(|dir| !taken.contains(dir) && !dest.join(dir).exists())
+        .unwrap_or_else(|| dir.clone());
+        if unique != dir {
+            info!(
+                id = song.id,
+                taken = dir,
+                dir = unique,
+                "Directory name taken by another song; added the song ID"
+            );
+        }
+        unique
+    }
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "id" => Ok(Self::Id),
+            "readable" => Ok(Self::Readable),
+            "uploader" => Ok(Self::Uploader),
+            "ksm-uploader" => Ok(Self::Ksm(KsmGroup::Uploader)),
+            "ksm-sync" => Ok(Self::Ksm(KsmGroup::Sync)),
+            _ if s.contains('{') => Ok(Self::Template(s.parse()?)),
+            _ => bail!(
+                "Unknown layout {s:?}, expected \"id\", \"readable\", \"uploader\", \
+                 \"ksm-uploader\", \"ksm-sync\", or a template"
+            ),
+        }
+    }
+}
+
+impl TryFrom<String> for Layout {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+/// Directories recorded for songs of a library, which other songs must not
+/// take even where they are missing, e.g. after an upload removed the local
+/// copy. Names are compared case-insensitively, as on Windows and macOS.
+#[derive(Debug, Default)]
+pub struct TakenDirs(HashSet<String>);
+
+impl TakenDirs {
+    pub fn insert(&mut self, dir: &str) {
+        self.0.insert(dir.to_lowercase());
+    }
+
+    pub fn contains(&self, dir: &str) -> bool {
+        self.0.contains(&dir.to_lowercase())
+    }
+}
+
+impl<'a> FromIterator<&'a str> for TakenDirs {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+        let mut taken = Self::default();
+        for dir in iter {
+            taken.insert(dir);
+        }
+        taken
+    }
+}
+
+/// A directory name template.
+///
+/// Placeholders in braces are replaced with the song's metadata; see
+/// [`Field`]. `/` separates nested directories. Each path component is
+/// sanitized after rendering, so values can never introduce extra nesting or
+/// escape the library.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// Song metadata available in templates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// `{id}`: the full song ID.
+    Id,
+    /// `{id_prefix}`: the first eight characters of the song ID.
+    IdPrefix,
+    /// `{title}`
+    Title,
+    /// `{artist}`
+    Artist,
+    /// `{uploader}`: the uploader's name, or ID if the name is unknown.
+    Uploader,
+    /// `{level_min}`: the lowest chart level.
+    LevelMin,
+    /// `{level_max}`: the highest chart level.
+    LevelMax,
+    /// `{year}`: the year the song was uploaded.
+    Year,
+}
+
+impl Field {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        Ok(match name {
+            "id" => Self::Id,
+            "id_prefix" => Self::IdPrefix,
+            "title" => Self::Title,
+            "artist" => Self::Artist,
+            "uploader" => Self::Uploader,
+            "level_min" => Self::LevelMin,
+            "level_max" => Self::LevelMax,
+            "year" => Self::Year,
+            _ => bail!("Unknown template field {{{name}}}"),
+        })
+    }
+
+    fn value(self, song: &Fields) -> String {
+        let levels = song.levels.iter();
+        match self {
+            Self::Id => song.id.to_owned(),
+            Self::IdPrefix => id_prefix(song),
+            Self::Title => song.title.to_owned(),
+            Self::Artist => song.artist.to_owned(),
+            Self::Uploader => song.uploader.to_owned(),
+            Self::LevelMin => levels.min().map(|l| l.to_string()).unwrap_or_default(),
+            Self::LevelMax => levels.max().map(|l| l.to_string()).unwrap_or_default(),
+            Self::Year => song
+                .uploaded_at
+                .map(|t| t.format("%Y").to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Template {
+    fn render(&self, song: &Fields) -> String {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => rendered.push_str(text),
+                // Separators in values are replaced here so that only the
+                // template itself can create nested directories.
+                Part::Field(field) => {
+                    rendered.push_str(&field.value(song).replace(['/', '\\'], "_"))
+                }
+            }
+        }
+        rendered
+            .split('/')
+            .map(sanitize)
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed '{{' in template {s:?}"))?;
+            parts.push(Part::Field(Field::parse(&rest[start + 1..start + end])?));
+            rest = &rest[start + end + 1..];
+        }
+        ensure!(!rest.contains('}'), "Unmatched '}}' in template {s:?}");
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+        ensure!(
+            parts.iter().any(|part| matches!(part, Part::Field(_))),
+            "Template {s:?} has no fields"
+        );
+        Ok(Self { parts })
+    }
+}
+
+fn id_prefix(song: &Fields) -> String {
+    song.id.chars().take(ID_PREFIX_LEN).collect()
+}
+
+/// Makes `name` safe to use as a single path component on all platforms.
+///
+/// Path separators, characters reserved on Windows, and control characters
+/// are replaced with `_`, surrounding whitespace and trailing dots are
+/// removed, and overly long names are truncated.
+pub fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(MAX_NAME_LEN)
+        .collect();
+    let name = name.trim().trim_end_matches('.').trim_end();
+    if name.is_empty() || name == "." {
+        String::from("_")
+    } else {
+        name.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn song(title: &str, artist: &str) -> Song {
+        serde_json::from_value(json!({
+            "id": "5441d590-4d43-11ee-a602-d95b1bfc2e6d",
+            "user_id": "user",
+            "title": title,
+            "artist": artist,
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+            "user": { "id": "user", "name": "Ixiot" },
+            "charts": [
+                { "difficulty": 1, "level": 5 },
+                { "difficulty": 4, "level": 18 },
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn dir_name() {
+        let song = song("Outbreak", "RG+Ice");
+        assert_eq!(
+            Layout::Id.dir_name(&(&song).into()),
+            "5441d590-4d43-11ee-a602-d95b1bfc2e6d"
+        );
+        assert_eq!(
+            Layout::Readable.dir_name(&(&song).into()),
+            "RG+Ice - Outbreak [5441d590]"
+        );
+        assert_eq!(
+            Layout::Uploader.dir_name(&(&song).into()),
+            "Ixiot/RG+Ice - Outbreak"
+        );
+    }
+
+    #[test]
+    fn template_dir_name() {
+        let layout = |s: &str| s.parse::<Layout>().unwrap();
+        assert_eq!(
+            layout("{artist}/{title} [{level_max}]")
+                .dir_name(&(&song("Outbreak", "RG+Ice")).into()),
+            "RG+Ice/Outbreak [18]"
+        );
+        assert_eq!(
+            layout("{uploader}/{year}/{level_min}-{id_prefix}").dir_name(&(&song("t", "a")).into()),
+            "Ixiot/2023/5-5441d590"
+        );
+        assert_eq!(
+            layout("{artist}/{title}").dir_name(&(&song("..", "AC/DC")).into()),
+            "AC_DC/_"
+        );
+        assert_eq!(
+            layout("{artist}//{title}").dir_name(&(&song("t", "a")).into()),
+            "a/_/t"
+        );
+    }
+
+    #[test]
+    fn ksm_dir_name() {
+        let song = song("Outbreak ☆ ♥", "RG+Ice");
+        assert_eq!(
+            "ksm-uploader"
+                .parse::<Layout>()
+                .unwrap()
+                .dir_name(&(&song).into()),
+            "Ixiot/RG+Ice - Outbreak ☆ _"
+        );
+        assert!("ksm-sync"
+            .parse::<Layout>()
+            .unwrap()
+            .dir_name(&(&song).into())
+            .starts_with("Nautica 20"));
+    }
+
+    #[test]
+    fn parse_layout() {
+        assert_eq!("id".parse::<Layout>().unwrap(), Layout::Id);
+        assert_eq!("readable".parse::<Layout>().unwrap(), Layout::Readable);
+        assert!("uploader".parse::<Layout>().unwrap().uses_uploader());
+        assert!("{uploader}/{title}"
+            .parse::<Layout>()
+            .unwrap()
+            .uses_uploader());
+        assert!(!"{artist}/{title}"
+            .parse::<Layout>()
+            .unwrap()
+            .uses_uploader());
+        assert!("flat".parse::<Layout>().is_err());
+        assert!("{artist".parse::<Layout>().is_err());
+        assert!("{artist}}".parse::<Layout>().is_err());
+        assert!("{album}".parse::<Layout>().is_err());
+    }
+
+    #[test]
+    fn unique_dir_name() {
+        let dest = tempdir().unwrap();
+        let layout: Layout = "{artist}/{title}".parse().unwrap();
+        let song = song("Outbreak", "RG+Ice");
+
+        let taken = TakenDirs::default();
+
+        assert_eq!(
+            layout.unique_dir_name(&(&song).into(), dest.path(), &taken),
+            "RG+Ice/Outbreak"
+        );
+        fs::create_dir_all(dest.path().join("RG+Ice/Outbreak")).unwrap();
+        assert_eq!(
+            layout.unique_dir_name(&(&song).into(), dest.path(), &taken),
+            "RG+Ice/Outbreak [5441d590]"
+        );
+        fs::create_dir_all(dest.path().join("RG+Ice/Outbreak [5441d590]")).unwrap();
+        assert_eq!(
+            layout.unique_dir_name(&(&song).into(), dest.path(), &taken),
+            "RG+Ice/Outbreak [5441d590-4d43-11ee-a602-d95b1bfc2e6d]"
+        );
+    }
+
+    #[test]
+    fn unique_dir_name_avoids_recorded_dirs() {
+        let dest = tempdir().unwrap();
+        let layout = Layout::Ksm(KsmGroup::Uploader);
+        let song = song("Outbreak", "RG+Ice");
+
+        // The song recorded there may only be missing locally.
+        let taken: TakenDirs = ["ixiot/rg+ice - outbreak"].into_iter().collect();
+        assert_eq!(
+            layout.unique_dir_name(&(&song).into(), dest.path(), &taken),
+            "Ixiot/RG+Ice - Outbreak [5441d590]"
+        );
+    }
+
+    #[test]
+    fn sanitize_name() {
+        assert_eq!(sanitize("AC/DC: Back?"), "AC_DC_ Back_");
+        assert_eq!(sanitize(" title... "), "title");
+        assert_eq!(sanitize(".."), "_");
+        assert_eq!(sanitize("a\tb"), "a_b");
+        assert_eq!(sanitize(&"x".repeat(200)).len(), MAX_NAME_LEN);
+    }
+}
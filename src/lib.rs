@@ -1,43 +1,262 @@
 #![allow(unused)]
 
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::io::Cursor;
+use std::io::Read;
+use std::io::Seek;
 use std::panic;
 use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::anyhow;
 use anyhow::bail;
-use attohttpc::Session;
+use anyhow::ensure;
+use anyhow::Context;
+use attohttpc::header;
+use attohttpc::header::HeaderMap;
+use attohttpc::header::HeaderValue;
+use attohttpc::StatusCode;
 use chardetng::EncodingDetector;
 use chrono::DateTime;
+use chrono::Local;
 use chrono::NaiveDateTime;
 use chrono::TimeZone;
 use chrono::Utc;
+use encoding_rs::Encoding;
+use encoding_rs::SHIFT_JIS;
 use pickledb::PickleDb;
 use pickledb::PickleDbDumpPolicy;
 use pickledb::SerializationMethod;
 use serde::de;
 use serde::Deserialize;
 use serde::Deserializer;
+use serde::Serialize;
 use tracing::info;
+use tracing::info_span;
 use tracing::warn;
+use url::form_urlencoded;
+use url::Url;
 use zip::ZipArchive;
 
+use crate::api::Routes;
+#[cfg(feature = "cassette")]
+use crate::cassette::Cassette;
+use crate::catalog::Catalog;
+use crate::copies::CopyConfig;
+use crate::dedup::Preference;
+use crate::failover::HttpStatus;
+use crate::failover::Mirrors;
+use crate::filter::Filter;
+use crate::http::Client;
+use crate::http::DeadlineExceeded;
+use crate::http::HttpConfig;
+use crate::http::Validators;
+use crate::jackets::JacketCache;
+use crate::jackets::JacketReport;
+use crate::layout::Layout;
+use crate::notify::Notifier;
+use crate::playlist::Playlist;
+use crate::playlist::PlaylistResp;
+use crate::playlist::PlaylistsResp;
+use crate::publish::PublishResp;
+use crate::queue::Queue;
+use crate::queue::QueueConfig;
+use crate::reorganize::Reorganization;
+use crate::schedule::Schedule;
+use crate::size::ByteSize;
+use crate::store::Entry;
+use crate::store::Store;
+use crate::systemd::Watchdog;
+use crate::trash::Disposal;
+use crate::uploaders::UploaderNames;
+
+pub mod api;
+pub mod audio;
+pub mod auth;
+pub mod bench;
+pub mod cas;
+#[cfg(feature = "cassette")]
+pub mod cassette;
+pub mod catalog;
+pub mod collection;
+pub mod config;
+pub mod copies;
+pub mod dedup;
+pub mod disk;
+pub mod email;
+pub mod failover;
+pub mod feed;
+pub mod filter;
+pub mod http;
+pub mod import;
+pub mod jackets;
+pub mod ksh;
+pub mod ksm;
+pub mod layout;
+pub mod longpath;
+pub mod loudness;
+pub mod metrics;
+pub mod mirror;
+pub mod notify;
+pub mod otlp;
+pub mod pack;
+pub mod pin;
+pub mod playlist;
+pub mod publish;
+pub mod quarantine;
+pub mod queue;
+pub mod remote;
+pub mod remove;
+pub mod render;
+pub mod reorganize;
+pub mod s3;
+pub mod schedule;
+pub mod search;
+pub mod size;
+pub mod state;
+pub mod stats;
+pub mod store;
+pub mod summary;
+pub mod systemd;
+pub mod thumbnail;
+pub mod transcode;
+pub mod trash;
+pub mod update;
+pub mod uploaders;
+pub mod usc;
+pub mod video;
+pub mod views;
+
 const NAUTICA_BASE_URL: &str = "https://ksm.dev";
 
-#[derive(Debug, Deserialize)]
-struct Song {
-    id: String,
-    user_id: String,
-    title: String,
-    artist: String,
+/// Prefix of the name an updated song is fetched to in the library before it
+/// replaces the earlier download.
+const REFRESHING_PREFIX: &str = ".refreshing-";
+
+/// Time without data after which a request counts as timed out and is
+/// retried.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Songs failing in a row after which a run is stopped as an outage.
+pub const DEFAULT_MAX_CONSECUTIVE_FAILURES: u32 = 10;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Song {
+    pub id: String,
+    pub user_id: String,
+    pub title: String,
+    pub artist: String,
     #[serde(deserialize_with = "datetime_from_uploaded_at")]
-    uploaded_at: DateTime<Utc>,
+    pub uploaded_at: DateTime<Utc>,
+    #[serde(deserialize_with = "datetime_from_uploaded_at")]
+    pub updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub user: Option<User>,
+    #[serde(default)]
+    pub jacket_url: Option<String>,
+    #[serde(default)]
+    pub preview_url: Option<String>,
+    #[serde(default)]
+    pub charts: Vec<Chart>,
+    #[serde(default)]
+    pub tags: Vec<Tag>,
+    /// Encoding of the file names in the archive, for uploads the server
+    /// flagged as having encoding problems.
+    #[serde(default)]
+    pub encoding: Option<String>,
+    /// Whether the server flagged the file names in the archive as garbled.
+    /// The server sends it as 0 or 1.
+    #[serde(default, deserialize_with = "bool_from_flag")]
+    pub mojibake: bool,
+    /// Number of times the song was downloaded from the server.
+    #[serde(default)]
+    pub downloads: u64,
+}
+
+impl Song {
+    /// Returns the encoding of the file names in the archive hinted by the
+    /// server: the one it names, or Shift_JIS, which most K-Shoot Mania
+    /// charts use, if it only flagged the upload.
+    pub fn name_encoding(&self) -> Option<&'static Encoding> {
+        let named = self
+            .encoding
+            .as_deref()
+            .and_then(|label| Encoding::for_label(label.trim().as_bytes()));
+        named.or((self.mojibake || self.encoding.is_some()).then_some(SHIFT_JIS))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct User {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserResp {
+    data: User,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Chart {
+    /// Difficulty slot, 1 (light) to 4 (infinite).
+    pub difficulty: u8,
+    pub level: u8,
+    /// Name of the charter, if they credited themselves.
+    #[serde(default)]
+    pub effector: Option<String>,
+    /// Background video for the chart, usually a YouTube link.
+    #[serde(default)]
+    pub video_link: Option<String>,
+}
+
+impl Chart {
+    /// Returns the short name of the difficulty slot, e.g. `EXH`.
+    pub fn difficulty_name(&self) -> &'static str {
+        usize::from(self.difficulty)
+            .checked_sub(1)
+            .and_then(|i| usc::DIFFICULTIES.get(i))
+            .map_or("?", |(_, short)| short)
+    }
+}
+
+impl fmt::Display for Chart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.difficulty_name(), self.level)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Tag {
+    pub value: String,
+}
+
+fn bool_from_flag<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flag {
+        Bool(bool),
+        Int(u8),
+    }
+    Ok(match Flag::deserialize(deserializer)? {
+        Flag::Bool(flag) => flag,
+        Flag::Int(flag) => flag != 0,
+    })
 }
 
 fn datetime_from_uploaded_at<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
@@ -45,305 +264,3793 @@ where
     D: Deserializer<'de>,
 {
     let uploaded_at: String = de::Deserialize::deserialize(deserializer)?;
+    // Songs written by this crate, e.g. to a catalog, use RFC 3339.
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(&uploaded_at) {
+        return Ok(datetime.with_timezone(&Utc));
+    }
     let datetime = NaiveDateTime::parse_from_str(&uploaded_at, "%Y-%m-%d %H:%M:%S")
         .map_err(de::Error::custom)?;
     Ok(Utc.from_utc_datetime(&datetime))
 }
 
-#[derive(Debug, Deserialize)]
+/// Orders in which the server can list songs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Sort {
+    /// Newest uploads first.
+    #[default]
+    Uploaded,
+
+    /// Most downloaded first.
+    Popular,
+
+    /// By title.
+    Title,
+
+    /// By artist.
+    Artist,
+}
+
+impl Sort {
+    fn as_param(self) -> &'static str {
+        match self {
+            Self::Uploaded => "uploaded",
+            Self::Popular => "downloads",
+            Self::Title => "title",
+            Self::Artist => "artist",
+        }
+    }
+}
+
+/// What a sync does when a song fails to download.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// Carry on with the remaining songs and report the failures at the end.
+    #[default]
+    Continue,
+
+    /// Stop the run at the first failure.
+    Abort,
+}
+
+#[derive(Debug, Default, Deserialize)]
 struct Links {
     next: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+struct Meta {
+    total: u64,
+    #[serde(default)]
+    current_page: Option<u64>,
+    #[serde(default)]
+    last_page: Option<u64>,
+}
+
 #[derive(Debug, Deserialize)]
 struct SongsResp {
     data: Vec<Song>,
+    #[serde(default)]
     links: Links,
+    #[serde(default)]
+    meta: Option<Meta>,
 }
 
-pub struct Downloader {
-    /// Destination directory to save songs.
-    dest: PathBuf,
+/// Number of scanned songs between progress messages while walking the
+/// catalog.
+const SCAN_PROGRESS_INTERVAL: u64 = 1000;
 
-    /// Base URL of the Nautica app server.
-    base_url: String,
+/// Iterates over all songs in the remote catalog, fetching pages lazily.
+struct Listing<'a> {
+    http: &'a Client,
+    mirrors: &'a Mirrors,
+    routes: &'a Routes,
 
-    sess: Session,
-}
+    /// Path of the listing, either the catalog or the liked songs.
+    path: &'a str,
 
-impl Downloader {
-    pub fn builder() -> DownloaderBuilder {
-        DownloaderBuilder::default()
-    }
+    /// Query of the listing without the page, for servers that do not link
+    /// to the next page.
+    query: String,
 
-    pub fn download_all(&self) -> anyhow::Result<()> {
-        let mut db = PickleDb::load_json(self.dest.join("meta.json"), PickleDbDumpPolicy::AutoDump)
-            .unwrap_or_else(|_| {
-                PickleDb::new_json(self.dest.join("meta.json"), PickleDbDumpPolicy::AutoDump)
-            });
-        let mut next_link = format!("{}/app/songs?sort=uploaded", self.base_url);
+    /// Path of the next page, or a link to it as returned by the server.
+    next_link: Option<String>,
+    songs: std::vec::IntoIter<Song>,
 
-        'outer: loop {
-            let resp = self.sess.get(&next_link).send()?;
-            let songs_resp: SongsResp = resp.json_utf8()?;
+    /// Number of songs yielded so far.
+    scanned: u64,
 
-            for song in songs_resp.data {
-                let song_dest = self.dest.join(&song.id);
+    /// Number of pages fetched so far.
+    pages: u64,
 
-                if db.get::<DateTime<Utc>>(&song.id).is_some() {
-                    info!(
-                        title = song.title,
-                        artist = song.artist,
-                        "This song already exists. Cancel the remaining downloads."
-                    );
-                    break 'outer;
-                }
+    /// Number of songs in the catalog, as reported by the last page.
+    total: Option<u64>,
+
+    /// ID of a song before which all songs are skipped, along with the song
+    /// itself.
+    start_after: Option<String>,
+}
+
+impl Listing<'_> {
+    /// Describes how far the walk has come, e.g. "song 342 / 12801".
+    fn progress(&self) -> String {
+        match self.total {
+            Some(total) => format!("song {} / {total}", self.scanned),
+            None => format!("song {}", self.scanned),
+        }
+    }
+}
 
-                info!(title = song.title, artist = song.artist, "Downloading");
+impl Iterator for Listing<'_> {
+    type Item = anyhow::Result<Song>;
 
-                if self.download(&song.id).is_ok() {
-                    db.set(&song.id, &Utc::now())?;
-                } else {
-                    warn!("Failed to download");
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(song) = self.songs.next() {
+                self.scanned += 1;
+                if let Some(start_after) = &self.start_after {
+                    if song.id == *start_after {
+                        info!(progress = self.progress(), "Starting after the given song");
+                        self.start_after = None;
+                    }
+                    continue;
                 }
+                return Some(Ok(song));
             }
-
-            if let Some(next) = songs_resp.links.next {
-                next_link = next;
-            } else {
-                break;
+            let Some(link) = self.next_link.take() else {
+                if let Some(start_after) = self.start_after.take() {
+                    warn!(
+                        id = start_after,
+                        "The song to start after is not in the listing"
+                    );
+                }
+                return None;
             };
+            let path = self.mirrors.path_of(&link);
+            let _span = info_span!("fetch_page", path).entered();
+            let songs_resp: SongsResp = match self
+                .mirrors
+                .send(&path, |url| self.http.get_api(url))
+                .and_then(|r| r.json())
+            {
+                Ok(songs_resp) => songs_resp,
+                Err(e) => return Some(Err(e)),
+            };
+            self.pages += 1;
+            self.next_link = songs_resp.links.next;
+            if let Some(meta) = songs_resp.meta {
+                self.total = Some(meta.total);
+                if let (None, Some(current), Some(last)) =
+                    (&self.next_link, meta.current_page, meta.last_page)
+                {
+                    self.next_link = (current < last).then(|| {
+                        format!(
+                            "{}?{}&{}={}",
+                            self.path,
+                            self.query,
+                            self.routes.page_param,
+                            current + 1
+                        )
+                    });
+                }
+            }
+            self.songs = songs_resp.data.into_iter();
         }
-        Ok(())
     }
+}
 
-    fn download(&self, song_id: &str) -> anyhow::Result<()> {
-        let resp = self
-            .sess
-            .get(format!("{}/songs/{}/download", self.base_url, song_id))
-            .send()?;
-        let dest = self.dest.join(song_id);
-        if !dest.exists() {
-            fs::create_dir(&dest)?;
-        }
+/// Picks the songs to download from the listing, song by song in listing
+/// order.
+///
+/// A full plan takes every song missing from the library or outdated in it.
+/// An incremental one stops at the first song that exists locally, and is
+/// returned oldest first so that a run stopped halfway never leaves a gap
+/// behind the newest local song, which the next incremental run would not
+/// notice.
+struct Planner<'a> {
+    downloader: &'a Downloader,
+    store: &'a Store,
+    full: bool,
+    songs: Vec<Song>,
 
-        let mut archive = ZipArchive::new(Cursor::new(resp.bytes()?))?;
+    /// Number of songs offered that match the filter.
+    ranked: usize,
 
-        for i in 0..archive.len() {
-            let mut file = archive.by_index(i)?;
+    /// Whether an incremental plan reached a local song.
+    stopped: bool,
+}
 
-            if file.name().ends_with('/') {
-                continue;
-            };
+impl<'a> Planner<'a> {
+    fn new(downloader: &'a Downloader, store: &'a Store, full: bool) -> Self {
+        Self {
+            downloader,
+            store,
+            full,
+            songs: Vec::new(),
+            ranked: 0,
+            stopped: false,
+        }
+    }
 
-            let filepath = {
-                // FIXME: Changing the file name encoding will likely break references
-                // from the ksh file. Need to modify the contents of the ksh file
-                // accordingly.
-                let mut det = EncodingDetector::new();
-                det.feed(file.name_raw(), true);
-                let encoding = det.guess(None, true);
+    /// Returns whether no further songs would be taken.
+    fn is_done(&self) -> bool {
+        self.stopped || self.downloader.top.is_some_and(|top| self.ranked >= top)
+    }
 
-                let (cow, _, had_errors) = encoding.decode(file.name_raw());
-                let enclosed_name = if had_errors {
-                    file.enclosed_name()
-                } else {
-                    enclosed_name(&cow)
-                };
-                match enclosed_name {
-                    Some(path) => path.to_owned(),
-                    None => {
-                        warn!(path = file.name(), "invalid file path");
-                        continue;
-                    }
+    /// Considers the next song of the listing.
+    fn offer(&mut self, song: &Song) {
+        if !self.downloader.filter.matches(song) {
+            return;
+        }
+        self.ranked += 1;
+        match self.store.get(&song.id) {
+            Some(entry) if self.full => {
+                if self.downloader.is_outdated(song, &entry) {
+                    self.songs.push(song.clone());
                 }
-            };
-
-            let filename = filepath.file_name().unwrap().to_str().unwrap();
-            let mut outfile = fs::File::create(dest.join(filename))?;
-            io::copy(&mut file, &mut outfile)?;
+            }
+            Some(_) => {
+                info!(
+                    title = song.title,
+                    artist = song.artist,
+                    "This song already exists. Cancel the remaining downloads."
+                );
+                self.stopped = true;
+            }
+            None => self.songs.push(song.clone()),
         }
-
-        Ok(())
     }
-}
 
-fn enclosed_name(file_name: &str) -> Option<&Path> {
-    if file_name.contains('\0') {
-        return None;
-    }
-    let path = Path::new(file_name);
-    let mut depth = 0usize;
-    for component in path.components() {
-        match component {
-            Component::Prefix(_) | Component::RootDir => return None,
-            Component::ParentDir => depth = depth.checked_sub(1)?,
-            Component::Normal(_) => depth += 1,
-            Component::CurDir => (),
+    fn finish(mut self) -> Vec<Song> {
+        if !self.full {
+            self.songs.reverse();
         }
+        self.songs
     }
-    Some(path)
 }
 
+/// Outcome of a sync run.
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+    /// Songs downloaded in this run.
+    pub downloaded: Vec<Song>,
+
+    /// Songs that failed to download.
+    pub failed: Vec<Song>,
+
+    /// Number of songs queued for this run.
+    pub total: usize,
+
+    /// Whether the run was cancelled before completion.
+    pub cancelled: bool,
+
+    /// Total size of the archives downloaded in this run.
+    pub bytes: u64,
+
+    /// Whether the run stopped early because the download budget ran out.
+    pub budget_exhausted: bool,
+
+    /// Songs removed after download because the library already had the same
+    /// content.
+    pub duplicates: Vec<Song>,
+
+    /// Songs the server no longer has. They are recorded in the library so
+    /// that later runs do not request them again.
+    pub removed: Vec<Song>,
+
+    /// IDs of songs removed because the account no longer likes them.
+    pub unfavorited: Vec<String>,
+
+    /// Songs moved to quarantine because their archive, charts, or music
+    /// failed validation.
+    pub quarantined: Vec<Song>,
+
+    /// Songs skipped because their archive is larger than the maximum song
+    /// size. They are not recorded, so a later run with a higher maximum
+    /// downloads them.
+    pub oversized: Vec<Song>,
+
+    /// Whether the run stopped at a failed song because of
+    /// [`OnError::Abort`].
+    pub aborted: bool,
+
+    /// Songs updated on the server whose archive turned out not to have
+    /// changed, so they were not downloaded again.
+    pub unchanged: Vec<Song>,
+}
+
+/// Changes that bring the local library in line with the liked songs of the
+/// account.
+#[derive(Debug, Default)]
+pub struct FavoritesPlan {
+    /// Liked songs that have not been downloaded yet.
+    pub new: Vec<Song>,
+
+    /// IDs of songs downloaded because they were liked, which no longer are.
+    pub unfavorited: Vec<String>,
+
+    /// Download size of the new songs looked up by
+    /// [`Downloader::estimate_run`], if any.
+    pub estimated_bytes: Option<u64>,
+}
+
+/// Error of a song skipped because its archive is larger than the maximum
+/// song size.
 #[derive(Debug)]
-pub struct DownloaderBuilder {
-    dest: PathBuf,
-    base_url: String,
+pub struct Oversized {
+    /// Size of the archive.
+    pub size: u64,
+
+    pub max: u64,
 }
 
-impl DownloaderBuilder {
-    pub fn dest<P: Into<PathBuf>>(mut self, dest: P) -> Self {
-        self.dest = dest.into();
-        self
+impl fmt::Display for Oversized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The archive of {} is larger than the maximum of {}",
+            ByteSize(self.size),
+            ByteSize(self.max)
+        )
     }
+}
 
-    pub fn base_url(mut self, base_url: String) -> Self {
-        self.base_url = base_url;
-        self
+impl std::error::Error for Oversized {}
+
+/// Error of a song whose archive could not be extracted. The archive is kept
+/// in quarantine.
+#[derive(Debug)]
+pub struct InvalidArchive;
+
+impl fmt::Display for InvalidArchive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid archive")
     }
+}
 
-    pub fn build(self) -> Downloader {
-        Downloader {
-            dest: self.dest,
-            base_url: self.base_url,
-            sess: Session::new(),
-        }
+impl std::error::Error for InvalidArchive {}
+
+/// Error of a song asked for again whose archive the server says did not
+/// change since it was downloaded.
+#[derive(Debug)]
+pub struct NotModified;
+
+impl fmt::Display for NotModified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("The archive did not change")
     }
 }
 
-impl Default for DownloaderBuilder {
-    fn default() -> Self {
-        Self {
-            dest: PathBuf::from("nautica"),
-            base_url: String::from(NAUTICA_BASE_URL),
-        }
+impl std::error::Error for NotModified {}
+
+/// Error of a run stopped because several songs in a row failed to download,
+/// which usually means the server is down rather than anything wrong with the
+/// songs.
+#[derive(Debug)]
+pub struct Outage {
+    /// Number of songs that failed in a row.
+    pub failures: u32,
+}
+
+impl fmt::Display for Outage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} songs in a row failed to download; the server seems to be down",
+            self.failures
+        )
     }
 }
 
-#[cfg(test)]
-mod test {
-    use std::fs::File;
+impl std::error::Error for Outage {}
 
-    use httpmock::MockServer;
-    use tempfile::tempdir;
+/// Difference between the remote catalog and the local library.
+#[derive(Debug, Default)]
+pub struct Diff {
+    /// Songs that have not been downloaded yet.
+    pub new: Vec<Song>,
 
-    use super::*;
+    /// Songs that were updated on the server after being downloaded.
+    pub updated: Vec<Song>,
 
-    #[test]
-    fn parse_songs_resp() {
-        let songs: SongsResp =
-            serde_json::from_reader(File::open("tests/fixtures/songs.json").unwrap()).unwrap();
-        assert_eq!(songs.data.len(), 10);
-        assert_eq!(
-            songs.data[0].uploaded_at,
-            Utc.with_ymd_and_hms(2023, 9, 7, 5, 56, 46).unwrap()
-        );
+    /// IDs of local songs that are no longer listed on the server.
+    pub removed: Vec<String>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.new.is_empty() && self.updated.is_empty() && self.removed.is_empty()
     }
+}
 
-    #[test]
-    fn download_ascii_encoding_zip() {
-        let server = MockServer::start();
-        let m = server.mock(|when, then| {
-            when.path("/songs/5441d590-4d43-11ee-a602-d95b1bfc2e6d/download");
-            then.header("content-type", "application/x-zip")
-                .status(200)
-                .body(include_bytes!(
-                    "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
-                ));
-        });
+pub struct Downloader {
+    /// Destination directory to save songs.
+    dest: PathBuf,
 
-        let dest = tempdir().unwrap();
+    /// Base URLs of the Nautica app server and its mirrors.
+    mirrors: Mirrors,
 
-        let downloader = Downloader::builder()
-            .dest(dest.path())
-            .base_url(server.base_url())
-            .build();
+    /// Routes of the API on those servers.
+    routes: Routes,
 
-        downloader
-            .download("5441d590-4d43-11ee-a602-d95b1bfc2e6d")
-            .unwrap();
+    /// Order of the song listing.
+    sort: Sort,
 
-        m.assert();
+    /// Number of songs per listing page; the server's default when unset.
+    per_page: Option<u32>,
 
-        let song_dest = dest.path().join("5441d590-4d43-11ee-a602-d95b1bfc2e6d");
-        assert_eq!(song_dest.read_dir().unwrap().collect::<Vec<_>>().len(), 6);
-        assert!(song_dest.join("Advanced.ksh").exists());
-        assert!(song_dest.join("Exhaust.ksh").exists());
-        assert!(song_dest.join("Novice.ksh").exists());
-        assert!(song_dest.join("Outbreak-jacket.png").exists());
-        assert!(song_dest.join("Outbreak.ksh").exists());
-        assert!(song_dest.join("Outbreak.ogg").exists());
-    }
+    /// Number of songs, from the start of the listing, that a sync considers.
+    top: Option<usize>,
 
-    #[test]
-    fn download_shift_jis_encoding_zip() {
-        let server = MockServer::start();
-        let m = server.mock(|when, then| {
-            when.path("/songs/89b54d80-4e6d-11ee-83d4-2ffdf82667a6/download");
-            then.header("content-type", "application/x-zip")
-                .status(200)
-                .body(include_bytes!(
-                    "../tests/fixtures/89b54d80-4e6d-11ee-83d4-2ffdf82667a6.zip"
-                ));
-        });
+    /// Page of the listing a sync begins at.
+    start_page: Option<u32>,
 
-        let dest = tempdir().unwrap();
+    /// ID of the song of the listing a sync begins after.
+    start_after: Option<String>,
 
-        let downloader = Downloader::builder()
-            .dest(dest.path())
-            .base_url(server.base_url())
-            .build();
+    /// Restricts which songs are synced.
+    filter: Filter,
 
-        downloader
-            .download("89b54d80-4e6d-11ee-83d4-2ffdf82667a6")
-            .unwrap();
+    /// Naming of song directories.
+    layout: Layout,
 
-        m.assert();
+    /// Bytes after which a run stops downloading further songs.
+    max_bytes: Option<u64>,
 
-        let song_dest = dest.path().join("89b54d80-4e6d-11ee-83d4-2ffdf82667a6");
-        assert_eq!(song_dest.read_dir().unwrap().collect::<Vec<_>>().len(), 9);
-        assert!(song_dest.join("3.wav").exists());
-        assert!(song_dest.join("5.wav").exists());
-        assert!(song_dest.join("6.wav").exists());
-        assert!(song_dest.join("8.wav").exists());
-        assert!(song_dest.join("10.wav").exists());
-        assert!(song_dest.join("chart.png").exists());
-        assert!(song_dest.join("チューリングラブ feat.Sou.ksh").exists());
-        assert!(song_dest.join("チューリングラブ feat.Sou.ogg").exists());
-        assert!(song_dest.join("チューリングラブ feat.Sou.png").exists());
-    }
+    /// Archive size above which a song is skipped.
+    max_song_size: Option<u64>,
 
-    #[test]
-    fn download_unknown_encoding_zip() {
-        let server = MockServer::start();
-        let m = server.mock(|when, then| {
-            when.path("/songs/9e523640-4fb1-11ee-a90f-e9c914456566/download");
-            then.header("content-type", "application/x-zip")
-                .status(200)
-                .body(include_bytes!(
-                    "../tests/fixtures/9e523640-4fb1-11ee-a90f-e9c914456566.zip"
-                ));
-        });
+    /// Free space to leave on the destination filesystem.
+    reserve: u64,
 
-        let dest = tempdir().unwrap();
+    /// Whether to look up the size of each song before a run to report the
+    /// total size and time remaining.
+    estimate: bool,
 
-        let downloader = Downloader::builder()
-            .dest(dest.path())
-            .base_url(server.base_url())
+    /// Whether to fetch only the jacket and preview audio of each song.
+    preview_only: bool,
+
+    /// Whether the library must never be written to.
+    read_only: bool,
+
+    /// Whether to remove downloaded songs whose content is already in the
+    /// library.
+    skip_duplicates: bool,
+
+    /// Which of the uploads of the same song by different users to keep, if
+    /// only one is.
+    prefer: Option<Preference>,
+
+    /// Whether to download songs updated on the server since they were
+    /// downloaded again, if their archive changed.
+    refresh_updated: bool,
+
+    /// Whether to sync the songs the logged-in account liked instead of the
+    /// catalog.
+    favorites: bool,
+
+    /// Directory to fetch songs into before moving them to the library.
+    temp_dir: Option<PathBuf>,
+
+    /// Whether to stop the run when a song fails to download.
+    on_error: OnError,
+
+    /// Number of songs failing in a row after which the run stops with an
+    /// [`Outage`].
+    max_consecutive_failures: Option<u32>,
+
+    /// Time a song may take to download before it is given up on.
+    max_duration_per_song: Option<Duration>,
+
+    /// Rules ordering the songs of the download queue.
+    queue_order: QueueConfig,
+
+    /// Other destinations downloaded songs are written to.
+    copies: Vec<CopyConfig>,
+
+    /// How songs that are no longer liked are disposed of.
+    disposal: Disposal,
+
+    /// Receivers of download events.
+    notifiers: Vec<Box<dyn Notifier>>,
+
+    /// Set to stop the current run after the song being downloaded.
+    cancelled: Arc<AtomicBool>,
+
+    /// Watchdog of the service manager, pinged as the sync makes progress.
+    watchdog: Option<Arc<Watchdog>>,
+
+    /// CSRF token of the logged-in session, for requests that change data.
+    xsrf_token: Option<String>,
+
+    /// Client for every request, reusing connections between requests.
+    /// Logging in and publishing are not retried.
+    http: Client,
+}
+
+impl Downloader {
+    pub fn builder() -> DownloaderBuilder {
+        DownloaderBuilder::default()
+    }
+
+    /// Downloads new songs, stopping at the first song that already exists
+    /// locally. In favorites mode, syncs the liked songs instead; see
+    /// [`Downloader::sync_favorites`].
+    pub fn download_all(&self) -> anyhow::Result<DownloadReport> {
+        if self.favorites {
+            return self.sync_favorites(self.plan_favorites()?);
+        }
+        self.download_songs(self.pending()?)
+    }
+
+    /// Walks the whole catalog and downloads every song missing from the
+    /// local library.
+    pub fn download_missing(&self) -> anyhow::Result<DownloadReport> {
+        if self.favorites {
+            return self.sync_favorites(self.plan_favorites()?);
+        }
+        self.download_songs(self.pending_full()?)
+    }
+
+    /// Logs in to the account `email` on the current server and saves the
+    /// session in the library for later runs.
+    pub fn login(&self, email: &str, password: &str) -> anyhow::Result<()> {
+        let cookie = auth::login(
+            &self.http,
+            self.mirrors.current(),
+            &self.routes,
+            email,
+            password,
+        )?;
+        auth::save_session(&self.dest, &cookie)
+    }
+
+    /// Compares the songs the logged-in account liked against the library.
+    /// Only meaningful in favorites mode.
+    ///
+    /// Songs that are no longer liked are only unfavorited if they were
+    /// downloaded by a favorites sync, so songs synced from the catalog are
+    /// never removed. Neither are songs starred locally.
+    pub fn plan_favorites(&self) -> anyhow::Result<FavoritesPlan> {
+        let store = Store::open_read_only(&self.dest);
+        let mut plan = FavoritesPlan::default();
+        let mut liked = HashSet::new();
+        for song in self.listing() {
+            let song = song.context("Failed to list liked songs; log in again if it expired")?;
+            if !self.filter.matches(&song) {
+                continue;
+            }
+            liked.insert(song.id.clone());
+            if !store.contains(&song.id) {
+                plan.new.push(song);
+            }
+        }
+        plan.unfavorited = store
+            .entries()
+            .into_iter()
+            .filter(|(id, entry)| entry.favorite && !entry.starred && !liked.contains(id))
+            .map(|(id, _)| id)
+            .collect();
+        Ok(plan)
+    }
+
+    /// Removes the songs no longer liked and downloads the newly liked ones.
+    pub fn sync_favorites(&self, plan: FavoritesPlan) -> anyhow::Result<DownloadReport> {
+        self.sync_songs(plan.new, plan.unfavorited, plan.estimated_bytes)
+    }
+
+    /// Lists the new songs that [`Downloader::download_all`] would download.
+    ///
+    /// Unless the listing is sorted by upload date, the whole catalog is
+    /// walked since existing songs can appear anywhere in it.
+    pub fn pending(&self) -> anyhow::Result<Vec<Song>> {
+        self.plan(self.walks_whole_catalog())
+    }
+
+    /// Returns whether an incremental sync still walks the whole catalog,
+    /// since existing songs can appear anywhere in the listing.
+    fn walks_whole_catalog(&self) -> bool {
+        self.favorites || self.sort != Sort::Uploaded
+    }
+
+    /// Lists the songs that [`Downloader::download_missing`] would download.
+    pub fn pending_full(&self) -> anyhow::Result<Vec<Song>> {
+        self.plan(true)
+    }
+
+    /// Lists the songs to download.
+    fn plan(&self, full: bool) -> anyhow::Result<Vec<Song>> {
+        let store = Store::open_read_only(&self.dest);
+        let mut planner = Planner::new(self, &store, full);
+        let mut listing = self.listing_from_start();
+        while !planner.is_done() {
+            let Some(song) = listing.next() else {
+                break;
+            };
+            let song = song?;
+            if listing.scanned.is_multiple_of(SCAN_PROGRESS_INTERVAL) {
+                info!(progress = listing.progress(), "Scanning the catalog");
+            }
+            planner.offer(&song);
+        }
+        Ok(planner.finish())
+    }
+
+    /// Queues the given songs and downloads the songs of the queue, skipping
+    /// any that already exist locally. Songs left in the queue by an earlier
+    /// run come first, unless the new ones have a higher priority; see
+    /// [`Queue`].
+    ///
+    /// With a download budget, the run stops once the budget is used up; the
+    /// song that crosses it is still downloaded in full.
+    ///
+    /// Fails before starting a song if free space on the destination has
+    /// dropped below the reserve.
+    pub fn download_songs(&self, songs: Vec<Song>) -> anyhow::Result<DownloadReport> {
+        self.sync_songs(songs, Vec::new(), None)
+    }
+
+    /// Downloads `songs` as [`Downloader::download_songs`] does, reporting
+    /// progress against `estimated_bytes` from [`Downloader::estimate_run`]
+    /// instead of looking up the size again.
+    pub fn download_estimated(
+        &self,
+        songs: Vec<Song>,
+        estimated_bytes: u64,
+    ) -> anyhow::Result<DownloadReport> {
+        self.sync_songs(songs, Vec::new(), Some(estimated_bytes))
+    }
+
+    /// Removes the `unfavorited` songs from the library, then downloads
+    /// `songs` as [`Downloader::download_songs`] does. The size of the run is
+    /// looked up here if it was to be estimated but `estimated_bytes` is not
+    /// given.
+    fn sync_songs(
+        &self,
+        songs: Vec<Song>,
+        unfavorited: Vec<String>,
+        estimated_bytes: Option<u64>,
+    ) -> anyhow::Result<DownloadReport> {
+        let _lock = self.lock()?;
+        let mut queue = Queue::open(&self.dest)?;
+        for song in songs {
+            queue.push(song)?;
+        }
+        let songs = self.queued_songs(&mut queue)?;
+        let mut store = Store::open(&self.dest);
+        let mut report = DownloadReport {
+            total: songs.len(),
+            ..Default::default()
+        };
+        for id in unfavorited {
+            let Some(entry) = remove::remove_song(&self.dest, &mut store, &id, self.disposal)?
+            else {
+                continue;
+            };
+            let _span = info_span!(
+                "remove_song",
+                id,
+                title = entry.title,
+                artist = entry.artist
+            )
+            .entered();
+            info!("Removed a song that is no longer liked");
+            report.unfavorited.push(id);
+        }
+        self.check_space()?;
+        for notifier in &self.notifiers {
+            if let Err(e) = notifier.run_started(report.total) {
+                warn!(error = %e, "Failed to send notification");
+            }
+        }
+        // Song IDs by fingerprint, for recognizing duplicates.
+        let mut fingerprints: HashMap<String, String> = store
+            .entries()
+            .into_iter()
+            .filter_map(|(id, entry)| Some((entry.fingerprint?, id)))
+            .collect();
+        // Songs left out for an upload of the same song by another user.
+        let same_songs = match &self.prefer {
+            Some(preference) => dedup::same_songs(&songs, &store, preference),
+            None => HashMap::new(),
+        };
+        let mut taken = store.taken_dirs();
+        let mut uploaders = UploaderNames::open(&self.dest);
+        let started = Instant::now();
+        let mut consecutive_failures: u32 = 0;
+        let estimated_bytes = if estimated_bytes.is_some() || !self.estimate {
+            estimated_bytes
+        } else {
+            let songs: Vec<_> = songs
+                .iter()
+                .filter(|s| {
+                    store
+                        .get(&s.id)
+                        .is_none_or(|entry| self.is_outdated(s, &entry))
+                })
+                .collect();
+            let bytes = self.estimate_size(&songs);
+            info!(songs = songs.len(), size = %ByteSize(bytes), "Estimated download size");
+            Some(bytes)
+        };
+
+        // Song being downloaded, whose status in the queue is set once it
+        // is finished.
+        let mut active: Option<String> = None;
+        for (i, mut song) in songs.into_iter().enumerate() {
+            if let Some(id) = active.take() {
+                finish_queued(&mut queue, &store, &id)?;
+            }
+            if self.is_cancelled() {
+                warn!("Cancelled");
+                report.cancelled = true;
+                break;
+            }
+
+            if self
+                .max_bytes
+                .is_some_and(|max_bytes| report.bytes >= max_bytes)
+            {
+                info!(bytes = report.bytes, "Download budget exhausted");
+                report.budget_exhausted = true;
+                break;
+            }
+            queue.set_status(&song.id, queue::Status::Active)?;
+            active = Some(song.id.clone());
+            self.ping_watchdog();
+
+            // Every message about the song carries its ID, title, and artist.
+            let _span = info_span!(
+                "download_song",
+                id = song.id,
+                title = song.title,
+                artist = song.artist
+            )
+            .entered();
+            let previous = store.get(&song.id);
+            if previous
+                .as_ref()
+                .is_some_and(|entry| !self.is_outdated(&song, entry))
+            {
+                continue;
+            }
+            if let Some(original) = same_songs.get(&song.id).filter(|_| previous.is_none()) {
+                info!(original, "Skipping the same song by another uploader");
+                let entry = Entry {
+                    duplicate_of: Some(original.clone()),
+                    ..Entry::new(&song, &song.id)
+                };
+                store.insert(&song.id, &entry)?;
+                report.duplicates.push(song);
+                continue;
+            }
+
+            if self.layout.uses_uploader() {
+                self.fill_uploader(&mut song, &mut uploaders);
+            }
+            // An updated song replaces the files of its earlier download.
+            let dir = match &previous {
+                Some(entry) => entry.dir(&song.id).to_owned(),
+                None => {
+                    let dir = self
+                        .layout
+                        .unique_dir_name(&(&song).into(), &self.dest, &taken);
+                    taken.insert(&dir);
+                    dir
+                }
+            };
+            let song_dest = self.dest.join(&dir);
+            // File operations use the extended-length form, which Windows
+            // does not limit to MAX_PATH.
+            let song_path = longpath::extended(&song_dest);
+            // An updated song is fetched next to its earlier download, which
+            // it only replaces once it turned out fine.
+            let refreshing = previous.is_some();
+            let fetched = if refreshing {
+                longpath::extended(&self.dest.join(format!("{REFRESHING_PREFIX}{}", song.id)))
+            } else {
+                song_path.clone()
+            };
+            if refreshing && fetched.exists() {
+                fs::remove_dir_all(&fetched)?;
+            }
+
+            self.check_space()?;
+
+            info!(
+                progress = format!("{} / {}", i + 1, report.total),
+                "Downloading"
+            );
+
+            let known = previous
+                .as_ref()
+                .and_then(|entry| entry.validators.clone())
+                .unwrap_or_default();
+            let mut validators = None;
+            let result = longpath::check_len(&song_dest).and_then(|()| {
+                self.staged(&song.id, &fetched, |dest| {
+                    if self.preview_only {
+                        self.download_preview(&song, dest)
+                    } else {
+                        let (archive, current) = self.fetch_archive(&song.id, &known)?;
+                        validators = Some(current);
+                        self.unpack(&song.id, &archive, song.name_encoding(), dest)?;
+                        for copy in &self.copies {
+                            // A copy that fails to be written does not fail
+                            // the song, which is in the library already.
+                            if let Err(e) = copy.write(&dir, &archive, dest) {
+                                warn!(error = format!("{e:#}"), "Failed to write a copy");
+                            }
+                        }
+                        Ok(archive.len() as u64)
+                    }
+                })
+            });
+            if result.is_err() && refreshing && fetched.exists() {
+                let _ = fs::remove_dir_all(&fetched);
+            }
+            let bytes = match result {
+                Ok(bytes) => bytes,
+                Err(e) if e.is::<Oversized>() => {
+                    warn!(error = %e, "Skipping the song");
+                    report.oversized.push(song);
+                    continue;
+                }
+                Err(e) if e.is::<NotModified>() => {
+                    info!("The archive did not change");
+                    consecutive_failures = 0;
+                    if let Some(mut entry) = previous {
+                        entry.downloaded_at = Utc::now();
+                        entry.updated_at = Some(song.updated_at);
+                        store.insert(&song.id, &entry)?;
+                    }
+                    report.unchanged.push(song);
+                    continue;
+                }
+                Err(e) if e.is::<InvalidArchive>() && refreshing => {
+                    warn!(error = %e, "The updated archive is invalid; keeping the earlier download");
+                    consecutive_failures = 0;
+                    // Only what was extracted from the update was held.
+                    fs::remove_dir_all(quarantine::dir(&self.dest, &song.id))?;
+                    report.failed.push(song);
+                    continue;
+                }
+                Err(e) if e.is::<InvalidArchive>() => {
+                    warn!(error = %e, "Moved the song to quarantine");
+                    // The server answered, so it is not down.
+                    consecutive_failures = 0;
+                    let mut entry = Entry::new(&song, &dir);
+                    entry.quarantined = Some(format!("{e:#}"));
+                    store.insert(&song.id, &entry)?;
+                    report.quarantined.push(song);
+                    continue;
+                }
+                Err(e) => {
+                    match e.downcast_ref::<HttpStatus>().map(|e| e.status) {
+                        Some(StatusCode::NOT_FOUND) => {
+                            warn!("The song was removed from the server and will not be requested again");
+                            // The server answered, so it is not down.
+                            consecutive_failures = 0;
+                            let entry = match previous {
+                                // The earlier download stays in the library;
+                                // it is as new as the song will get.
+                                Some(entry) => Entry {
+                                    downloaded_at: Utc::now(),
+                                    ..entry
+                                },
+                                None => Entry {
+                                    removed: true,
+                                    ..Entry::new(&song, &dir)
+                                },
+                            };
+                            store.insert(&song.id, &entry)?;
+                            report.removed.push(song);
+                            continue;
+                        }
+                        Some(StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED) => warn!(
+                            error = %e,
+                            "Failed to download; the server refused access, so log in again if the session expired"
+                        ),
+                        _ if e.is::<DeadlineExceeded>() => warn!(
+                            error = %e,
+                            "Gave up on the song, which took too long to download; it will be tried again in the next run"
+                        ),
+                        _ => warn!(error = %e, "Failed to download"),
+                    }
+                    consecutive_failures += 1;
+                    if self
+                        .max_consecutive_failures
+                        .is_some_and(|max| consecutive_failures >= max)
+                    {
+                        // The earlier songs in the streak are not at fault
+                        // either, so leave them out of the failures.
+                        let kept = report.failed.len() + 1 - consecutive_failures as usize;
+                        report.failed.truncate(kept);
+                        report.aborted = true;
+                        // Notifiers may write the store themselves.
+                        drop(store);
+                        self.finish_run(&report);
+                        return Err(e.context(Outage {
+                            failures: consecutive_failures,
+                        }));
+                    }
+                    for notifier in &self.notifiers {
+                        if let Err(e) = notifier.song_failed(&song) {
+                            warn!(error = %e, "Failed to send notification");
+                        }
+                    }
+                    report.failed.push(song);
+                    if self.on_error == OnError::Abort {
+                        warn!("Stopping at the first failure");
+                        report.aborted = true;
+                        break;
+                    }
+                    continue;
+                }
+            };
+            consecutive_failures = 0;
+            report.bytes += bytes;
+            if let Some(total) = estimated_bytes {
+                let eta = eta(started.elapsed(), report.bytes, total);
+                info!(
+                    downloaded = %ByteSize(report.bytes),
+                    total = %ByteSize(total),
+                    eta = %humantime::format_duration(eta),
+                    "Progress"
+                );
+            }
+            let mut entry = Entry::new(&song, &dir);
+            entry.favorite = self.favorites;
+            entry.validators = validators.filter(|validators| !validators.is_empty());
+            // Keep the local tags and star of an updated song.
+            if let Some(previous) = previous {
+                entry.tags = previous.tags;
+                entry.starred = previous.starred;
+            }
+            entry.read_song_info(&fetched);
+            if entry.is_mismatched() {
+                warn!(
+                    chart_title = entry.chart_title,
+                    chart_artist = entry.chart_artist,
+                    "The charts name the song differently"
+                );
+            }
+            // Previews have no charts or music to check.
+            let invalid = (!self.preview_only)
+                .then(|| quarantine::check(&fetched, &entry))
+                .flatten();
+            if let Some(reason) = invalid.as_ref().filter(|_| refreshing) {
+                warn!(
+                    reason,
+                    "The updated song is invalid; keeping the earlier download"
+                );
+                fs::remove_dir_all(&fetched)?;
+                report.failed.push(song);
+                continue;
+            }
+            if let Some(reason) = invalid {
+                warn!(reason, "Moving the song to quarantine");
+                quarantine::hold(&self.dest, &song.id, &fetched, &reason)?;
+                entry.quarantined = Some(reason);
+                store.insert(&song.id, &entry)?;
+                report.quarantined.push(song);
+                continue;
+            }
+            entry.fingerprint = dedup::fingerprint(&fetched).unwrap_or_default();
+            // An update may match the song's own earlier download.
+            let original = entry
+                .fingerprint
+                .as_ref()
+                .and_then(|fingerprint| fingerprints.get(fingerprint))
+                .filter(|original| **original != song.id);
+            if let Some(original) = original.filter(|_| self.skip_duplicates) {
+                info!(original, "Removing duplicate of a local song");
+                fs::remove_dir_all(&fetched)?;
+                if refreshing && song_path.exists() {
+                    fs::remove_dir_all(&song_path)?;
+                }
+                entry.duplicate_of = Some(original.clone());
+                store.insert(&song.id, &entry)?;
+                report.duplicates.push(song);
+                continue;
+            }
+            if let Some(fingerprint) = &entry.fingerprint {
+                fingerprints
+                    .entry(fingerprint.clone())
+                    .or_insert_with(|| song.id.clone());
+            }
+            if refreshing {
+                replace_dir(&fetched, &song_path)?;
+            }
+            store.insert(&song.id, &entry)?;
+            for notifier in &self.notifiers {
+                if let Err(e) = notifier.song_downloaded(&song, &song_dest) {
+                    warn!(error = %e, "Failed to send notification");
+                }
+            }
+            // Notifiers may write the store themselves.
+            store.reload();
+            report.downloaded.push(song);
+        }
+        if let Some(id) = active {
+            finish_queued(&mut queue, &store, &id)?;
+        }
+        // Notifiers may write the store themselves.
+        drop(store);
+
+        self.finish_run(&report);
+        Ok(report)
+    }
+
+    /// Returns the pending songs of `queue` in order, looking up the songs
+    /// queued by ID in the listing. Songs the listing does not have fail.
+    fn queued_songs(&self, queue: &mut Queue) -> anyhow::Result<Vec<Song>> {
+        let mut unknown: HashSet<String> = queue
+            .pending()
+            .into_iter()
+            .filter(|item| item.song.is_none())
+            .map(|item| item.id.clone())
+            .collect();
+        if !unknown.is_empty() {
+            info!(songs = unknown.len(), "Looking up queued songs");
+            for song in self.listing() {
+                let song = song?;
+                if unknown.remove(&song.id) {
+                    queue.resolve(song)?;
+                    if unknown.is_empty() {
+                        break;
+                    }
+                }
+            }
+            for id in unknown {
+                warn!(id, "The queued song was not found on the server");
+                queue.set_status(&id, queue::Status::Failed)?;
+            }
+        }
+        let mut songs: Vec<Song> = queue
+            .pending()
+            .into_iter()
+            .filter_map(|item| item.song.clone())
+            .collect();
+        let rules = &self.queue_order.rules;
+        if rules.is_empty() {
+            return Ok(songs);
+        }
+        let sizes = if rules.contains(&queue::Rule::Smallest) {
+            info!(songs = songs.len(), "Looking up song sizes");
+            songs
+                .iter()
+                .filter_map(|song| Some((song.id.clone(), self.content_length(&song.id)?)))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        self.queue_order.sort(&mut songs, &sizes);
+        // Explicit priorities still come first.
+        songs.sort_by_key(|song| cmp::Reverse(queue.get(&song.id).map_or(0, |item| item.priority)));
+        Ok(songs)
+    }
+
+    fn finish_run(&self, report: &DownloadReport) {
+        for notifier in &self.notifiers {
+            self.ping_watchdog();
+            if let Err(e) = notifier.run_finished(report) {
+                warn!(error = %e, "Failed to send notification");
+            }
+        }
+    }
+
+    /// Keeps running incremental syncs, waiting `interval` between runs. If
+    /// `schedule` is given, full scans run at the scheduled times instead.
+    ///
+    /// Errors during a sync are logged and retried on the next run.
+    pub fn watch(&self, interval: Duration, schedule: Option<&Schedule>) -> anyhow::Result<()> {
+        let mut next_full_scan = schedule.and_then(|s| s.next_after(Local::now()));
+        loop {
+            let full = next_full_scan.is_some_and(|t| t <= Local::now());
+            if full {
+                info!("Starting a full scan");
+                next_full_scan = schedule.and_then(|s| s.next_after(Local::now()));
+            }
+            let report = if full {
+                self.download_missing()
+            } else {
+                self.download_all()
+            };
+            match report {
+                Ok(report) => {
+                    for song in &report.downloaded {
+                        info!(
+                            id = song.id,
+                            title = song.title,
+                            artist = song.artist,
+                            "New song downloaded"
+                        );
+                    }
+                    info!(
+                        downloaded = report.downloaded.len(),
+                        failed = report.failed.len(),
+                        total = report.total,
+                        "Sync finished"
+                    );
+                }
+                Err(e) => warn!(error = %e, "Sync failed"),
+            }
+
+            let mut wait = interval;
+            if let Some(t) = next_full_scan {
+                wait = wait.min((t - Local::now()).to_std().unwrap_or_default());
+            }
+            if !self.sleep(wait) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns a flag that stops the current run when set, e.g. from a signal
+    /// handler.
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancelled)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Sleeps for `duration` unless cancelled in the meantime. Returns `false`
+    /// if cancelled.
+    fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        while !self.is_cancelled() {
+            self.ping_watchdog();
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            thread::sleep((deadline - now).min(Duration::from_secs(1)));
+        }
+        false
+    }
+
+    /// Looks up the download size of a run of `songs` before it starts: the
+    /// songs among them that are new or outdated, and those an earlier run
+    /// left in the queue.
+    pub fn estimate_run(&self, songs: &[Song]) -> anyhow::Result<u64> {
+        let store = Store::open_read_only(&self.dest);
+        let queue = Queue::read(&self.dest)?;
+        let ids: HashSet<&str> = songs.iter().map(|song| song.id.as_str()).collect();
+        let queued = queue
+            .pending()
+            .into_iter()
+            .filter_map(|item| item.song.as_ref())
+            .filter(|song| !ids.contains(song.id.as_str()));
+        let songs: Vec<_> = songs
+            .iter()
+            .chain(queued)
+            .filter(|song| {
+                store
+                    .get(&song.id)
+                    .is_none_or(|entry| self.is_outdated(song, &entry))
+            })
+            .collect();
+        Ok(self.estimate_size(&songs))
+    }
+
+    /// Sums the archive sizes the server reports for `songs`. Songs whose size
+    /// cannot be determined are not counted.
+    pub fn estimate_size(&self, songs: &[&Song]) -> u64 {
+        songs
+            .iter()
+            .filter_map(|song| self.content_length(&song.id))
+            .sum()
+    }
+
+    fn content_length(&self, song_id: &str) -> Option<u64> {
+        let resp = self
+            .mirrors
+            .send(&self.routes.download_path(song_id), |url| {
+                self.http.head(url)
+            })
+            .ok()?;
+        resp.headers()
+            .get(header::CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    fn check_space(&self) -> anyhow::Result<()> {
+        if self.reserve == 0 {
+            return Ok(());
+        }
+        if let Some(available) = disk::available_space(&self.dest)? {
+            ensure!(
+                available >= self.reserve,
+                "Only {} free in {}, below the reserve of {}",
+                ByteSize(available),
+                self.dest.display(),
+                ByteSize(self.reserve)
+            );
+        }
+        Ok(())
+    }
+
+    fn ping_watchdog(&self) {
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.ping();
+        }
+    }
+
+    /// Takes an exclusive lock on the library so that syncs never overlap,
+    /// whether they come from watch mode or a separate invocation. Everything
+    /// that changes the library takes it, so a read-only downloader refuses
+    /// here before writing anything.
+    fn lock(&self) -> anyhow::Result<fs::File> {
+        ensure!(!self.read_only, "{} is read-only", self.dest.display());
+        store::lock(&self.dest)
+    }
+
+    /// Groups the local songs that have the same charts and audio; see
+    /// [`dedup::find_duplicates`].
+    pub fn find_duplicates(&self) -> anyhow::Result<Vec<Vec<(String, Entry)>>> {
+        let _lock = self.lock()?;
+        dedup::find_duplicates(&self.dest)
+    }
+
+    /// Moves the existing song directories to match the layout, rolling back
+    /// on failure.
+    pub fn reorganize(&self) -> anyhow::Result<Reorganization> {
+        let _lock = self.lock()?;
+        reorganize::reorganize(&self.dest, &self.layout, self.disposal)
+    }
+
+    /// Takes a snapshot of the songs of [`Downloader::catalog`], the whole
+    /// remote catalog unless a filter is set, without downloading anything.
+    pub fn snapshot(&self) -> anyhow::Result<Catalog> {
+        let songs = self.catalog().collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Catalog {
+            fetched_at: Utc::now(),
+            base_url: self.mirrors.current().to_owned(),
+            songs,
+        })
+    }
+
+    /// Compares the whole remote catalog against the local library without
+    /// downloading anything. The new songs are those
+    /// [`Downloader::download_all`] would download: the songs left in the
+    /// queue by an earlier run, and then the pending ones.
+    pub fn diff(&self) -> anyhow::Result<Diff> {
+        self.compare(false)
+    }
+
+    /// Compares as [`Downloader::diff`] does, with the new songs being those
+    /// [`Downloader::download_missing`] would download.
+    pub fn diff_missing(&self) -> anyhow::Result<Diff> {
+        self.compare(true)
+    }
+
+    fn compare(&self, full: bool) -> anyhow::Result<Diff> {
+        let store = Store::open_read_only(&self.dest);
+
+        let mut diff = Diff::default();
+        let mut remote = HashMap::new();
+        // The songs a sync would download are planned in the same walk, from
+        // where the sync would begin.
+        let mut planner = Planner::new(self, &store, full || self.walks_whole_catalog());
+        let mut start_after = self.start_after.as_deref();
+        let mut listing = self.listing();
+        while let Some(song) = listing.next() {
+            let song = song?;
+            if self.filter.matches(&song) {
+                if let Some(entry) = store.get(&song.id) {
+                    if self.is_outdated(&song, &entry) {
+                        diff.updated.push(song.clone());
+                    }
+                }
+            }
+            if self
+                .start_page
+                .is_some_and(|page| listing.pages < u64::from(page))
+            {
+                // Before the page the sync begins at.
+            } else if let Some(id) = start_after {
+                if id == song.id {
+                    start_after = None;
+                }
+            } else if !planner.is_done() {
+                planner.offer(&song);
+            }
+            remote.insert(song.id.clone(), song);
+        }
+
+        let queue = Queue::read(&self.dest)?;
+        let queued = queue
+            .pending()
+            .into_iter()
+            .filter_map(|item| item.song.clone().or_else(|| remote.get(&item.id).cloned()));
+        let mut planned = HashSet::new();
+        for song in queued.chain(planner.finish()) {
+            if !store.contains(&song.id) && planned.insert(song.id.clone()) {
+                diff.new.push(song);
+            }
+        }
+
+        diff.removed = store
+            .entries()
+            .into_iter()
+            .filter(|(id, entry)| {
+                self.filter.matches_entry(id, entry)
+                    && !remote.contains_key(id)
+                    && !import::is_local(id)
+            })
+            .map(|(id, _)| id)
+            .collect();
+        Ok(diff)
+    }
+
+    /// Iterates over the songs in the remote catalog that match the filter,
+    /// newest uploads first. Pages are fetched as the iterator advances.
+    pub fn catalog(&self) -> impl Iterator<Item = anyhow::Result<Song>> + '_ {
+        self.listing_from_start()
+            .filter(|song| song.as_ref().map_or(true, |song| self.filter.matches(song)))
+    }
+
+    fn listing(&self) -> Listing<'_> {
+        let routes = &self.routes;
+        let path = if self.favorites {
+            &routes.favorites
+        } else {
+            &routes.songs
+        };
+        let mut params = form_urlencoded::Serializer::new(String::new());
+        params.append_pair(&routes.sort_param, self.sort.as_param());
+        if let Some(per_page) = self.per_page {
+            params.append_pair(&routes.per_page_param, &per_page.to_string());
+        }
+        if let Some(query) = &self.filter.query {
+            params.append_pair(&routes.query_param, query);
+        }
+        let query = params.finish();
+        Listing {
+            http: &self.http,
+            mirrors: &self.mirrors,
+            routes,
+            path,
+            next_link: Some(format!("{path}?{query}")),
+            query,
+            songs: Vec::new().into_iter(),
+            scanned: 0,
+            pages: 0,
+            total: None,
+            start_after: None,
+        }
+    }
+
+    /// Like [`Downloader::listing`], but begins the walk at the start page
+    /// or after the start song if one is set. Walks that compare the whole
+    /// listing against the library, e.g. to find removed songs, must not
+    /// use this.
+    fn listing_from_start(&self) -> Listing<'_> {
+        let mut listing = self.listing();
+        if let Some(page) = self.start_page {
+            listing.next_link = Some(format!(
+                "{}?{}&{}={page}",
+                listing.path, listing.query, self.routes.page_param
+            ));
+        }
+        listing.start_after = self.start_after.clone();
+        listing
+    }
+
+    /// Downloads and extracts a song into `dest`, returning the size of its
+    /// archive. File names are decoded from `name_encoding` if given, or
+    /// else from the encoding guessed from them.
+    fn download(
+        &self,
+        song_id: &str,
+        name_encoding: Option<&'static Encoding>,
+        dest: &Path,
+    ) -> anyhow::Result<u64> {
+        let (archive, _) = self.fetch_archive(song_id, &Validators::default())?;
+        self.unpack(song_id, &archive, name_encoding, dest)?;
+        Ok(archive.len() as u64)
+    }
+
+    /// Returns whether `song`, downloaded as `entry`, was updated on the
+    /// server since and is to be downloaded again.
+    fn is_outdated(&self, song: &Song, entry: &Entry) -> bool {
+        self.refresh_updated && entry.is_kept() && song.updated_at > entry.downloaded_at
+    }
+
+    /// Downloads the archive of a song, along with its validators. Fails with
+    /// [`NotModified`] if the archive still has the `known` validators.
+    fn fetch_archive(
+        &self,
+        song_id: &str,
+        known: &Validators,
+    ) -> anyhow::Result<(Vec<u8>, Validators)> {
+        // The size is looked up first so that an oversized archive is not
+        // downloaded at all, unless the server does not tell it.
+        if let Some(max) = self.max_song_size {
+            if let Some(size) = self.content_length(song_id) {
+                ensure!(size <= max, Oversized { size, max });
+            }
+        }
+        let deadline = self.max_duration_per_song.map(|max| Instant::now() + max);
+        let resp = self
+            .mirrors
+            .send(&self.routes.download_path(song_id), |url| {
+                self.http.get_if(url, known, deadline)
+            })?;
+        if resp.is_not_modified() {
+            return Err(NotModified.into());
+        }
+        let validators = Validators::of(&resp);
+        let bytes = resp.bytes();
+        let size = bytes.len() as u64;
+        if let Some(max) = self.max_song_size {
+            ensure!(size <= max, Oversized { size, max });
+        }
+        Ok((bytes, validators))
+    }
+
+    /// Extracts the archive of a song into `dest`, moving what was extracted
+    /// to quarantine if the archive turns out to be invalid.
+    fn unpack(
+        &self,
+        song_id: &str,
+        archive: &[u8],
+        name_encoding: Option<&'static Encoding>,
+        dest: &Path,
+    ) -> anyhow::Result<()> {
+        if !dest.exists() {
+            fs::create_dir_all(dest)?;
+        }
+
+        if let Err(e) = extract(Cursor::new(archive), name_encoding, dest) {
+            // What was extracted goes to quarantine along with the archive.
+            quarantine::hold(
+                &self.dest,
+                song_id,
+                dest,
+                &format!("Invalid archive: {e:#}"),
+            )?;
+            fs::write(
+                quarantine::dir(&self.dest, song_id).join(quarantine::ARCHIVE_FILENAME),
+                archive,
+            )?;
+            return Err(e.context(InvalidArchive));
+        }
+        Ok(())
+    }
+
+    /// Runs `fetch` to fill the directory `dest` of the song `id`. With a
+    /// temporary directory, the song is fetched there and only moved to
+    /// `dest` once complete.
+    fn staged(
+        &self,
+        id: &str,
+        dest: &Path,
+        fetch: impl FnOnce(&Path) -> anyhow::Result<u64>,
+    ) -> anyhow::Result<u64> {
+        let Some(temp_dir) = &self.temp_dir else {
+            return fetch(dest);
+        };
+        let staging = longpath::extended(&temp_dir.join(format!(".nautica-{id}")));
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(&staging)?;
+        let result = fetch(&staging).and_then(|size| {
+            move_dir(&staging, dest)?;
+            Ok(size)
+        });
+        if result.is_err() && staging.exists() {
+            let _ = fs::remove_dir_all(&staging);
+        }
+        result
+    }
+
+    /// Fills in the uploader of a song the listing left without one, from
+    /// `uploaders` or else from the server, so that directories grouped by
+    /// uploader get the name rather than the user ID.
+    fn fill_uploader(&self, song: &mut Song, uploaders: &mut UploaderNames) {
+        if let Some(user) = &song.user {
+            cache_uploader(uploaders, &user.id, &user.name);
+            return;
+        }
+        if song.user_id.is_empty() {
+            return;
+        }
+        let name = match uploaders.get(&song.user_id) {
+            Some(name) => name.to_owned(),
+            None => match self.fetch_user(&song.user_id) {
+                Ok(user) => {
+                    cache_uploader(uploaders, &song.user_id, &user.name);
+                    user.name
+                }
+                Err(e) => {
+                    warn!(error = %e, "Failed to look up the uploader; using the user ID");
+                    return;
+                }
+            },
+        };
+        song.user = Some(User {
+            id: song.user_id.clone(),
+            name,
+        });
+    }
+
+    fn fetch_user(&self, id: &str) -> anyhow::Result<User> {
+        let resp: UserResp = self
+            .mirrors
+            .send(&self.routes.user_path(id), |url| self.http.get_api(url))?
+            .json()?;
+        Ok(resp.data)
+    }
+
+    /// Fetches the jacket and preview audio of a song into `dest`, returning
+    /// their total size. Files the server does not have are skipped.
+    fn download_preview(&self, song: &Song, dest: &Path) -> anyhow::Result<u64> {
+        let mut size = 0;
+        let mut fetched = 0;
+        let files = [
+            ("jacket", "png", &song.jacket_url),
+            ("preview", "mp3", &song.preview_url),
+        ];
+        for (stem, default_ext, url) in files {
+            let Some(url) = url else {
+                continue;
+            };
+            let Some(bytes) = self.fetch(url)? else {
+                continue;
+            };
+            let ext = url_extension(url).unwrap_or_else(|| default_ext.to_owned());
+            fs::create_dir_all(dest)?;
+            fs::write(dest.join(format!("{stem}.{ext}")), &bytes)?;
+            size += bytes.len() as u64;
+            fetched += 1;
+        }
+        ensure!(fetched > 0, "No jacket or preview for {}", song.id);
+        Ok(size)
+    }
+
+    /// Downloads the jackets of the songs in the remote catalog that match
+    /// the filter into the [`JacketCache`], skipping jackets already cached.
+    pub fn cache_jackets(&self) -> anyhow::Result<JacketReport> {
+        let cache = JacketCache::new(&self.dest);
+        let mut report = JacketReport::default();
+        for song in self.catalog() {
+            let song = song?;
+            if self.is_cancelled() {
+                warn!("Cancelled");
+                break;
+            }
+            let _span = info_span!(
+                "fetch_jacket",
+                id = song.id,
+                title = song.title,
+                artist = song.artist
+            )
+            .entered();
+            if cache.get(&song.id).is_some() {
+                report.cached += 1;
+                continue;
+            }
+            let Some(url) = &song.jacket_url else {
+                report.missing += 1;
+                continue;
+            };
+            match self.fetch(url) {
+                Ok(Some(bytes)) => {
+                    if let Err(e) = jackets::check(&bytes) {
+                        warn!(error = %e, "Invalid jacket");
+                        report.invalid.push(song.id);
+                        continue;
+                    }
+                    let ext = url_extension(url).unwrap_or_default();
+                    cache.insert(&song.id, &ext, &bytes)?;
+                    report.fetched += 1;
+                }
+                Ok(None) => report.missing += 1,
+                Err(e) => {
+                    warn!(error = %e, "Failed to download jacket");
+                    report.failed.push(song.id);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Lists the playlists of the logged-in account, without their songs.
+    pub fn playlists(&self) -> anyhow::Result<Vec<Playlist>> {
+        let resp: PlaylistsResp = self
+            .mirrors
+            .send(&self.routes.playlists, |url| self.http.get_api(url))
+            .context("Failed to list playlists; log in again if the session expired")?
+            .json()?;
+        Ok(resp.data)
+    }
+
+    /// Fetches the playlist `id` with its songs. Public playlists of other
+    /// accounts need no login.
+    pub fn playlist(&self, id: &str) -> anyhow::Result<Playlist> {
+        let resp: PlaylistResp = self
+            .mirrors
+            .send(&self.routes.playlist_path(id), |url| self.http.get_api(url))
+            .with_context(|| format!("Failed to fetch playlist {id}"))?
+            .json()?;
+        Ok(resp.data)
+    }
+
+    /// Publishes the song in `dir` to the logged-in account, returning the
+    /// song as created on the server. Fails without uploading anything if
+    /// [`publish::check`] finds problems.
+    pub fn publish(&self, dir: &Path) -> anyhow::Result<Song> {
+        let xsrf_token = self
+            .xsrf_token
+            .as_deref()
+            .context("Not logged in; run the login command first")?;
+        let problems = publish::check(dir)?;
+        ensure!(
+            problems.is_empty(),
+            "{} cannot be published:\n  {}",
+            dir.display(),
+            problems.join("\n  ")
+        );
+        let name = dir.file_name().map_or_else(
+            || String::from("song"),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        let (content_type, body) =
+            publish::multipart("file", &format!("{name}.zip"), &publish::package(dir)?);
+        // Not failed over, so that a timeout never publishes a song twice.
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(&content_type)?);
+        headers.insert("X-XSRF-TOKEN", HeaderValue::from_str(xsrf_token)?);
+        let resp = self.http.post(
+            &format!("{}{}", self.mirrors.current(), self.routes.publish),
+            &headers,
+            &body,
+        )?;
+        ensure!(
+            resp.is_success(),
+            "Failed to publish {}: {} {}",
+            dir.display(),
+            resp.status(),
+            String::from_utf8_lossy(&resp.body)
+        );
+        let resp: PublishResp = resp.json()?;
+        Ok(resp.data)
+    }
+
+    /// Downloads `url`, returning `None` if the server does not have it.
+    fn fetch(&self, url: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let resp = self.http.get(url)?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        ensure!(
+            resp.is_success(),
+            HttpStatus {
+                url: url.to_owned(),
+                status: resp.status(),
+            }
+        );
+        Ok(Some(resp.bytes()))
+    }
+}
+
+fn cache_uploader(uploaders: &mut UploaderNames, id: &str, name: &str) {
+    if let Err(e) = uploaders.insert(id, name) {
+        warn!(error = %e, "Failed to cache the uploader's name");
+    }
+}
+
+/// Returns the file extension in the path of `url`, e.g. `jpg` for
+/// `https://example.com/songs/5441d590/jacket.jpg?v=2`.
+fn url_extension(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let ext = Path::new(url.path()).extension()?.to_str()?;
+    Some(ext.to_owned())
+}
+
+/// Sets the status of the song `id` in `queue` once a run is finished with
+/// it: done if it was recorded in the library, or else failed.
+fn finish_queued(queue: &mut Queue, store: &Store, id: &str) -> anyhow::Result<()> {
+    let status = if store.contains(id) {
+        queue::Status::Done
+    } else {
+        queue::Status::Failed
+    };
+    queue.set_status(id, status)
+}
+
+/// Moves the directory `from` to `to`, copying it when they are on different
+/// filesystems.
+fn move_dir(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_dir(from, to)?;
+    fs::remove_dir_all(from)
+}
+
+/// Replaces the directory `old`, if any, with `new`. The old one is moved
+/// aside first and put back if `new` cannot take its place.
+fn replace_dir(new: &Path, old: &Path) -> io::Result<()> {
+    let mut aside = old.as_os_str().to_owned();
+    aside.push(".old");
+    let aside = PathBuf::from(aside);
+    if aside.exists() {
+        fs::remove_dir_all(&aside)?;
+    }
+    let replacing = old.exists();
+    if replacing {
+        fs::rename(old, &aside)?;
+    }
+    if let Err(e) = move_dir(new, old) {
+        if replacing {
+            let _ = fs::rename(&aside, old);
+        }
+        return Err(e);
+    }
+    if replacing {
+        fs::remove_dir_all(&aside)?;
+    }
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Extrapolates the time left to download `total` bytes from the rate so far.
+fn eta(elapsed: Duration, done: u64, total: u64) -> Duration {
+    if done == 0 {
+        return Duration::ZERO;
+    }
+    let remaining = total.saturating_sub(done) as f64 / done as f64;
+    Duration::from_secs(elapsed.mul_f64(remaining).as_secs())
+}
+
+/// Extracts the files of a song archive into `dest`, flattening directories.
+/// File names are decoded from `name_encoding` if given, or else from the
+/// encoding guessed from them.
+pub(crate) fn extract<R: Read + Seek>(
+    reader: R,
+    name_encoding: Option<&'static Encoding>,
+    dest: &Path,
+) -> anyhow::Result<()> {
+    let mut archive = ZipArchive::new(reader)?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+
+        if file.name().ends_with('/') {
+            continue;
+        };
+
+        let filepath = {
+            // FIXME: Changing the file name encoding will likely break references
+            // from the ksh file. Need to modify the contents of the ksh file
+            // accordingly.
+            let encoding = name_encoding.unwrap_or_else(|| {
+                let mut det = EncodingDetector::new();
+                det.feed(file.name_raw(), true);
+                det.guess(None, true)
+            });
+
+            let (cow, _, had_errors) = encoding.decode(file.name_raw());
+            let enclosed_name = if had_errors {
+                file.enclosed_name()
+            } else {
+                enclosed_name(&cow)
+            };
+            match enclosed_name {
+                Some(path) => path.to_owned(),
+                None => {
+                    warn!(path = file.name(), "invalid file path");
+                    continue;
+                }
+            }
+        };
+
+        let filename = filepath.file_name().unwrap().to_str().unwrap();
+        let mut outfile = fs::File::create(dest.join(filename))?;
+        io::copy(&mut file, &mut outfile)?;
+    }
+
+    Ok(())
+}
+
+fn enclosed_name(file_name: &str) -> Option<&Path> {
+    if file_name.contains('\0') {
+        return None;
+    }
+    let path = Path::new(file_name);
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => return None,
+            Component::ParentDir => depth = depth.checked_sub(1)?,
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+        }
+    }
+    Some(path)
+}
+
+#[derive(Debug)]
+pub struct DownloaderBuilder {
+    dest: PathBuf,
+    base_urls: Vec<String>,
+    routes: Routes,
+    sort: Sort,
+    per_page: Option<u32>,
+    top: Option<usize>,
+    start_page: Option<u32>,
+    start_after: Option<String>,
+    filter: Filter,
+    layout: Layout,
+    max_bytes: Option<u64>,
+    max_song_size: Option<u64>,
+    reserve: u64,
+    estimate: bool,
+    preview_only: bool,
+    read_only: bool,
+    skip_duplicates: bool,
+    prefer: Option<Preference>,
+    refresh_updated: bool,
+    favorites: bool,
+    session: Option<String>,
+    http: HttpConfig,
+    #[cfg(feature = "cassette")]
+    cassette: Option<Cassette>,
+    temp_dir: Option<PathBuf>,
+    on_error: OnError,
+    max_consecutive_failures: Option<u32>,
+    max_duration_per_song: Option<Duration>,
+    queue_order: QueueConfig,
+    copies: Vec<CopyConfig>,
+    disposal: Disposal,
+    notifiers: Vec<Box<dyn Notifier>>,
+    cancelled: Option<Arc<AtomicBool>>,
+    watchdog: Option<Arc<Watchdog>>,
+}
+
+impl DownloaderBuilder {
+    pub fn dest<P: Into<PathBuf>>(mut self, dest: P) -> Self {
+        self.dest = dest.into();
+        self
+    }
+
+    pub fn base_url(mut self, base_url: String) -> Self {
+        self.base_urls = vec![base_url];
+        self
+    }
+
+    /// Sets the servers to use in order, failing over to the next one when
+    /// requests keep failing on one. Ignored if empty.
+    pub fn base_urls(mut self, base_urls: Vec<String>) -> Self {
+        if !base_urls.is_empty() {
+            self.base_urls = base_urls;
+        }
+        self
+    }
+
+    /// Sets the routes of the API, for servers that differ from Nautica.
+    pub fn routes(mut self, routes: Routes) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    pub fn sort(mut self, sort: Sort) -> Self {
+        self.sort = sort;
+        self
+    }
+
+    /// Sets how many songs each listing request fetches.
+    pub fn per_page(mut self, per_page: u32) -> Self {
+        self.per_page = Some(per_page);
+        self
+    }
+
+    /// Only considers the first `top` songs of the listing that match the
+    /// filter, e.g. the most downloaded ones with [`Sort::Popular`]. Those
+    /// already in the library count towards it.
+    pub fn top(mut self, top: usize) -> Self {
+        self.top = Some(top);
+        self
+    }
+
+    /// Begins walking the listing at page `page` instead of the first, e.g.
+    /// to recover a run by hand. Songs on the earlier pages are left alone.
+    pub fn start_page(mut self, page: u32) -> Self {
+        self.start_page = Some(page);
+        self
+    }
+
+    /// Begins walking the listing right after the song `id`. If the song is
+    /// not in the listing, nothing is listed.
+    pub fn start_after(mut self, id: &str) -> Self {
+        self.start_after = Some(id.to_owned());
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Limits how many bytes a single run may download.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Skips songs whose archive is larger than `max_song_size` bytes, e.g.
+    /// packs with videos on a mirror with little disk space.
+    pub fn max_song_size(mut self, max_song_size: u64) -> Self {
+        self.max_song_size = Some(max_song_size);
+        self
+    }
+
+    /// Sets the free space to keep on the destination filesystem.
+    pub fn reserve(mut self, reserve: u64) -> Self {
+        self.reserve = reserve;
+        self
+    }
+
+    /// Looks up the size of each song before a run to log the total size and
+    /// time remaining as it progresses.
+    pub fn estimate(mut self, estimate: bool) -> Self {
+        self.estimate = estimate;
+        self
+    }
+
+    /// Fetches only the jacket and preview audio of each song instead of its
+    /// archive, for a lightweight library to browse before downloading.
+    pub fn preview_only(mut self, preview_only: bool) -> Self {
+        self.preview_only = preview_only;
+        self
+    }
+
+    /// Never writes to the library, e.g. a NAS export mounted read-only.
+    /// Comparing with the server still works; syncs and other changes fail.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Removes downloaded songs whose charts and audio are the same as a song
+    /// already in the library. They are remembered so they are not downloaded
+    /// again.
+    pub fn skip_duplicates(mut self, skip_duplicates: bool) -> Self {
+        self.skip_duplicates = skip_duplicates;
+        self
+    }
+
+    /// Downloads only the upload `preference` picks among uploads of the same
+    /// song, by title and artist, by different users, remembering the others
+    /// as duplicates. Songs already in the library are kept over new ones.
+    pub fn prefer(mut self, preference: Preference) -> Self {
+        self.prefer = Some(preference);
+        self
+    }
+
+    /// Downloads songs updated on the server since they were downloaded
+    /// again, in full syncs. The server is asked for each archive only if it
+    /// changed since, so unchanged ones are not transferred; see
+    /// [`DownloadReport::unchanged`].
+    pub fn refresh_updated(mut self, refresh_updated: bool) -> Self {
+        self.refresh_updated = refresh_updated;
+        self
+    }
+
+    /// Syncs exactly the songs the logged-in account liked: newly liked songs
+    /// are downloaded and songs no longer liked are removed again. Requires a
+    /// [`DownloaderBuilder::session`].
+    pub fn favorites(mut self, favorites: bool) -> Self {
+        self.favorites = favorites;
+        self
+    }
+
+    /// Fetches songs into `temp_dir`, e.g. on a fast local disk, and moves
+    /// each one to the library once complete.
+    pub fn temp_dir<P: Into<PathBuf>>(mut self, temp_dir: P) -> Self {
+        self.temp_dir = Some(temp_dir.into());
+        self
+    }
+
+    /// Sets whether a run stops at the first song that fails to download.
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+    /// Stops a run with an [`Outage`] error once this many songs in a row
+    /// failed to download, leaving them out of the failures. `None` never
+    /// stops.
+    pub fn max_consecutive_failures(mut self, max: Option<u32>) -> Self {
+        self.max_consecutive_failures = max;
+        self
+    }
+
+    /// Gives up on a song whose download takes longer than `max`, e.g. a
+    /// huge archive on a dying connection, so that it cannot stall the run.
+    /// The song fails and is tried again in the next run.
+    pub fn max_duration_per_song(mut self, max: Duration) -> Self {
+        self.max_duration_per_song = Some(max);
+        self
+    }
+
+    /// Orders the songs of the download queue by the rules of `config`
+    /// rather than the order they were listed in.
+    pub fn queue_order(mut self, config: QueueConfig) -> Self {
+        self.queue_order = config;
+        self
+    }
+
+    /// Also writes each downloaded song to the destination of `config`.
+    pub fn copy_to(mut self, config: CopyConfig) -> Self {
+        self.copies.push(config);
+        self
+    }
+
+    /// Sets how songs that are no longer liked are disposed of.
+    pub fn disposal(mut self, disposal: Disposal) -> Self {
+        self.disposal = disposal;
+        self
+    }
+
+    /// Sends the session cookies saved by [`Downloader::login`] with every
+    /// request.
+    pub fn session(mut self, cookie: String) -> Self {
+        self.session = Some(cookie);
+        self
+    }
+
+    /// Sets how connections to the servers are reused between requests.
+    pub fn http(mut self, http: HttpConfig) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Records the responses of the servers to `cassette`, or replays them
+    /// from it, e.g. for tests without a network.
+    #[cfg(feature = "cassette")]
+    pub fn cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    pub fn notifier<N: Notifier + 'static>(mut self, notifier: N) -> Self {
+        self.notifiers.push(Box::new(notifier));
+        self
+    }
+
+    /// Uses `cancelled` as the [`Downloader::cancel_flag`], e.g. to keep one
+    /// signal handler across downloaders rebuilt after a configuration
+    /// change.
+    pub fn cancel_flag(mut self, cancelled: Arc<AtomicBool>) -> Self {
+        self.cancelled = Some(cancelled);
+        self
+    }
+
+    /// Pings `watchdog` while syncs make progress, i.e. as responses arrive
+    /// and songs are downloaded, and while watch mode waits for the next
+    /// run.
+    pub fn watchdog(mut self, watchdog: Arc<Watchdog>) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    pub fn build(self) -> Downloader {
+        let mirrors = Mirrors::new(self.base_urls).breaker(
+            self.http.breaker_threshold,
+            Duration::from_secs(self.http.breaker_cooldown),
+        );
+        let mut http = Client::new(&self.http);
+        http.timeout(READ_TIMEOUT);
+        if let Some(watchdog) = &self.watchdog {
+            http.watchdog(Arc::clone(watchdog));
+        }
+        #[cfg(feature = "cassette")]
+        if let Some(cassette) = self.cassette {
+            http.cassette(cassette);
+        }
+        if let Some(cookie) = &self.session {
+            // Sanctum only accepts the session from its own frontend.
+            if let Err(e) = http.header(header::REFERER, mirrors.current()) {
+                warn!(error = %e, "Ignoring the server of the saved session");
+            }
+            if let Err(e) = http.header(header::COOKIE, cookie) {
+                warn!(error = %e, "Ignoring the saved session");
+            }
+        }
+        Downloader {
+            dest: self.dest,
+            mirrors,
+            routes: self.routes,
+            sort: self.sort,
+            per_page: self.per_page,
+            top: self.top,
+            start_page: self.start_page,
+            start_after: self.start_after,
+            filter: self.filter,
+            layout: self.layout,
+            max_bytes: self.max_bytes,
+            max_song_size: self.max_song_size,
+            reserve: self.reserve,
+            estimate: self.estimate,
+            preview_only: self.preview_only,
+            read_only: self.read_only,
+            skip_duplicates: self.skip_duplicates,
+            prefer: self.prefer,
+            refresh_updated: self.refresh_updated,
+            favorites: self.favorites,
+            temp_dir: self.temp_dir,
+            on_error: self.on_error,
+            max_consecutive_failures: self.max_consecutive_failures,
+            max_duration_per_song: self.max_duration_per_song,
+            queue_order: self.queue_order,
+            copies: self.copies,
+            disposal: self.disposal,
+            notifiers: self.notifiers,
+            cancelled: self.cancelled.unwrap_or_default(),
+            watchdog: self.watchdog,
+            xsrf_token: self.session.as_deref().and_then(auth::xsrf_token),
+            http,
+        }
+    }
+}
+
+impl Default for DownloaderBuilder {
+    fn default() -> Self {
+        Self {
+            dest: PathBuf::from("nautica"),
+            base_urls: vec![String::from(NAUTICA_BASE_URL)],
+            routes: Routes::default(),
+            sort: Sort::default(),
+            per_page: None,
+            top: None,
+            start_page: None,
+            start_after: None,
+            filter: Filter::default(),
+            layout: Layout::default(),
+            max_bytes: None,
+            max_song_size: None,
+            reserve: 0,
+            estimate: false,
+            preview_only: false,
+            read_only: false,
+            skip_duplicates: false,
+            prefer: None,
+            refresh_updated: false,
+            favorites: false,
+            session: None,
+            http: HttpConfig::default(),
+            #[cfg(feature = "cassette")]
+            cassette: None,
+            temp_dir: None,
+            on_error: OnError::default(),
+            max_consecutive_failures: Some(DEFAULT_MAX_CONSECUTIVE_FAILURES),
+            max_duration_per_song: None,
+            queue_order: QueueConfig::default(),
+            copies: Vec::new(),
+            disposal: Disposal::default(),
+            notifiers: Vec::new(),
+            cancelled: None,
+            watchdog: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+
+    use httpmock::MockServer;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn parse_songs_resp() {
+        let songs: SongsResp =
+            serde_json::from_reader(File::open("tests/fixtures/songs.json").unwrap()).unwrap();
+        assert_eq!(songs.data.len(), 10);
+        assert_eq!(songs.meta.unwrap().total, 5321);
+        let chart = &songs.data[0].charts[0];
+        assert_eq!(chart.to_string(), "INF18");
+        assert_eq!(chart.effector.as_deref(), Some("Ixiot"));
+        assert!(!songs.data[0].mojibake);
+        assert_eq!(
+            songs.data[0].uploaded_at,
+            Utc.with_ymd_and_hms(2023, 9, 7, 5, 56, 46).unwrap()
+        );
+    }
+
+    #[test]
+    fn download_ascii_encoding_zip() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.path("/songs/5441d590-4d43-11ee-a602-d95b1bfc2e6d/download");
+            then.header("content-type", "application/x-zip")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+                ));
+        });
+
+        let dest = tempdir().unwrap();
+
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .build();
+
+        let song_dest = dest.path().join("5441d590-4d43-11ee-a602-d95b1bfc2e6d");
+        downloader
+            .download("5441d590-4d43-11ee-a602-d95b1bfc2e6d", None, &song_dest)
+            .unwrap();
+
+        m.assert();
+
+        assert_eq!(song_dest.read_dir().unwrap().collect::<Vec<_>>().len(), 6);
+        assert!(song_dest.join("Advanced.ksh").exists());
+        assert!(song_dest.join("Exhaust.ksh").exists());
+        assert!(song_dest.join("Novice.ksh").exists());
+        assert!(song_dest.join("Outbreak-jacket.png").exists());
+        assert!(song_dest.join("Outbreak.ksh").exists());
+        assert!(song_dest.join("Outbreak.ogg").exists());
+    }
+
+    #[cfg(feature = "cassette")]
+    #[test]
+    fn replay_recorded_download() {
+        let id = "5441d590-4d43-11ee-a602-d95b1bfc2e6d";
+        let server = MockServer::start();
+        let mut m = server.mock(|when, then| {
+            when.path(format!("/songs/{id}/download"));
+            then.header("content-type", "application/x-zip")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+                ));
+        });
+        let cassette = tempdir().unwrap();
+        let download = |cassette| {
+            let dest = tempdir().unwrap();
+            let downloader = Downloader::builder()
+                .dest(dest.path())
+                .base_url(server.base_url())
+                .cassette(cassette)
+                .build();
+            let song_dest = dest.path().join(id);
+            downloader.download(id, None, &song_dest).unwrap();
+            assert!(song_dest.join("Outbreak.ksh").exists());
+        };
+
+        download(Cassette::record(cassette.path()));
+        m.assert();
+        // Replaying needs no server.
+        m.delete();
+        download(Cassette::replay(cassette.path()));
+    }
+
+    #[test]
+    fn download_shift_jis_encoding_zip() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.path("/songs/89b54d80-4e6d-11ee-83d4-2ffdf82667a6/download");
+            then.header("content-type", "application/x-zip")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/89b54d80-4e6d-11ee-83d4-2ffdf82667a6.zip"
+                ));
+        });
+
+        let dest = tempdir().unwrap();
+
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .build();
+
+        let song_dest = dest.path().join("89b54d80-4e6d-11ee-83d4-2ffdf82667a6");
+        downloader
+            .download("89b54d80-4e6d-11ee-83d4-2ffdf82667a6", None, &song_dest)
+            .unwrap();
+
+        m.assert();
+
+        assert_eq!(song_dest.read_dir().unwrap().collect::<Vec<_>>().len(), 9);
+        assert!(song_dest.join("3.wav").exists());
+        assert!(song_dest.join("5.wav").exists());
+        assert!(song_dest.join("6.wav").exists());
+        assert!(song_dest.join("8.wav").exists());
+        assert!(song_dest.join("10.wav").exists());
+        assert!(song_dest.join("chart.png").exists());
+        assert!(song_dest.join("チューリングラブ feat.Sou.ksh").exists());
+        assert!(song_dest.join("チューリングラブ feat.Sou.ogg").exists());
+        assert!(song_dest.join("チューリングラブ feat.Sou.png").exists());
+    }
+
+    #[test]
+    fn download_unknown_encoding_zip() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.path("/songs/9e523640-4fb1-11ee-a90f-e9c914456566/download");
+            then.header("content-type", "application/x-zip")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/9e523640-4fb1-11ee-a90f-e9c914456566.zip"
+                ));
+        });
+
+        let dest = tempdir().unwrap();
+
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .build();
+
+        let song_dest = dest.path().join("9e523640-4fb1-11ee-a90f-e9c914456566");
+        downloader
+            .download("9e523640-4fb1-11ee-a90f-e9c914456566", None, &song_dest)
+            .unwrap();
+
+        m.assert();
+
+        assert_eq!(song_dest.read_dir().unwrap().collect::<Vec<_>>().len(), 3);
+        assert!(song_dest.join("audio.ogg").exists());
+        assert!(song_dest.join("jacket.png").exists());
+
+        // chardetng guessed Big5 but not sure.
+        assert!(song_dest.join("哈姘屋怨姥恍鏺泆絯.ksh").exists());
+    }
+
+    #[test]
+    fn download_zip_with_encoding_hint() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/songs/9e523640-4fb1-11ee-a90f-e9c914456566/download");
+            then.header("content-type", "application/x-zip")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/9e523640-4fb1-11ee-a90f-e9c914456566.zip"
+                ));
+        });
+
+        let dest = tempdir().unwrap();
+
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .build();
+
+        let mut json = song_json(
+            "9e523640-4fb1-11ee-a90f-e9c914456566",
+            "2023-09-01 00:00:00",
+        );
+        json["mojibake"] = json!(1);
+        let mut song: Song = serde_json::from_value(json).unwrap();
+        assert_eq!(song.name_encoding(), Some(SHIFT_JIS));
+        song.encoding = Some(String::from("euc-kr"));
+
+        let song_dest = dest.path().join(&song.id);
+        downloader
+            .download(&song.id, song.name_encoding(), &song_dest)
+            .unwrap();
+
+        assert!(song_dest.join("アスノヨゾラ哨戒班.ksh").exists());
+    }
+
+    fn song_json(id: &str, updated_at: &str) -> serde_json::Value {
+        json!({
+            "id": id,
+            "user_id": "user",
+            "title": format!("title of {id}"),
+            "artist": "artist",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": updated_at,
+        })
+    }
+
+    #[test]
+    fn diff_against_local_library() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [
+                    song_json("new", "2023-09-01 00:00:00"),
+                    song_json("updated", "2023-09-10 00:00:00"),
+                    song_json("unchanged", "2023-09-01 00:00:00"),
+                    song_json("older", "2023-09-01 00:00:00"),
+                    song_json("queued", "2023-09-01 00:00:00"),
+                    song_json("gone", "2023-09-10 00:00:00"),
+                ],
+                "links": { "next": null },
+            }));
+        });
+
+        let dest = tempdir().unwrap();
+        Queue::open(dest.path())
+            .unwrap()
+            .push_id("queued", 0)
+            .unwrap();
+        let mut db =
+            PickleDb::new_json(dest.path().join("meta.json"), PickleDbDumpPolicy::AutoDump);
+        let downloaded_at = Utc.with_ymd_and_hms(2023, 9, 5, 0, 0, 0).unwrap();
+        db.set("updated", &downloaded_at).unwrap();
+        db.set("unchanged", &downloaded_at).unwrap();
+        db.set("removed", &downloaded_at).unwrap();
+        db.set("0123456789abcdef-local", &downloaded_at).unwrap();
+        drop(db);
+        // Removed from the server once, so a sync never requests it again.
+        let gone = serde_json::from_value(song_json("gone", "2023-09-01 00:00:00")).unwrap();
+        let entry = Entry {
+            downloaded_at,
+            removed: true,
+            ..Entry::new(&gone, "gone")
+        };
+        Store::open(dest.path()).insert("gone", &entry).unwrap();
+
+        let builder = || {
+            Downloader::builder()
+                .dest(dest.path())
+                .base_url(server.base_url())
+        };
+        let diff = builder().build().diff().unwrap();
+
+        // The catalog is walked once.
+        m.assert_hits(1);
+        let ids = |songs: &[Song]| songs.iter().map(|s| s.id.clone()).collect::<Vec<_>>();
+        // Like a sync, songs older than the newest local one are left out
+        // unless they were queued.
+        assert_eq!(ids(&diff.new), ["queued", "new"]);
+        // Updated songs are only downloaded again if asked to.
+        assert!(diff.updated.is_empty());
+        assert_eq!(diff.removed, ["removed"]);
+
+        let diff = builder().refresh_updated(true).build().diff().unwrap();
+        assert_eq!(ids(&diff.updated), ["updated"]);
+    }
+
+    #[test]
+    fn download_all_stops_at_existing_song() {
+        let server = MockServer::start();
+        let song = song_json(
+            "5441d590-4d43-11ee-a602-d95b1bfc2e6d",
+            "2023-09-01 00:00:00",
+        );
+        let listing = server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [song, song_json("old", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        let download = server.mock(|when, then| {
+            when.path("/songs/5441d590-4d43-11ee-a602-d95b1bfc2e6d/download");
+            then.header("content-type", "application/x-zip")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+                ));
+        });
+
+        let dest = tempdir().unwrap();
+        let mut store = Store::open(dest.path());
+        store
+            .insert(
+                "old",
+                &Entry::new(
+                    &serde_json::from_value(song_json("old", "2023-09-01 00:00:00")).unwrap(),
+                    "old",
+                ),
+            )
+            .unwrap();
+
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .build();
+        let report = downloader.download_all().unwrap();
+
+        listing.assert();
+        download.assert();
+        assert_eq!(report.downloaded.len(), 1);
+        assert!(report.failed.is_empty());
+        let entry = Store::open_read_only(dest.path())
+            .get("5441d590-4d43-11ee-a602-d95b1bfc2e6d")
+            .unwrap();
+        assert_eq!(entry.bpm.unwrap().to_string(), "11.875-475");
+        assert!((entry.duration.unwrap() - 125.684).abs() < 0.001);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ping_watchdog_during_sync() {
+        use std::os::unix::net::UnixDatagram;
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200)
+                .json_body(json!({ "data": [], "links": { "next": null } }));
+        });
+        let dest = tempdir().unwrap();
+        let path = dest.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_nonblocking(true).unwrap();
+
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .watchdog(Arc::new(Watchdog::new(&path, Duration::from_secs(60))))
+            .build();
+        downloader.download_all().unwrap();
+
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+    }
+
+    #[test]
+    fn sync_refuses_to_overlap() {
+        let dest = tempdir().unwrap();
+        let downloader = Downloader::builder().dest(dest.path()).build();
+
+        let _lock = downloader.lock().unwrap();
+        assert!(downloader.lock().is_err());
+        assert!(downloader.download_all().is_err());
+    }
+
+    #[test]
+    fn cancelled_sync_downloads_nothing() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [song_json("new", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+
+        let dest = tempdir().unwrap();
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .build();
+        downloader.cancel_flag().store(true, Ordering::Relaxed);
+        let report = downloader.download_all().unwrap();
+
+        assert!(report.cancelled);
+        assert!(report.downloaded.is_empty());
+        assert!(report.failed.is_empty());
+    }
+
+    #[test]
+    fn query_is_sent_and_applied_locally() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.path("/app/songs")
+                .query_param("sort", "uploaded")
+                .query_param("q", "title of b");
+            then.status(200).json_body(json!({
+                "data": [song_json("a", "2023-09-01 00:00:00"), song_json("b", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+
+        let dest = tempdir().unwrap();
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .filter(Filter {
+                query: Some(String::from("title of b")),
+                ..Default::default()
+            })
+            .build();
+        let pending = downloader.pending().unwrap();
+
+        m.assert();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, "b");
+    }
+
+    #[test]
+    fn fork_with_custom_routes_and_pagination() {
+        let server = MockServer::start();
+        // Mocks are matched in order, so the second page comes first.
+        let page2 = server.mock(|when, then| {
+            when.path("/fork/api/list").query_param("p", "2");
+            then.status(200).json_body(json!({
+                "data": [song_json("a", "2023-09-01 00:00:00")],
+                "meta": { "total": 2, "current_page": 2, "last_page": 2 },
+            }));
+        });
+
+        let page1 = server.mock(|when, then| {
+            when.path("/fork/api/list")
+                .query_param("order", "uploaded")
+                .query_param("limit", "1");
+            then.status(200).json_body(json!({
+                "data": [song_json("b", "2023-09-01 00:00:00")],
+                "meta": { "total": 2, "current_page": 1, "last_page": 2 },
+            }));
+        });
+        let downloader = Downloader::builder()
+            .base_url(server.url("/fork"))
+            .routes(Routes {
+                songs: String::from("/api/list"),
+                page_param: String::from("p"),
+                per_page_param: String::from("limit"),
+                sort_param: String::from("order"),
+                ..Default::default()
+            })
+            .per_page(1)
+            .build();
+        let ids: Vec<_> = downloader.catalog().map(|song| song.unwrap().id).collect();
+
+        page1.assert();
+        page2.assert();
+        assert_eq!(ids, ["b", "a"]);
+    }
+
+    #[test]
+    fn sync_liked_songs() {
+        let server = MockServer::start();
+        let liked = server.mock(|when, then| {
+            when.path("/app/user/songs/liked")
+                .header("cookie", "nautica_session=abc");
+            then.status(200).json_body(json!({
+                "data": [song_json("liked", "2023-09-01 00:00:00"), song_json("kept", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        let download = server.mock(|when, then| {
+            when.path_contains("/download");
+            then.header("content-type", "application/x-zip")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+                ));
+        });
+
+        let dest = tempdir().unwrap();
+        let mut db =
+            PickleDb::new_json(dest.path().join("meta.json"), PickleDbDumpPolicy::AutoDump);
+        let favorite = json!({ "downloaded_at": Utc::now(), "favorite": true });
+        db.set("kept", &favorite).unwrap();
+        db.set("unliked", &favorite).unwrap();
+        let starred = json!({ "downloaded_at": Utc::now(), "favorite": true, "starred": true });
+        db.set("starred", &starred).unwrap();
+        db.set("synced", &Utc::now()).unwrap();
+        fs::create_dir(dest.path().join("unliked")).unwrap();
+
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .session(String::from("nautica_session=abc"))
+            .favorites(true)
+            .disposal(Disposal::Trash)
+            .build();
+        let report = downloader.download_all().unwrap();
+
+        liked.assert();
+        download.assert_hits(1);
+        assert_eq!(report.downloaded[0].id, "liked");
+        assert_eq!(report.unfavorited, ["unliked"]);
+        assert!(!dest.path().join("unliked").exists());
+        let batch = fs::read_dir(dest.path().join(".trash"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert!(batch.path().join("unliked").is_dir());
+        let store = Store::open_read_only(dest.path());
+        assert!(store.get("liked").unwrap().favorite);
+        assert!(!store.contains("unliked"));
+        assert!(store.contains("starred"));
+        assert!(store.contains("synced"));
+    }
+
+    #[test]
+    fn fetch_playlists() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/user/playlists");
+            then.status(200).json_body(json!({
+                "data": [{ "id": "p1", "name": "Practice" }],
+            }));
+        });
+        server.mock(|when, then| {
+            when.path("/app/playlists/p1");
+            then.status(200).json_body(json!({
+                "data": {
+                    "id": "p1",
+                    "name": "Practice",
+                    "songs": [song_json("a", "2023-09-01 00:00:00")],
+                },
+            }));
+        });
+
+        let downloader = Downloader::builder().base_url(server.base_url()).build();
+        let playlists = downloader.playlists().unwrap();
+        assert_eq!(playlists[0].name, "Practice");
+        assert!(playlists[0].songs.is_empty());
+        let playlist = downloader.playlist(&playlists[0].id).unwrap();
+        assert_eq!(playlist.songs[0].id, "a");
+        assert!(downloader.playlist("missing").is_err());
+    }
+
+    #[test]
+    fn publish_song() {
+        let server = MockServer::start();
+        let publish = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/app/user/songs")
+                .header("x-xsrf-token", "abc=")
+                .header("cookie", "XSRF-TOKEN=abc%3D; nautica_session=s")
+                .header_exists("content-type")
+                .body_contains("filename=\"song.zip\"");
+            then.status(201)
+                .json_body(json!({ "data": song_json("new", "2023-09-01 00:00:00") }));
+        });
+
+        let dest = tempdir().unwrap();
+        let dir = dest.path().join("song");
+        fs::create_dir(&dir).unwrap();
+        fs::write(
+            dir.join("chart.ksh"),
+            "title=t\nartist=a\ndifficulty=light\nlevel=3\n--\n",
+        )
+        .unwrap();
+        let logged_out = Downloader::builder().base_url(server.base_url()).build();
+        assert!(logged_out.publish(&dir).is_err());
+
+        let downloader = Downloader::builder()
+            .base_url(server.base_url())
+            .session(String::from("XSRF-TOKEN=abc%3D; nautica_session=s"))
+            .build();
+        assert_eq!(downloader.publish(&dir).unwrap().id, "new");
+        publish.assert();
+
+        fs::write(dir.join("chart.ksh"), "title=t\n--\n").unwrap();
+        assert!(downloader.publish(&dir).is_err());
+        publish.assert_hits(1);
+    }
+
+    #[test]
+    fn log_in_and_publish_through_mapped_host() {
+        let server = MockServer::start();
+        let host = format!("nautica.test:{}", server.port());
+        server.mock(|when, then| {
+            when.path("/sanctum/csrf-cookie").header("host", &host);
+            then.status(204).header("set-cookie", "XSRF-TOKEN=abc");
+        });
+        let login = server.mock(|when, then| {
+            when.path("/login").header("host", &host);
+            then.status(204)
+                .header("set-cookie", "nautica_session=s; httponly");
+        });
+        let publish = server.mock(|when, then| {
+            when.path("/app/user/songs").header("host", &host);
+            then.status(201)
+                .json_body(json!({ "data": song_json("new", "2023-09-01 00:00:00") }));
+        });
+
+        let dest = tempdir().unwrap();
+        let dir = dest.path().join("song");
+        fs::create_dir(&dir).unwrap();
+        fs::write(
+            dir.join("chart.ksh"),
+            "title=t\nartist=a\ndifficulty=light\nlevel=3\n--\n",
+        )
+        .unwrap();
+        let http = HttpConfig {
+            resolve: vec![format!("{host}:127.0.0.1").parse().unwrap()],
+            ..Default::default()
+        };
+        let builder = || {
+            Downloader::builder()
+                .dest(dest.path())
+                .base_url(format!("http://{host}"))
+                .http(http.clone())
+        };
+        builder()
+            .build()
+            .login("me@example.com", "hunter2")
+            .unwrap();
+        login.assert();
+
+        let cookie = auth::load_session(dest.path()).unwrap().unwrap();
+        let downloader = builder().session(cookie).build();
+        assert_eq!(downloader.publish(&dir).unwrap().id, "new");
+        publish.assert();
+    }
+
+    #[test]
+    fn stage_downloads_in_temp_dir() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [song_json("a", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        server.mock(|when, then| {
+            when.path("/songs/a/download");
+            then.status(200).body(include_bytes!(
+                "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+            ));
+        });
+
+        let dest = tempdir().unwrap();
+        let temp_dir = tempdir().unwrap();
+        let report = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .temp_dir(temp_dir.path())
+            .build()
+            .download_all()
+            .unwrap();
+
+        assert_eq!(report.downloaded.len(), 1);
+        assert!(dest.path().join("a/Outbreak.ksh").exists());
+        assert_eq!(temp_dir.path().read_dir().unwrap().count(), 0);
+
+        let copied = tempdir().unwrap();
+        fs::write(temp_dir.path().join("chart.ksh"), "chart").unwrap();
+        copy_dir(temp_dir.path(), &copied.path().join("song")).unwrap();
+        assert!(copied.path().join("song/chart.ksh").exists());
+    }
+
+    #[test]
+    fn log_song_context() {
+        #[derive(Clone, Default)]
+        struct Logs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [song_json("a", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        server.mock(|when, then| {
+            when.path_contains("/download");
+            then.status(403);
+        });
+
+        let logs = Logs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let dest = tempdir().unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            Downloader::builder()
+                .dest(dest.path())
+                .base_url(server.base_url())
+                .build()
+                .download_all()
+                .unwrap();
+        });
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let failure = logs
+            .lines()
+            .find(|line| line.contains("Failed to download"))
+            .unwrap();
+        assert!(failure.contains(r#"download_song{id="a" title="title of a" artist="artist"}"#));
+    }
+
+    #[test]
+    fn abort_at_first_failure() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [song_json("b", "2023-09-01 00:00:00"), song_json("a", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        let download = server.mock(|when, then| {
+            when.path_contains("/download");
+            then.status(403);
+        });
+
+        let dest = tempdir().unwrap();
+        let builder = || {
+            Downloader::builder()
+                .dest(dest.path())
+                .base_url(server.base_url())
+        };
+        let report = builder().build().download_all().unwrap();
+        assert_eq!(report.failed.len(), 2);
+        assert!(!report.aborted);
+
+        let report = builder()
+            .on_error(OnError::Abort)
+            .build()
+            .download_all()
+            .unwrap();
+        assert_eq!(report.failed.len(), 1);
+        assert!(report.aborted);
+        download.assert_hits(3);
+    }
+
+    #[test]
+    fn record_removed_songs() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [song_json("gone", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        let download = server.mock(|when, then| {
+            when.path("/songs/gone/download");
+            then.status(404);
+        });
+
+        let dest = tempdir().unwrap();
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .build();
+        let report = downloader.download_all().unwrap();
+        assert_eq!(report.removed[0].id, "gone");
+        assert!(report.failed.is_empty());
+        let store = Store::open_read_only(dest.path());
+        assert!(store.get("gone").unwrap().removed);
+        assert!(store.entries().is_empty());
+
+        assert!(downloader.download_missing().unwrap().removed.is_empty());
+        download.assert_hits(1);
+    }
+
+    #[test]
+    fn stop_on_outage() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [
+                    song_json("d", "2023-09-01 00:00:00"),
+                    song_json("c", "2023-09-01 00:00:00"),
+                    song_json("b", "2023-09-01 00:00:00"),
+                    song_json("a", "2023-09-01 00:00:00"),
+                ],
+                "links": { "next": null },
+            }));
+        });
+        let download = server.mock(|when, then| {
+            when.path_contains("/download");
+            then.status(403);
+        });
+
+        let dest = tempdir().unwrap();
+        let error = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .max_consecutive_failures(Some(2))
+            .build()
+            .download_all()
+            .unwrap_err();
+        download.assert_hits(2);
+        assert_eq!(error.downcast_ref::<Outage>().unwrap().failures, 2);
+    }
+
+    #[test]
+    fn give_up_on_slow_song() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [
+                    song_json("slow", "2023-09-02 00:00:00"),
+                    song_json("a", "2023-09-01 00:00:00"),
+                ],
+                "links": { "next": null },
+            }));
+        });
+        let slow = server.mock(|when, then| {
+            when.path("/songs/slow/download");
+            then.status(200)
+                .delay(Duration::from_secs(3))
+                .body(include_bytes!(
+                    "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+                ));
+        });
+        server.mock(|when, then| {
+            when.path("/songs/a/download");
+            then.status(200).body(include_bytes!(
+                "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+            ));
+        });
+
+        let dest = tempdir().unwrap();
+        let start = Instant::now();
+        let report = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .max_duration_per_song(Duration::from_millis(300))
+            .build()
+            .download_all()
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(3));
+        // Not retried within the run.
+        slow.assert_hits(1);
+        assert_eq!(report.failed[0].id, "slow");
+        assert_eq!(report.downloaded[0].id, "a");
+        assert!(Store::open_read_only(dest.path()).get("slow").is_none());
+    }
+
+    #[test]
+    fn non_chronological_sort_walks_whole_catalog() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.path("/app/songs")
+                .query_param("sort", "downloads")
+                .query_param("per_page", "50");
+            then.status(200).json_body(json!({
+                "data": [song_json("old", "2023-09-01 00:00:00"), song_json("new", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+
+        let dest = tempdir().unwrap();
+        let mut db =
+            PickleDb::new_json(dest.path().join("meta.json"), PickleDbDumpPolicy::AutoDump);
+        db.set("old", &Utc::now()).unwrap();
+
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .sort(Sort::Popular)
+            .per_page(50)
             .build();
+        let pending = downloader.pending().unwrap();
 
-        downloader
-            .download("9e523640-4fb1-11ee-a90f-e9c914456566")
+        m.assert();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, "new");
+    }
+
+    #[test]
+    fn download_top_songs() {
+        let server = MockServer::start();
+        let first_page = server.mock(|when, then| {
+            when.path("/app/songs").query_param("sort", "downloads");
+            then.status(200).json_body(json!({
+                "data": [
+                    song_json("first", "2023-09-01 00:00:00"),
+                    song_json("second", "2023-09-01 00:00:00"),
+                    song_json("third", "2023-09-01 00:00:00"),
+                ],
+                "links": { "next": server.url("/app/songs/more") },
+            }));
+        });
+        let next_page = server.mock(|when, then| {
+            when.path("/app/songs/more");
+            then.status(200).json_body(json!({
+                "data": [],
+                "links": { "next": null },
+            }));
+        });
+
+        let dest = tempdir().unwrap();
+        let mut db =
+            PickleDb::new_json(dest.path().join("meta.json"), PickleDbDumpPolicy::AutoDump);
+        db.set("first", &Utc::now()).unwrap();
+
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .sort(Sort::Popular)
+            .top(3)
+            .build();
+        let pending = downloader.pending().unwrap();
+
+        first_page.assert();
+        next_page.assert_hits(0);
+        let ids: Vec<_> = pending.iter().map(|song| song.id.as_str()).collect();
+        assert_eq!(ids, ["second", "third"]);
+    }
+
+    #[test]
+    fn resume_queued_songs() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [
+                    song_json("c", "2023-09-03 00:00:00"),
+                    song_json("b", "2023-09-02 00:00:00"),
+                    song_json("a", "2023-09-01 00:00:00"),
+                ],
+                "links": { "next": null },
+            }));
+        });
+        server.mock(|when, then| {
+            when.path_contains("/download");
+            then.status(200).body(include_bytes!(
+                "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+            ));
+        });
+
+        let dest = tempdir().unwrap();
+        let report = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .max_bytes(1)
+            .build()
+            .download_all()
             .unwrap();
+        assert_eq!(report.downloaded[0].id, "a");
+        let queue = Queue::read(dest.path()).unwrap();
+        assert_eq!(queue.get("a").unwrap().status, queue::Status::Done);
+        assert_eq!(queue.get("b").unwrap().status, queue::Status::Pending);
 
-        m.assert();
+        // Queued by ID, e.g. by another tool, and looked up in the listing.
+        let mut queue = Queue::open(dest.path()).unwrap();
+        queue.push_id("x", 0).unwrap();
+        queue.push_id("c", 1).unwrap();
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .build();
+        let report = downloader.download_songs(Vec::new()).unwrap();
+        let ids: Vec<_> = report.downloaded.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, ["c", "b"]);
+        let queue = Queue::read(dest.path()).unwrap();
+        assert_eq!(queue.get("x").unwrap().status, queue::Status::Failed);
+        assert!(queue.pending().is_empty());
+    }
 
-        let song_dest = dest.path().join("9e523640-4fb1-11ee-a90f-e9c914456566");
-        assert_eq!(song_dest.read_dir().unwrap().collect::<Vec<_>>().len(), 3);
-        assert!(song_dest.join("audio.ogg").exists());
-        assert!(song_dest.join("jacket.png").exists());
+    #[test]
+    fn budget_stops_run_and_keeps_newest_for_next_run() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [song_json("newer", "2023-09-01 00:00:00"), song_json("older", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        let download = server.mock(|when, then| {
+            when.path_contains("/download");
+            then.header("content-type", "application/x-zip")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+                ));
+        });
 
-        // chardetng guessed Big5 but not sure.
-        assert!(song_dest.join("哈姘屋怨姥恍鏺泆絯.ksh").exists());
+        let dest = tempdir().unwrap();
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .max_bytes(1)
+            .build();
+        let report = downloader.download_all().unwrap();
+
+        download.assert_hits(1);
+        assert!(report.budget_exhausted);
+        assert!(report.bytes > 0);
+        assert_eq!(report.downloaded.len(), 1);
+        assert_eq!(report.downloaded[0].id, "older");
+
+        let pending = downloader.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, "newer");
+    }
+
+    #[test]
+    fn skip_duplicate_uploads() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [song_json("reupload", "2023-09-02 00:00:00"), song_json("original", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        server.mock(|when, then| {
+            when.path_contains("/download");
+            then.header("content-type", "application/x-zip")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+                ));
+        });
+
+        let dest = tempdir().unwrap();
+        let report = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .skip_duplicates(true)
+            .build()
+            .download_all()
+            .unwrap();
+
+        assert_eq!(report.downloaded.len(), 1);
+        assert_eq!(report.downloaded[0].id, "original");
+        assert_eq!(report.duplicates.len(), 1);
+        assert!(!dest.path().join("reupload").exists());
+        let store = Store::open_read_only(dest.path());
+        assert!(store.contains("reupload"));
+        assert_eq!(
+            store.get("reupload").unwrap().duplicate_of.as_deref(),
+            Some("original")
+        );
+        assert_eq!(store.entries().len(), 1);
+    }
+
+    #[test]
+    fn refresh_updated_songs_only_if_changed() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [song_json("a", "2023-09-10 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        let unchanged = server.mock(|when, then| {
+            when.path_contains("/download")
+                .header("if-none-match", "\"v1\"");
+            then.status(304);
+        });
+        let download = server.mock(|when, then| {
+            when.path_contains("/download");
+            then.header("content-type", "application/x-zip")
+                .header("etag", "\"v1\"")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+                ));
+        });
+
+        let dest = tempdir().unwrap();
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .refresh_updated(true)
+            .build();
+        let report = downloader.download_missing().unwrap();
+        assert_eq!(report.downloaded.len(), 1);
+        download.assert_hits(1);
+
+        // Songs downloaded after their last update are not asked for.
+        let report = downloader.download_missing().unwrap();
+        assert_eq!(report.total, 0);
+
+        let mut store = Store::open(dest.path());
+        let mut entry = store.get("a").unwrap();
+        assert_eq!(
+            entry.validators.as_ref().unwrap().etag.as_deref(),
+            Some("\"v1\"")
+        );
+        entry.downloaded_at = Utc.with_ymd_and_hms(2023, 9, 5, 0, 0, 0).unwrap();
+        store.insert("a", &entry).unwrap();
+        drop(store);
+
+        let report = downloader.download_missing().unwrap();
+        unchanged.assert_hits(1);
+        download.assert_hits(1);
+        assert_eq!(report.unchanged.len(), 1);
+        assert!(report.downloaded.is_empty());
+        let entry = Store::open_read_only(dest.path()).get("a").unwrap();
+        assert!(entry.downloaded_at > Utc.with_ymd_and_hms(2023, 9, 10, 0, 0, 0).unwrap());
+        assert!(dest.path().join(entry.dir("a")).exists());
+    }
+
+    #[test]
+    fn keep_earlier_download_when_refresh_fails() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [song_json("a", "2023-09-10 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        let mut download = server.mock(|when, then| {
+            when.path_contains("/download");
+            then.header("content-type", "application/x-zip")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+                ));
+        });
+
+        let dest = tempdir().unwrap();
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .refresh_updated(true)
+            .build();
+        downloader.download_missing().unwrap();
+        let outdate = || {
+            let mut store = Store::open(dest.path());
+            let mut entry = store.get("a").unwrap();
+            entry.downloaded_at = Utc.with_ymd_and_hms(2023, 9, 5, 0, 0, 0).unwrap();
+            entry.tags = vec![String::from("keep")];
+            entry.starred = true;
+            store.insert("a", &entry).unwrap();
+        };
+        let assert_kept = || {
+            let entry = Store::open_read_only(dest.path()).get("a").unwrap();
+            assert!(entry.is_kept());
+            assert_eq!(entry.tags, ["keep"]);
+            assert!(entry.starred);
+            assert!(fs::read_dir(dest.path().join(entry.dir("a")))
+                .unwrap()
+                .next()
+                .is_some());
+            assert!(quarantine::list(dest.path()).unwrap().is_empty());
+            assert!(!dest.path().join(format!("{REFRESHING_PREFIX}a")).exists());
+        };
+
+        outdate();
+        download.delete();
+        download = server.mock(|when, then| {
+            when.path_contains("/download");
+            then.status(200).body("not a zip");
+        });
+        let report = downloader.download_missing().unwrap();
+        assert_eq!(report.failed.len(), 1);
+        assert_kept();
+
+        outdate();
+        download.delete();
+        download = server.mock(|when, then| {
+            when.path_contains("/download");
+            then.status(404);
+        });
+        let report = downloader.download_missing().unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert_kept();
+        // The song is not asked for again.
+        assert_eq!(downloader.download_missing().unwrap().total, 0);
+
+        // A good update replaces the earlier download.
+        outdate();
+        download.delete();
+        server.mock(|when, then| {
+            when.path_contains("/download");
+            then.header("content-type", "application/x-zip")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+                ));
+        });
+        let report = downloader.download_missing().unwrap();
+        assert_eq!(report.downloaded.len(), 1);
+        assert_kept();
+        assert!(fs::read_dir(dest.path()).unwrap().all(|entry| !entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".old")));
+    }
+
+    #[test]
+    fn group_by_uploader_names() {
+        let server = MockServer::start();
+        let mut named = song_json("named", "2023-09-03 00:00:00");
+        named["user_id"] = json!("u2");
+        named["user"] = json!({ "id": "u2", "name": "RG+Ice" });
+        let mut other = song_json("other", "2023-09-02 00:00:00");
+        other["title"] = json!("title of a");
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [named, other, song_json("a", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        server.mock(|when, then| {
+            when.path_contains("/download");
+            then.header("content-type", "application/x-zip")
+                .status(200)
+                .body(include_bytes!(
+                    "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+                ));
+        });
+        let user = server.mock(|when, then| {
+            when.path("/app/users/user");
+            then.status(200)
+                .json_body(json!({ "data": { "id": "user", "name": "Ixiot" } }));
+        });
+
+        let dest = tempdir().unwrap();
+        let report = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .layout(Layout::Uploader)
+            .build()
+            .download_all()
+            .unwrap();
+
+        assert_eq!(report.downloaded.len(), 3);
+        // The name is looked up once, and songs with the same name get the
+        // ID appended.
+        user.assert_hits(1);
+        assert!(dest.path().join("RG+Ice/artist - title of named").is_dir());
+        assert!(dest.path().join("Ixiot/artist - title of a").is_dir());
+        assert!(dest
+            .path()
+            .join("Ixiot/artist - title of a [other]")
+            .is_dir());
+        assert_eq!(
+            Store::open_read_only(dest.path())
+                .get("a")
+                .unwrap()
+                .uploader(),
+            "Ixiot"
+        );
+        assert_eq!(UploaderNames::open(dest.path()).get("u2"), Some("RG+Ice"));
+    }
+
+    #[test]
+    fn start_listing_at_page_or_song() {
+        let server = MockServer::start();
+        let second = server.mock(|when, then| {
+            when.path("/app/songs").query_param("page", "2");
+            then.status(200).json_body(json!({
+                "data": [song_json("c", "2023-09-02 00:00:00"), song_json("d", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        let first = server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [song_json("a", "2023-09-04 00:00:00"), song_json("b", "2023-09-03 00:00:00")],
+                "links": { "next": "/app/songs?sort=uploaded&page=2" },
+            }));
+        });
+        let dest = tempdir().unwrap();
+        let ids = |builder: DownloaderBuilder| -> Vec<String> {
+            let downloader = builder
+                .dest(dest.path())
+                .base_url(server.base_url())
+                .build();
+            let songs = downloader.pending_full().unwrap();
+            songs.into_iter().map(|song| song.id).collect()
+        };
+
+        assert_eq!(ids(Downloader::builder().start_page(2)), ["c", "d"]);
+        first.assert_hits(0);
+        assert_eq!(ids(Downloader::builder().start_after("a")), ["b", "c", "d"]);
+        assert_eq!(ids(Downloader::builder().start_after("b")), ["c", "d"]);
+        assert!(ids(Downloader::builder().start_after("gone")).is_empty());
+        second.assert_hits(4);
+
+        // A diff walks the whole catalog, but plans from the same start.
+        let new = |builder: DownloaderBuilder| -> Vec<String> {
+            let downloader = builder
+                .dest(dest.path())
+                .base_url(server.base_url())
+                .build();
+            let diff = downloader.diff_missing().unwrap();
+            diff.new.into_iter().map(|song| song.id).collect()
+        };
+        assert_eq!(new(Downloader::builder().start_page(2)), ["c", "d"]);
+        assert_eq!(new(Downloader::builder().start_after("a")), ["b", "c", "d"]);
+        assert_eq!(
+            new(Downloader::builder().start_page(2).start_after("c")),
+            ["d"]
+        );
+    }
+
+    #[test]
+    fn sync_stops_below_disk_reserve() {
+        let dest = tempdir().unwrap();
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .reserve(u64::MAX)
+            .build();
+
+        let song = serde_json::from_value(song_json("new", "2023-09-01 00:00:00")).unwrap();
+        let err = downloader.download_songs(vec![song]).unwrap_err();
+        assert!(err.to_string().contains("below the reserve"));
+    }
+
+    #[test]
+    fn preview_only_fetches_jacket_and_preview() {
+        let server = MockServer::start();
+        let jacket = server.mock(|when, then| {
+            when.path("/songs/a/jacket.jpg");
+            then.status(200).body("jpg");
+        });
+        server.mock(|when, then| {
+            when.path("/songs/b/preview.mp3");
+            then.status(404);
+        });
+        let archive = server.mock(|when, then| {
+            when.path_contains("/download");
+            then.status(200);
+        });
+
+        let mut with_jacket = song_json("a", "2023-09-01 00:00:00");
+        with_jacket["jacket_url"] = json!(server.url("/songs/a/jacket.jpg"));
+        let mut without_files = song_json("b", "2023-09-01 00:00:00");
+        without_files["preview_url"] = json!(server.url("/songs/b/preview.mp3"));
+        let songs = [with_jacket, without_files]
+            .into_iter()
+            .map(|song| serde_json::from_value(song).unwrap())
+            .collect();
+
+        let dest = tempdir().unwrap();
+        let report = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .preview_only(true)
+            .build()
+            .download_songs(songs)
+            .unwrap();
+
+        jacket.assert();
+        archive.assert_hits(0);
+        assert_eq!(report.bytes, 3);
+        assert_eq!(report.downloaded.len(), 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(
+            fs::read_to_string(dest.path().join("a/jacket.jpg")).unwrap(),
+            "jpg"
+        );
+    }
+
+    #[test]
+    fn cache_jackets_of_catalog() {
+        let server = MockServer::start();
+        let mut with_jacket = song_json("a", "2023-09-01 00:00:00");
+        with_jacket["jacket_url"] = json!(server.url("/songs/a/jacket.jpg"));
+        let mut not_found = song_json("b", "2023-09-01 00:00:00");
+        not_found["jacket_url"] = json!(server.url("/songs/b/jacket.png"));
+        let mut invalid = song_json("d", "2023-09-01 00:00:00");
+        invalid["jacket_url"] = json!(server.url("/songs/d/jacket.png"));
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [with_jacket, not_found, song_json("c", "2023-09-01 00:00:00"), invalid],
+                "links": { "next": null },
+            }));
+        });
+        let jacket = server.mock(|when, then| {
+            when.path("/songs/a/jacket.jpg");
+            then.status(200)
+                .body(b"\xff\xd8\xff\xc0\0\x0b\x08\0\x10\0\x10\x01\0\0\0\xff\xd9".as_slice());
+        });
+        server.mock(|when, then| {
+            when.path("/songs/b/jacket.png");
+            then.status(404);
+        });
+        server.mock(|when, then| {
+            when.path("/songs/d/jacket.png");
+            then.status(200).body("<html>Maintenance</html>");
+        });
+
+        let dest = tempdir().unwrap();
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .build();
+        let report = downloader.cache_jackets().unwrap();
+        assert_eq!((report.fetched, report.cached, report.missing), (1, 0, 2));
+        assert_eq!(report.invalid, ["d"]);
+        assert_eq!(JacketCache::new(dest.path()).get("d"), None);
+        assert_eq!(
+            JacketCache::new(dest.path()).get("a"),
+            Some(dest.path().join(".jackets/a.jpg"))
+        );
+
+        let report = downloader.cache_jackets().unwrap();
+        assert_eq!((report.fetched, report.cached), (0, 1));
+        jacket.assert_hits(1);
+    }
+
+    #[test]
+    fn estimate_size_from_head_requests() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.method(httpmock::Method::HEAD)
+                .path_contains("/download");
+            then.status(200).header("content-length", "1500");
+        });
+
+        let downloader = Downloader::builder().base_url(server.base_url()).build();
+        let songs: Vec<Song> = ["a", "b"]
+            .iter()
+            .map(|id| serde_json::from_value(song_json(id, "2023-09-01 00:00:00")).unwrap())
+            .collect();
+
+        assert_eq!(
+            downloader.estimate_size(&songs.iter().collect::<Vec<_>>()),
+            3000
+        );
+        m.assert_hits(2);
+    }
+
+    #[test]
+    fn estimate_run_with_queued_songs() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.method(httpmock::Method::HEAD)
+                .path_contains("/download");
+            then.status(200).header("content-length", "1500");
+        });
+
+        let dest = tempdir().unwrap();
+        let song =
+            |id| -> Song { serde_json::from_value(song_json(id, "2023-09-01 00:00:00")).unwrap() };
+        let mut queue = Queue::open(dest.path()).unwrap();
+        queue.push(song("queued")).unwrap();
+        queue.push(song("a")).unwrap();
+        drop(queue);
+        let mut db =
+            PickleDb::new_json(dest.path().join("meta.json"), PickleDbDumpPolicy::AutoDump);
+        db.set("existing", &Utc::now()).unwrap();
+
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .build();
+        let songs = vec![song("a"), song("b"), song("existing")];
+
+        // a, b, and the queued song, each once.
+        assert_eq!(downloader.estimate_run(&songs).unwrap(), 4500);
+        m.assert_hits(3);
+    }
+
+    #[test]
+    fn skip_oversized_songs() {
+        let archive = include_bytes!("../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip");
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [
+                    song_json("big", "2023-09-03 00:00:00"),
+                    song_json("unsized", "2023-09-02 00:00:00"),
+                    song_json("a", "2023-09-01 00:00:00"),
+                ],
+                "links": { "next": null },
+            }));
+        });
+        server.mock(|when, then| {
+            when.method(httpmock::Method::HEAD)
+                .path("/songs/big/download");
+            then.status(200).header("content-length", "500000000");
+        });
+        server.mock(|when, then| {
+            when.method(httpmock::Method::HEAD)
+                .path("/songs/a/download");
+            then.status(200)
+                .header("content-length", archive.len().to_string());
+        });
+        let big = server.mock(|when, then| {
+            when.method(httpmock::Method::GET)
+                .path("/songs/big/download");
+            then.status(200).body(archive);
+        });
+        // Without a size from HEAD, the archive is checked once downloaded.
+        server.mock(|when, then| {
+            when.method(httpmock::Method::GET)
+                .path("/songs/unsized/download");
+            then.status(200).body(vec![0; archive.len() + 1]);
+        });
+        server.mock(|when, then| {
+            when.method(httpmock::Method::GET).path("/songs/a/download");
+            then.status(200).body(archive);
+        });
+
+        let dest = tempdir().unwrap();
+        let report = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .max_song_size(archive.len() as u64)
+            .build()
+            .download_all()
+            .unwrap();
+        big.assert_hits(0);
+        let oversized: Vec<_> = report.oversized.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(oversized, ["unsized", "big"]);
+        assert!(report.failed.is_empty());
+        assert_eq!(report.downloaded[0].id, "a");
+        assert!(!dest.path().join("unsized").exists());
+        assert!(Store::open_read_only(dest.path()).get("big").is_none());
+    }
+
+    #[test]
+    fn quarantine_invalid_archive() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [song_json("broken", "2023-09-01 00:00:00")],
+                "links": { "next": null },
+            }));
+        });
+        server.mock(|when, then| {
+            when.path("/songs/broken/download");
+            then.status(200).body("not a zip");
+        });
+
+        let dest = tempdir().unwrap();
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .build();
+        let report = downloader.download_all().unwrap();
+        assert_eq!(report.quarantined[0].id, "broken");
+        assert!(report.failed.is_empty());
+        assert!(!dest.path().join("broken").exists());
+        let quarantined = quarantine::dir(dest.path(), "broken");
+        assert_eq!(
+            fs::read(quarantined.join(quarantine::ARCHIVE_FILENAME)).unwrap(),
+            b"not a zip"
+        );
+        let reasons = quarantine::list(dest.path()).unwrap();
+        assert!(reasons[0].1.starts_with("Invalid archive"));
+        let store = Store::open_read_only(dest.path());
+        assert!(store.get("broken").unwrap().quarantined.is_some());
+        assert!(store.entries().is_empty());
+
+        // Not downloaded again until released.
+        assert!(downloader.download_all().unwrap().quarantined.is_empty());
+    }
+
+    #[test]
+    fn eta_from_rate() {
+        let elapsed = Duration::from_secs(10);
+        assert_eq!(eta(elapsed, 0, 100), Duration::ZERO);
+        assert_eq!(eta(elapsed, 25, 100), Duration::from_secs(30));
+        assert_eq!(eta(elapsed, 100, 50), Duration::ZERO);
     }
 }
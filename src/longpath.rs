@@ -0,0 +1,104 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::ensure;
+
+/// Longest path most Windows programs can open, including K-Shoot Mania and
+/// USC reading the library.
+pub const MAX_PATH: usize = 260;
+
+/// Room left in [`MAX_PATH`] for the names of the files in a song directory.
+const FILE_NAME_RESERVE: usize = 60;
+
+/// Returns `path` in the extended-length form (`\\?\C:\...`) on Windows, so
+/// that file operations work beyond [`MAX_PATH`]. Elsewhere `path` is
+/// returned unchanged.
+#[cfg(windows)]
+pub fn extended(path: &Path) -> PathBuf {
+    use std::ffi::OsString;
+    use std::path::Component;
+    use std::path::Prefix;
+
+    // Extended-length paths are not normalized by Windows, so `/` and `..`
+    // have to be resolved first.
+    let Ok(absolute) = std::path::absolute(path) else {
+        return path.to_owned();
+    };
+    let mut components = absolute.components();
+    let Some(Component::Prefix(prefix)) = components.next() else {
+        return absolute;
+    };
+    let mut extended = match prefix.kind() {
+        Prefix::Disk(_) => {
+            let mut extended = OsString::from(r"\\?\");
+            extended.push(prefix.as_os_str());
+            extended
+        }
+        Prefix::UNC(server, share) => {
+            let mut extended = OsString::from(r"\\?\UNC\");
+            extended.push(server);
+            extended.push(r"\");
+            extended.push(share);
+            extended
+        }
+        // Already extended-length, or a device.
+        _ => return absolute,
+    };
+    extended.push(r"\");
+    let mut extended = PathBuf::from(extended);
+    extended.extend(components.filter(|c| !matches!(c, Component::RootDir)));
+    extended
+}
+
+#[cfg(not(windows))]
+pub fn extended(path: &Path) -> PathBuf {
+    path.to_owned()
+}
+
+/// Checks that the song directory `dir` leaves room for its files within
+/// [`MAX_PATH`] on Windows, where longer paths cannot be opened by the game.
+pub fn check_len(dir: &Path) -> anyhow::Result<()> {
+    if cfg!(windows) {
+        check_len_within(dir, MAX_PATH)?;
+    }
+    Ok(())
+}
+
+fn check_len_within(dir: &Path, max: usize) -> anyhow::Result<()> {
+    // Windows counts UTF-16 code units.
+    let len = dir.as_os_str().to_string_lossy().encode_utf16().count();
+    let limit = max - FILE_NAME_RESERVE;
+    ensure!(
+        len <= limit,
+        "Path of {} is {len} characters long, more than the {limit} that leave room \
+         for its files; use a shorter destination or layout",
+        dir.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check_path_length() {
+        let dest = Path::new("C:/nautica");
+        assert!(check_len_within(&dest.join("a".repeat(100)), MAX_PATH).is_ok());
+        // Japanese characters are one UTF-16 code unit each.
+        assert!(check_len_within(&dest.join("曲".repeat(200)), MAX_PATH).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn extended_length_path() {
+        assert_eq!(
+            extended(Path::new(r"C:\nautica/Artist - Title\..\Song")),
+            Path::new(r"\\?\C:\nautica\Song")
+        );
+        assert_eq!(
+            extended(Path::new(r"\\server\share\nautica")),
+            Path::new(r"\\?\UNC\server\share\nautica")
+        );
+    }
+}
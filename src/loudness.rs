@@ -0,0 +1,316 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::ensure;
+use anyhow::Context;
+use tracing::info_span;
+use tracing::warn;
+
+use crate::ksh;
+use crate::notify::Notifier;
+use crate::store::Entry;
+use crate::store::Store;
+use crate::transcode::FFMPEG;
+use crate::Song;
+
+/// Loudness that ReplayGain 2.0 normalizes to, in LUFS.
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+
+/// Audio formats whose gain tags ffmpeg can write without re-encoding.
+const TAGGABLE_EXTENSIONS: &[&str] = &["ogg", "mp3", "flac"];
+
+/// Returns the gain in dB that brings music of `loudness` LUFS to the
+/// [`REFERENCE_LOUDNESS`].
+pub fn gain(loudness: f64) -> f64 {
+    REFERENCE_LOUDNESS - loudness
+}
+
+/// Measures the integrated loudness (EBU R 128) of songs with ffmpeg.
+#[derive(Debug, Clone)]
+pub struct Analyzer {
+    program: String,
+    tag: bool,
+}
+
+impl Default for Analyzer {
+    fn default() -> Self {
+        Self {
+            program: String::from(FFMPEG),
+            tag: false,
+        }
+    }
+}
+
+impl Analyzer {
+    /// Sets the ffmpeg executable to run.
+    pub fn program(mut self, program: impl Into<String>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Also writes `REPLAYGAIN_TRACK_GAIN` tags into the music files that
+    /// support them.
+    pub fn tag(mut self, tag: bool) -> Self {
+        self.tag = tag;
+        self
+    }
+
+    /// Measures the integrated loudness of the music of the song in `dir`, in
+    /// LUFS.
+    pub fn analyze_dir(&self, dir: &Path) -> anyhow::Result<f64> {
+        let music = ksh::music_file(dir)?;
+        let loudness = self.integrated_loudness(&music)?;
+        if self.tag && is_taggable(&music) {
+            self.write_gain_tag(&music, gain(loudness))?;
+        }
+        Ok(loudness)
+    }
+
+    /// Analyzes the songs `ids` in the library `dest` and records their
+    /// loudness in the metadata store. Returns the number of songs analyzed;
+    /// songs that fail are logged and skipped.
+    pub fn analyze_library<I>(&self, dest: &Path, ids: I) -> anyhow::Result<usize>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut store = Store::open(dest);
+        let mut analyzed = 0;
+        for id in ids {
+            let Some(entry) = store.get(&id) else {
+                continue;
+            };
+            let _span = info_span!(
+                "analyze_loudness",
+                id,
+                title = entry.title,
+                artist = entry.artist
+            )
+            .entered();
+            match self.analyze_dir(&dest.join(entry.dir(&id))) {
+                Ok(loudness) => {
+                    store.insert(
+                        &id,
+                        &Entry {
+                            loudness: Some(loudness),
+                            ..entry
+                        },
+                    )?;
+                    analyzed += 1;
+                }
+                Err(e) => warn!(error = %e, "Failed to analyze loudness"),
+            }
+        }
+        Ok(analyzed)
+    }
+
+    fn integrated_loudness(&self, music: &Path) -> anyhow::Result<f64> {
+        let output = Command::new(&self.program)
+            .args(["-nostdin", "-hide_banner", "-nostats", "-i"])
+            .arg(music)
+            .args(["-map", "0:a:0", "-af", "ebur128", "-f", "null", "-"])
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("Failed to run {}", self.program))?;
+        ensure!(
+            output.status.success(),
+            "{} exited with {} for {}",
+            self.program,
+            output.status,
+            music.display()
+        );
+        parse_integrated_loudness(&String::from_utf8_lossy(&output.stderr))
+            .with_context(|| format!("No loudness measured for {}", music.display()))
+    }
+
+    fn write_gain_tag(&self, music: &Path, gain: f64) -> anyhow::Result<()> {
+        let ext = music.extension().unwrap_or_default().to_string_lossy();
+        let tagged = music.with_extension(format!("tagged.{ext}"));
+        let status = Command::new(&self.program)
+            .args(["-nostdin", "-loglevel", "error", "-y", "-i"])
+            .arg(music)
+            .args(["-map", "0", "-c", "copy", "-metadata"])
+            .arg(format!("REPLAYGAIN_TRACK_GAIN={gain:+.2} dB"))
+            .arg(&tagged)
+            .stdin(Stdio::null())
+            .status()
+            .with_context(|| format!("Failed to run {}", self.program))?;
+        if !status.success() {
+            let _ = fs::remove_file(&tagged);
+        }
+        ensure!(
+            status.success(),
+            "{} exited with {status} while tagging {}",
+            self.program,
+            music.display()
+        );
+        fs::rename(&tagged, music)?;
+        Ok(())
+    }
+}
+
+fn is_taggable(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| TAGGABLE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Extracts the integrated loudness from the summary the `ebur128` filter
+/// prints at the end of its log.
+fn parse_integrated_loudness(log: &str) -> Option<f64> {
+    let (_, summary) = log.rsplit_once("Summary:")?;
+    summary
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("I:"))?
+        .trim()
+        .strip_suffix("LUFS")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Analyzes the loudness of each downloaded song.
+#[derive(Debug)]
+pub struct LoudnessNotifier {
+    dest: PathBuf,
+    analyzer: Analyzer,
+}
+
+impl LoudnessNotifier {
+    pub fn new(dest: PathBuf, analyzer: Analyzer) -> Self {
+        Self { dest, analyzer }
+    }
+}
+
+impl Notifier for LoudnessNotifier {
+    fn song_downloaded(&self, song: &Song, _path: &Path) -> anyhow::Result<()> {
+        self.analyzer
+            .analyze_library(&self.dest, [song.id.clone()])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_ebur128_summary() {
+        let log = "\
+[Parsed_ebur128_0 @ 0x600] t: 1.2  TARGET:-23 LUFS    M: -20.1 S:-120.7     I: -20.1 LUFS
+[Parsed_ebur128_0 @ 0x600] Summary:
+
+  Integrated loudness:
+    I:         -14.2 LUFS
+    Threshold: -24.4 LUFS
+
+  Loudness range:
+    LRA:         5.3 LU
+";
+        assert_eq!(parse_integrated_loudness(log), Some(-14.2));
+        assert_eq!(parse_integrated_loudness("I: -14.2 LUFS"), None);
+        assert!((gain(-14.2) - -3.8).abs() < 1e-9);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn analyze_library_records_loudness() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use chrono::Utc;
+        use tempfile::tempdir;
+
+        let dest = tempdir().unwrap();
+        let ffmpeg = dest.path().join("ffmpeg");
+        fs::write(
+            &ffmpeg,
+            "#!/bin/sh\nprintf 'Summary:\\n  Integrated loudness:\\n    I: -9.5 LUFS\\n' >&2\n",
+        )
+        .unwrap();
+        fs::set_permissions(&ffmpeg, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let song = dest.path().join("a");
+        fs::create_dir(&song).unwrap();
+        fs::write(song.join("chart.ksh"), "m=song.ogg;song_f.ogg\n--\n").unwrap();
+        fs::write(song.join("song.ogg"), "").unwrap();
+        let entry = Entry {
+            downloaded_at: Utc::now(),
+            title: String::from("title"),
+            artist: String::from("artist"),
+            user_id: String::from("user"),
+            ..Default::default()
+        };
+        Store::open(dest.path()).insert("a", &entry).unwrap();
+
+        let analyzer = Analyzer::default().program(ffmpeg.to_string_lossy());
+        let ids = [String::from("a"), String::from("missing")];
+        assert_eq!(analyzer.analyze_library(dest.path(), ids).unwrap(), 1);
+        assert_eq!(
+            Store::open_read_only(dest.path())
+                .get("a")
+                .unwrap()
+                .loudness,
+            Some(-9.5)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn record_loudness_after_sync() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use httpmock::MockServer;
+        use serde_json::json;
+        use tempfile::tempdir;
+
+        use crate::Downloader;
+
+        let id = "5441d590-4d43-11ee-a602-d95b1bfc2e6d";
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [{
+                    "id": id,
+                    "user_id": "user",
+                    "title": "Outbreak",
+                    "artist": "artist",
+                    "uploaded_at": "2023-09-01 00:00:00",
+                    "updated_at": "2023-09-01 00:00:00",
+                }],
+                "links": { "next": null },
+            }));
+        });
+        server.mock(|when, then| {
+            when.path(format!("/songs/{id}/download"));
+            then.status(200).body(include_bytes!(
+                "../tests/fixtures/5441d590-4d43-11ee-a602-d95b1bfc2e6d.zip"
+            ));
+        });
+
+        let bin = tempdir().unwrap();
+        let ffmpeg = bin.path().join("ffmpeg");
+        fs::write(
+            &ffmpeg,
+            "#!/bin/sh\nprintf 'Summary:\\n  Integrated loudness:\\n    I: -14.0 LUFS\\n' >&2\n",
+        )
+        .unwrap();
+        fs::set_permissions(&ffmpeg, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let dest = tempdir().unwrap();
+        let analyzer = Analyzer::default().program(ffmpeg.to_string_lossy());
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(server.base_url())
+            .notifier(LoudnessNotifier::new(dest.path().to_owned(), analyzer))
+            .build();
+        assert_eq!(downloader.download_all().unwrap().downloaded.len(), 1);
+        // The loudness outlives the store of the sync.
+        assert_eq!(
+            Store::open_read_only(dest.path()).get(id).unwrap().loudness,
+            Some(-14.0)
+        );
+    }
+}
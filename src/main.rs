@@ -1,30 +1,2978 @@
+use std::collections::HashSet;
+use std::fs;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::hash::RandomState;
+use std::io;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
+use std::process;
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use anyhow::ensure;
+use anyhow::Context;
 use clap::Parser;
+use clap::Subcommand;
+use nautica_downloader_rs::auth;
+use nautica_downloader_rs::bench;
+use nautica_downloader_rs::bench::Bench;
+use nautica_downloader_rs::cas;
+use nautica_downloader_rs::cas::CasNotifier;
+use nautica_downloader_rs::collection;
+use nautica_downloader_rs::collection::Collection;
+use nautica_downloader_rs::collection::Collections;
+use nautica_downloader_rs::collection::CollectionsNotifier;
+use nautica_downloader_rs::config::Config;
+use nautica_downloader_rs::dedup::Preference;
+use nautica_downloader_rs::email::EmailNotifier;
+use nautica_downloader_rs::feed::FeedNotifier;
+use nautica_downloader_rs::filter::Bounds;
+use nautica_downloader_rs::filter::Filter;
+use nautica_downloader_rs::filter::IdList;
+use nautica_downloader_rs::http::HostMapping;
+use nautica_downloader_rs::http::HttpConfig;
+use nautica_downloader_rs::http::DEFAULT_POOL_SIZE;
+use nautica_downloader_rs::import::Importer;
+use nautica_downloader_rs::ksh;
+use nautica_downloader_rs::ksm::KsmNotifier;
+use nautica_downloader_rs::layout::Layout;
+use nautica_downloader_rs::loudness::Analyzer;
+use nautica_downloader_rs::loudness::LoudnessNotifier;
+use nautica_downloader_rs::metrics::Metrics;
+use nautica_downloader_rs::metrics::MetricsNotifier;
+use nautica_downloader_rs::mirror::Mirror;
+use nautica_downloader_rs::notify::DesktopNotifier;
+use nautica_downloader_rs::notify::DiscordNotifier;
+use nautica_downloader_rs::notify::HookNotifier;
+use nautica_downloader_rs::notify::WebhookNotifier;
+use nautica_downloader_rs::otlp::OtlpLayer;
+use nautica_downloader_rs::pack::Packer;
+use nautica_downloader_rs::playlist::playlist_id;
+use nautica_downloader_rs::publish;
+use nautica_downloader_rs::quarantine;
+use nautica_downloader_rs::queue;
+use nautica_downloader_rs::queue::Queue;
+use nautica_downloader_rs::remote::RemoteConfig;
+use nautica_downloader_rs::remote::UploadNotifier;
+use nautica_downloader_rs::remote::Uploader;
+use nautica_downloader_rs::remove;
+use nautica_downloader_rs::render;
+use nautica_downloader_rs::s3::Bucket;
+use nautica_downloader_rs::schedule::Schedule;
+use nautica_downloader_rs::search::SearchIndex;
+use nautica_downloader_rs::search::SearchIndexNotifier;
+use nautica_downloader_rs::size::ByteSize;
+use nautica_downloader_rs::state;
+use nautica_downloader_rs::stats;
+use nautica_downloader_rs::stats::Stats;
+use nautica_downloader_rs::store;
+use nautica_downloader_rs::store::Store;
+use nautica_downloader_rs::store::TagChange;
+use nautica_downloader_rs::summary::Summary;
+use nautica_downloader_rs::systemd;
+use nautica_downloader_rs::systemd::Watchdog;
+use nautica_downloader_rs::thumbnail::ThumbnailCache;
+use nautica_downloader_rs::thumbnail::ThumbnailNotifier;
+use nautica_downloader_rs::transcode;
+use nautica_downloader_rs::transcode::TranscodeNotifier;
+use nautica_downloader_rs::transcode::Transcoder;
+use nautica_downloader_rs::trash;
+use nautica_downloader_rs::trash::Disposal;
+use nautica_downloader_rs::update;
+use nautica_downloader_rs::update::Updater;
+use nautica_downloader_rs::usc::MapDatabase;
+use nautica_downloader_rs::usc::UscNotifier;
+use nautica_downloader_rs::video::VideoNotifier;
+use nautica_downloader_rs::views;
+use nautica_downloader_rs::views::ViewsNotifier;
+use nautica_downloader_rs::Chart;
+use nautica_downloader_rs::DownloadReport;
 use nautica_downloader_rs::Downloader;
+use nautica_downloader_rs::DownloaderBuilder;
+use nautica_downloader_rs::OnError;
+use nautica_downloader_rs::Outage;
+use nautica_downloader_rs::Song;
+use nautica_downloader_rs::Sort;
+use nautica_downloader_rs::DEFAULT_MAX_CONSECUTIVE_FAILURES;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+mod pick;
+mod style;
+mod tui;
+
+use style::ColorChoice;
 
 /// Downloads songs from Nautica (ksm.dev)
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    after_help = "Exit codes:\n  0    all songs were downloaded\n  1    fatal error\n  2    some songs failed to download\n  3    stopped because the server seems to be down\n  130  cancelled"
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Destination directory
     #[arg(default_value = PathBuf::from("./nautica").into_os_string())]
     dest: PathBuf,
+
+    #[command(flatten)]
+    sync: SyncArgs,
+
+    /// When to color the output
+    #[arg(long, global = true, value_enum, default_value_t)]
+    color: ColorChoice,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download new songs (the default when no command is given)
+    Sync {
+        #[command(flatten)]
+        lib: LibraryArgs,
+
+        #[command(flatten)]
+        sync: SyncArgs,
+    },
+
+    /// Show which songs a sync would download, update, or mark removed
+    Diff {
+        #[command(flatten)]
+        lib: LibraryArgs,
+
+        #[command(flatten)]
+        sync: SyncArgs,
+
+        #[command(flatten)]
+        report: ReportArgs,
+    },
+
+    /// Write the complete remote song index to a JSON file without
+    /// downloading anything, e.g. to plan offline or to compare snapshots
+    /// taken on different days
+    Catalog {
+        /// File to write, or "-" for standard output
+        #[arg(long, value_name = "PATH", default_value = "catalog.json")]
+        out: PathBuf,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+
+        #[command(flatten)]
+        sync: SyncArgs,
+    },
+
+    /// Show statistics about the local library, with a breakdown per
+    /// uploader
+    Stats {
+        /// Output format: "text", "md" for a leaderboard of the uploaders to
+        /// publish, or "json"
+        #[arg(long, value_enum, default_value_t)]
+        format: stats::Format,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+
+        #[command(flatten)]
+        report: ReportArgs,
+    },
+
+    /// List local songs, e.g. to build a set by BPM and length
+    List {
+        /// Only list songs uploaded by this user ID or name (repeatable)
+        #[arg(long = "user", value_name = "ID_OR_NAME")]
+        users: Vec<String>,
+
+        /// Only list songs whose title or artist contain all of these words
+        #[arg(short, long)]
+        query: Option<String>,
+
+        /// Only list songs with a chart at this level or higher
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=20))]
+        min_level: Option<u8>,
+
+        /// Only list songs with a chart at this level or lower
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=20))]
+        max_level: Option<u8>,
+
+        /// Only list songs with a chart by this effector (repeatable)
+        #[arg(long = "effector", value_name = "NAME")]
+        effectors: Vec<String>,
+
+        /// Only list songs whose jacket is by this illustrator (repeatable)
+        #[arg(long = "illustrator", value_name = "NAME")]
+        illustrators: Vec<String>,
+
+        /// Only list songs with this local tag (repeatable)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+
+        /// Only list songs starred locally
+        #[arg(long)]
+        starred: bool,
+
+        /// List songs starred locally before the others
+        #[arg(long)]
+        starred_first: bool,
+
+        /// Only list songs whose music may not play, checking its headers
+        /// again, e.g. after broken re-encodes
+        #[arg(long)]
+        broken_audio: bool,
+
+        /// Only list songs whose title or artist in the chart files differs
+        /// from the listed one, reading the charts again, e.g. to report
+        /// renamed or garbled uploads
+        #[arg(long)]
+        mismatched: bool,
+
+        /// Only list songs whose whole BPM range is within this range (e.g.
+        /// 170..200, 180.., 190)
+        #[arg(long, value_name = "MIN..MAX")]
+        bpm: Option<Bounds<f64>>,
+
+        /// Only list songs whose length is within this range (e.g. 2m..3m30s)
+        #[arg(long, value_name = "MIN..MAX")]
+        duration: Option<Bounds<humantime::Duration>>,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+
+        #[command(flatten)]
+        report: ReportArgs,
+    },
+
+    /// Search the local library offline by title, artist, uploader, effector,
+    /// or file name; kana also match romaji
+    Find {
+        /// Words that must all appear
+        #[arg(required = true)]
+        text: Vec<String>,
+
+        /// Only show songs with a chart by this effector (repeatable)
+        #[arg(long = "effector", value_name = "NAME")]
+        effectors: Vec<String>,
+
+        /// Only show songs whose jacket is by this illustrator (repeatable)
+        #[arg(long = "illustrator", value_name = "NAME")]
+        illustrators: Vec<String>,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Find local songs that were uploaded more than once under different IDs,
+    /// with the same content or by different users under the same title and
+    /// artist
+    Duplicates(LibraryArgs),
+
+    /// Regenerate the symlink views (by level, artist, and uploader)
+    Views(LibraryArgs),
+
+    /// Keep running and periodically download new songs
+    Watch {
+        /// Time to wait between syncs (e.g. 30m, 1h)
+        #[arg(long, default_value = "30m")]
+        interval: humantime::Duration,
+
+        /// Cron expression (e.g. "0 4 * * *") for full scans of the catalog
+        #[arg(long)]
+        schedule: Option<Schedule>,
+
+        /// Serve Prometheus metrics at http://ADDR/metrics (e.g.
+        /// 127.0.0.1:9101)
+        #[arg(long, value_name = "ADDR")]
+        metrics: Option<String>,
+
+        /// Keep an Atom feed of the songs downloaded most recently at this
+        /// path, e.g. for a community site to embed
+        #[arg(long, value_name = "PATH")]
+        feed: Option<PathBuf>,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+
+        #[command(flatten)]
+        sync: SyncArgs,
+    },
+
+    /// Browse the remote catalog and pick songs to download
+    Tui {
+        #[command(flatten)]
+        lib: LibraryArgs,
+
+        #[command(flatten)]
+        sync: SyncArgs,
+    },
+
+    /// Fuzzy-find songs in the remote catalog and download the selection
+    Pick {
+        #[command(flatten)]
+        lib: LibraryArgs,
+
+        #[command(flatten)]
+        sync: SyncArgs,
+    },
+
+    /// Download the jackets of remote songs into <DEST>/.jackets for browsing
+    /// without downloading the songs
+    Jackets {
+        #[command(flatten)]
+        lib: LibraryArgs,
+
+        #[command(flatten)]
+        sync: SyncArgs,
+    },
+
+    /// Check the jackets of local songs and make thumbnails of them in
+    /// <DEST>/.thumbnails
+    Thumbnails {
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Measure the loudness of local songs that have not been analyzed yet
+    Loudness {
+        /// Analyze every song again
+        #[arg(long)]
+        all: bool,
+
+        /// Also write ReplayGain tags into the music files
+        #[arg(long)]
+        replaygain: bool,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Move existing song directories to match a new layout
+    Reorganize {
+        /// Naming of song directories: "id", "readable", or a template
+        /// [default: the configured layout]
+        #[arg(long)]
+        layout: Option<Layout>,
+
+        /// Move the folders left with only file manager clutter, such as
+        /// .DS_Store, into <DEST>/.trash; they are kept otherwise
+        #[arg(long)]
+        use_trash: bool,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Draw the charts of a song as SVG images next to the charts
+    Render {
+        /// Song ID, ID prefix, or part of the title, or the path of a chart
+        /// or song directory
+        target: String,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Open a song's directory in the file manager
+    Open {
+        /// Song ID, ID prefix, or part of the title
+        query: String,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Add or remove local tags of a song, e.g. `tag outbreak +stamina
+    /// -practice`, or show its tags
+    Tag {
+        /// Song ID, ID prefix, or part of the title
+        query: String,
+
+        /// Tags to add (+TAG) or remove (-TAG); options have to come before
+        /// the song
+        #[arg(value_name = "+TAG|-TAG", allow_hyphen_values = true)]
+        changes: Vec<TagChange>,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Pick random local songs matching the filters, e.g. for a practice
+    /// session roulette
+    Random {
+        /// Number of songs to pick
+        #[arg(short = 'n', long, default_value_t = 1)]
+        count: usize,
+
+        /// Only pick songs with a chart in this level range (e.g. 16..18,
+        /// 18.., 16)
+        #[arg(long, value_name = "MIN..MAX")]
+        level: Option<Bounds<u8>>,
+
+        /// Only pick songs uploaded by this user ID or name (repeatable)
+        #[arg(long = "user", value_name = "ID_OR_NAME")]
+        users: Vec<String>,
+
+        /// Only pick songs whose title or artist contain all of these words
+        #[arg(short, long)]
+        query: Option<String>,
+
+        /// Only pick songs with this local tag (repeatable)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+
+        /// Only pick songs starred locally
+        #[arg(long)]
+        starred: bool,
+
+        /// Open the directories of the songs picked in the file manager
+        #[arg(long)]
+        open: bool,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Star a song locally, which keeps it from being removed by favorites
+    /// syncs or uploads
+    Star {
+        /// Song ID, ID prefix, or part of the title
+        query: String,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Remove the local star of a song
+    Unstar {
+        /// Song ID, ID prefix, or part of the title
+        query: String,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Import song archives obtained outside Nautica into the library under
+    /// local IDs
+    Import {
+        /// Zip archive, or directory of zip archives
+        path: PathBuf,
+
+        /// Naming of song directories: "id", "readable", or a template
+        /// [default: the configured layout]
+        #[arg(long)]
+        layout: Option<Layout>,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Delete songs from the library together with their metadata, given by
+    /// ID or by filters
+    Remove {
+        /// Song IDs, ID prefixes, or parts of titles
+        queries: Vec<String>,
+
+        /// Remove the songs with a chart in this level range (e.g. 17..18,
+        /// 18.., 16)
+        #[arg(long, value_name = "MIN..MAX")]
+        level: Option<Bounds<u8>>,
+
+        /// Remove the songs uploaded by this user ID or name (repeatable)
+        #[arg(long = "user", value_name = "ID_OR_NAME")]
+        users: Vec<String>,
+
+        /// Remove the songs whose title or artist contain all of these words
+        #[arg(short, long)]
+        query: Option<String>,
+
+        /// Remove the songs with this local tag (repeatable)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+
+        /// Add the IDs of the removed songs to this blocklist file so that
+        /// syncs given it do not download them again
+        #[arg(long, value_name = "FILE")]
+        blocklist: Option<PathBuf>,
+
+        /// Move the songs into <DEST>/.trash instead of deleting them, in
+        /// case the filters matched more than intended
+        #[arg(long)]
+        use_trash: bool,
+
+        /// Remove without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Bundle the local songs matching the filters into a zip archive with
+    /// UTF-8 file names and a manifest of their sources, for sharing
+    Pack {
+        /// Archive to write
+        #[arg(short, long, value_name = "PATH", default_value = "pack.zip")]
+        output: PathBuf,
+
+        /// Only pack songs with a chart in this level range (e.g. 17..18,
+        /// 18.., 16)
+        #[arg(long, value_name = "MIN..MAX")]
+        level: Option<Bounds<u8>>,
+
+        /// Only pack songs uploaded by this user ID or name (repeatable)
+        #[arg(long = "user", value_name = "ID_OR_NAME")]
+        users: Vec<String>,
+
+        /// Only pack songs whose title or artist contain all of these words
+        #[arg(short, long)]
+        query: Option<String>,
+
+        /// Only pack songs with this local tag (repeatable)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+
+        /// Only pack songs starred locally
+        #[arg(long)]
+        starred: bool,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Upload the local songs not yet in the configured S3 bucket or remote
+    /// destination
+    Upload {
+        /// WebDAV or SFTP destination, e.g. sftp://nas/charts [default: the
+        /// configured one]
+        #[arg(long, value_name = "URL")]
+        remote: Option<String>,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Serve the local library over HTTP with the same API as Nautica, so
+    /// that other clients can sync from it with --base-url
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "0.0.0.0:8080")]
+        listen: String,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Measure how fast songs are listed, downloaded, and extracted, by
+    /// syncing the local library from itself over loopback into a scratch
+    /// directory with each connection setting
+    Bench {
+        /// Benchmark against this server instead of the local library
+        #[arg(long, value_name = "URL")]
+        base_url: Option<String>,
+
+        /// Number of songs to download with each setting
+        #[arg(long, value_name = "N", default_value_t = 100)]
+        songs: usize,
+
+        /// Number of songs fetched per listing request
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..=100), default_value_t = bench::DEFAULT_PER_PAGE)]
+        per_page: u32,
+
+        /// Idle connections kept per server; repeat to compare several, each
+        /// after a run without keep-alive
+        #[arg(long = "pool-size", value_name = "N", default_values_t = [1, DEFAULT_POOL_SIZE])]
+        pool_sizes: Vec<usize>,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Log in to a Nautica account for syncing its liked songs; the password
+    /// is read from NAUTICA_PASSWORD or asked for
+    Login {
+        /// Email address of the account
+        email: String,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Forget the account logged in with the login command
+    Logout(LibraryArgs),
+
+    /// Download the songs of playlists and record each playlist as a
+    /// collection, written to USC when a database is configured
+    Playlist {
+        /// Playlist URLs or IDs [default: every playlist of the logged-in
+        /// account]
+        playlists: Vec<String>,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+
+        #[command(flatten)]
+        sync: SyncArgs,
+    },
+
+    /// Publish a song directory to the logged-in Nautica account, after
+    /// checking its charts and the files they refer to
+    Publish {
+        /// Directory of the song
+        dir: PathBuf,
+
+        /// Only check the song without publishing it
+        #[arg(long)]
+        check: bool,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Replace this program with the latest release from GitHub, checked
+    /// against the published SHA-256 checksum
+    SelfUpdate {
+        /// Only check whether a newer release is available
+        #[arg(long)]
+        check: bool,
+    },
+
+    /// Manage USC collections of local songs, kept up to date after each sync
+    Collection {
+        #[command(subcommand)]
+        command: CollectionCommand,
+    },
+
+    /// Transfer the metadata of the library to keep two machines in step
+    State {
+        #[command(subcommand)]
+        command: StateCommand,
+    },
+
+    /// Manage the content-addressed store of song files
+    Cas {
+        #[command(subcommand)]
+        command: CasCommand,
+    },
+
+    /// Show or add to the download queue, which every sync drains; a run cut
+    /// off halfway resumes with the songs it left
+    Queue {
+        #[command(subcommand)]
+        command: QueueCommand,
+    },
+
+    /// Manage the songs moved to .quarantine because their archive, charts,
+    /// or music failed validation after download
+    Quarantine {
+        #[command(subcommand)]
+        command: QuarantineCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CasCommand {
+    /// Move the files of all local songs into the store, deduplicating them
+    Store(LibraryArgs),
+
+    /// Check the stored files against their hashes
+    Verify {
+        #[command(flatten)]
+        lib: LibraryArgs,
+
+        #[command(flatten)]
+        report: ReportArgs,
+    },
+
+    /// Remove the stored files no song refers to any more
+    Prune {
+        /// Move the files into <DEST>/.trash instead of deleting them
+        #[arg(long)]
+        use_trash: bool,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum QueueCommand {
+    /// Show the queued songs with their status and priority
+    List(LibraryArgs),
+
+    /// Queue songs by ID for the next sync, which looks them up in the
+    /// listing
+    Add {
+        /// IDs of the songs
+        #[arg(required = true)]
+        ids: Vec<String>,
+
+        /// Songs with a higher priority are downloaded first
+        #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+        priority: i32,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Queue the songs that failed to download again
+    Retry(LibraryArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum QuarantineCommand {
+    /// Show the quarantined songs and why they failed validation
+    List(LibraryArgs),
+
+    /// Move a repaired song back into the library after checking it again;
+    /// a song quarantined for a broken archive is downloaded again by the
+    /// next sync instead
+    Release {
+        /// ID of the song
+        id: String,
+
+        /// Move the song back even if it still fails validation
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum StateCommand {
+    /// Write the metadata of the library to a file
+    Export {
+        /// File to write
+        #[arg(short, long, value_name = "PATH", default_value = "state.json")]
+        output: PathBuf,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+
+    /// Record the songs of an exported state: songs whose files were copied
+    /// into the library are adopted, and the rest are left for `sync
+    /// --missing` to download
+    Import {
+        /// File written by `state export`
+        input: PathBuf,
+
+        #[command(flatten)]
+        lib: LibraryArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CollectionCommand {
+    /// Create or update a collection of the local songs matching the filters
+    Create {
+        /// Name of the collection
+        name: String,
+
+        /// Only include songs uploaded by this user ID or name (repeatable)
+        #[arg(long = "user", value_name = "ID_OR_NAME")]
+        users: Vec<String>,
+
+        /// Only include songs whose title or artist contain all of these words
+        #[arg(short, long)]
+        query: Option<String>,
+
+        /// Only include songs with a chart at this level or higher
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=20))]
+        min_level: Option<u8>,
+
+        /// Only include songs with a chart at this level or lower
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=20))]
+        max_level: Option<u8>,
+
+        /// Only include songs with this local tag (repeatable)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+
+        /// Only include songs starred locally
+        #[arg(long)]
+        starred: bool,
+
+        #[command(flatten)]
+        usc: UscArgs,
+    },
+
+    /// Delete a collection from USC and stop updating it
+    Delete {
+        /// Name of the collection
+        name: String,
+
+        #[command(flatten)]
+        usc: UscArgs,
+    },
+
+    /// List the collections
+    List(LibraryArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct UscArgs {
+    /// unnamed_sdvx_clone song database (maps.db) [default: usc_db from the
+    /// config]
+    #[arg(long, value_name = "PATH")]
+    usc_db: Option<PathBuf>,
+
+    #[command(flatten)]
+    lib: LibraryArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct LibraryArgs {
+    /// Destination directory
+    #[arg(short, long, default_value = PathBuf::from("./nautica").into_os_string())]
+    dest: PathBuf,
+
+    /// Configuration file [default: <DEST>/nautica.toml]
+    #[arg(short, long)]
+    config: Option<PathBuf>,
 }
 
-fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+#[derive(clap::Args, Debug)]
+struct ReportArgs {
+    /// Never write to the destination, e.g. a NAS export mounted read-only
+    #[arg(long)]
+    read_only: bool,
+
+    /// Write the output to this file instead of standard output; with
+    /// --read-only, it has to be outside the destination
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+}
+
+impl ReportArgs {
+    /// Opens where the output of a command on the library `dest` goes.
+    fn output(&self, dest: &Path) -> anyhow::Result<Box<dyn Write>> {
+        let Some(path) = &self.report else {
+            return Ok(Box::new(io::stdout()));
+        };
+        if self.read_only {
+            let dir = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            let dir = dir
+                .canonicalize()
+                .with_context(|| format!("Failed to open {}", dir.display()))?;
+            ensure!(
+                !dest.canonicalize().is_ok_and(|dest| dir.starts_with(dest)),
+                "The report has to be written outside the read-only destination"
+            );
+        }
+        // Styles would end up in the file as escape codes.
+        ColorChoice::Never.init();
+        let file = fs::File::create(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(Box::new(file))
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct SyncArgs {
+    /// Shell command to run after each downloaded song; the song is passed in
+    /// the NAUTICA_SONG_{ID,TITLE,ARTIST,PATH} environment variables (on
+    /// Windows the command runs without cmd and never expands them)
+    #[arg(long)]
+    hook: Option<String>,
+
+    /// Only sync songs uploaded by this user ID or name (repeatable)
+    #[arg(long = "user", value_name = "ID_OR_NAME")]
+    users: Vec<String>,
+
+    /// Only sync songs whose title, artist, or tags contain all of these words
+    #[arg(short, long)]
+    query: Option<String>,
+
+    /// Only sync songs with a chart at this level or higher
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=20))]
+    min_level: Option<u8>,
+
+    /// Only sync songs with a chart at this level or lower
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=20))]
+    max_level: Option<u8>,
+
+    /// Only sync songs with a chart by this effector (repeatable)
+    #[arg(long = "effector", value_name = "NAME")]
+    effectors: Vec<String>,
+
+    /// File of song or user IDs (one per line) to never download
+    #[arg(long, value_name = "FILE")]
+    blocklist: Option<PathBuf>,
+
+    /// File of song or user IDs (one per line) to restrict the sync to
+    #[arg(long, value_name = "FILE")]
+    allowlist: Option<PathBuf>,
+
+    /// Stop downloading once this much has been downloaded in a run (e.g.
+    /// 500MB, 5GB); the rest is picked up by the next run
+    #[arg(long, value_name = "SIZE")]
+    max_bytes: Option<ByteSize>,
+
+    /// Skip songs whose archive is larger than this (e.g. 300MB), such as
+    /// packs with videos; they are listed in the report and not recorded
+    #[arg(long, value_name = "SIZE")]
+    max_song_size: Option<ByteSize>,
+
+    /// Free space to keep on the destination filesystem; the sync stops
+    /// before a song once less is left
+    #[arg(long, value_name = "SIZE", default_value = "500MB")]
+    reserve: ByteSize,
+
+    /// Number of songs fetched per listing request [default: server's choice]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    per_page: Option<u32>,
+
+    /// Look up the size of each song first to show the total size and time
+    /// remaining
+    #[arg(long)]
+    estimate: bool,
+
+    /// Naming of new song directories: "id", "readable", "uploader" (one
+    /// directory per uploader), "ksm-uploader" or "ksm-sync" (to sync into a
+    /// K-Shoot Mania songs directory grouped by uploader or sync day), or a
+    /// template such as
+    /// "{artist}/{title} [{level_max}]" [default: id]
+    #[arg(long)]
+    layout: Option<Layout>,
+
+    /// Regenerate the symlink views in <DEST>/views after the sync
+    #[arg(long)]
+    views: bool,
+
+    /// Transcode WAV audio to OGG with ffmpeg after download and point the
+    /// charts at the new files
+    #[arg(long)]
+    transcode: bool,
+
+    /// Vorbis quality for --transcode, from 0 to 10 [default: 6]; implies
+    /// --transcode
+    #[arg(long, value_name = "QUALITY", value_parser = clap::value_parser!(u8).range(0..=10))]
+    ogg_quality: Option<u8>,
+
+    /// Keep the WAV files after transcoding
+    #[arg(long)]
+    keep_wav: bool,
+
+    /// Measure the loudness of downloaded songs with ffmpeg and record it in
+    /// the metadata
+    #[arg(long)]
+    loudness: bool,
+
+    /// Also write ReplayGain tags into the music files; implies --loudness
+    #[arg(long)]
+    replaygain: bool,
+
+    /// Remove downloaded songs whose charts and audio are already in the
+    /// library under another ID
+    #[arg(long)]
+    skip_duplicates: bool,
+
+    /// Of the uploads of the same song (by title and artist) by different
+    /// users, only download the one picked by "earliest", "most-downloaded",
+    /// or "uploader:<ID or name>"; songs already in the library are kept
+    #[arg(long, value_name = "PREFERENCE")]
+    prefer: Option<Preference>,
+
+    /// Download songs updated on the server since they were downloaded again
+    /// in full syncs, asking for each archive only if it changed
+    #[arg(long)]
+    refresh_updated: bool,
 
+    /// Record the background video links of each song in video_links.txt in
+    /// the song directory
+    #[arg(long)]
+    videos: bool,
+
+    /// Shell command to fetch each background video link, run in the song
+    /// directory with the link in NAUTICA_VIDEO_URL; implies --videos
+    #[arg(long, value_name = "COMMAND")]
+    video_fetcher: Option<String>,
+
+    /// Add downloaded songs to this unnamed_sdvx_clone song database
+    /// (maps.db) so they show up without a rescan
+    #[arg(long, value_name = "PATH")]
+    usc_db: Option<PathBuf>,
+
+    /// Fetch only the jacket and preview audio of each song into
+    /// <DEST>/previews, a lightweight library to audition songs before
+    /// downloading them in full
+    #[arg(long, conflicts_with_all = ["estimate", "usc_db"])]
+    preview_only: bool,
+
+    /// Order in which songs are listed and downloaded
+    #[arg(long, value_enum, default_value_t)]
+    sort: Sort,
+
+    /// Walk the whole catalog for songs missing from the library instead of
+    /// stopping at the newest local song, e.g. after `state import`
+    #[arg(long, conflicts_with = "favorites")]
+    missing: bool,
+
+    /// Only sync the first N matching songs in the listing order, e.g. the
+    /// most downloaded ones with --sort popular
+    #[arg(long, value_name = "N", conflicts_with = "favorites")]
+    top: Option<usize>,
+
+    /// Begin walking the listing at this page instead of the first, e.g. to
+    /// recover a run by hand
+    #[arg(long, value_name = "N", conflicts_with_all = ["favorites", "start_after"])]
+    start_page: Option<u32>,
+
+    /// Begin walking the listing right after the song with this ID
+    #[arg(long, value_name = "SONG_ID", conflicts_with = "favorites")]
+    start_after: Option<String>,
+
+    /// Also write the summary of the songs downloaded in the run to this
+    /// file as Markdown, e.g. to post to a community chat
+    #[arg(long, value_name = "PATH")]
+    summary_file: Option<PathBuf>,
+
+    /// Upload downloaded songs to this WebDAV or SFTP destination (e.g.
+    /// sftp://nas/charts) and remove the local copies
+    #[arg(long, value_name = "URL")]
+    remote: Option<String>,
+
+    /// What to do when a song fails to download: "continue" with the rest
+    /// and report failures at the end, or "abort" the run to investigate
+    /// [default: the configured on_error, or continue]
+    #[arg(long, value_enum, value_name = "POLICY")]
+    on_error: Option<OnError>,
+
+    /// Stop the run as a server outage once this many songs in a row failed
+    /// to download; 0 never stops [default: the configured
+    /// max_consecutive_failures, or 10]
+    #[arg(long, value_name = "N")]
+    max_consecutive_failures: Option<u32>,
+
+    /// Give up on a song whose download takes longer than this, e.g. 10m, so
+    /// that a huge file on a dying connection cannot stall the run; it is
+    /// tried again in the next run [default: the configured
+    /// max_duration_per_song in seconds, or no limit]
+    #[arg(long, value_name = "DURATION")]
+    max_duration_per_song: Option<humantime::Duration>,
+
+    /// Directory to download and extract songs into before moving them to
+    /// the destination, e.g. a fast local disk when the library is on a NAS
+    /// [default: the configured temp_dir, or download in place]
+    #[arg(long, value_name = "PATH")]
+    temp_dir: Option<PathBuf>,
+
+    /// Server to sync from, e.g. a mirror started with the serve command;
+    /// repeat to fail over to the next server when one keeps failing
+    /// [default: the configured base_urls, or https://ksm.dev]
+    #[arg(long = "base-url", value_name = "URL")]
+    base_urls: Vec<String>,
+
+    /// Connect to this address for a host and port instead of looking it up,
+    /// as in curl, e.g. ksm.dev:443:192.168.1.10 for a LAN mirror; repeat for
+    /// more hosts [default: the configured resolve]
+    #[arg(long = "resolve", value_name = "HOST:PORT:ADDRESS")]
+    resolve: Vec<HostMapping>,
+
+    /// Sync exactly the songs liked by the account logged in with the login
+    /// command, removing songs again once they are unliked
+    #[arg(long)]
+    favorites: bool,
+
+    /// Move songs removed by --favorites into <DEST>/.trash instead of
+    /// deleting them
+    #[arg(long)]
+    use_trash: bool,
+
+    /// Download without asking for confirmation
+    #[arg(short, long)]
+    yes: bool,
+}
+
+impl UscArgs {
+    fn open(&self) -> anyhow::Result<MapDatabase> {
+        let path = match &self.usc_db {
+            Some(path) => path.clone(),
+            None => self
+                .lib
+                .config()?
+                .usc_db
+                .context("No USC database given; use --usc-db or set usc_db in the config")?,
+        };
+        MapDatabase::open(&path)
+    }
+}
+
+impl LibraryArgs {
+    fn config(&self) -> anyhow::Result<Config> {
+        let path = match &self.config {
+            Some(path) => path.clone(),
+            None => self.dest.join("nautica.toml"),
+        };
+        Config::load(&path)
+    }
+}
+
+/// Exit code when every song was downloaded.
+const EXIT_SUCCESS: u8 = 0;
+/// Exit code when the run could not complete, e.g. the listing was unreachable.
+const EXIT_FATAL: u8 = 1;
+/// Exit code when the run completed but some songs failed to download.
+const EXIT_PARTIAL_FAILURE: u8 = 2;
+/// Exit code when the run stopped because the server seems to be down.
+const EXIT_OUTAGE: u8 = 3;
+/// Exit code when the run was interrupted by Ctrl-C.
+const EXIT_CANCELLED: u8 = 130;
+
+/// Library subdirectory that preview-only syncs go to.
+const PREVIEWS_DIRNAME: &str = "previews";
+
+fn main() -> ExitCode {
+    #[cfg(windows)]
+    enable_utf8_console();
     let args = Args::parse();
+    let color = args.color.init();
+    let (otlp, _otlp_guard) = OtlpLayer::from_env().unzip();
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer().with_ansi(color))
+        .with(otlp)
+        .init();
+
+    match run(args) {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("{}", style::fail(format!("Error: {e:?}")));
+            if e.downcast_ref::<Outage>().is_some() {
+                ExitCode::from(EXIT_OUTAGE)
+            } else {
+                ExitCode::from(EXIT_FATAL)
+            }
+        }
+    }
+}
+
+/// Switches the console to UTF-8 so that Japanese titles printed by this and
+/// the programs it runs, such as hooks, are not garbled.
+#[cfg(windows)]
+fn enable_utf8_console() {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleOutputCP(code_page: u32) -> i32;
+    }
+    const CP_UTF8: u32 = 65001;
+    // SAFETY: Only changes the code page of the attached console, if any.
+    unsafe {
+        SetConsoleOutputCP(CP_UTF8);
+    }
+}
+
+fn run(args: Args) -> anyhow::Result<u8> {
+    match args.command {
+        None => {
+            let lib = LibraryArgs {
+                dest: args.dest,
+                config: None,
+            };
+            return sync(downloader(&lib, &args.sync)?.build(), &args.sync);
+        }
+        Some(Command::Sync {
+            lib,
+            sync: sync_args,
+        }) => {
+            return sync(downloader(&lib, &sync_args)?.build(), &sync_args);
+        }
+        Some(Command::Diff { lib, sync, report }) => {
+            // Preview-only syncs keep their songs in a directory that is
+            // created on demand.
+            ensure!(
+                !(report.read_only && sync.preview_only),
+                "--preview-only cannot be used with --read-only"
+            );
+            let mut out = report.output(&lib.dest)?;
+            let downloader = downloader(&lib, &sync)?.read_only(report.read_only).build();
+            let diff = if sync.missing {
+                downloader.diff_missing()?
+            } else {
+                downloader.diff()?
+            };
+            for song in &diff.new {
+                let line = format!("+ {} {} / {}", song.id, song.title, song.artist);
+                writeln!(out, "{}", style::success(line))?;
+            }
+            for song in &diff.updated {
+                let line = format!("~ {} {} / {}", song.id, song.title, song.artist);
+                writeln!(out, "{}", style::skip(line))?;
+            }
+            for id in &diff.removed {
+                writeln!(out, "{}", style::fail(format!("- {id}")))?;
+            }
+            writeln!(
+                out,
+                "{} to download, {} to update, {} removed",
+                diff.new.len(),
+                diff.updated.len(),
+                diff.removed.len()
+            )?;
+        }
+        Some(Command::Catalog { out, lib, sync }) => {
+            let catalog = downloader(&lib, &sync)?.build().snapshot()?;
+            if out == Path::new("-") {
+                println!("{}", catalog.to_json()?);
+            } else {
+                catalog.write(&out)?;
+                println!("{} songs written to {}", catalog.songs.len(), out.display());
+            }
+        }
+        Some(Command::Stats {
+            format,
+            lib,
+            report,
+        }) => {
+            let mut out = report.output(&lib.dest)?;
+            let stats = Stats::collect(&lib.dest)?;
+            match format {
+                stats::Format::Text => {}
+                stats::Format::Md => {
+                    write!(out, "{}", stats.to_markdown())?;
+                    return Ok(EXIT_SUCCESS);
+                }
+                stats::Format::Json => {
+                    writeln!(out, "{}", serde_json::to_string_pretty(&stats)?)?;
+                    return Ok(EXIT_SUCCESS);
+                }
+            }
+            writeln!(out, "Songs: {}", stats.songs)?;
+            writeln!(out, "Size: {}", ByteSize(stats.bytes))?;
+            writeln!(out, "\nSongs per uploader:")?;
+            for (name, uploader) in stats.leaderboard() {
+                let average_level = uploader
+                    .average_level
+                    .map(|level| format!(", average level {level:.1}"))
+                    .unwrap_or_default();
+                let last_upload = uploader
+                    .last_upload
+                    .as_ref()
+                    .map(|last| {
+                        format!(
+                            ", last upload {} ({})",
+                            last.title,
+                            last.uploaded_at.format("%Y-%m-%d")
+                        )
+                    })
+                    .unwrap_or_default();
+                writeln!(
+                    out,
+                    "  {name}: {} songs, {}{average_level}{last_upload}",
+                    uploader.songs,
+                    ByteSize(uploader.bytes)
+                )?;
+            }
+            writeln!(out, "\nCharts per level:")?;
+            for (level, count) in &stats.per_level {
+                writeln!(out, "  {level:>2}: {count}")?;
+            }
+            if !stats.per_effector.is_empty() {
+                writeln!(out, "\nCharts per effector:")?;
+                for (effector, count) in &stats.per_effector {
+                    writeln!(out, "  {effector}: {count}")?;
+                }
+            }
+            writeln!(out, "\nDownloads per month:")?;
+            for (month, count) in &stats.per_month {
+                writeln!(out, "  {month}: {count}")?;
+            }
+        }
+        Some(Command::List {
+            users,
+            query,
+            min_level,
+            max_level,
+            effectors,
+            illustrators,
+            tags,
+            starred,
+            starred_first,
+            broken_audio,
+            mismatched,
+            bpm,
+            duration,
+            lib,
+            report,
+        }) => {
+            let mut out = report.output(&lib.dest)?;
+            let filter = Filter {
+                users,
+                query,
+                min_level,
+                max_level,
+                effectors,
+                illustrators,
+                tags,
+                starred,
+                ..Default::default()
+            };
+            let duration = duration.map(|d| d.map(|d| d.as_secs_f64()));
+            list_songs(
+                &mut out,
+                &lib.dest,
+                &filter,
+                bpm,
+                duration,
+                starred_first,
+                Problems {
+                    broken_audio,
+                    mismatched,
+                },
+            )?;
+        }
+        Some(Command::Find {
+            text,
+            effectors,
+            illustrators,
+            lib,
+        }) => {
+            let filter = Filter {
+                effectors,
+                illustrators,
+                ..Default::default()
+            };
+            let store = Store::open_read_only(&lib.dest);
+            let index = SearchIndex::open(&lib.dest)?;
+            let mut found = index.find(&text.join(" "));
+            found.retain(|doc| {
+                store.get(&doc.id).is_some_and(|mut entry| {
+                    entry.backfill_song_info(&lib.dest, &doc.id);
+                    filter.matches_entry(&doc.id, &entry)
+                })
+            });
+            for doc in &found {
+                println!(
+                    "{} {} / {} ({})",
+                    doc.id, doc.title, doc.artist, doc.uploader
+                );
+            }
+            if found.is_empty() {
+                eprintln!("No matching songs");
+            }
+        }
+        Some(Command::Duplicates(lib)) => {
+            let groups = Downloader::builder()
+                .dest(&lib.dest)
+                .build()
+                .find_duplicates()?;
+            for group in &groups {
+                for (id, entry) in group {
+                    println!(
+                        "{id} {} / {} ({})",
+                        entry.title,
+                        entry.artist,
+                        entry.uploader()
+                    );
+                }
+                println!();
+            }
+            println!("{} songs with duplicates", groups.len());
+        }
+        Some(Command::Views(lib)) => {
+            let links = views::generate(&lib.dest)?;
+            println!("{links} links created");
+        }
+        Some(Command::Watch {
+            interval,
+            schedule,
+            metrics,
+            feed,
+            lib,
+            sync,
+        }) => {
+            let metrics = match metrics {
+                Some(addr) => {
+                    let metrics = Arc::new(Metrics::default());
+                    let addr = metrics.serve(addr.as_str())?;
+                    println!("Serving metrics at http://{addr}/metrics");
+                    Some(metrics)
+                }
+                None => None,
+            };
+            let stop = Arc::new(AtomicBool::new(false));
+            let stop_on_ctrlc = Arc::clone(&stop);
+            ctrlc::set_handler(move || stop_on_ctrlc.store(true, Ordering::Relaxed))?;
+            let reload = systemd::handle_signals(Arc::clone(&stop))?;
+            let watchdog = Watchdog::from_env().map(Arc::new);
+            let build = |stop: &Arc<AtomicBool>| {
+                watcher(
+                    &lib,
+                    &sync,
+                    metrics.as_ref(),
+                    feed.as_deref(),
+                    watchdog.as_ref(),
+                    stop,
+                )
+            };
+
+            let mut downloader = build(&stop)?;
+            systemd::notify_or_warn("READY=1");
+            loop {
+                downloader.watch(interval.into(), schedule.as_ref())?;
+                if !reload.swap(false, Ordering::Relaxed) {
+                    break;
+                }
+                systemd::notify_or_warn("RELOADING=1");
+                println!("Reloading the configuration");
+                stop.store(false, Ordering::Relaxed);
+                match build(&stop) {
+                    Ok(reloaded) => downloader = reloaded,
+                    Err(e) => eprintln!("Keeping the previous configuration: {e:?}"),
+                }
+                systemd::notify_or_warn("READY=1");
+            }
+            systemd::notify_or_warn("STOPPING=1");
+            // Stopping the service is no failure.
+            if systemd::terminated() {
+                return Ok(EXIT_SUCCESS);
+            }
+            return Ok(EXIT_CANCELLED);
+        }
+        Some(Command::Tui { lib, sync }) => {
+            let downloader = downloader(&lib, &sync)?.build();
+            let songs = tui::browse(downloader.catalog(), &lib.dest)?;
+            if songs.is_empty() {
+                return Ok(EXIT_SUCCESS);
+            }
+            return download(downloader, songs, None, &sync);
+        }
+        Some(Command::Pick { lib, sync }) => {
+            let downloader = downloader(&lib, &sync)?.build();
+            let songs = pick::pick(&downloader)?;
+            if songs.is_empty() {
+                return Ok(EXIT_SUCCESS);
+            }
+            return download(downloader, songs, None, &sync);
+        }
+        Some(Command::Jackets { lib, sync }) => {
+            let downloader = downloader(&lib, &sync)?.build();
+            cancel_on_ctrlc(&downloader)?;
+            let report = downloader.cache_jackets()?;
+            println!(
+                "{} downloaded, {} already cached, {} without a jacket, {} invalid, {} failed",
+                report.fetched,
+                report.cached,
+                report.missing,
+                report.invalid.len(),
+                report.failed.len()
+            );
+            if !report.failed.is_empty() || !report.invalid.is_empty() {
+                return Ok(EXIT_PARTIAL_FAILURE);
+            }
+        }
+        Some(Command::Thumbnails { lib }) => {
+            let report = ThumbnailCache::new(&lib.dest).generate_library(&lib.dest);
+            let line = format!(
+                "{} generated, {} up to date, {} without a jacket",
+                report.generated, report.fresh, report.missing
+            );
+            println!("{}", style::success(line));
+            for id in &report.invalid {
+                println!("{}", style::fail(format!("{id}: invalid jacket")));
+            }
+            for id in &report.failed {
+                println!("{}", style::fail(format!("{id}: failed to make thumbnail")));
+            }
+            if !report.failed.is_empty() || !report.invalid.is_empty() {
+                return Ok(EXIT_PARTIAL_FAILURE);
+            }
+        }
+        Some(Command::Loudness {
+            all,
+            replaygain,
+            lib,
+        }) => {
+            let _lock = store::lock(&lib.dest)?;
+            let ids: Vec<_> = Store::open_read_only(&lib.dest)
+                .entries()
+                .into_iter()
+                .filter(|(_, entry)| all || entry.loudness.is_none())
+                .map(|(id, _)| id)
+                .collect();
+            let total = ids.len();
+            let analyzed = Analyzer::default()
+                .tag(replaygain)
+                .analyze_library(&lib.dest, ids)?;
+            println!("{analyzed} of {total} songs analyzed");
+            if analyzed < total {
+                return Ok(EXIT_PARTIAL_FAILURE);
+            }
+        }
+        Some(Command::Reorganize {
+            layout,
+            use_trash,
+            lib,
+        }) => {
+            let config = lib.config()?;
+            let disposal = config.disposal(use_trash);
+            let reorganization = Downloader::builder()
+                .dest(&lib.dest)
+                .layout(layout.or(config.layout.clone()).unwrap_or_default())
+                .disposal(disposal)
+                .build()
+                .reorganize()?;
+            if disposal == Disposal::Trash {
+                trash::purge(&lib.dest, config.trash_retention())?;
+            }
+            for (id, old, new) in &reorganization.moved {
+                println!("{id} {old} -> {new}");
+            }
+            println!(
+                "{} moved, {} skipped",
+                reorganization.moved.len(),
+                reorganization.skipped.len()
+            );
+        }
+        Some(Command::Render { target, lib }) => {
+            let path = Path::new(&target);
+            let charts = if path.is_file() {
+                vec![path.to_owned()]
+            } else {
+                let dir = if path.is_dir() {
+                    path.to_owned()
+                } else {
+                    let (id, entry) = Store::open_read_only(&lib.dest).resolve(&target)?;
+                    lib.dest.join(entry.dir(&id))
+                };
+                // Only KSH charts can be rendered.
+                let mut charts = ksh::charts(&dir)?;
+                charts.retain(|chart| !ksh::is_kson(chart));
+                charts
+            };
+            ensure!(!charts.is_empty(), "No charts found for {target:?}");
+            for chart in charts {
+                println!("{}", render::render_file(&chart)?.display());
+            }
+        }
+        Some(Command::Open { query, lib }) => {
+            let (id, entry) = Store::open_read_only(&lib.dest).resolve(&query)?;
+            println!("{id} {} / {}", entry.title, entry.artist);
+            open_in_file_manager(&lib.dest.join(entry.dir(&id)))?;
+        }
+        Some(Command::Random {
+            count,
+            level,
+            users,
+            query,
+            tags,
+            starred,
+            open,
+            lib,
+        }) => {
+            let filter = Filter {
+                users,
+                query,
+                min_level: level.and_then(|level| level.min),
+                max_level: level.and_then(|level| level.max),
+                tags,
+                starred,
+                ..Default::default()
+            };
+            let entries: Vec<_> = Store::open_read_only(&lib.dest)
+                .entries()
+                .into_iter()
+                .filter(|(id, entry)| filter.matches_entry(id, entry))
+                .collect();
+            ensure!(!entries.is_empty(), "No local songs match");
+            for (id, entry) in pick_random(entries, count) {
+                let charts: Vec<_> = entry.charts.iter().map(Chart::to_string).collect();
+                println!(
+                    "{id} {} / {}\t{}",
+                    entry.title,
+                    entry.artist,
+                    charts.join(" ")
+                );
+                if open {
+                    open_in_file_manager(&lib.dest.join(entry.dir(&id)))?;
+                }
+            }
+        }
+        Some(Command::Tag {
+            query,
+            changes,
+            lib,
+        }) => {
+            let _lock = store::lock(&lib.dest)?;
+            let mut store = Store::open(&lib.dest);
+            let (id, mut entry) = store.resolve(&query)?;
+            if !changes.is_empty() {
+                for change in &changes {
+                    entry.apply(change);
+                }
+                store.insert(&id, &entry)?;
+                update_marked_collections(&lib)?;
+            }
+            println!(
+                "{id} {} / {}\t{}",
+                entry.title,
+                entry.artist,
+                entry.tags.join(" ")
+            );
+        }
+        Some(Command::Star { query, lib }) => star(&lib, &query, true)?,
+        Some(Command::Unstar { query, lib }) => star(&lib, &query, false)?,
+        Some(Command::Import { path, layout, lib }) => {
+            let layout = layout.or(lib.config()?.layout).unwrap_or_default();
+            let _lock = store::lock(&lib.dest)?;
+            let report = Importer::default()
+                .layout(layout)
+                .import(&lib.dest, &path)?;
+            println!(
+                "{}",
+                style::success(format!("{} songs imported", report.imported.len()))
+            );
+            if !report.failed.is_empty() {
+                let line = format!("{} failed:", report.failed.len());
+                println!("{}", style::fail(line));
+                for archive in &report.failed {
+                    println!("{}", style::fail(format!("  {}", archive.display())));
+                }
+                return Ok(EXIT_PARTIAL_FAILURE);
+            }
+        }
+        Some(Command::Remove {
+            queries,
+            level,
+            users,
+            query,
+            tags,
+            blocklist,
+            use_trash,
+            yes,
+            lib,
+        }) => {
+            let filter = Filter {
+                users,
+                query,
+                min_level: level.and_then(|level| level.min),
+                max_level: level.and_then(|level| level.max),
+                tags,
+                ..Default::default()
+            };
+            let disposal = lib.config()?.disposal(use_trash);
+            return remove_songs(&lib, &queries, &filter, blocklist.as_deref(), disposal, yes);
+        }
+        Some(Command::Pack {
+            output,
+            level,
+            users,
+            query,
+            tags,
+            starred,
+            lib,
+        }) => {
+            let filter = Filter {
+                users,
+                query,
+                min_level: level.and_then(|level| level.min),
+                max_level: level.and_then(|level| level.max),
+                tags,
+                starred,
+                ..Default::default()
+            };
+            let config = lib.config()?;
+            let mut packer = Packer::default().routes(config.api.clone());
+            if let Some(base_url) = config.base_urls.first() {
+                packer = packer.base_url(base_url);
+            }
+            let songs = packer.pack(&lib.dest, &filter, &output)?;
+            println!(
+                "{}",
+                style::success(format!("{songs} songs packed into {}", output.display()))
+            );
+        }
+        Some(Command::Upload { remote, lib }) => {
+            let uploader = uploader(&lib.config()?, remote.as_deref())?
+                .context("No S3 bucket or remote destination configured")?;
+            let _lock = store::lock(&lib.dest)?;
+            let ids = Store::open_read_only(&lib.dest)
+                .entries()
+                .into_iter()
+                .filter(|(_, entry)| entry.remote_keys.is_empty())
+                .map(|(id, _)| id);
+            let uploaded = uploader.upload_library(&lib.dest, ids)?;
+            println!("{uploaded} songs uploaded");
+        }
+        Some(Command::Serve { listen, lib }) => {
+            let mirror = Mirror::bind(&lib.dest, listen.as_str())?;
+            if let Some(addr) = mirror.local_addr() {
+                println!("Serving {} at http://{addr}", lib.dest.display());
+            }
+            mirror.run();
+        }
+        Some(Command::Bench {
+            base_url,
+            songs,
+            per_page,
+            pool_sizes,
+            lib,
+        }) => {
+            let base_url = match base_url {
+                Some(base_url) => base_url,
+                None => {
+                    ensure!(
+                        !Store::open_read_only(&lib.dest).entries().is_empty(),
+                        "No songs to benchmark in {}",
+                        lib.dest.display()
+                    );
+                    let mirror = Mirror::bind(&lib.dest, "127.0.0.1:0")?;
+                    let addr = mirror
+                        .local_addr()
+                        .context("The mirror has no IP address")?;
+                    thread::spawn(move || mirror.run());
+                    format!("http://{addr}")
+                }
+            };
+            let mut bench = Bench::new(base_url)
+                .songs(songs)
+                .per_page(per_page)
+                .setting(HttpConfig {
+                    keep_alive: false,
+                    ..Default::default()
+                });
+            for pool_size in pool_sizes {
+                bench = bench.setting(HttpConfig {
+                    pool_size,
+                    ..Default::default()
+                });
+            }
+            let results = bench.run()?;
+            println!(
+                "{:<16} {:>10} {:>10} {:>14}",
+                "setting", "pages/s", "songs/s", "extract MB/s"
+            );
+            for result in &results {
+                println!("{result}");
+            }
+        }
+        Some(Command::Login { email, lib }) => {
+            let config = lib.config()?;
+            let password = match std::env::var("NAUTICA_PASSWORD") {
+                Ok(password) => password,
+                Err(_) => prompt_password()?,
+            };
+            Downloader::builder()
+                .dest(&lib.dest)
+                .base_urls(config.base_urls)
+                .routes(config.api)
+                .http(config.http)
+                .build()
+                .login(&email, &password)?;
+            println!("{}", style::success(format!("Logged in as {email}")));
+        }
+        Some(Command::Logout(lib)) => {
+            if auth::remove_session(&lib.dest)? {
+                println!("Logged out");
+            } else {
+                println!("Not logged in");
+            }
+        }
+        Some(Command::Playlist {
+            playlists,
+            lib,
+            sync,
+        }) => return download_playlists(&playlists, &lib, &sync),
+        Some(Command::Publish { dir, check, lib }) => {
+            if check {
+                let problems = publish::check(&dir)?;
+                for problem in &problems {
+                    println!("{}", style::fail(problem));
+                }
+                ensure!(problems.is_empty(), "{} cannot be published", dir.display());
+                let line = format!("{} can be published", dir.display());
+                println!("{}", style::success(line));
+                return Ok(EXIT_SUCCESS);
+            }
+            let config = lib.config()?;
+            let mut builder = Downloader::builder()
+                .dest(&lib.dest)
+                .base_urls(config.base_urls)
+                .routes(config.api)
+                .http(config.http);
+            if let Some(cookie) = auth::load_session(&lib.dest)? {
+                builder = builder.session(cookie);
+            }
+            let song = builder.build().publish(&dir)?;
+            let line = format!("Published {} / {} as {}", song.title, song.artist, song.id);
+            println!("{}", style::success(line));
+        }
+        Some(Command::SelfUpdate { check }) => {
+            let updater = Updater::default();
+            let release = updater.latest()?;
+            let current = env!("CARGO_PKG_VERSION");
+            if !update::is_newer(release.version()) {
+                println!("{}", style::skip(format!("Already up to date ({current})")));
+                return Ok(EXIT_SUCCESS);
+            }
+            println!("{current} -> {}", release.version());
+            if check {
+                return Ok(EXIT_SUCCESS);
+            }
+            update::replace_current_exe(&updater.download(&release)?)?;
+            let line = format!("Updated to {}", release.version());
+            println!("{}", style::success(line));
+        }
+        Some(Command::Collection { command }) => manage_collection(command)?,
+        Some(Command::Cas { command }) => return manage_cas(command),
+        Some(Command::Queue { command }) => manage_queue(command)?,
+        Some(Command::Quarantine { command }) => manage_quarantine(command)?,
+        Some(Command::State {
+            command: StateCommand::Export { output, lib },
+        }) => {
+            let songs = state::export(&lib.dest, &output)?;
+            let line = format!("{songs} songs exported to {}", output.display());
+            println!("{}", style::success(line));
+        }
+        Some(Command::State {
+            command: StateCommand::Import { input, lib },
+        }) => {
+            let _lock = store::lock(&lib.dest)?;
+            let report = state::import(&lib.dest, &input)?;
+            let line = format!(
+                "{} copied songs adopted, {} not kept recorded, {} already known",
+                report.adopted.len(),
+                report.skipped.len(),
+                report.known
+            );
+            println!("{}", style::success(line));
+            if !report.missing.is_empty() {
+                let line = format!(
+                    "{} songs missing; run `sync --missing` to download them",
+                    report.missing.len()
+                );
+                println!("{}", style::skip(line));
+            }
+        }
+    }
+    Ok(EXIT_SUCCESS)
+}
+
+/// Problems that `list` restricts the songs to, found by reading their files
+/// again.
+#[derive(Debug, Clone, Copy)]
+struct Problems {
+    broken_audio: bool,
+    mismatched: bool,
+}
+
+fn list_songs(
+    out: &mut dyn Write,
+    dest: &Path,
+    filter: &Filter,
+    bpm: Option<Bounds<f64>>,
+    duration: Option<Bounds<f64>>,
+    starred_first: bool,
+    problems: Problems,
+) -> anyhow::Result<()> {
+    let mut entries = Store::open_read_only(dest).entries();
+    if starred_first {
+        entries.sort_by_key(|(_, entry)| !entry.starred);
+    }
+    for (id, mut entry) in entries {
+        if problems.broken_audio || problems.mismatched {
+            entry.read_song_info(&dest.join(entry.dir(&id)));
+            if problems.broken_audio && entry.audio_problem.is_none()
+                || problems.mismatched && !entry.is_mismatched()
+            {
+                continue;
+            }
+        } else {
+            entry.backfill_song_info(dest, &id);
+        }
+        if !filter.matches_entry(&id, &entry) {
+            continue;
+        }
+        let bpm_matches = bpm.is_none_or(|bounds| {
+            entry
+                .bpm
+                .is_some_and(|bpm| bounds.contains(&bpm.min) && bounds.contains(&bpm.max))
+        });
+        let duration_matches =
+            duration.is_none_or(|bounds| entry.duration.is_some_and(|d| bounds.contains(&d)));
+        if !bpm_matches || !duration_matches {
+            continue;
+        }
+        let bpm = entry
+            .bpm
+            .map(|bpm| format!("{bpm} BPM"))
+            .unwrap_or_default();
+        let duration = entry
+            .duration
+            .map(|d| format!("{}:{:02}", d as u64 / 60, d as u64 % 60))
+            .unwrap_or_default();
+        let charts: Vec<_> = entry.charts.iter().map(Chart::to_string).collect();
+        let star = if entry.starred { " ★" } else { "" };
+        writeln!(
+            out,
+            "{id}{star} {} / {}\t{}\t{bpm}\t{duration}",
+            entry.title,
+            entry.artist,
+            charts.join(" ")
+        )?;
+        if let Some(problem) = &entry.audio_problem {
+            writeln!(out, "  {}", style::fail(problem))?;
+        }
+        if entry.is_mismatched() {
+            let line = format!(
+                "charts: {} / {}",
+                entry.chart_title.as_ref().unwrap_or(&entry.title),
+                entry.chart_artist.as_ref().unwrap_or(&entry.artist)
+            );
+            writeln!(out, "  {}", style::skip(line))?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns `count` of `items` picked at random, or all of them shuffled if
+/// there are fewer.
+fn pick_random<T>(mut items: Vec<T>, count: usize) -> Vec<T> {
+    // Picks need no strong randomness, so the randomly seeded keys of the
+    // standard hasher are enough.
+    let count = count.min(items.len());
+    for i in 0..count {
+        let random = RandomState::new().build_hasher().finish() as usize;
+        let j = i + random % (items.len() - i);
+        items.swap(i, j);
+    }
+    items.truncate(count);
+    items
+}
+
+fn manage_cas(command: CasCommand) -> anyhow::Result<u8> {
+    match command {
+        CasCommand::Store(lib) => {
+            let _lock = store::lock(&lib.dest)?;
+            let (songs, deduplicated) = cas::store_library(&lib.dest)?;
+            let line = format!("{songs} songs stored, {deduplicated} files deduplicated");
+            println!("{}", style::success(line));
+        }
+        CasCommand::Verify { lib, report } => {
+            let mut out = report.output(&lib.dest)?;
+            let verification = cas::verify(&lib.dest)?;
+            for path in &verification.corrupt {
+                writeln!(
+                    out,
+                    "{}",
+                    style::fail(format!("corrupt {}", path.display()))
+                )?;
+            }
+            for path in &verification.mismatched {
+                writeln!(
+                    out,
+                    "{}",
+                    style::fail(format!("mismatched {}", path.display()))
+                )?;
+            }
+            if !verification.corrupt.is_empty() || !verification.mismatched.is_empty() {
+                return Ok(EXIT_PARTIAL_FAILURE);
+            }
+            let line = format!("{} stored files verified", verification.blobs);
+            writeln!(out, "{}", style::success(line))?;
+        }
+        CasCommand::Prune { use_trash, lib } => {
+            // A sync stores a song's files before its manifest refers to them.
+            let _lock = store::lock(&lib.dest)?;
+            let config = lib.config()?;
+            let disposal = config.disposal(use_trash);
+            let removed = cas::prune(&lib.dest, disposal)?;
+            if disposal == Disposal::Trash {
+                trash::purge(&lib.dest, config.trash_retention())?;
+            }
+            println!("{removed} unreferenced files removed");
+        }
+    }
+    Ok(EXIT_SUCCESS)
+}
+
+fn manage_queue(command: QueueCommand) -> anyhow::Result<()> {
+    match command {
+        QueueCommand::List(lib) => {
+            let queue = Queue::read(&lib.dest)?;
+            for item in queue.items() {
+                let song = item
+                    .song
+                    .as_ref()
+                    .map(|song| format!(" {} / {}", song.title, song.artist))
+                    .unwrap_or_default();
+                let line = format!("{:<7} {:>3} {}{song}", item.status, item.priority, item.id);
+                match item.status {
+                    queue::Status::Failed => println!("{}", style::fail(line)),
+                    queue::Status::Done => println!("{}", style::success(line)),
+                    _ => println!("{line}"),
+                }
+            }
+        }
+        QueueCommand::Add { ids, priority, lib } => {
+            let mut queue = Queue::read(&lib.dest)?;
+            for id in &ids {
+                queue.push_id(id, priority)?;
+            }
+            println!("{} songs queued for the next sync", ids.len());
+        }
+        QueueCommand::Retry(lib) => {
+            let retried = Queue::read(&lib.dest)?.retry_failed()?;
+            println!("{retried} failed songs queued again for the next sync");
+        }
+    }
+    Ok(())
+}
+
+fn manage_quarantine(command: QuarantineCommand) -> anyhow::Result<()> {
+    match command {
+        QuarantineCommand::List(lib) => {
+            let store = Store::open_read_only(&lib.dest);
+            for (id, reason) in quarantine::list(&lib.dest)? {
+                match store.get(&id) {
+                    Some(entry) => println!("{id} {} / {}", entry.title, entry.artist),
+                    None => println!("{id}"),
+                }
+                println!("  {}", style::fail(reason.trim_end()));
+            }
+        }
+        QuarantineCommand::Release { id, force, lib } => {
+            let _lock = store::lock(&lib.dest)?;
+            if quarantine::release(&lib.dest, &id, force)? {
+                println!("{}", style::success(format!("Released {id}")));
+            } else {
+                let line = format!("Released {id}; the next sync downloads it again");
+                println!("{}", style::success(line));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn star(lib: &LibraryArgs, query: &str, starred: bool) -> anyhow::Result<()> {
+    let _lock = store::lock(&lib.dest)?;
+    let mut store = Store::open(&lib.dest);
+    let (id, mut entry) = store.resolve(query)?;
+    entry.starred = starred;
+    store.insert(&id, &entry)?;
+    update_marked_collections(lib)?;
+    let verb = if starred { "Starred" } else { "Unstarred" };
+    println!("{verb} {id} {} / {}", entry.title, entry.artist);
+    Ok(())
+}
+
+/// Removes the songs matched by `queries`, or by `filter` if none are given,
+/// along with their metadata, adding their IDs to `blocklist` if given.
+fn remove_songs(
+    lib: &LibraryArgs,
+    queries: &[String],
+    filter: &Filter,
+    blocklist: Option<&Path>,
+    disposal: Disposal,
+    yes: bool,
+) -> anyhow::Result<u8> {
+    let _lock = store::lock(&lib.dest)?;
+    let mut store = Store::open(&lib.dest);
+    let songs = if queries.is_empty() {
+        ensure!(
+            !filter.users.is_empty()
+                || filter.query.is_some()
+                || filter.min_level.is_some()
+                || filter.max_level.is_some()
+                || !filter.tags.is_empty(),
+            "Give the songs to remove or filters matching them"
+        );
+        let (songs, starred) = remove::matching(&store, filter);
+        if starred > 0 {
+            let line = format!("Keeping {starred} starred songs; give their IDs to remove them");
+            println!("{}", style::skip(line));
+        }
+        songs
+    } else {
+        queries
+            .iter()
+            .map(|query| store.resolve(query))
+            .collect::<anyhow::Result<Vec<_>>>()?
+    };
+    if songs.is_empty() {
+        println!("{}", style::skip("No songs to remove"));
+        return Ok(EXIT_SUCCESS);
+    }
+    for (id, entry) in &songs {
+        println!("{id} {} / {}", entry.title, entry.artist);
+    }
+    if !yes && !confirm(&format!("Remove these {} songs?", songs.len()))? {
+        return Ok(EXIT_CANCELLED);
+    }
+    if let Some(blocklist) = blocklist {
+        IdList::append(blocklist, songs.iter().map(|(id, _)| id.as_str()))?;
+    }
+    for (id, _) in &songs {
+        remove::remove_song(&lib.dest, &mut store, id, disposal)?;
+    }
+    let ids: Vec<_> = songs.iter().map(|(id, _)| id.as_str()).collect();
+    SearchIndex::open(&lib.dest)?.update(&lib.dest, ids)?;
+    cas::prune(&lib.dest, disposal)?;
+    if disposal == Disposal::Trash {
+        trash::purge(&lib.dest, lib.config()?.trash_retention())?;
+    }
+    update_marked_collections(lib)?;
+    println!(
+        "{}",
+        style::success(format!("{} songs removed", songs.len()))
+    );
+    Ok(EXIT_SUCCESS)
+}
+
+/// Brings the collections filtering by local tags or stars up to date after
+/// they changed.
+fn update_marked_collections(lib: &LibraryArgs) -> anyhow::Result<()> {
+    let Some(usc_db) = lib.config()?.usc_db else {
+        return Ok(());
+    };
+    let collections = Collections::load(&lib.dest)?;
+    let mut marked = collections
+        .iter()
+        .filter(|c| c.uses_local_marks())
+        .peekable();
+    if marked.peek().is_none() {
+        return Ok(());
+    }
+    let mut db = MapDatabase::open(&usc_db)?;
+    for collection in marked {
+        collection::sync(&lib.dest, &mut db, collection)?;
+    }
+    Ok(())
+}
+
+fn manage_collection(command: CollectionCommand) -> anyhow::Result<()> {
+    match command {
+        CollectionCommand::Create {
+            name,
+            users,
+            query,
+            min_level,
+            max_level,
+            tags,
+            starred,
+            usc,
+        } => {
+            let collection = Collection {
+                name,
+                users,
+                query,
+                min_level,
+                max_level,
+                tags,
+                starred,
+                ..Default::default()
+            };
+            let songs = collection::sync(&usc.lib.dest, &mut usc.open()?, &collection)?;
+            println!("{}: {songs} songs", collection.name);
+            let mut collections = Collections::load(&usc.lib.dest)?;
+            collections.insert(collection);
+            collections.save(&usc.lib.dest)?;
+        }
+        CollectionCommand::Delete { name, usc } => {
+            usc.open()?.delete_collection(&name)?;
+            let mut collections = Collections::load(&usc.lib.dest)?;
+            if collections.remove(&name) {
+                collections.save(&usc.lib.dest)?;
+            }
+        }
+        CollectionCommand::List(lib) => {
+            for collection in Collections::load(&lib.dest)?.iter() {
+                println!("{}", collection.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn sync(downloader: Downloader, args: &SyncArgs) -> anyhow::Result<u8> {
+    if args.favorites {
+        return sync_favorites(downloader, args);
+    }
+    let pending = if args.missing {
+        downloader.pending_full()?
+    } else {
+        downloader.pending()?
+    };
+    if pending.is_empty() {
+        println!("{}", style::skip("No new songs"));
+        return Ok(EXIT_SUCCESS);
+    }
+    let estimated_bytes = announce_new_songs(&downloader, &pending, args)?;
+    if !args.yes && !confirm("Download them?")? {
+        return Ok(EXIT_CANCELLED);
+    }
+
+    download(downloader, pending, estimated_bytes, args)
+}
+
+/// Prints how many new songs were found and, with --estimate, how much they
+/// take to download, which is returned.
+fn announce_new_songs(
+    downloader: &Downloader,
+    songs: &[Song],
+    args: &SyncArgs,
+) -> anyhow::Result<Option<u64>> {
+    if !args.estimate {
+        println!("{} new songs found", songs.len());
+        return Ok(None);
+    }
+    let bytes = downloader.estimate_run(songs)?;
+    println!(
+        "{} new songs found, about {} to download",
+        songs.len(),
+        ByteSize(bytes)
+    );
+    Ok(Some(bytes))
+}
+
+fn sync_favorites(downloader: Downloader, args: &SyncArgs) -> anyhow::Result<u8> {
+    let mut plan = downloader.plan_favorites()?;
+    if plan.new.is_empty() && plan.unfavorited.is_empty() {
+        println!("{}", style::skip("Liked songs are up to date"));
+        return Ok(EXIT_SUCCESS);
+    }
+    if args.estimate {
+        let bytes = downloader.estimate_run(&plan.new)?;
+        plan.estimated_bytes = Some(bytes);
+        println!(
+            "{} newly liked songs (about {}), {} no longer liked",
+            plan.new.len(),
+            ByteSize(bytes),
+            plan.unfavorited.len()
+        );
+    } else {
+        println!(
+            "{} newly liked songs, {} no longer liked",
+            plan.new.len(),
+            plan.unfavorited.len()
+        );
+    }
+    if !args.yes && !confirm("Download and remove them?")? {
+        return Ok(EXIT_CANCELLED);
+    }
+    cancel_on_ctrlc(&downloader)?;
+    let report = downloader.sync_favorites(plan)?;
+    print_report(&report);
+    report_summary(&report, args)?;
+    Ok(exit_code(&report))
+}
+
+/// Downloads the songs of `playlists`, or of every playlist of the account if
+/// none are given, and records the playlists as collections.
+fn download_playlists(
+    playlists: &[String],
+    lib: &LibraryArgs,
+    sync: &SyncArgs,
+) -> anyhow::Result<u8> {
+    let config = lib.config()?;
+    let downloader = downloader(lib, sync)?.build();
+    let ids: Vec<_> = if playlists.is_empty() {
+        ensure!(
+            auth::load_session(&lib.dest)?.is_some(),
+            "Not logged in; run the login command or give playlist URLs"
+        );
+        downloader.playlists()?.into_iter().map(|p| p.id).collect()
+    } else {
+        playlists
+            .iter()
+            .map(|p| playlist_id(p).to_owned())
+            .collect()
+    };
+
+    let store = Store::open_read_only(&lib.dest);
+    let mut collections = Collections::load(&lib.dest)?;
+    let mut seen = HashSet::new();
+    let mut pending = Vec::new();
+    for id in &ids {
+        let playlist = downloader.playlist(id)?;
+        println!("{}: {} songs", playlist.name, playlist.songs.len());
+        collections.insert(Collection::from_playlist(&playlist));
+        pending.extend(
+            playlist
+                .songs
+                .into_iter()
+                .filter(|song| !store.contains(&song.id) && seen.insert(song.id.clone())),
+        );
+    }
+    collections.save(&lib.dest)?;
+
+    let mut code = EXIT_SUCCESS;
+    if !pending.is_empty() {
+        let estimated_bytes = announce_new_songs(&downloader, &pending, sync)?;
+        if !sync.yes && !confirm("Download them?")? {
+            return Ok(EXIT_CANCELLED);
+        }
+        code = download(downloader, pending, estimated_bytes, sync)?;
+    }
+    // Membership may have changed even if no song was downloaded.
+    if let Some(usc_db) = sync.usc_db.clone().or(config.usc_db) {
+        let mut db = MapDatabase::open(&usc_db)?;
+        for collection in collections.iter().filter(|c| c.playlist.is_some()) {
+            collection::sync(&lib.dest, &mut db, collection)?;
+        }
+    }
+    Ok(code)
+}
+
+fn download(
+    downloader: Downloader,
+    songs: Vec<Song>,
+    estimated_bytes: Option<u64>,
+    args: &SyncArgs,
+) -> anyhow::Result<u8> {
+    cancel_on_ctrlc(&downloader)?;
+    let report = match estimated_bytes {
+        Some(bytes) => downloader.download_estimated(songs, bytes)?,
+        None => downloader.download_songs(songs)?,
+    };
+    print_report(&report);
+    report_summary(&report, args)?;
+    Ok(exit_code(&report))
+}
 
+/// Prints the outcome of a run, one line each for the downloaded, skipped,
+/// and failed songs.
+fn print_report(report: &DownloadReport) {
+    let downloaded = format!(
+        "{} downloaded ({})",
+        report.downloaded.len(),
+        ByteSize(report.bytes)
+    );
+    println!("{}", style::success(downloaded));
+    let skipped = report.duplicates.len() + report.removed.len();
+    if skipped > 0 {
+        let line = format!(
+            "{skipped} skipped ({} duplicates, {} removed from the server)",
+            report.duplicates.len(),
+            report.removed.len()
+        );
+        println!("{}", style::skip(line));
+    }
+    if !report.unchanged.is_empty() {
+        let line = format!(
+            "{} updated on the server with an unchanged archive",
+            report.unchanged.len()
+        );
+        println!("{}", style::skip(line));
+    }
+    if !report.quarantined.is_empty() {
+        let line = format!(
+            "{} quarantined; see `quarantine list`:",
+            report.quarantined.len()
+        );
+        println!("{}", style::fail(line));
+        for song in &report.quarantined {
+            let line = format!("  {} {} / {}", song.id, song.title, song.artist);
+            println!("{}", style::fail(line));
+        }
+    }
+    if !report.oversized.is_empty() {
+        let line = format!("{} too large:", report.oversized.len());
+        println!("{}", style::skip(line));
+        for song in &report.oversized {
+            let line = format!("  {} {} / {}", song.id, song.title, song.artist);
+            println!("{}", style::skip(line));
+        }
+    }
+    if !report.failed.is_empty() {
+        let line = format!("{} failed:", report.failed.len());
+        println!("{}", style::fail(line));
+        for song in &report.failed {
+            let line = format!("  {} {} / {}", song.id, song.title, song.artist);
+            println!("{}", style::fail(line));
+        }
+    }
+}
+
+/// Prints what is new after a run, grouped per uploader and per level band,
+/// and writes it to the summary file if one is given.
+fn report_summary(report: &DownloadReport, args: &SyncArgs) -> anyhow::Result<()> {
+    let summary = Summary::new(&report.downloaded);
+    if summary.songs > 0 {
+        print!("{summary}");
+    }
+    // The file is written even when nothing is new, so that a stale summary
+    // is never posted twice.
+    if let Some(path) = &args.summary_file {
+        fs::write(path, summary.to_markdown())
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn exit_code(report: &DownloadReport) -> u8 {
+    if report.cancelled {
+        EXIT_CANCELLED
+    } else if !report.failed.is_empty() {
+        EXIT_PARTIAL_FAILURE
+    } else {
+        EXIT_SUCCESS
+    }
+}
+
+/// Asks a yes/no question on the terminal. Always answers yes when stdin is
+/// not a terminal so that scheduled runs are never blocked.
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Reads a password from stdin, prompting for it on a terminal, where it is
+/// not echoed.
+fn prompt_password() -> anyhow::Result<String> {
+    let password = if io::stdin().is_terminal() {
+        rpassword::prompt_password("Password: ")?
+    } else {
+        let mut password = String::new();
+        io::stdin().read_line(&mut password)?;
+        password.trim_end_matches(['\r', '\n']).to_owned()
+    };
+    ensure!(!password.is_empty(), "No password given");
+    Ok(password)
+}
+
+/// Stops the downloader gracefully on the first Ctrl-C.
+fn cancel_on_ctrlc(downloader: &Downloader) -> anyhow::Result<()> {
+    let cancelled = downloader.cancel_flag();
+    ctrlc::set_handler(move || cancelled.store(true, Ordering::Relaxed))?;
+    Ok(())
+}
+
+fn downloader(lib: &LibraryArgs, sync: &SyncArgs) -> anyhow::Result<DownloaderBuilder> {
     ensure!(
-        args.dest.exists(),
+        lib.dest.exists(),
         "Destination directory must exist: {}",
-        args.dest.to_string_lossy()
+        lib.dest.to_string_lossy()
     );
+    let config = lib.config()?;
+    let filter = Filter {
+        users: sync.users.clone(),
+        query: sync.query.clone(),
+        min_level: sync.min_level,
+        max_level: sync.max_level,
+        effectors: sync.effectors.clone(),
+        blocklist: match &sync.blocklist {
+            Some(path) => IdList::load(path)?,
+            None => IdList::default(),
+        },
+        allowlist: sync.allowlist.as_deref().map(IdList::load).transpose()?,
+        ..Default::default()
+    };
+    let dest = if sync.preview_only {
+        let dest = lib.dest.join(PREVIEWS_DIRNAME);
+        fs::create_dir_all(&dest)?;
+        dest
+    } else {
+        lib.dest.clone()
+    };
+    let uploader = uploader(&config, sync.remote.as_deref())?;
+    let layout = sync
+        .layout
+        .clone()
+        .or(config.layout.clone())
+        .unwrap_or_default();
+    let ksm = matches!(layout, Layout::Ksm(_));
+    let mut builder = Downloader::builder()
+        .dest(&dest)
+        .sort(sync.sort)
+        .filter(filter)
+        .layout(layout)
+        .reserve(sync.reserve.0)
+        .estimate(sync.estimate)
+        .preview_only(sync.preview_only)
+        .skip_duplicates(sync.skip_duplicates || config.skip_duplicates)
+        .refresh_updated(sync.refresh_updated || config.refresh_updated);
+    if let Some(preference) = sync.prefer.clone().or(config.prefer.clone()) {
+        builder = builder.prefer(preference);
+    }
+    if let Some(per_page) = sync.per_page {
+        builder = builder.per_page(per_page);
+    }
+    if let Some(top) = sync.top {
+        builder = builder.top(top);
+    }
+    if let Some(page) = sync.start_page {
+        builder = builder.start_page(page);
+    }
+    if let Some(id) = &sync.start_after {
+        builder = builder.start_after(id);
+    }
+    let mut http = config.http.clone();
+    if !sync.resolve.is_empty() {
+        http.resolve = sync.resolve.clone();
+    }
+    let base_urls = if sync.base_urls.is_empty() {
+        config.base_urls.clone()
+    } else {
+        sync.base_urls.clone()
+    };
+    builder = builder
+        .base_urls(base_urls)
+        .routes(config.api.clone())
+        .http(http)
+        .queue_order(config.queue.clone());
+    for copy in &config.copies {
+        builder = builder.copy_to(copy.clone());
+    }
+    match auth::load_session(&lib.dest)? {
+        Some(cookie) => builder = builder.session(cookie),
+        None => ensure!(
+            !sync.favorites,
+            "Not logged in; run the login command before syncing liked songs"
+        ),
+    }
+    builder = builder
+        .favorites(sync.favorites)
+        .disposal(config.disposal(sync.use_trash));
+    builder = builder.on_error(sync.on_error.or(config.on_error).unwrap_or_default());
+    let max_consecutive_failures = sync
+        .max_consecutive_failures
+        .or(config.max_consecutive_failures)
+        .unwrap_or(DEFAULT_MAX_CONSECUTIVE_FAILURES);
+    builder = builder.max_consecutive_failures(Some(max_consecutive_failures).filter(|&n| n > 0));
+    let max_duration_per_song = sync
+        .max_duration_per_song
+        .map(Duration::from)
+        .or(config.max_duration_per_song.map(Duration::from_secs));
+    if let Some(max) = max_duration_per_song {
+        builder = builder.max_duration_per_song(max);
+    }
+    if let Some(temp_dir) = sync.temp_dir.clone().or(config.temp_dir.clone()) {
+        builder = builder.temp_dir(temp_dir);
+    }
+    if let Some(ByteSize(max_bytes)) = sync.max_bytes {
+        builder = builder.max_bytes(max_bytes);
+    }
+    if let Some(ByteSize(max_song_size)) = sync.max_song_size {
+        builder = builder.max_song_size(max_song_size);
+    }
+    // Transcode first so that later steps see the final files.
+    if sync.transcode || sync.ogg_quality.is_some() || config.transcode.is_some() {
+        let settings = config.transcode.clone().unwrap_or_default();
+        let transcoder = Transcoder::default()
+            .quality(
+                sync.ogg_quality
+                    .or(settings.quality)
+                    .unwrap_or(transcode::DEFAULT_QUALITY),
+            )
+            .keep_originals(sync.keep_wav || settings.keep_originals);
+        builder = builder.notifier(TranscodeNotifier::new(transcoder));
+    }
+    if let Some(hook) = sync.hook.clone().or(config.hook) {
+        builder = builder.notifier(HookNotifier::new(hook));
+    }
+    if ksm {
+        builder = builder.notifier(KsmNotifier);
+    }
+    let video_fetcher = sync.video_fetcher.clone().or(config.video_fetcher);
+    if sync.videos || config.videos || video_fetcher.is_some() {
+        builder = builder.notifier(VideoNotifier::new(video_fetcher));
+    }
+    // Previews have no charts for USC to play.
+    let usc_db = sync.usc_db.clone().or(config.usc_db);
+    if let Some(usc_db) = usc_db.filter(|_| !sync.preview_only) {
+        builder = builder
+            .notifier(UscNotifier::new(usc_db.clone()))
+            .notifier(CollectionsNotifier::new(dest.clone(), usc_db));
+    }
+    let replaygain = sync.replaygain || config.replaygain;
+    if sync.loudness || config.loudness || replaygain {
+        let analyzer = Analyzer::default().tag(replaygain);
+        builder = builder.notifier(LoudnessNotifier::new(dest.clone(), analyzer));
+    }
+    if sync.views || config.views {
+        builder = builder.notifier(ViewsNotifier::new(dest.clone()));
+    }
+    builder = builder.notifier(SearchIndexNotifier::new(dest.clone()));
+    if config.thumbnails {
+        builder = builder.notifier(ThumbnailNotifier::new(ThumbnailCache::new(&dest)));
+    }
+    // Blobs are shared between songs, so no step may change the files after
+    // they are stored.
+    if config.cas {
+        builder = builder.notifier(CasNotifier::new(dest.clone()));
+    }
+    // Upload last since the local copies may be removed.
+    if let Some(uploader) = uploader {
+        builder = builder.notifier(UploadNotifier::new(dest.clone(), uploader));
+    }
+    for webhook in config.notifications.webhooks {
+        builder = builder.notifier(WebhookNotifier::new(webhook.url, webhook.events));
+    }
+    for discord in config.notifications.discord {
+        let mut notifier = DiscordNotifier::new(discord.url)
+            .batch(discord.batch)
+            .routes(config.api.clone());
+        if let Some(base_url) = config.base_urls.first() {
+            notifier = notifier.base_url(base_url);
+        }
+        builder = builder.notifier(notifier);
+    }
+    if let Some(email) = config.notifications.email {
+        builder = builder.notifier(EmailNotifier::new(email)?);
+    }
+    Ok(builder)
+}
+
+/// Creates the downloader for watch mode from the current configuration,
+/// stopped by `stop` and pinging `watchdog` if given.
+fn watcher(
+    lib: &LibraryArgs,
+    sync: &SyncArgs,
+    metrics: Option<&Arc<Metrics>>,
+    feed: Option<&Path>,
+    watchdog: Option<&Arc<Watchdog>>,
+    stop: &Arc<AtomicBool>,
+) -> anyhow::Result<Downloader> {
+    let config = lib.config()?;
+    let mut builder = downloader(lib, sync)?.cancel_flag(Arc::clone(stop));
+    if let Some(watchdog) = watchdog {
+        builder = builder.watchdog(Arc::clone(watchdog));
+    }
+    if config.notifications.desktop {
+        let mut notifier = DesktopNotifier::default();
+        if config.thumbnails {
+            notifier = notifier.thumbnails(ThumbnailCache::new(&lib.dest));
+        }
+        builder = builder.notifier(notifier);
+    }
+    if let Some(metrics) = metrics {
+        builder = builder.notifier(MetricsNotifier::new(Arc::clone(metrics)));
+    }
+    if let Some(feed) = feed {
+        let mut feed = FeedNotifier::open(feed)?.routes(config.api.clone());
+        if let Some(base_url) = config.base_urls.first() {
+            feed = feed.base_url(base_url);
+        }
+        builder = builder.notifier(feed);
+    }
+    Ok(builder.build())
+}
 
-    let downloader = Downloader::builder().dest(args.dest).build();
-    downloader.download_all()?;
+/// Creates the uploader for the S3 bucket or remote destination, if one is
+/// configured or `remote` is given.
+fn uploader(config: &Config, remote: Option<&str>) -> anyhow::Result<Option<Uploader>> {
+    let remote = match remote {
+        Some(url) => Some(RemoteConfig {
+            url: url.to_owned(),
+            keep_local: config.remote.as_ref().is_some_and(|r| r.keep_local),
+        }),
+        None => config.remote.clone(),
+    };
+    ensure!(
+        config.s3.is_none() || remote.is_none(),
+        "Configure either an S3 bucket or a remote destination, not both"
+    );
+    if let Some(s3) = &config.s3 {
+        let uploader = Uploader::new(Bucket::new(s3)?)
+            .prefix(&s3.prefix)
+            .keep_local(s3.keep_local);
+        return Ok(Some(uploader));
+    }
+    remote
+        .map(|remote| Ok(Uploader::from_url(&remote.url)?.keep_local(remote.keep_local)))
+        .transpose()
+}
+
+fn open_in_file_manager(path: &Path) -> anyhow::Result<()> {
+    let program = if cfg!(target_os = "windows") {
+        "explorer"
+    } else if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    process::Command::new(program)
+        .arg(path)
+        .spawn()
+        .with_context(|| format!("Failed to run {program}"))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use httpmock::MockServer;
+    use nautica_downloader_rs::store::Entry;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn pass_per_page_to_listing() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.path("/app/songs").query_param("per_page", "5");
+            then.status(200)
+                .json_body(json!({ "data": [], "links": { "next": null } }));
+        });
+
+        let dest = tempdir().unwrap();
+        let args = Args::try_parse_from([
+            "nautica-downloader-rs",
+            "sync",
+            "--dest",
+            dest.path().to_str().unwrap(),
+            "--per-page",
+            "5",
+            "--base-url",
+            &server.base_url(),
+        ])
+        .unwrap();
+        let Some(Command::Sync { lib, sync }) = args.command else {
+            panic!("not a sync: {:?}", args.command);
+        };
+        let songs = downloader(&lib, &sync).unwrap().build().pending().unwrap();
+
+        m.assert();
+        assert!(songs.is_empty());
+    }
+
+    #[test]
+    fn remove_waits_for_sync() {
+        let dest = tempdir().unwrap();
+        let song: Song = serde_json::from_value(json!({
+            "id": "a",
+            "user_id": "user",
+            "title": "title",
+            "artist": "artist",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+        }))
+        .unwrap();
+        fs::create_dir(dest.path().join("a")).unwrap();
+        Store::open(dest.path())
+            .insert("a", &Entry::new(&song, "a"))
+            .unwrap();
+        let lib = LibraryArgs {
+            dest: dest.path().to_owned(),
+            config: None,
+        };
+        let remove = || {
+            let queries = [String::from("a")];
+            remove_songs(
+                &lib,
+                &queries,
+                &Filter::default(),
+                None,
+                Disposal::Delete,
+                true,
+            )
+        };
+
+        let sync = store::lock(dest.path()).unwrap();
+        assert!(remove().is_err());
+        assert!(dest.path().join("a").exists());
+        drop(sync);
+        assert_eq!(remove().unwrap(), EXIT_SUCCESS);
+        assert!(Store::open_read_only(dest.path()).get("a").is_none());
+    }
+
+    #[test]
+    fn writing_commands_wait_for_sync() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [{
+                    "id": "b",
+                    "user_id": "user",
+                    "title": "title",
+                    "artist": "artist",
+                    "uploaded_at": "2023-09-02 00:00:00",
+                    "updated_at": "2023-09-02 00:00:00",
+                }],
+                "links": { "next": null },
+            }));
+        });
+        let dest = tempdir().unwrap();
+        let song: Song = serde_json::from_value(json!({
+            "id": "a",
+            "user_id": "user",
+            "title": "title",
+            "artist": "artist",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+        }))
+        .unwrap();
+        fs::create_dir(dest.path().join("a")).unwrap();
+        fs::write(dest.path().join("a/chart.ksh"), "title=title\n--\n").unwrap();
+        Store::open(dest.path())
+            .insert("a", &Entry::new(&song, "a"))
+            .unwrap();
+        let outside = tempdir().unwrap();
+        let state = outside.path().join("state.json");
+        let state_arg = state.to_str().unwrap();
+        let dest_arg = dest.path().to_str().unwrap();
+        let parse = |command: &[&str]| {
+            let dest = ["--dest", dest_arg];
+            // Tag changes take the arguments after them.
+            let dest = if command.contains(&"--dest") {
+                &[][..]
+            } else {
+                &dest
+            };
+            Args::try_parse_from(["nautica-downloader-rs"].iter().chain(command).chain(dest))
+                .unwrap()
+        };
+        run(parse(&["state", "export", "--output", state_arg])).unwrap();
+        let meta = fs::read(dest.path().join("meta.json")).unwrap();
+
+        let base_url = server.base_url();
+        let sync = store::lock(dest.path()).unwrap();
+        for command in [
+            &["sync", "--yes", "--base-url", &base_url][..],
+            &["remove", "a", "--yes"],
+            &["tag", "--dest", dest_arg, "a", "+stamina"],
+            &["star", "a"],
+            &["unstar", "a"],
+            &[
+                "import",
+                "tests/fixtures/89b54d80-4e6d-11ee-83d4-2ffdf82667a6.zip",
+            ],
+            &["state", "import", state_arg],
+            &["loudness", "--all"],
+            &["reorganize", "--layout", "readable"],
+            &["upload", "--remote", "webdav://127.0.0.1:9/charts"],
+            &["cas", "store"],
+            &["cas", "prune"],
+        ] {
+            let error = format!("{:#}", run(parse(command)).unwrap_err());
+            assert!(error.contains("Another sync"), "{command:?}: {error}");
+        }
+        drop(sync);
+        assert_eq!(fs::read(dest.path().join("meta.json")).unwrap(), meta);
+        assert!(dest.path().join("a/chart.ksh").exists());
+    }
+
+    #[test]
+    fn read_only_commands_leave_dest_untouched() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/app/songs");
+            then.status(200).json_body(json!({
+                "data": [{
+                    "id": "b",
+                    "user_id": "user",
+                    "title": "title",
+                    "artist": "artist",
+                    "uploaded_at": "2023-09-02 00:00:00",
+                    "updated_at": "2023-09-02 00:00:00",
+                }],
+                "links": { "next": null },
+            }));
+        });
+        let dest = tempdir().unwrap();
+        let song: Song = serde_json::from_value(json!({
+            "id": "a",
+            "user_id": "user",
+            "title": "title",
+            "artist": "artist",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+        }))
+        .unwrap();
+        fs::create_dir(dest.path().join("a")).unwrap();
+        fs::write(dest.path().join("a/chart.ksh"), "title=title\n--\n").unwrap();
+        Store::open(dest.path())
+            .insert("a", &Entry::new(&song, "a"))
+            .unwrap();
+        fn walk(dir: &Path, files: &mut Vec<(PathBuf, Vec<u8>)>) {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    files.push((path.clone(), Vec::new()));
+                    walk(&path, files);
+                } else {
+                    files.push((path.clone(), fs::read(&path).unwrap()));
+                }
+            }
+        }
+        let snapshot = || {
+            let mut snapshot = Vec::new();
+            walk(dest.path(), &mut snapshot);
+            snapshot.sort();
+            snapshot
+        };
+        let before = snapshot();
+
+        let outside = tempdir().unwrap();
+        let report = outside.path().join("report.txt");
+        let dest_arg = dest.path().to_str().unwrap();
+        let report_arg = report.to_str().unwrap();
+        let base_url = server.base_url();
+        for command in [
+            &["diff", "--base-url", &base_url][..],
+            &["list"],
+            &["stats"],
+            &["cas", "verify"],
+        ] {
+            let args =
+                Args::try_parse_from(["nautica-downloader-rs"].iter().chain(command).chain(&[
+                    "--dest",
+                    dest_arg,
+                    "--read-only",
+                    "--report",
+                    report_arg,
+                ]))
+                .unwrap();
+            assert_eq!(run(args).unwrap(), EXIT_SUCCESS, "{command:?}");
+        }
+        assert_eq!(snapshot(), before);
+
+        // Syncing would change the library.
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(base_url)
+            .read_only(true)
+            .build();
+        assert!(downloader.download_all().is_err());
+        assert_eq!(snapshot(), before);
+    }
+
+    #[test]
+    fn keep_read_only_report_outside_dest() {
+        let dest = tempdir().unwrap();
+        let outside = tempdir().unwrap();
+        let inside = dest.path().join("diff.txt");
+        let args = ReportArgs {
+            read_only: true,
+            report: Some(inside.clone()),
+        };
+        assert!(args.output(dest.path()).is_err());
+        assert!(!inside.exists());
+
+        let args = ReportArgs {
+            read_only: true,
+            report: Some(outside.path().join("diff.txt")),
+        };
+        writeln!(args.output(dest.path()).unwrap(), "+ a").unwrap();
+        assert_eq!(
+            fs::read_to_string(outside.path().join("diff.txt")).unwrap(),
+            "+ a\n"
+        );
+        assert_eq!(fs::read_dir(dest.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::anyhow;
+use chrono::Utc;
+use tiny_http::Header;
+use tiny_http::Method;
+use tiny_http::Response;
+use tiny_http::Server;
+use tracing::warn;
+
+use crate::notify::Notifier;
+use crate::DownloadReport;
+use crate::Song;
+
+/// Counters of a long-running watcher, exposed in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    downloaded: AtomicU64,
+    failed: AtomicU64,
+    bytes: AtomicU64,
+    runs: AtomicU64,
+
+    /// Songs left in the current run.
+    queued: AtomicU64,
+
+    /// Unix time of the end of the last sync, zero before the first one.
+    last_success: AtomicU64,
+}
+
+impl Metrics {
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "nautica_songs_downloaded_total",
+                "counter",
+                "Songs downloaded.",
+                &self.downloaded,
+            ),
+            (
+                "nautica_songs_failed_total",
+                "counter",
+                "Songs that failed to download.",
+                &self.failed,
+            ),
+            (
+                "nautica_downloaded_bytes_total",
+                "counter",
+                "Size of the downloaded archives.",
+                &self.bytes,
+            ),
+            (
+                "nautica_runs_total",
+                "counter",
+                "Syncs that ran to the end.",
+                &self.runs,
+            ),
+            (
+                "nautica_queue_depth",
+                "gauge",
+                "Songs left to download in the current sync.",
+                &self.queued,
+            ),
+            (
+                "nautica_last_success_timestamp_seconds",
+                "gauge",
+                "Unix time at which the last sync finished.",
+                &self.last_success,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            let value = value.load(Ordering::Relaxed);
+            let _ = write!(
+                text,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        }
+        text
+    }
+
+    /// Serves the metrics at `/metrics` on `addr` from a background thread,
+    /// returning the address it listens on.
+    pub fn serve(self: &Arc<Self>, addr: impl ToSocketAddrs) -> anyhow::Result<SocketAddr> {
+        let server = Server::http(addr).map_err(|e| anyhow!(e))?;
+        let local_addr = server
+            .server_addr()
+            .to_ip()
+            .ok_or_else(|| anyhow!("Not listening on an IP address"))?;
+        let metrics = Arc::clone(self);
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = if request.method() == &Method::Get && request.url() == "/metrics" {
+                    let content_type =
+                        Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+                            .expect("valid header");
+                    Response::from_string(metrics.render()).with_header(content_type)
+                } else {
+                    Response::from_string("Not Found").with_status_code(404)
+                };
+                if let Err(e) = request.respond(response) {
+                    warn!(error = %e, "Failed to respond to a metrics request");
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+}
+
+/// Updates [`Metrics`] as syncs progress.
+#[derive(Debug)]
+pub struct MetricsNotifier {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsNotifier {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics }
+    }
+
+    fn dequeue(&self) {
+        let _ = self
+            .metrics
+            .queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+}
+
+impl Notifier for MetricsNotifier {
+    fn run_started(&self, total: usize) -> anyhow::Result<()> {
+        self.metrics.queued.store(total as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn song_downloaded(&self, _song: &Song, _path: &Path) -> anyhow::Result<()> {
+        self.metrics.downloaded.fetch_add(1, Ordering::Relaxed);
+        self.dequeue();
+        Ok(())
+    }
+
+    fn song_failed(&self, _song: &Song) -> anyhow::Result<()> {
+        self.metrics.failed.fetch_add(1, Ordering::Relaxed);
+        self.dequeue();
+        Ok(())
+    }
+
+    fn run_finished(&self, report: &DownloadReport) -> anyhow::Result<()> {
+        let metrics = &self.metrics;
+        metrics.bytes.fetch_add(report.bytes, Ordering::Relaxed);
+        metrics.runs.fetch_add(1, Ordering::Relaxed);
+        metrics.queued.store(0, Ordering::Relaxed);
+        if !report.cancelled {
+            let now = Utc::now().timestamp().max(0) as u64;
+            metrics.last_success.store(now, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serve_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        let notifier = MetricsNotifier::new(Arc::clone(&metrics));
+        notifier.run_started(2).unwrap();
+        notifier.dequeue();
+        assert!(metrics
+            .render()
+            .contains("# TYPE nautica_queue_depth gauge\nnautica_queue_depth 1\n"));
+        notifier
+            .run_finished(&DownloadReport {
+                bytes: 1024,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let addr = metrics.serve("127.0.0.1:0").unwrap();
+        let text = attohttpc::get(format!("http://{addr}/metrics"))
+            .send()
+            .unwrap()
+            .text()
+            .unwrap();
+        assert!(text.contains("nautica_downloaded_bytes_total 1024\n"));
+        assert!(text.contains("nautica_queue_depth 0\n"));
+        assert!(!text.contains("nautica_last_success_timestamp_seconds 0\n"));
+        let resp = attohttpc::get(format!("http://{addr}/")).send().unwrap();
+        assert_eq!(resp.status(), 404);
+    }
+}
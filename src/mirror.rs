@@ -0,0 +1,405 @@
+use std::fs;
+use std::io::Cursor;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::anyhow;
+use serde_json::json;
+use serde_json::Value;
+use tiny_http::Header;
+use tiny_http::Method;
+use tiny_http::Request;
+use tiny_http::Response;
+use tiny_http::Server;
+use tracing::info;
+use tracing::warn;
+use url::form_urlencoded;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use crate::dedup;
+use crate::filter::Filter;
+use crate::jackets;
+use crate::store::Entry;
+use crate::store::Store;
+
+/// Songs per page when the client does not ask for a page size.
+const DEFAULT_PER_PAGE: usize = 10;
+
+/// Largest page size a client can ask for.
+const MAX_PER_PAGE: usize = 100;
+
+/// Number of requests handled at once, so that one large download does not
+/// hold up song listings.
+const WORKERS: usize = 4;
+
+const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Serves the local library over HTTP with the same endpoints as Nautica
+/// (`/app/songs` and `/songs/{id}/download`), so that the downloader and other
+/// tools can sync from it instead of the internet.
+pub struct Mirror {
+    dest: PathBuf,
+    server: Server,
+}
+
+impl Mirror {
+    /// Listens on `addr` for the library `dest`.
+    pub fn bind(dest: &Path, addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let server = Server::http(addr).map_err(|e| anyhow!(e))?;
+        Ok(Self {
+            dest: dest.to_owned(),
+            server,
+        })
+    }
+
+    /// Returns the address the mirror is listening on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Handles requests until the process exits.
+    pub fn run(self) {
+        let mirror = Arc::new(self);
+        let workers: Vec<_> = (0..WORKERS)
+            .map(|_| {
+                let mirror = Arc::clone(&mirror);
+                thread::spawn(move || {
+                    while let Ok(request) = mirror.server.recv() {
+                        mirror.handle(request);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+
+    fn handle(&self, request: Request) {
+        let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+        let params: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        info!(method = %request.method(), url = request.url(), "Request");
+
+        let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+        let result = match (request.method(), segments.as_slice()) {
+            (Method::Get, ["app", "songs"]) => {
+                let base_url = base_url(&request, self.local_addr());
+                let page = self.songs_page(&base_url, &params);
+                Ok(json_response(&page))
+            }
+            (Method::Get, ["songs", id, "download"]) => self.archive(id, false),
+            (Method::Head, ["songs", id, "download"]) => self.archive(id, true),
+            _ => Err(404),
+        };
+        let response = match result {
+            Ok(response) => request.respond(response),
+            Err(status) => request.respond(Response::empty(status)),
+        };
+        if let Err(e) = response {
+            warn!(error = %e, "Failed to respond");
+        }
+    }
+
+    /// Lists the songs in the library like Nautica does, newest upload first
+    /// unless `sort` says otherwise, filtered by `q` and paginated by `page`
+    /// and `per_page`.
+    fn songs_page(&self, base_url: &str, params: &[(String, String)]) -> Value {
+        let param = |key| {
+            params
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        let filter = Filter {
+            query: param("q").map(str::to_owned),
+            ..Default::default()
+        };
+        let mut songs: Vec<_> = Store::open_read_only(&self.dest)
+            .entries()
+            .into_iter()
+            .filter(|(id, entry)| filter.matches_entry(id, entry))
+            .collect();
+        match param("sort") {
+            Some("title") => songs.sort_by(|(_, a), (_, b)| a.title.cmp(&b.title)),
+            Some("artist") => songs.sort_by(|(_, a), (_, b)| a.artist.cmp(&b.artist)),
+            // Download counts are not known locally.
+            _ => songs.sort_by_key(|(_, entry)| std::cmp::Reverse(uploaded_at(entry))),
+        }
+
+        let per_page = param("per_page")
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE);
+        let total = songs.len();
+        let last_page = total.div_ceil(per_page).max(1);
+        let page = param("page")
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(1)
+            .clamp(1, last_page);
+        let page_url = |page: usize| {
+            let mut query = form_urlencoded::Serializer::new(String::new());
+            for (key, value) in params.iter().filter(|(key, _)| key != "page") {
+                query.append_pair(key, value);
+            }
+            query.append_pair("page", &page.to_string());
+            format!("{base_url}/app/songs?{}", query.finish())
+        };
+        let data: Vec<_> = songs
+            .iter()
+            .skip((page - 1) * per_page)
+            .take(per_page)
+            .map(|(id, entry)| song_json(id, entry))
+            .collect();
+        json!({
+            "data": data,
+            "links": {
+                "first": page_url(1),
+                "last": page_url(last_page),
+                "prev": (page > 1).then(|| page_url(page - 1)),
+                "next": (page < last_page).then(|| page_url(page + 1)),
+            },
+            "meta": {
+                "current_page": page,
+                "last_page": last_page,
+                "path": format!("{base_url}/app/songs"),
+                "per_page": per_page,
+                "total": total,
+            },
+        })
+    }
+
+    /// Zips the files of the song `id`, or only answers whether it exists
+    /// for a `head` request, without a length since the archive is not built.
+    fn archive(&self, id: &str, head: bool) -> Result<Response<Cursor<Vec<u8>>>, u16> {
+        let store = Store::open_read_only(&self.dest);
+        let entry = store.get(id).filter(Entry::is_kept);
+        let dir = self.dest.join(entry.ok_or(404_u16)?.dir(id));
+        let disposition = format!("attachment; filename=\"{id}.zip\"");
+        let headers = vec![
+            header("Content-Type", "application/zip"),
+            header("Content-Disposition", &disposition),
+        ];
+        if head {
+            if !dir.is_dir() {
+                return Err(404);
+            }
+            return Ok(Response::new(
+                200.into(),
+                headers,
+                Cursor::new(Vec::new()),
+                None,
+                None,
+            ));
+        }
+        let bytes = zip_dir(&dir).map_err(|e| {
+            warn!(id, error = %e, "Failed to archive song");
+            500_u16
+        })?;
+        Ok(headers
+            .into_iter()
+            .fold(Response::from_data(bytes), Response::with_header))
+    }
+}
+
+/// Returns the URL clients reached the mirror at, for pagination links.
+fn base_url(request: &Request, addr: Option<SocketAddr>) -> String {
+    let host = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Host"))
+        .map(|h| h.value.to_string())
+        .or_else(|| addr.map(|addr| addr.to_string()))
+        .unwrap_or_else(|| String::from("localhost"));
+    format!("http://{host}")
+}
+
+/// Songs downloaded by older versions have no upload time recorded.
+fn uploaded_at(entry: &Entry) -> chrono::DateTime<chrono::Utc> {
+    entry.uploaded_at.unwrap_or(entry.downloaded_at)
+}
+
+fn song_json(id: &str, entry: &Entry) -> Value {
+    let uploaded_at = uploaded_at(entry);
+    // Songs downloaded by older versions have no update time recorded.
+    let updated_at = entry.updated_at.unwrap_or(uploaded_at);
+    // Songs downloaded by older versions only have their levels.
+    let charts: Vec<_> = if entry.charts.is_empty() {
+        entry
+            .levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| json!({ "difficulty": i + 1, "level": level }))
+            .collect()
+    } else {
+        entry.charts.iter().map(|chart| json!(chart)).collect()
+    };
+    json!({
+        "id": id,
+        "user_id": entry.user_id,
+        "title": entry.title,
+        "artist": entry.artist,
+        "uploaded_at": uploaded_at.format(DATETIME_FORMAT).to_string(),
+        "updated_at": updated_at.format(DATETIME_FORMAT).to_string(),
+        "user": entry.user_name.as_ref().map(|name| json!({
+            "id": entry.user_id,
+            "name": name,
+        })),
+        "charts": charts,
+    })
+}
+
+/// Zips the song in `dir` as the server archives it: its charts, audio, and
+/// images, without the files this tool keeps next to them, such as import
+/// sidecars and CAS manifests.
+fn zip_dir(dir: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut files: Vec<_> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    files.sort();
+    for path in files
+        .iter()
+        .filter(|path| path.is_file() && is_song_file(path))
+    {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        zip.start_file(name, FileOptions::default())?;
+        zip.write_all(&fs::read(path)?)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+fn is_song_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase)
+        .is_some_and(|ext| {
+            ext == "ksh"
+                || dedup::AUDIO_EXTENSIONS.contains(&ext.as_str())
+                || jackets::EXTENSIONS.contains(&ext.as_str())
+        })
+}
+
+fn json_response(value: &Value) -> Response<Cursor<Vec<u8>>> {
+    Response::from_data(value.to_string()).with_header(header("Content-Type", "application/json"))
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field, value).expect("header is valid ASCII")
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+    use chrono::Utc;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::cas;
+    use crate::import;
+    use crate::Downloader;
+
+    fn entry(title: &str, uploaded_at: &str) -> Entry {
+        Entry {
+            downloaded_at: Utc::now(),
+            title: title.to_owned(),
+            artist: String::from("artist"),
+            user_id: String::from("user"),
+            user_name: Some(String::from("Ixiot")),
+            levels: vec![16, 18],
+            uploaded_at: Some(Utc.from_utc_datetime(
+                &chrono::NaiveDateTime::parse_from_str(uploaded_at, DATETIME_FORMAT).unwrap(),
+            )),
+            ..Default::default()
+        }
+    }
+
+    /// Returns a WAV file of a second of silence, which passes validation.
+    fn wav() -> Vec<u8> {
+        let mut wav = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0".to_vec();
+        // PCM, 1 channel, 8000 Hz, 8000 bytes per second.
+        wav.extend([1, 0, 1, 0]);
+        wav.extend(8000u32.to_le_bytes());
+        wav.extend(8000u32.to_le_bytes());
+        wav.extend([1, 0, 8, 0]);
+        wav.extend(b"data");
+        wav.extend(8000u32.to_le_bytes());
+        wav.extend(vec![0x80; 8000]);
+        wav
+    }
+
+    #[test]
+    fn sync_from_mirror() {
+        let library = tempdir().unwrap();
+        let mut store = Store::open(library.path());
+        for (id, title, uploaded_at) in [
+            ("a", "Old", "2023-09-01 00:00:00"),
+            ("b", "New", "2023-09-02 00:00:00"),
+            ("c", "Newest", "2023-09-03 00:00:00"),
+        ] {
+            store.insert(id, &entry(title, uploaded_at)).unwrap();
+            fs::create_dir(library.path().join(id)).unwrap();
+            let chart = format!("title={title}\r\nm=music.wav\r\n--\r\n");
+            fs::write(library.path().join(id).join("chart.ksh"), chart).unwrap();
+            fs::write(library.path().join(id).join("music.wav"), wav()).unwrap();
+            fs::write(library.path().join(id).join(import::SIDECAR_FILENAME), "{}").unwrap();
+            fs::write(library.path().join(id).join(cas::MANIFEST_FILENAME), "{}").unwrap();
+        }
+        let mut updated = store.get("b").unwrap();
+        updated.updated_at = Some(Utc.with_ymd_and_hms(2023, 9, 5, 0, 0, 0).unwrap());
+        store.insert("b", &updated).unwrap();
+
+        let mirror = Mirror::bind(library.path(), "127.0.0.1:0").unwrap();
+        let addr = mirror.local_addr().unwrap();
+        thread::spawn(move || mirror.run());
+
+        let dest = tempdir().unwrap();
+        let downloader = Downloader::builder()
+            .dest(dest.path())
+            .base_url(format!("http://{addr}"))
+            .per_page(2)
+            .build();
+        let songs: Vec<_> = downloader.catalog().map(|song| song.unwrap()).collect();
+        let titles: Vec<_> = songs.iter().map(|song| song.title.as_str()).collect();
+        assert_eq!(titles, ["Newest", "New", "Old"]);
+        assert_eq!(songs[1].updated_at.to_string(), "2023-09-05 00:00:00 UTC");
+        assert_eq!(songs[2].updated_at, songs[2].uploaded_at);
+
+        let report = downloader.download_all().unwrap();
+        assert_eq!(report.downloaded.len(), 3);
+        assert!(fs::read_to_string(dest.path().join("a/chart.ksh"))
+            .unwrap()
+            .starts_with("title=Old"));
+        // Only the song itself is served, not what the library keeps with it.
+        let mut files: Vec<_> = fs::read_dir(dest.path().join("a"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["chart.ksh", "music.wav"]);
+        let entry = Store::open_read_only(dest.path()).get("b").unwrap();
+        assert_eq!(entry.levels, [16, 18]);
+        assert_eq!(entry.uploader(), "Ixiot");
+
+        let missing = attohttpc::get(format!("http://{addr}/songs/missing/download"))
+            .send()
+            .unwrap();
+        assert_eq!(missing.status(), 404);
+        let head = attohttpc::head(format!("http://{addr}/songs/a/download"))
+            .send()
+            .unwrap();
+        assert_eq!(head.status(), 200);
+        assert_eq!(head.headers()["Content-Type"], "application/zip");
+        let missing = attohttpc::head(format!("http://{addr}/songs/missing/download"))
+            .send()
+            .unwrap();
+        assert_eq!(missing.status(), 404);
+    }
+}
@@ -0,0 +1,514 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use attohttpc::Session;
+use attohttpc::StatusCode;
+use notify_rust::Notification;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::api::Routes;
+use crate::thumbnail::ThumbnailCache;
+use crate::Chart;
+use crate::DownloadReport;
+use crate::Song;
+use crate::NAUTICA_BASE_URL;
+
+/// Receives events from a [`Downloader`](crate::Downloader).
+///
+/// Errors returned by a notifier are logged and never abort a sync.
+pub trait Notifier: fmt::Debug + Send + Sync {
+    /// Called before a sync starts downloading `total` songs.
+    fn run_started(&self, _total: usize) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called after a song has been downloaded to `path`.
+    fn song_downloaded(&self, song: &Song, path: &Path) -> anyhow::Result<()>;
+
+    /// Called after a song failed to download.
+    fn song_failed(&self, _song: &Song) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called after a sync has finished.
+    fn run_finished(&self, _report: &DownloadReport) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Shows a native desktop notification for each downloaded song.
+#[derive(Debug, Default)]
+pub struct DesktopNotifier {
+    thumbnails: Option<ThumbnailCache>,
+}
+
+impl DesktopNotifier {
+    /// Shows the thumbnails of the jackets from `thumbnails` where they were
+    /// made, instead of the full-size jackets.
+    pub fn thumbnails(mut self, thumbnails: ThumbnailCache) -> Self {
+        self.thumbnails = Some(thumbnails);
+        self
+    }
+}
+
+impl Notifier for DesktopNotifier {
+    fn song_downloaded(&self, song: &Song, path: &Path) -> anyhow::Result<()> {
+        let mut notification = Notification::new();
+        notification
+            .summary(&song.title)
+            .body(&song.artist)
+            .appname(env!("CARGO_PKG_NAME"));
+        #[cfg(not(target_os = "macos"))]
+        let thumbnail = self
+            .thumbnails
+            .as_ref()
+            .and_then(|thumbnails| thumbnails.get(&song.id));
+        if let Some(jacket) = thumbnail.or_else(|| find_jacket(path)) {
+            notification.image_path(&jacket.to_string_lossy());
+        }
+        notification.show()?;
+        Ok(())
+    }
+}
+
+/// Kinds of events a [`WebhookNotifier`] can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    SongDownloaded,
+    RunFinished,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum WebhookPayload<'a> {
+    SongDownloaded {
+        song: &'a Song,
+        path: &'a Path,
+    },
+    RunFinished {
+        downloaded: Vec<&'a str>,
+        failed: Vec<&'a str>,
+    },
+}
+
+/// POSTs a JSON payload to a URL for each subscribed event.
+#[derive(Debug)]
+pub struct WebhookNotifier {
+    url: String,
+    events: Vec<Event>,
+    sess: Session,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: String, events: Vec<Event>) -> Self {
+        Self {
+            url,
+            events,
+            sess: Session::new(),
+        }
+    }
+
+    fn post(&self, event: Event, payload: &WebhookPayload) -> anyhow::Result<()> {
+        if !self.events.contains(&event) {
+            return Ok(());
+        }
+        self.sess
+            .post(&self.url)
+            .json(payload)?
+            .send()?
+            .error_for_status()
+            .with_context(|| format!("Webhook {} failed", self.url))?;
+        Ok(())
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn song_downloaded(&self, song: &Song, path: &Path) -> anyhow::Result<()> {
+        self.post(
+            Event::SongDownloaded,
+            &WebhookPayload::SongDownloaded { song, path },
+        )
+    }
+
+    fn run_finished(&self, report: &DownloadReport) -> anyhow::Result<()> {
+        self.post(
+            Event::RunFinished,
+            &WebhookPayload::RunFinished {
+                downloaded: report.downloaded.iter().map(|s| s.id.as_str()).collect(),
+                failed: report.failed.iter().map(|s| s.id.as_str()).collect(),
+            },
+        )
+    }
+}
+
+/// Maximum number of embeds Discord accepts in one message.
+const DISCORD_MAX_EMBEDS: usize = 10;
+
+/// Times a Discord message is retried while the webhook is rate limited.
+const DISCORD_MAX_ATTEMPTS: u32 = 5;
+
+/// Posts an embed per downloaded song to a Discord webhook, with the title,
+/// artist, levels, and jacket of the song and a link to it.
+///
+/// Embeds are batched into messages of up to 10, sent once a batch is full
+/// and at the end of the run, to stay within Discord's rate limits.
+#[derive(Debug)]
+pub struct DiscordNotifier {
+    url: String,
+    batch: usize,
+    base_url: String,
+    routes: Routes,
+    pending: Mutex<Vec<Value>>,
+    sess: Session,
+}
+
+impl DiscordNotifier {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            batch: DISCORD_MAX_EMBEDS,
+            base_url: String::from(NAUTICA_BASE_URL),
+            routes: Routes::default(),
+            pending: Mutex::new(Vec::new()),
+            sess: Session::new(),
+        }
+    }
+
+    /// Sets the number of songs per message, from 1 to 10.
+    pub fn batch(mut self, batch: usize) -> Self {
+        self.batch = batch.clamp(1, DISCORD_MAX_EMBEDS);
+        self
+    }
+
+    /// Sets the server the embeds link the songs to.
+    pub fn base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Sets the routes of the API, for servers that differ from Nautica.
+    pub fn routes(mut self, routes: Routes) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    fn embed(&self, song: &Song) -> Value {
+        let levels: Vec<_> = song.charts.iter().map(Chart::to_string).collect();
+        let uploader = song.user.as_ref().map_or(&song.user_id, |user| &user.name);
+        let mut embed = json!({
+            "title": song.title,
+            "description": song.artist,
+            "url": format!("{}{}", self.base_url, self.routes.download_path(&song.id)),
+            "author": { "name": uploader },
+            "timestamp": song.uploaded_at,
+        });
+        if !levels.is_empty() {
+            embed["fields"] = json!([{ "name": "Levels", "value": levels.join(" / ") }]);
+        }
+        if let Some(jacket_url) = &song.jacket_url {
+            embed["thumbnail"] = json!({ "url": jacket_url });
+        }
+        embed
+    }
+
+    /// Sends the pending embeds, `batch` at a time.
+    fn flush(&self, pending: &mut Vec<Value>) -> anyhow::Result<()> {
+        for embeds in pending.chunks(self.batch) {
+            self.post(embeds)?;
+        }
+        pending.clear();
+        Ok(())
+    }
+
+    fn post(&self, embeds: &[Value]) -> anyhow::Result<()> {
+        for _ in 0..DISCORD_MAX_ATTEMPTS {
+            let resp = self
+                .sess
+                .post(&self.url)
+                .json(&json!({ "embeds": embeds }))?
+                .send()?;
+            if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+                // Discord tells how long to wait, in seconds.
+                let retry_after = resp
+                    .json::<Value>()
+                    .ok()
+                    .and_then(|body| body["retry_after"].as_f64())
+                    .unwrap_or(1.0);
+                thread::sleep(Duration::from_secs_f64(retry_after.clamp(0.0, 60.0)));
+                continue;
+            }
+            // The URL is left out of errors since it contains the webhook's
+            // token.
+            resp.error_for_status().context("Discord webhook failed")?;
+            return Ok(());
+        }
+        bail!("Discord webhook kept rate limiting");
+    }
+}
+
+impl Notifier for DiscordNotifier {
+    fn song_downloaded(&self, song: &Song, _path: &Path) -> anyhow::Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.push(self.embed(song));
+        if pending.len() >= self.batch {
+            self.flush(&mut pending)?;
+        }
+        Ok(())
+    }
+
+    fn run_finished(&self, _report: &DownloadReport) -> anyhow::Result<()> {
+        self.flush(&mut self.pending.lock().unwrap())
+    }
+}
+
+/// Runs a shell command after each downloaded song.
+///
+/// The command receives the song in the `NAUTICA_SONG_ID`,
+/// `NAUTICA_SONG_TITLE`, `NAUTICA_SONG_ARTIST`, and `NAUTICA_SONG_PATH`
+/// environment variables. On Windows it runs without `cmd`, which would expand
+/// them into the command line.
+#[derive(Debug)]
+pub struct HookNotifier {
+    command: String,
+}
+
+impl HookNotifier {
+    pub fn new(command: String) -> Self {
+        Self { command }
+    }
+}
+
+impl Notifier for HookNotifier {
+    fn song_downloaded(&self, song: &Song, path: &Path) -> anyhow::Result<()> {
+        let status = shell(&self.command)
+            .env("NAUTICA_SONG_ID", &song.id)
+            .env("NAUTICA_SONG_TITLE", &song.title)
+            .env("NAUTICA_SONG_ARTIST", &song.artist)
+            .env("NAUTICA_SONG_PATH", path)
+            .status()
+            .with_context(|| format!("Failed to run hook {:?}", self.command))?;
+        ensure!(
+            status.success(),
+            "Hook {:?} exited with {status}",
+            self.command
+        );
+        Ok(())
+    }
+}
+
+/// Returns a command that runs `command` with `sh`, or on Windows directly.
+///
+/// `cmd` would expand `%VARIABLES%` into the command line before parsing it,
+/// so that a song titled `a & del ...` could run a command of its own. Words
+/// are separated by spaces there instead, and double quotes group them.
+pub(crate) fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut words = split_words(command).into_iter();
+        let mut program = Command::new(words.next().unwrap_or_default());
+        program.args(words);
+        program
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+}
+
+/// Splits `command` at spaces outside double quotes, dropping the quotes.
+fn split_words(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    for c in command.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// Finds the jacket image of the song in `dir`, preferring files named like a
+/// jacket over other images.
+pub(crate) fn find_jacket(dir: &Path) -> Option<PathBuf> {
+    let mut images: Vec<_> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| {
+                    ["png", "jpg", "jpeg"].contains(&ext.to_ascii_lowercase().as_str())
+                })
+        })
+        .collect();
+    images.sort_by_key(|path| {
+        let name = path.file_name().unwrap().to_string_lossy().to_lowercase();
+        (!name.contains("jacket"), name)
+    });
+    images.into_iter().next()
+}
+
+#[cfg(test)]
+mod test {
+    use httpmock::Method::POST;
+    use httpmock::MockServer;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn find_jacket_prefers_jacket_name() {
+        let dir = tempdir().unwrap();
+        assert_eq!(find_jacket(dir.path()), None);
+
+        fs::write(dir.path().join("chart.png"), "").unwrap();
+        fs::write(dir.path().join("song.ksh"), "").unwrap();
+        assert_eq!(find_jacket(dir.path()), Some(dir.path().join("chart.png")));
+
+        fs::write(dir.path().join("Outbreak-jacket.PNG"), "").unwrap();
+        assert_eq!(
+            find_jacket(dir.path()),
+            Some(dir.path().join("Outbreak-jacket.PNG"))
+        );
+    }
+
+    #[test]
+    fn webhook_posts_subscribed_events() {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.method(POST).path("/hook").json_body(json!({
+                "event": "run_finished",
+                "downloaded": [],
+                "failed": [],
+            }));
+            then.status(204);
+        });
+
+        let notifier = WebhookNotifier::new(server.url("/hook"), vec![Event::RunFinished]);
+        notifier.run_finished(&DownloadReport::default()).unwrap();
+        m.assert();
+
+        let notifier = WebhookNotifier::new(server.url("/hook"), vec![Event::SongDownloaded]);
+        notifier.run_finished(&DownloadReport::default()).unwrap();
+        m.assert_hits(1);
+    }
+
+    #[test]
+    fn discord_batches_embeds() {
+        let server = MockServer::start();
+        let song = |id: &str| -> Song {
+            serde_json::from_value(json!({
+                "id": id,
+                "user_id": "user",
+                "title": "Outbreak",
+                "artist": "RG+Ice",
+                "uploaded_at": "2023-09-07 05:56:46",
+                "updated_at": "2023-09-07 15:05:04",
+                "user": { "id": "user", "name": "Ixiot" },
+                "jacket_url": "https://ksm.dev/jackets/5441d590.png",
+                "charts": [{ "difficulty": 3, "level": 16 }, { "difficulty": 4, "level": 18 }],
+            }))
+            .unwrap()
+        };
+        let full = server.mock(|when, then| {
+            when.method(POST)
+                .path("/discord")
+                .body_contains("/songs/a/download")
+                .body_contains("/songs/b/download");
+            then.status(204);
+        });
+        let rest = server.mock(|when, then| {
+            when.method(POST)
+                .path("/discord")
+                .body_contains("/songs/c/download");
+            then.status(204);
+        });
+
+        let notifier = DiscordNotifier::new(server.url("/discord"))
+            .batch(2)
+            .base_url("http://mirror");
+        let embed = notifier.embed(&song("5441d590"));
+        assert_eq!(embed["url"], "http://mirror/songs/5441d590/download");
+        assert_eq!(embed["author"]["name"], "Ixiot");
+        assert_eq!(embed["fields"][0]["value"], "EXH16 / INF18");
+        assert_eq!(
+            embed["thumbnail"]["url"],
+            "https://ksm.dev/jackets/5441d590.png"
+        );
+
+        for id in ["a", "b", "c"] {
+            notifier.song_downloaded(&song(id), Path::new(id)).unwrap();
+        }
+        full.assert();
+        rest.assert_hits(0);
+        notifier.run_finished(&DownloadReport::default()).unwrap();
+        rest.assert();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hook_receives_song_env() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out");
+        let song: Song = serde_json::from_value(json!({
+            "id": "5441d590",
+            "user_id": "user",
+            "title": "Outbreak",
+            "artist": "RG+Ice",
+            "uploaded_at": "2023-09-07 05:56:46",
+            "updated_at": "2023-09-07 15:05:04",
+        }))
+        .unwrap();
+
+        let notifier = HookNotifier::new(format!(
+            "echo \"$NAUTICA_SONG_ID $NAUTICA_SONG_TITLE $NAUTICA_SONG_ARTIST $NAUTICA_SONG_PATH\" > {}",
+            out.display()
+        ));
+        notifier
+            .song_downloaded(&song, Path::new("/songs/5441d590"))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "5441d590 Outbreak RG+Ice /songs/5441d590\n"
+        );
+
+        let notifier = HookNotifier::new(String::from("exit 3"));
+        assert!(notifier
+            .song_downloaded(&song, Path::new("/songs/5441d590"))
+            .is_err());
+    }
+
+    #[test]
+    fn split_hook_into_words() {
+        assert_eq!(
+            split_words(r#"C:\tools\refresh.exe --db "C:\Program Files\usc" "" %A%"#),
+            [
+                r"C:\tools\refresh.exe",
+                "--db",
+                r"C:\Program Files\usc",
+                "",
+                "%A%"
+            ]
+        );
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use attohttpc::Session;
+use serde_json::json;
+use serde_json::Value;
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::span;
+use tracing::warn;
+use tracing::Event;
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// How often finished spans are sent to the collector.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Spans after which a batch is sent without waiting for the interval.
+const MAX_BATCH: usize = 512;
+
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// OTLP span kind of spans that are neither requests to nor from a server.
+const SPAN_KIND_INTERNAL: u8 = 1;
+
+/// Tracing layer exporting spans to an OpenTelemetry collector over OTLP/HTTP
+/// with JSON encoding, which Jaeger, Grafana Tempo, and the OpenTelemetry
+/// Collector accept. Events inside a span are exported as its span events.
+pub struct OtlpLayer {
+    sender: Sender<Message>,
+}
+
+/// Sends the spans that are left when dropped. Keep it alive until the end of
+/// `main`.
+pub struct OtlpGuard {
+    sender: Sender<Message>,
+    exporter: Option<JoinHandle<()>>,
+}
+
+enum Message {
+    Span(SpanData),
+    Shutdown,
+}
+
+impl OtlpLayer {
+    /// Exports spans of the service `service_name` to the traces endpoint
+    /// `url`, e.g. `http://localhost:4318/v1/traces`, from a background
+    /// thread.
+    pub fn new(url: String, service_name: String) -> (Self, OtlpGuard) {
+        let (sender, receiver) = mpsc::channel();
+        let exporter = thread::spawn(move || {
+            let mut sess = Session::new();
+            sess.timeout(EXPORT_TIMEOUT);
+            let mut batch = Vec::new();
+            loop {
+                let shutdown = match receiver.recv_timeout(EXPORT_INTERVAL) {
+                    Ok(Message::Span(span)) => {
+                        batch.push(span);
+                        if batch.len() < MAX_BATCH {
+                            continue;
+                        }
+                        false
+                    }
+                    Ok(Message::Shutdown) | Err(RecvTimeoutError::Disconnected) => true,
+                    Err(RecvTimeoutError::Timeout) => false,
+                };
+                if !batch.is_empty() {
+                    let payload = payload(&service_name, &batch);
+                    batch.clear();
+                    let result = sess
+                        .post(&url)
+                        .json(&payload)
+                        .and_then(|req| req.send())
+                        .and_then(|resp| resp.error_for_status());
+                    if let Err(e) = result {
+                        warn!(error = %e, "Failed to export spans");
+                    }
+                }
+                if shutdown {
+                    break;
+                }
+            }
+        });
+        let guard = OtlpGuard {
+            sender: sender.clone(),
+            exporter: Some(exporter),
+        };
+        (Self { sender }, guard)
+    }
+
+    /// Exports to the collector named by the standard OpenTelemetry
+    /// environment variables, if any: `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`,
+    /// or `OTEL_EXPORTER_OTLP_ENDPOINT` with `/v1/traces` appended. The
+    /// service is named by `OTEL_SERVICE_NAME`.
+    pub fn from_env() -> Option<(Self, OtlpGuard)> {
+        let url = env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+            .ok()
+            .filter(|url| !url.is_empty())
+            .or_else(|| {
+                let endpoint = env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+                (!endpoint.is_empty())
+                    .then(|| format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            })?;
+        let service_name =
+            env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| String::from(env!("CARGO_PKG_NAME")));
+        Some(Self::new(url, service_name))
+    }
+}
+
+impl Drop for OtlpGuard {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Message::Shutdown);
+        if let Some(exporter) = self.exporter.take() {
+            let _ = exporter.join();
+        }
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            let data = extensions.get::<SpanData>()?;
+            Some((data.trace_id, data.span_id))
+        });
+        let mut data = SpanData {
+            trace_id: parent.map_or_else(random_id, |(trace_id, _)| trace_id),
+            span_id: random_id(),
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            name: attrs.metadata().name(),
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes: Vec::new(),
+            events: Vec::new(),
+        };
+        attrs.record(&mut Attributes(&mut data.attributes));
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            values.record(&mut Attributes(&mut data.attributes));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let mut attributes = vec![attribute("level", event.metadata().level())];
+        event.record(&mut Attributes(&mut attributes));
+        // The message is the name of the event rather than an attribute.
+        let name = attributes
+            .iter()
+            .position(|attribute| attribute["key"] == "message")
+            .map(|i| attributes.remove(i)["value"]["stringValue"].take())
+            .and_then(|name| name.as_str().map(str::to_owned))
+            .unwrap_or_else(|| event.metadata().name().to_owned());
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            data.events.push(json!({
+                "timeUnixNano": unix_nanos(SystemTime::now()),
+                "name": name,
+                "attributes": attributes,
+            }));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(mut data) = extensions.remove::<SpanData>() {
+            data.end = SystemTime::now();
+            let _ = self.sender.send(Message::Span(data));
+        }
+    }
+}
+
+/// A span being recorded, kept in the extensions of the tracing span.
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+
+    /// OTLP `KeyValue`s of the span fields.
+    attributes: Vec<Value>,
+
+    /// OTLP span events.
+    events: Vec<Value>,
+}
+
+/// Records fields as OTLP `KeyValue`s.
+struct Attributes<'a>(&'a mut Vec<Value>);
+
+impl Visit for Attributes<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push(attribute(field.name(), value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        // 64-bit integers are strings in the JSON encoding of protobuf.
+        self.0.push(json!({
+            "key": field.name(),
+            "value": { "intValue": value.to_string() },
+        }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.push(json!({
+            "key": field.name(),
+            "value": { "intValue": value.to_string() },
+        }));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.push(json!({
+            "key": field.name(),
+            "value": { "doubleValue": value },
+        }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.push(json!({
+            "key": field.name(),
+            "value": { "boolValue": value },
+        }));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push(attribute(field.name(), format!("{value:?}")));
+    }
+}
+
+fn attribute(key: &str, value: impl fmt::Display) -> Value {
+    json!({
+        "key": key,
+        "value": { "stringValue": value.to_string() },
+    })
+}
+
+/// Builds an OTLP `ExportTraceServiceRequest` in its JSON encoding.
+fn payload(service_name: &str, spans: &[SpanData]) -> Value {
+    let spans: Vec<_> = spans
+        .iter()
+        .map(|span| {
+            let mut value = json!({
+                "traceId": hex::encode(span.trace_id),
+                "spanId": hex::encode(span.span_id),
+                "name": span.name,
+                "kind": SPAN_KIND_INTERNAL,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": span.attributes,
+                "events": span.events,
+            });
+            if let Some(parent_span_id) = span.parent_span_id {
+                value["parentSpanId"] = json!(hex::encode(parent_span_id));
+            }
+            value
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", service_name)],
+            },
+            "scopeSpans": [{
+                "scope": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let nanos = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    nanos.to_string()
+}
+
+/// Returns a random ID of `N` bytes. IDs only need to be unique, so the
+/// randomly seeded keys of the standard hasher are enough.
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut id = [0; N];
+    for chunk in id.chunks_mut(8) {
+        let random = RandomState::new().build_hasher().finish().to_le_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+    id
+}
+
+#[cfg(test)]
+mod test {
+    use httpmock::prelude::*;
+    use tracing::info;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[test]
+    fn export_spans() {
+        let server = MockServer::start();
+        let traces = server.mock(|when, then| {
+            when.method(POST)
+                .path("/v1/traces")
+                .body_contains(r#""stringValue":"test""#)
+                .body_contains(r#""name":"download_song""#)
+                .body_contains(r#""key":"id","value":{"stringValue":"a"}"#)
+                .body_contains(r#""name":"fetch_page""#)
+                .body_contains(r#""name":"Downloading""#)
+                .body_contains(r#""parentSpanId""#);
+            then.status(200);
+        });
+
+        let (layer, guard) = OtlpLayer::new(server.url("/v1/traces"), String::from("test"));
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = info_span!("download_song", id = "a").entered();
+            info!("Downloading");
+            info_span!("fetch_page").in_scope(|| {});
+        });
+        drop(guard);
+        traces.assert();
+    }
+}
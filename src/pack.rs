@@ -0,0 +1,219 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use anyhow::ensure;
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Serialize;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+use zip::ZipWriter;
+
+use crate::api::Routes;
+use crate::filter::Filter;
+use crate::layout::Fields;
+use crate::layout::Layout;
+use crate::store::Store;
+use crate::NAUTICA_BASE_URL;
+
+/// Name of the manifest at the root of a pack.
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// Bundles local songs into a zip archive for sharing, e.g. at offline
+/// events. Each song is put in a readable directory, with UTF-8 file names
+/// whatever the encoding of the original upload, and a manifest records
+/// where the songs came from.
+#[derive(Debug)]
+pub struct Packer {
+    base_url: String,
+    routes: Routes,
+}
+
+/// Contents of the manifest of a pack.
+#[derive(Debug, Serialize)]
+struct Manifest {
+    created_at: DateTime<Utc>,
+    songs: Vec<ManifestSong>,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestSong {
+    id: String,
+    title: String,
+    artist: String,
+    uploader: String,
+
+    /// Directory of the song in the pack.
+    dir: String,
+
+    /// Where the song can be downloaded from.
+    url: String,
+}
+
+impl Default for Packer {
+    fn default() -> Self {
+        Self {
+            base_url: String::from(NAUTICA_BASE_URL),
+            routes: Routes::default(),
+        }
+    }
+}
+
+impl Packer {
+    /// Sets the server the manifest links the songs to.
+    pub fn base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Sets the routes of the API, for servers that differ from Nautica.
+    pub fn routes(mut self, routes: Routes) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// Writes the songs in the library `dest` that match `filter` to the
+    /// archive `output`. Returns the number of songs packed.
+    pub fn pack(&self, dest: &Path, filter: &Filter, output: &Path) -> anyhow::Result<usize> {
+        let store = Store::open_read_only(dest);
+        let songs: Vec<_> = store
+            .entries()
+            .into_iter()
+            .filter(|(id, entry)| filter.matches_entry(id, entry))
+            .filter(|(id, entry)| dest.join(entry.dir(id)).is_dir())
+            .collect();
+        ensure!(!songs.is_empty(), "No local songs match");
+
+        let file = File::create(output)
+            .with_context(|| format!("Failed to create {}", output.display()))?;
+        let mut zip = ZipWriter::new(file);
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        let mut manifest = Manifest {
+            created_at: Utc::now(),
+            songs: Vec::new(),
+        };
+        for (id, entry) in &songs {
+            let dir = Layout::Readable.dir_name(&Fields::entry(id, entry));
+            let mut paths: Vec<_> = fs::read_dir(dest.join(entry.dir(id)))?
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.is_file())
+                .filter(|path| {
+                    !path
+                        .file_name()
+                        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+                })
+                .collect();
+            paths.sort();
+            for path in paths {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                zip.start_file(format!("{dir}/{name}"), options)?;
+                io::copy(&mut File::open(&path)?, &mut zip)?;
+            }
+            manifest.songs.push(ManifestSong {
+                id: id.clone(),
+                title: entry.title.clone(),
+                artist: entry.artist.clone(),
+                uploader: entry.uploader().to_owned(),
+                dir,
+                url: format!("{}{}", self.base_url, self.routes.download_path(id)),
+            });
+        }
+        zip.start_file(MANIFEST_FILENAME, options)?;
+        serde_json::to_writer_pretty(&mut zip, &manifest)?;
+        zip.finish()?;
+        Ok(manifest.songs.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use serde_json::json;
+    use serde_json::Value;
+    use tempfile::tempdir;
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::store::Entry;
+    use crate::Song;
+
+    fn song(id: &str, title: &str, level: u8) -> Song {
+        serde_json::from_value(json!({
+            "id": id,
+            "user_id": "user",
+            "title": title,
+            "artist": "RG+Ice",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+            "charts": [{ "difficulty": 4, "level": level }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pack_matching_songs() {
+        let dest = tempdir().unwrap();
+        let mut store = Store::open(dest.path());
+        for (id, title, level) in [
+            ("5441d590", "Outbreak", 18),
+            ("89b54d80", "チューリングラブ", 17),
+            ("9e523640", "Easy", 5),
+        ] {
+            let dir = dest.path().join(id);
+            fs::create_dir(&dir).unwrap();
+            fs::write(dir.join(format!("{title}.ksh")), "chart").unwrap();
+            fs::write(dir.join(".partial"), "").unwrap();
+            store
+                .insert(id, &Entry::new(&song(id, title, level), id))
+                .unwrap();
+        }
+
+        let output = dest.path().join("pack.zip");
+        let filter = Filter {
+            min_level: Some(17),
+            max_level: Some(18),
+            ..Default::default()
+        };
+        let packed = Packer::default()
+            .base_url("http://mirror/")
+            .pack(dest.path(), &filter, &output)
+            .unwrap();
+        assert_eq!(packed, 2);
+
+        let mut archive = ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        let mut names: Vec<_> = archive.file_names().collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "RG+Ice - Outbreak [5441d590]/Outbreak.ksh",
+                "RG+Ice - チューリングラブ [89b54d80]/チューリングラブ.ksh",
+                MANIFEST_FILENAME,
+            ]
+        );
+        let mut manifest = String::new();
+        archive
+            .by_name(MANIFEST_FILENAME)
+            .unwrap()
+            .read_to_string(&mut manifest)
+            .unwrap();
+        let manifest: Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["songs"][0]["id"], "5441d590");
+        assert_eq!(
+            manifest["songs"][1]["url"],
+            "http://mirror/songs/89b54d80/download"
+        );
+
+        let filter = Filter {
+            min_level: Some(19),
+            ..Default::default()
+        };
+        assert!(Packer::default()
+            .pack(dest.path(), &filter, &output)
+            .is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::HashSet;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use nautica_downloader_rs::Downloader;
+use nautica_downloader_rs::Song;
+use ratatui::crossterm::event;
+use ratatui::crossterm::event::Event;
+use ratatui::crossterm::event::KeyCode;
+use ratatui::crossterm::event::KeyEventKind;
+use ratatui::layout::Constraint;
+use ratatui::layout::Layout;
+use ratatui::style::Modifier;
+use ratatui::style::Style;
+use ratatui::widgets::List;
+use ratatui::widgets::ListDirection;
+use ratatui::widgets::ListState;
+use ratatui::widgets::Paragraph;
+use ratatui::DefaultTerminal;
+use ratatui::Frame;
+
+/// Fuzzy finder over the remote catalog, which keeps loading in the
+/// background while the user types.
+struct Picker {
+    songs: Vec<Song>,
+    loading: bool,
+    query: String,
+    selected: HashSet<String>,
+    matcher: SkimMatcherV2,
+    matches: Vec<usize>,
+    state: ListState,
+}
+
+impl Picker {
+    fn new() -> Self {
+        Self {
+            songs: Vec::new(),
+            loading: true,
+            query: String::new(),
+            selected: HashSet::new(),
+            matcher: SkimMatcherV2::default(),
+            matches: Vec::new(),
+            state: ListState::default().with_selected(Some(0)),
+        }
+    }
+
+    /// Ranks the loaded songs against the query, best match first.
+    fn update_matches(&mut self) {
+        let mut scored: Vec<_> = self
+            .songs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, song)| {
+                let text = format!("{} {}", song.title, song.artist);
+                Some((self.matcher.fuzzy_match(&text, &self.query)?, i))
+            })
+            .collect();
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        self.matches = scored.into_iter().map(|(_, i)| i).collect();
+        if self.state.selected().unwrap_or(0) >= self.matches.len() {
+            self.state
+                .select(Some(self.matches.len().saturating_sub(1)));
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [list_area, status, prompt] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let items = self.matches.iter().map(|&i| {
+            let song = &self.songs[i];
+            let mark = if self.selected.contains(&song.id) {
+                ">"
+            } else {
+                " "
+            };
+            format!("{mark} {} / {}", song.title, song.artist)
+        });
+        let list = List::new(items)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .direction(ListDirection::BottomToTop);
+        frame.render_stateful_widget(list, list_area, &mut self.state);
+
+        frame.render_widget(
+            Paragraph::new(format!(
+                "  {}/{}{}  ({} selected)",
+                self.matches.len(),
+                self.songs.len(),
+                if self.loading { "+" } else { "" },
+                self.selected.len()
+            )),
+            status,
+        );
+        frame.render_widget(Paragraph::new(format!("> {}", self.query)), prompt);
+    }
+
+    /// Handles a key press. Returns `Some(download)` when the finder should
+    /// close.
+    fn on_key(&mut self, code: KeyCode) -> Option<bool> {
+        let current = self.state.selected().unwrap_or(0);
+        match code {
+            KeyCode::Esc => return Some(false),
+            KeyCode::Enter => {
+                if self.selected.is_empty() {
+                    let &i = self.matches.get(current)?;
+                    self.selected.insert(self.songs[i].id.clone());
+                }
+                return Some(true);
+            }
+            KeyCode::Up if current + 1 < self.matches.len() => {
+                self.state.select(Some(current + 1));
+            }
+            KeyCode::Down => self.state.select(Some(current.saturating_sub(1))),
+            KeyCode::Tab => {
+                if let Some(&i) = self.matches.get(current) {
+                    let id = &self.songs[i].id;
+                    if !self.selected.remove(id) {
+                        self.selected.insert(id.clone());
+                    }
+                    let next = (current + 1).min(self.matches.len() - 1);
+                    self.state.select(Some(next));
+                }
+            }
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.update_matches();
+            }
+            KeyCode::Char(c) => {
+                self.query.push(c);
+                self.state.select(Some(0));
+                self.update_matches();
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        rx: &mpsc::Receiver<Song>,
+    ) -> anyhow::Result<bool> {
+        loop {
+            let loaded = self.songs.len();
+            while self.loading {
+                match rx.try_recv() {
+                    Ok(song) => self.songs.push(song),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => self.loading = false,
+                }
+            }
+            if self.songs.len() > loaded {
+                self.update_matches();
+            }
+
+            terminal.draw(|frame| self.draw(frame))?;
+            if !event::poll(Duration::from_millis(100))? {
+                continue;
+            }
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                if let Some(download) = self.on_key(key.code) {
+                    return Ok(download);
+                }
+            }
+        }
+    }
+}
+
+/// Opens a fuzzy finder over the remote catalog and returns the selected songs.
+pub fn pick(downloader: &Downloader) -> anyhow::Result<Vec<Song>> {
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            for song in downloader.catalog() {
+                // Stop when loading fails or the finder has been closed.
+                let Ok(song) = song else { break };
+                if tx.send(song).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut picker = Picker::new();
+        let mut terminal = ratatui::init();
+        let result = picker.run(&mut terminal, &rx);
+        ratatui::restore();
+        drop(rx);
+
+        if !result? {
+            return Ok(Vec::new());
+        }
+        Ok(picker
+            .songs
+            .into_iter()
+            .filter(|song| picker.selected.contains(&song.id))
+            .collect())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn song(id: &str, title: &str) -> Song {
+        serde_json::from_value(json!({
+            "id": id,
+            "user_id": "user",
+            "title": title,
+            "artist": "artist",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn rank_and_select_matches() {
+        let mut picker = Picker::new();
+        picker.songs = vec![
+            song("a", "Outbreak"),
+            song("b", "Brain Power"),
+            song("c", "Break Out"),
+        ];
+        for c in "break".chars() {
+            picker.on_key(KeyCode::Char(c));
+        }
+        // Contiguous matches rank first; songs without one are left out.
+        assert_eq!(picker.matches, [2, 0]);
+
+        // Tab marks a song and moves on; Enter keeps the marked songs.
+        picker.on_key(KeyCode::Tab);
+        assert_eq!(picker.state.selected(), Some(1));
+        assert_eq!(picker.on_key(KeyCode::Enter), Some(true));
+        assert_eq!(picker.selected, HashSet::from([String::from("c")]));
+
+        // Without marks, Enter takes the highlighted song.
+        picker.selected.clear();
+        assert_eq!(picker.on_key(KeyCode::Enter), Some(true));
+        assert_eq!(picker.selected, HashSet::from([String::from("a")]));
+        assert_eq!(picker.on_key(KeyCode::Esc), Some(false));
+    }
+}
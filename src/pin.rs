@@ -0,0 +1,76 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Deserialize;
+
+const PREFIX: &str = "sha256//";
+
+/// SHA-256 hash of a server's public key (the DER of its
+/// SubjectPublicKeyInfo), written `sha256//<base64>` as in curl's
+/// `--pinnedpubkey`, which checks it.
+///
+/// Pinning the public key survives renewals that keep the key.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pin([u8; 32]);
+
+impl FromStr for Pin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let hash = s
+            .trim()
+            .strip_prefix(PREFIX)
+            .ok_or_else(|| anyhow!("Invalid pin {s:?}, expected \"{PREFIX}<base64>\""))?;
+        let hash = BASE64
+            .decode(hash)
+            .map_err(|e| anyhow!("Invalid pin {s:?}: {e}"))?;
+        let hash = hash
+            .try_into()
+            .map_err(|_| anyhow!("Invalid pin {s:?}, expected a SHA-256 hash"))?;
+        Ok(Self(hash))
+    }
+}
+
+impl TryFrom<String> for Pin {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{PREFIX}{}", BASE64.encode(self.0))
+    }
+}
+
+impl fmt::Debug for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_pins() {
+        let pin: Pin = "sha256//glT6Wo+YNUVltq0ZsQmaWXWsz2Q6gjaOXnC1zOGqOVA="
+            .parse()
+            .unwrap();
+        assert_eq!(
+            pin.to_string(),
+            "sha256//glT6Wo+YNUVltq0ZsQmaWXWsz2Q6gjaOXnC1zOGqOVA="
+        );
+        assert!("glT6Wo+YNUVltq0ZsQmaWXWsz2Q6gjaOXnC1zOGqOVA="
+            .parse::<Pin>()
+            .is_err());
+        assert!("sha256//AAAA".parse::<Pin>().is_err());
+    }
+}
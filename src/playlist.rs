@@ -0,0 +1,52 @@
+use serde::Deserialize;
+use url::Url;
+
+use crate::Song;
+
+/// A playlist on Nautica, either one of the account's own or a public one.
+#[derive(Debug, Deserialize)]
+pub struct Playlist {
+    pub id: String,
+    pub name: String,
+
+    /// Songs of the playlist; empty when listing the account's playlists.
+    #[serde(default)]
+    pub songs: Vec<Song>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PlaylistResp {
+    pub data: Playlist,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PlaylistsResp {
+    pub data: Vec<Playlist>,
+}
+
+/// Returns the ID of a playlist given by its URL, e.g.
+/// `https://ksm.dev/playlists/9a1f0c20`, or by its ID.
+pub fn playlist_id(playlist: &str) -> &str {
+    if Url::parse(playlist).is_err() {
+        return playlist;
+    }
+    let path = playlist.split(['?', '#']).next().unwrap_or_default();
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn id_from_url() {
+        assert_eq!(playlist_id("9a1f0c20"), "9a1f0c20");
+        assert_eq!(
+            playlist_id("https://ksm.dev/playlists/9a1f0c20/?sort=title"),
+            "9a1f0c20"
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Cursor;
+use std::io::Write;
+use std::path::Path;
+
+use chrono::Utc;
+use serde::Deserialize;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+use zip::ZipWriter;
+
+use crate::ksh;
+use crate::ksh::Header;
+use crate::Song;
+
+/// Header fields that name files of the song. Values without an extension
+/// refer to images built into the game.
+const FILE_FIELDS: &[&str] = &["m", "jacket", "bg", "layer", "icon"];
+
+/// Checks that the song in `dir` can be published, returning the problems
+/// found: every chart needs a title, artist, level, and difficulty, the
+/// charts must agree on the title and artist, and every file they refer to
+/// must be in `dir`.
+pub fn check(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let charts = ksh::charts(dir)?;
+    if charts.is_empty() {
+        return Ok(vec![format!("No charts in {}", dir.display())]);
+    }
+    let mut problems = Vec::new();
+    let mut songs = HashSet::new();
+    let mut difficulties = HashSet::new();
+    for chart in &charts {
+        let name = chart.file_name().unwrap_or_default().to_string_lossy();
+        let header = Header::read(chart)?;
+        for key in ["title", "artist"] {
+            if header.get(key).is_none_or(str::is_empty) {
+                problems.push(format!("{name}: no {key}"));
+            }
+        }
+        songs.insert((
+            header.get("title").unwrap_or_default().to_owned(),
+            header.get("artist").unwrap_or_default().to_owned(),
+        ));
+        if !header
+            .level()
+            .is_some_and(|level| (1..=20).contains(&level))
+        {
+            problems.push(format!("{name}: level must be 1 to 20"));
+        }
+        match header.difficulty_index() {
+            Some(index) if !difficulties.insert(index) => {
+                problems.push(format!("{name}: another chart has the same difficulty"));
+            }
+            Some(_) => {}
+            None => problems.push(format!("{name}: unknown difficulty")),
+        }
+        for key in FILE_FIELDS {
+            let Some(value) = header.get(key) else {
+                continue;
+            };
+            // Alternative tracks follow the music, separated by semicolons.
+            for file in value.split(';').filter(|file| file.contains('.')) {
+                if !dir.join(file).is_file() {
+                    problems.push(format!("{name}: {key}={file} is missing"));
+                }
+            }
+        }
+    }
+    if songs.len() > 1 {
+        problems.push(String::from("The charts have different titles or artists"));
+    }
+    Ok(problems)
+}
+
+/// Packages the files in `dir` into a zip archive with UTF-8 file names, so
+/// that non-ASCII names survive whatever the uploader's locale. Hidden files
+/// are left out.
+pub fn package(dir: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            !path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        })
+        .collect();
+    paths.sort();
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for path in paths {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        zip.start_file(name, options)?;
+        zip.write_all(&fs::read(&path)?)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Encodes `bytes` as the file `filename` in the form field `field` of a
+/// `multipart/form-data` body, returning the content type and the body.
+pub(crate) fn multipart(field: &str, filename: &str, bytes: &[u8]) -> (String, Vec<u8>) {
+    let boundary = format!("nautica-{:x}", Utc::now().timestamp_micros());
+    let mut body = format!(
+        "--{boundary}\r\nContent-Disposition: form-data; name=\"{field}\"; filename=\"{}\"\r\nContent-Type: application/zip\r\n\r\n",
+        filename.replace('"', "")
+    )
+    .into_bytes();
+    body.extend_from_slice(bytes);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    (format!("multipart/form-data; boundary={boundary}"), body)
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PublishResp {
+    pub data: Song,
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+    use zip::ZipArchive;
+
+    use super::*;
+
+    #[test]
+    fn check_song() {
+        let dir = tempdir().unwrap();
+        let chart = |name: &str, content: &str| fs::write(dir.path().join(name), content).unwrap();
+        chart(
+            "exh.ksh",
+            "title=曲\nartist=someone\ndifficulty=challenge\nlevel=16\nm=曲.ogg;曲_f.ogg\njacket=曲.png\nbg=desert\n--\n",
+        );
+        fs::write(dir.path().join("曲.ogg"), "music").unwrap();
+        fs::write(dir.path().join("曲.png"), "jacket").unwrap();
+        assert_eq!(
+            check(dir.path()).unwrap(),
+            ["exh.ksh: m=曲_f.ogg is missing"]
+        );
+
+        chart(
+            "mxm.ksh",
+            "title=other\nartist=someone\ndifficulty=challenge\nlevel=21\n--\n",
+        );
+        fs::write(dir.path().join("曲_f.ogg"), "music").unwrap();
+        assert_eq!(
+            check(dir.path()).unwrap(),
+            [
+                "mxm.ksh: level must be 1 to 20",
+                "mxm.ksh: another chart has the same difficulty",
+                "The charts have different titles or artists",
+            ]
+        );
+    }
+
+    #[test]
+    fn package_with_utf8_names() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("曲.ksh"), "title=曲").unwrap();
+        fs::write(dir.path().join(".session"), "secret").unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(package(dir.path()).unwrap())).unwrap();
+        assert_eq!(archive.len(), 1);
+        let file = archive.by_index(0).unwrap();
+        assert_eq!(file.name_raw(), "曲.ksh".as_bytes());
+        assert_eq!(file.name(), "曲.ksh");
+    }
+}
@@ -0,0 +1,169 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+
+use crate::ksh;
+use crate::ksh::Header;
+use crate::move_dir;
+use crate::store::Entry;
+use crate::store::Store;
+
+const QUARANTINE_DIRNAME: &str = ".quarantine";
+const REASON_FILENAME: &str = "reason.txt";
+
+/// File name the archive of a song that could not be extracted is kept
+/// under in its quarantine.
+pub const ARCHIVE_FILENAME: &str = "archive.zip";
+
+/// Returns the directory in `<dest>/.quarantine` that holds the files of the
+/// song `id` while it is quarantined.
+pub fn dir(dest: &Path, id: &str) -> PathBuf {
+    dest.join(QUARANTINE_DIRNAME).join(id)
+}
+
+/// Checks the song extracted into `dir`, whose info `entry` was read from
+/// it, returning why it is suspect: no charts, a chart that does not parse,
+/// or music that will not play.
+pub fn check(dir: &Path, entry: &Entry) -> Option<String> {
+    let charts = match ksh::charts(dir) {
+        Ok(charts) => charts,
+        Err(e) => return Some(format!("Failed to read {}: {e}", dir.display())),
+    };
+    if charts.is_empty() {
+        return Some(String::from("No chart files"));
+    }
+    for chart in &charts {
+        if let Err(e) = Header::read(chart) {
+            return Some(format!("{e:#}"));
+        }
+    }
+    entry.audio_problem.clone()
+}
+
+/// Moves the files of the song `id` from `from`, if it exists, into its
+/// quarantine with a file stating `reason`, replacing an earlier quarantine
+/// of the song.
+pub fn hold(dest: &Path, id: &str, from: &Path, reason: &str) -> anyhow::Result<()> {
+    let dir = dir(dest, id);
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    if from.exists() {
+        move_dir(from, &dir)
+            .with_context(|| format!("Failed to move {} to quarantine", from.display()))?;
+    } else {
+        fs::create_dir_all(&dir)?;
+    }
+    fs::write(dir.join(REASON_FILENAME), reason)?;
+    Ok(())
+}
+
+/// Returns the IDs of the quarantined songs with the reasons, sorted by ID.
+pub fn list(dest: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let root = dest.join(QUARANTINE_DIRNAME);
+    if !root.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut songs = Vec::new();
+    for entry in fs::read_dir(&root)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let reason = fs::read_to_string(entry.path().join(REASON_FILENAME)).unwrap_or_default();
+        songs.push((entry.file_name().to_string_lossy().into_owned(), reason));
+    }
+    songs.sort();
+    Ok(songs)
+}
+
+/// Moves the repaired song `id` from quarantine back into the library,
+/// checking it again first unless `force` is set. A song quarantined for its
+/// archive is forgotten instead, so that the next sync downloads it again.
+/// Returns whether the song was moved back.
+pub fn release(dest: &Path, id: &str, force: bool) -> anyhow::Result<bool> {
+    let quarantined = dir(dest, id);
+    ensure!(quarantined.is_dir(), "{id} is not in quarantine");
+    let mut store = Store::open(dest);
+    let Some(mut entry) = store.get(id) else {
+        bail!("{id} is not in the library");
+    };
+    if quarantined.join(ARCHIVE_FILENAME).exists() {
+        store.remove(id)?;
+        fs::remove_dir_all(&quarantined)?;
+        return Ok(false);
+    }
+    entry.read_song_info(&quarantined);
+    if !force {
+        if let Some(reason) = check(&quarantined, &entry) {
+            bail!("{id} is still invalid: {reason}");
+        }
+    }
+    fs::remove_file(quarantined.join(REASON_FILENAME))?;
+    let target = dest.join(entry.dir(id));
+    ensure!(!target.exists(), "{} already exists", target.display());
+    move_dir(&quarantined, &target)?;
+    entry.quarantined = None;
+    store.insert(id, &entry)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::Song;
+
+    #[test]
+    fn hold_and_release() {
+        let dest = tempdir().unwrap();
+        let song: Song = serde_json::from_value(json!({
+            "id": "a",
+            "user_id": "user",
+            "title": "Outbreak",
+            "artist": "RG+Ice",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+        }))
+        .unwrap();
+        let song_dir = dest.path().join("a");
+        fs::create_dir(&song_dir).unwrap();
+        fs::write(
+            song_dir.join("exh.ksh"),
+            "title=Outbreak\r\nm=music.ogg\r\n--\r\n",
+        )
+        .unwrap();
+        let mut entry = Entry::new(&song, "a");
+        entry.read_song_info(&song_dir);
+        let reason = check(&song_dir, &entry).unwrap();
+
+        hold(dest.path(), "a", &song_dir, &reason).unwrap();
+        entry.quarantined = Some(reason.clone());
+        Store::open(dest.path()).insert("a", &entry).unwrap();
+        assert!(!song_dir.exists());
+        assert_eq!(list(dest.path()).unwrap(), [("a".to_owned(), reason)]);
+        assert!(Store::open_read_only(dest.path()).entries().is_empty());
+
+        assert!(release(dest.path(), "a", false)
+            .unwrap_err()
+            .to_string()
+            .starts_with("a is still invalid"));
+        assert!(release(dest.path(), "a", true).unwrap());
+        assert!(song_dir.join("exh.ksh").exists());
+        assert!(list(dest.path()).unwrap().is_empty());
+        assert_eq!(Store::open_read_only(dest.path()).entries().len(), 1);
+        assert!(release(dest.path(), "a", true).is_err());
+
+        // A song whose archive could not be extracted is downloaded again.
+        hold(dest.path(), "a", &song_dir, "Invalid archive").unwrap();
+        fs::write(dir(dest.path(), "a").join(ARCHIVE_FILENAME), "zip").unwrap();
+        assert!(!release(dest.path(), "a", false).unwrap());
+        assert!(!Store::open_read_only(dest.path()).contains("a"));
+    }
+}
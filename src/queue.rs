@@ -0,0 +1,467 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::Song;
+
+/// Name of the file in the library holding the download queue.
+const QUEUE_FILENAME: &str = ".queue.jsonl";
+
+/// State of a song in the download queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Waiting to be downloaded.
+    Pending,
+
+    /// Being downloaded. A song still active when a queue is opened was cut
+    /// off by an interrupted run, and is pending again.
+    Active,
+
+    /// Downloaded, or otherwise recorded in the library.
+    Done,
+
+    /// Failed to download; queued again by the next sync that lists it, or
+    /// by `queue retry`.
+    Failed,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Pending => "pending",
+            Status::Active => "active",
+            Status::Done => "done",
+            Status::Failed => "failed",
+        })
+    }
+}
+
+/// Rules ordering the pending songs of the queue, from the `[queue]` table of
+/// the configuration file, instead of the order they were listed in.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Rules applied in turn: a rule only orders the songs that the rules
+    /// before it rank equal. Explicit priorities come before all rules.
+    pub rules: Vec<Rule>,
+
+    /// Uploader IDs or names (case-insensitive) whose songs the
+    /// [`Rule::Uploaders`] rule puts first, in this order.
+    pub uploaders: Vec<String>,
+}
+
+/// A rule ordering the pending songs of the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// Songs of the configured uploaders first.
+    Uploaders,
+
+    /// Songs with higher-level charts first.
+    HighestLevel,
+
+    /// Smaller archives first, for quick wins. Archives of unknown size
+    /// come last.
+    Smallest,
+}
+
+impl QueueConfig {
+    /// Sorts `songs` by the rules, keeping the order of songs they rank
+    /// equal. `sizes` are the archive sizes by song ID, for
+    /// [`Rule::Smallest`].
+    pub fn sort(&self, songs: &mut [Song], sizes: &HashMap<String, u64>) {
+        songs.sort_by(|a, b| {
+            self.rules.iter().fold(cmp::Ordering::Equal, |order, rule| {
+                order.then_with(|| match rule {
+                    Rule::Uploaders => self.uploader_rank(a).cmp(&self.uploader_rank(b)),
+                    Rule::HighestLevel => highest_level(b).cmp(&highest_level(a)),
+                    Rule::Smallest => {
+                        let size = |song: &Song| sizes.get(&song.id).copied().unwrap_or(u64::MAX);
+                        size(a).cmp(&size(b))
+                    }
+                })
+            })
+        });
+    }
+
+    /// Returns the position of the song's uploader in the configured
+    /// uploaders, or a position after all of them.
+    fn uploader_rank(&self, song: &Song) -> usize {
+        let name = song.user.as_ref().map(|user| user.name.as_str());
+        self.uploaders
+            .iter()
+            .position(|uploader| {
+                *uploader == song.user_id
+                    || name.is_some_and(|name| name.eq_ignore_ascii_case(uploader))
+            })
+            .unwrap_or(self.uploaders.len())
+    }
+}
+
+fn highest_level(song: &Song) -> u8 {
+    song.charts
+        .iter()
+        .map(|chart| chart.level)
+        .max()
+        .unwrap_or(0)
+}
+
+/// A song in the download queue.
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub id: String,
+    pub status: Status,
+
+    /// Items with a higher priority are downloaded first.
+    pub priority: i32,
+
+    /// The song as listed by the server, or `None` if it was queued by ID
+    /// and has not been looked up yet.
+    pub song: Option<Song>,
+}
+
+/// A line of the queue file, changing the item `id`. Fields left out keep
+/// their earlier values.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    id: String,
+    status: Status,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    priority: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    song: Option<Song>,
+}
+
+/// Songs to download, kept in `<dest>/.queue.jsonl` so that a run cut off
+/// halfway resumes where it left off, and so that other tools can queue
+/// songs for the next run.
+///
+/// Each line of the file is a JSON object changing one song, e.g.
+/// `{"id":"5441d590-…","status":"pending","priority":1}`; later lines win.
+/// Lines are only ever appended while the queue is in use, and the file is
+/// rewritten without the done songs when it is opened for a run.
+#[derive(Debug)]
+pub struct Queue {
+    path: PathBuf,
+    items: Vec<Item>,
+    index: HashMap<String, usize>,
+}
+
+impl Queue {
+    /// Reads the queue of the library `dest` without changing it.
+    pub fn read(dest: &Path) -> anyhow::Result<Self> {
+        let path = dest.join(QUEUE_FILENAME);
+        let mut queue = Self {
+            path,
+            items: Vec::new(),
+            index: HashMap::new(),
+        };
+        let content = match fs::read_to_string(&queue.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(queue),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", queue.path.display()))
+            }
+        };
+        for (i, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(record) => queue.apply(record),
+                Err(e) => warn!(
+                    error = %e,
+                    path = %queue.path.display(),
+                    line = i + 1,
+                    "Ignoring broken line of the queue"
+                ),
+            }
+        }
+        Ok(queue)
+    }
+
+    /// Opens the queue of the library `dest` for a run. Songs left active by
+    /// an interrupted run are pending again, and done songs are dropped.
+    pub fn open(dest: &Path) -> anyhow::Result<Self> {
+        let mut queue = Self::read(dest)?;
+        queue.items.retain(|item| item.status != Status::Done);
+        for item in &mut queue.items {
+            if item.status == Status::Active {
+                item.status = Status::Pending;
+            }
+        }
+        queue.reindex();
+        let mut content = String::new();
+        for item in &queue.items {
+            content.push_str(&serde_json::to_string(&Record {
+                id: item.id.clone(),
+                status: item.status,
+                priority: Some(item.priority),
+                song: item.song.clone(),
+            })?);
+            content.push('\n');
+        }
+        fs::write(&queue.path, content)
+            .with_context(|| format!("Failed to write {}", queue.path.display()))?;
+        Ok(queue)
+    }
+
+    /// Returns the songs in the order they were queued.
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Item> {
+        self.index.get(id).map(|&i| &self.items[i])
+    }
+
+    /// Queues `song` unless it is already waiting. A song that is done or
+    /// failed is queued again.
+    pub fn push(&mut self, song: Song) -> anyhow::Result<()> {
+        if self
+            .get(&song.id)
+            .is_some_and(|item| item.status == Status::Pending && item.song.is_some())
+        {
+            return Ok(());
+        }
+        self.append(Record {
+            id: song.id.clone(),
+            status: Status::Pending,
+            priority: None,
+            song: Some(song),
+        })
+    }
+
+    /// Queues the song `id` with `priority`, to be looked up in the listing
+    /// when its turn comes unless it is queued with its details already.
+    pub fn push_id(&mut self, id: &str, priority: i32) -> anyhow::Result<()> {
+        self.append(Record {
+            id: id.to_owned(),
+            status: Status::Pending,
+            priority: Some(priority),
+            song: None,
+        })
+    }
+
+    /// Fills in the details of the song queued by ID.
+    pub fn resolve(&mut self, song: Song) -> anyhow::Result<()> {
+        let status = self
+            .get(&song.id)
+            .map_or(Status::Pending, |item| item.status);
+        self.append(Record {
+            id: song.id.clone(),
+            status,
+            priority: None,
+            song: Some(song),
+        })
+    }
+
+    pub fn set_status(&mut self, id: &str, status: Status) -> anyhow::Result<()> {
+        self.append(Record {
+            id: id.to_owned(),
+            status,
+            priority: None,
+            song: None,
+        })
+    }
+
+    /// Queues the failed songs again, returning how many there were.
+    pub fn retry_failed(&mut self) -> anyhow::Result<usize> {
+        let failed: Vec<_> = self
+            .items
+            .iter()
+            .filter(|item| item.status == Status::Failed)
+            .map(|item| item.id.clone())
+            .collect();
+        for id in &failed {
+            self.set_status(id, Status::Pending)?;
+        }
+        Ok(failed.len())
+    }
+
+    /// Returns the pending songs in the order to download them: by priority,
+    /// and then in the order they were queued.
+    pub fn pending(&self) -> Vec<&Item> {
+        let mut pending: Vec<_> = self
+            .items
+            .iter()
+            .filter(|item| item.status == Status::Pending)
+            .collect();
+        pending.sort_by_key(|item| std::cmp::Reverse(item.priority));
+        pending
+    }
+
+    fn append(&mut self, record: Record) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        self.apply(record);
+        Ok(())
+    }
+
+    fn apply(&mut self, record: Record) {
+        match self.index.get(&record.id) {
+            Some(&i) => {
+                let item = &mut self.items[i];
+                item.status = record.status;
+                if let Some(priority) = record.priority {
+                    item.priority = priority;
+                }
+                if record.song.is_some() {
+                    item.song = record.song;
+                }
+            }
+            None => {
+                self.index.insert(record.id.clone(), self.items.len());
+                self.items.push(Item {
+                    id: record.id,
+                    status: record.status,
+                    priority: record.priority.unwrap_or_default(),
+                    song: record.song,
+                });
+            }
+        }
+    }
+
+    fn reindex(&mut self) {
+        self.index = self
+            .items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| (item.id.clone(), i))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn song(id: &str) -> Song {
+        serde_json::from_value(json!({
+            "id": id,
+            "user_id": "user",
+            "title": id,
+            "artist": "RG+Ice",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+        }))
+        .unwrap()
+    }
+
+    fn ids(songs: &[Song]) -> Vec<&str> {
+        songs.iter().map(|song| song.id.as_str()).collect()
+    }
+
+    fn pending(queue: &Queue) -> Vec<&str> {
+        queue
+            .pending()
+            .into_iter()
+            .map(|item| item.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn resume_interrupted_run() {
+        let dest = tempdir().unwrap();
+        let mut queue = Queue::open(dest.path()).unwrap();
+        for id in ["a", "b", "c", "d"] {
+            queue.push(song(id)).unwrap();
+        }
+        queue.set_status("a", Status::Done).unwrap();
+        queue.set_status("b", Status::Failed).unwrap();
+        queue.set_status("c", Status::Active).unwrap();
+        // Queued by another tool.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(dest.path().join(QUEUE_FILENAME))
+            .unwrap()
+            .write_all(b"{\"id\":\"e\",\"status\":\"pending\",\"priority\":1}\n")
+            .unwrap();
+
+        let read = Queue::read(dest.path()).unwrap();
+        assert_eq!(read.get("a").unwrap().status, Status::Done);
+        assert_eq!(read.get("c").unwrap().status, Status::Active);
+
+        let mut queue = Queue::open(dest.path()).unwrap();
+        assert!(queue.get("a").is_none());
+        assert_eq!(pending(&queue), ["e", "c", "d"]);
+        assert!(queue.get("e").unwrap().song.is_none());
+        assert_eq!(queue.get("d").unwrap().song.as_ref().unwrap().title, "d");
+
+        assert_eq!(queue.retry_failed().unwrap(), 1);
+        assert_eq!(pending(&queue), ["e", "b", "c", "d"]);
+        queue.resolve(song("e")).unwrap();
+        queue.push(song("d")).unwrap();
+        let queue = Queue::open(dest.path()).unwrap();
+        assert_eq!(pending(&queue), ["e", "b", "c", "d"]);
+        assert!(queue.get("e").unwrap().song.is_some());
+    }
+
+    #[test]
+    fn order_by_rules() {
+        let mut songs = Vec::new();
+        for (id, user_id, level) in [
+            ("a", "u1", 12),
+            ("b", "u2", 18),
+            ("c", "u3", 15),
+            ("d", "u2", 16),
+        ] {
+            let mut song = song(id);
+            song.user_id = user_id.to_owned();
+            song.charts = serde_json::from_value(json!([
+                { "difficulty": 3, "level": level },
+            ]))
+            .unwrap();
+            songs.push(song);
+        }
+        let sizes = HashMap::from([
+            ("a".to_owned(), 30),
+            ("b".to_owned(), 20),
+            ("d".to_owned(), 10),
+        ]);
+
+        let mut config = QueueConfig::default();
+        config.sort(&mut songs, &sizes);
+        assert_eq!(ids(&songs), ["a", "b", "c", "d"]);
+
+        config.rules = vec![Rule::Smallest];
+        config.sort(&mut songs, &sizes);
+        assert_eq!(ids(&songs), ["d", "b", "a", "c"]);
+
+        config.rules = vec![Rule::HighestLevel];
+        config.sort(&mut songs, &sizes);
+        assert_eq!(ids(&songs), ["b", "d", "c", "a"]);
+
+        config.rules = vec![Rule::Uploaders, Rule::Smallest];
+        config.uploaders = vec!["u3".to_owned(), "u2".to_owned()];
+        config.sort(&mut songs, &sizes);
+        assert_eq!(ids(&songs), ["c", "d", "b", "a"]);
+
+        let config: QueueConfig =
+            toml::from_str("rules = [\"uploaders\", \"highest_level\", \"smallest\"]").unwrap();
+        assert_eq!(
+            config.rules,
+            [Rule::Uploaders, Rule::HighestLevel, Rule::Smallest]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::filter::Filter;
+use crate::longpath;
+use crate::quarantine;
+use crate::store::Entry;
+use crate::store::Store;
+use crate::trash::Disposal;
+
+/// Prefix of the name a song directory is moved to while it is removed.
+const REMOVING_PREFIX: &str = ".removing-";
+
+/// Removes the song `id` from the library `dest` along with everything kept
+/// about it: its directory, including the import sidecar and blob manifest
+/// in it, its quarantine, and its entry in `store`. Returns the entry.
+///
+/// The files are first moved aside and only disposed of once the entry is
+/// gone, so that a failure leaves the song as it was. Blobs the song shared
+/// are left for [`crate::cas::prune`].
+pub fn remove_song(
+    dest: &Path,
+    store: &mut Store,
+    id: &str,
+    disposal: Disposal,
+) -> anyhow::Result<Option<Entry>> {
+    let Some(entry) = store.get(id) else {
+        return Ok(None);
+    };
+    let mut moved = Vec::new();
+    for name in [
+        PathBuf::from(entry.dir(id)),
+        quarantine::dir(Path::new(""), id),
+    ] {
+        let dir = longpath::extended(&dest.join(&name));
+        if !dir.exists() {
+            continue;
+        }
+        let aside = longpath::extended(&dest.join(format!("{REMOVING_PREFIX}{}", moved.len())));
+        if aside.exists() {
+            fs::remove_dir_all(&aside)?;
+        }
+        if let Err(e) = fs::rename(&dir, &aside) {
+            restore(&moved);
+            return Err(e).with_context(|| format!("Failed to remove {}", dir.display()));
+        }
+        moved.push((dir, aside, name));
+    }
+    if let Err(e) = store.remove(id) {
+        restore(&moved);
+        return Err(e);
+    }
+    for (_, aside, name) in &moved {
+        disposal.dispose(dest, aside, name)?;
+    }
+    Ok(Some(entry))
+}
+
+/// Returns the songs of `store` that `filter` matches, for removing them by
+/// filter, along with the number of matching starred songs left out: those
+/// are only removed when given by ID.
+pub fn matching(store: &Store, filter: &Filter) -> (Vec<(String, Entry)>, usize) {
+    let (starred, songs): (Vec<_>, Vec<_>) = store
+        .entries()
+        .into_iter()
+        .filter(|(id, entry)| filter.matches_entry(id, entry))
+        .partition(|(_, entry)| entry.starred);
+    (songs, starred.len())
+}
+
+/// Moves the directories moved aside back into place.
+fn restore(moved: &[(PathBuf, PathBuf, PathBuf)]) {
+    for (dir, aside, _) in moved.iter().rev() {
+        let _ = fs::rename(aside, dir);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::import::SIDECAR_FILENAME;
+    use crate::Song;
+
+    #[test]
+    fn remove_song_and_metadata() {
+        let dest = tempdir().unwrap();
+        let song: Song = serde_json::from_value(json!({
+            "id": "a",
+            "user_id": "user",
+            "title": "Outbreak",
+            "artist": "RG+Ice",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+        }))
+        .unwrap();
+        let song_dir = dest.path().join("RG+Ice/Outbreak");
+        fs::create_dir_all(&song_dir).unwrap();
+        fs::write(song_dir.join("exh.ksh"), "title=Outbreak").unwrap();
+        fs::write(song_dir.join(SIDECAR_FILENAME), "{}").unwrap();
+        quarantine::hold(dest.path(), "a", &dest.path().join("none"), "Old").unwrap();
+        let mut store = Store::open(dest.path());
+        store
+            .insert("a", &Entry::new(&song, "RG+Ice/Outbreak"))
+            .unwrap();
+
+        let entry = remove_song(dest.path(), &mut store, "a", Disposal::Delete)
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.title, "Outbreak");
+        assert!(!song_dir.exists());
+        assert!(quarantine::list(dest.path()).unwrap().is_empty());
+        assert!(!Store::open_read_only(dest.path()).contains("a"));
+        assert_eq!(
+            fs::read_dir(dest.path())
+                .unwrap()
+                .filter(|entry| entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(REMOVING_PREFIX))
+                .count(),
+            0
+        );
+        assert!(remove_song(dest.path(), &mut store, "a", Disposal::Delete)
+            .unwrap()
+            .is_none());
+
+        // Trashed songs can be restored from the trash.
+        fs::create_dir(dest.path().join("b")).unwrap();
+        store.insert("b", &Entry::new(&song, "b")).unwrap();
+        remove_song(dest.path(), &mut store, "b", Disposal::Trash).unwrap();
+        assert!(!dest.path().join("b").exists());
+        assert!(!store.contains("b"));
+        assert_eq!(fs::read_dir(dest.path().join(".trash")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn keep_starred_songs_matching_filters() {
+        let dest = tempdir().unwrap();
+        let mut store = Store::open(dest.path());
+        for (id, starred) in [("a", false), ("b", true)] {
+            let song: Song = serde_json::from_value(json!({
+                "id": id,
+                "user_id": "user",
+                "title": "Outbreak",
+                "artist": "RG+Ice",
+                "uploaded_at": "2023-09-01 00:00:00",
+                "updated_at": "2023-09-01 00:00:00",
+            }))
+            .unwrap();
+            let entry = Entry {
+                starred,
+                ..Entry::new(&song, id)
+            };
+            store.insert(id, &entry).unwrap();
+        }
+        let filter = Filter {
+            users: vec![String::from("user")],
+            ..Default::default()
+        };
+        let (songs, starred) = matching(&store, &filter);
+        assert_eq!(songs.len(), 1);
+        assert_eq!(songs[0].0, "a");
+        assert_eq!(starred, 1);
+    }
+}
@@ -0,0 +1,371 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::ensure;
+use anyhow::Context;
+
+use crate::ksh;
+
+/// Height of a quarter note in pixels.
+const BEAT_HEIGHT: f64 = 48.0;
+
+/// Width of a BT lane in pixels; FX notes span two lanes.
+const LANE_WIDTH: f64 = 24.0;
+
+/// Space on each side of the lanes for measure numbers and BPM changes.
+const MARGIN: f64 = 48.0;
+
+const WIDTH: f64 = LANE_WIDTH * 4.0 + MARGIN * 2.0;
+
+/// Height of a chip note in pixels.
+const CHIP_HEIGHT: f64 = 6.0;
+
+const BT_COLOR: &str = "#eee";
+const FX_COLOR: &str = "#f80";
+const LASER_COLORS: [&str; 2] = ["#0cf", "#f3c"];
+
+/// A line of notes in the chart body, e.g. `1020|02|0o`.
+#[derive(Debug, Clone, Copy)]
+struct NoteLine<'a> {
+    bt: &'a [u8],
+    fx: &'a [u8],
+    lasers: &'a [u8],
+}
+
+impl<'a> NoteLine<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let mut parts = line.split('|');
+        let bt = parts.next()?.as_bytes();
+        let fx = parts.next()?.as_bytes();
+        // Lasers may be followed by a spin, e.g. `0o@(192`.
+        let lasers = parts.next()?.as_bytes();
+        (bt.len() == 4 && fx.len() == 2 && lasers.len() >= 2).then_some(Self {
+            bt,
+            fx,
+            lasers: &lasers[..2],
+        })
+    }
+}
+
+/// Converts a laser position character (`0` to `9`, `A` to `Z`, `a` to `o`)
+/// to a fraction of the lane width.
+fn laser_position(c: u8) -> Option<f64> {
+    let position = match c {
+        b'0'..=b'9' => c - b'0',
+        b'A'..=b'Z' => c - b'A' + 10,
+        b'a'..=b'o' => c - b'a' + 36,
+        _ => return None,
+    };
+    Some(f64::from(position) / 50.0)
+}
+
+/// Something in the chart body, placed at its distance in pixels from the
+/// start of the chart.
+#[derive(Debug)]
+enum Event<'a> {
+    Measure(f64, usize),
+    Bpm(f64, &'a str),
+    Notes(f64, NoteLine<'a>),
+}
+
+/// Lays out the chart body, returning the events and the total height. Each
+/// measure is as tall as its time signature and split evenly between its note
+/// lines.
+fn layout(chart: &str) -> (Vec<Event<'_>>, f64) {
+    let mut events = Vec::new();
+    let mut beat = (4.0, 4.0);
+    let mut y = 0.0;
+    let mut measure: Vec<&str> = Vec::new();
+    let mut number = 0;
+    // The first separator ends the header.
+    let body = chart
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != "--")
+        .skip(1);
+    for line in body {
+        if line != "--" {
+            measure.push(line);
+            continue;
+        }
+        for option in measure.iter().take_while(|line| !line.contains('|')) {
+            let beat_value = option.strip_prefix("beat=").and_then(|v| v.split_once('/'));
+            if let Some((n, d)) = beat_value {
+                if let (Ok(n), Ok(d)) = (n.parse::<f64>(), d.parse::<f64>()) {
+                    beat = (n, d);
+                }
+            }
+        }
+        let height = BEAT_HEIGHT * 4.0 * beat.0 / beat.1;
+        let lines = measure.iter().filter(|line| line.contains('|')).count();
+        let step = height / lines.max(1) as f64;
+        number += 1;
+        events.push(Event::Measure(y, number));
+        let mut line_y = y;
+        for line in measure.drain(..) {
+            if let Some(notes) = NoteLine::parse(line) {
+                events.push(Event::Notes(line_y, notes));
+                line_y += step;
+            } else if let Some(bpm) = line.strip_prefix("t=") {
+                events.push(Event::Bpm(line_y, bpm));
+            }
+        }
+        y += height;
+    }
+    (events, y)
+}
+
+/// SVG elements drawn bottom to top, like the game scrolls.
+struct Canvas {
+    height: f64,
+    background: String,
+    notes: String,
+    labels: String,
+}
+
+impl Canvas {
+    /// Converts a distance from the start of the chart to an SVG y
+    /// coordinate.
+    fn top(&self, y: f64) -> f64 {
+        self.height - y
+    }
+
+    fn measure_line(&mut self, y: f64, number: usize) {
+        let y = self.top(y);
+        let _ = writeln!(
+            self.background,
+            r##"<line x1="{MARGIN}" y1="{y}" x2="{}" y2="{y}" stroke="#666"/>"##,
+            WIDTH - MARGIN
+        );
+        let _ = writeln!(
+            self.labels,
+            r##"<text x="4" y="{}" fill="#888" font-size="10">{number}</text>"##,
+            y - 2.0
+        );
+    }
+
+    fn bpm(&mut self, y: f64, bpm: &str) {
+        let _ = writeln!(
+            self.labels,
+            r##"<text x="{}" y="{}" fill="#6f6" font-size="10">{}</text>"##,
+            WIDTH - MARGIN + 4.0,
+            self.top(y) - 2.0,
+            escape(bpm)
+        );
+    }
+
+    /// Draws a note over `lanes` lanes starting at lane `lane`, from `from` to
+    /// `to`, or a chip note if they are equal.
+    fn note(&mut self, lane: usize, lanes: usize, from: f64, to: f64, color: &str) {
+        let x = MARGIN + lane as f64 * LANE_WIDTH;
+        let width = lanes as f64 * LANE_WIDTH;
+        let element = if from == to {
+            format!(
+                r#"<rect x="{}" y="{}" width="{}" height="{CHIP_HEIGHT}" fill="{color}"/>"#,
+                x + 1.0,
+                self.top(from) - CHIP_HEIGHT,
+                width - 2.0
+            )
+        } else {
+            format!(
+                r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{color}" fill-opacity="0.5"/>"#,
+                x + 3.0,
+                self.top(to),
+                width - 6.0,
+                to - from
+            )
+        };
+        self.notes.push_str(&element);
+        self.notes.push('\n');
+    }
+
+    fn laser(&mut self, from: (f64, f64), to: (f64, f64), color: &str) {
+        let x = |position: f64| MARGIN + position * LANE_WIDTH * 4.0;
+        let _ = writeln!(
+            self.notes,
+            r#"<line x1="{}" y1="{}" x2="{}" y2="{}" stroke="{color}" stroke-width="6" stroke-opacity="0.7"/>"#,
+            x(from.1),
+            self.top(from.0),
+            x(to.1),
+            self.top(to.0)
+        );
+    }
+
+    fn finish(self) -> String {
+        let height = self.height;
+        let mut svg = format!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{height}" viewBox="0 0 {WIDTH} {height}">
+<rect width="{WIDTH}" height="{height}" fill="#111"/>
+"##
+        );
+        for lane in 0..=4 {
+            let x = MARGIN + f64::from(lane) * LANE_WIDTH;
+            let _ = writeln!(
+                svg,
+                r##"<line x1="{x}" y1="0" x2="{x}" y2="{height}" stroke="#444"/>"##
+            );
+        }
+        svg.push_str(&self.background);
+        svg.push_str(&self.notes);
+        svg.push_str(&self.labels);
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+/// Renders a ksh chart as a tall SVG image showing its BT and FX notes,
+/// lasers, BPM changes, and numbered measures, read from bottom to top.
+pub fn render_svg(chart: &str) -> String {
+    let (events, height) = layout(chart);
+    let mut canvas = Canvas {
+        height: height.max(BEAT_HEIGHT),
+        background: String::new(),
+        notes: String::new(),
+        labels: String::new(),
+    };
+
+    // Where the long notes and laser segments in progress started.
+    let mut long_bt: [Option<f64>; 4] = [None; 4];
+    let mut long_fx: [Option<f64>; 2] = [None; 2];
+    let mut lasers: [Option<(f64, f64)>; 2] = [None; 2];
+    for event in events {
+        match event {
+            Event::Measure(y, number) => canvas.measure_line(y, number),
+            Event::Bpm(y, bpm) => canvas.bpm(y, bpm),
+            Event::Notes(y, line) => {
+                // BT long notes are `2`, FX long notes are `1`.
+                let lanes = line.bt.iter().map(|&c| (c, b'1', b'2', 1, BT_COLOR));
+                let fx = line.fx.iter().map(|&c| (c, b'2', b'1', 2, FX_COLOR));
+                let starts = long_bt.iter_mut().chain(long_fx.iter_mut());
+                for (i, ((c, chip, long, width, color), start)) in
+                    lanes.chain(fx).zip(starts).enumerate()
+                {
+                    let lane = if i < 4 { i } else { (i - 4) * 2 };
+                    if c != long {
+                        if let Some(from) = start.take() {
+                            canvas.note(lane, width, from, y, color);
+                        }
+                    }
+                    if c == chip {
+                        canvas.note(lane, width, y, y, color);
+                    } else if c == long {
+                        start.get_or_insert(y);
+                    }
+                }
+                for (i, &c) in line.lasers.iter().enumerate() {
+                    if let Some(position) = laser_position(c) {
+                        if let Some(from) = lasers[i] {
+                            canvas.laser(from, (y, position), LASER_COLORS[i]);
+                        }
+                        lasers[i] = Some((y, position));
+                    } else if c != b':' {
+                        lasers[i] = None;
+                    }
+                }
+            }
+        }
+    }
+    // Close long notes that run to the end of the chart.
+    let end = height;
+    for (lane, start) in long_bt.iter_mut().enumerate() {
+        if let Some(from) = start.take() {
+            canvas.note(lane, 1, from, end, BT_COLOR);
+        }
+    }
+    for (i, start) in long_fx.iter_mut().enumerate() {
+        if let Some(from) = start.take() {
+            canvas.note(i * 2, 2, from, end, FX_COLOR);
+        }
+    }
+    canvas.finish()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Renders the chart at `path` into an SVG image next to it, returning the
+/// path of the image.
+pub fn render_file(path: &Path) -> anyhow::Result<PathBuf> {
+    ensure!(
+        !ksh::is_kson(path),
+        "Rendering KSON charts is not supported: {}",
+        path.display()
+    );
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let svg = render_svg(&ksh::decode(&bytes));
+    let out = path.with_extension("svg");
+    fs::write(&out, svg).with_context(|| format!("Failed to write {}", out.display()))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CHART: &str = "\
+title=Test
+t=120-240
+--
+beat=4/4
+t=120
+1000|00|0o
+0200|10|::
+0200|10|:-
+0002|02|o-
+--
+beat=2/4
+t=240
+0000|00|--
+--
+";
+
+    #[test]
+    fn layout_measures() {
+        let (events, height) = layout(CHART);
+        // A 4/4 measure and a 2/4 measure.
+        assert_eq!(height, BEAT_HEIGHT * 4.0 + BEAT_HEIGHT * 2.0);
+        let notes: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Notes(y, _) => Some(*y),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notes, [0.0, 48.0, 96.0, 144.0, 192.0]);
+        let bpms: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Bpm(y, bpm) => Some((*y, *bpm)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bpms, [(0.0, "120"), (192.0, "240")]);
+    }
+
+    #[test]
+    fn render_notes() {
+        let svg = render_svg(CHART);
+        assert!(svg.starts_with("<svg"));
+        // The background, a BT chip, two BT long notes, an FX chip, and an FX
+        // long note.
+        assert_eq!(svg.matches("<rect").count(), 1 + 5);
+        assert_eq!(svg.matches(r#"fill-opacity="0.5""#).count(), 3);
+        // The left laser sweeps across once; the right one never moves.
+        assert_eq!(svg.matches(LASER_COLORS[0]).count(), 1);
+        assert_eq!(svg.matches(LASER_COLORS[1]).count(), 0);
+        assert!(svg.contains(">240</text>"));
+    }
+
+    #[test]
+    fn laser_positions() {
+        assert_eq!(laser_position(b'0'), Some(0.0));
+        assert_eq!(laser_position(b'o'), Some(1.0));
+        assert_eq!(laser_position(b'P'), Some(0.5));
+        assert_eq!(laser_position(b'-'), None);
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use tracing::warn;
+
+use crate::layout::Fields;
+use crate::layout::Layout;
+use crate::layout::TakenDirs;
+use crate::store::Entry;
+use crate::store::Store;
+use crate::trash::Disposal;
+
+const JOURNAL_FILENAME: &str = ".reorganize.journal";
+
+/// Directory that song directories are moved through, so that new names can
+/// never clash with directories that are yet to be moved.
+const STAGING_DIRNAME: &str = ".reorganize";
+
+/// Files that file managers leave in the folders they showed, which would keep
+/// a folder from being removed once its songs have moved out.
+const CLUTTER_FILENAMES: &[&str] = &[".DS_Store", "Thumbs.db", "desktop.ini"];
+
+/// Outcome of a reorganization.
+#[derive(Debug, Default)]
+pub struct Reorganization {
+    /// Songs that were moved, as (ID, old directory, new directory).
+    pub moved: Vec<(String, String, String)>,
+
+    /// IDs of songs left in place because their directory is missing or their
+    /// metadata was recorded by an older version without titles.
+    pub skipped: Vec<String>,
+}
+
+/// Moves the song directories in `dest` to match `layout` and records the new
+/// locations in the metadata store. Folders left with nothing but file manager
+/// clutter are moved into the trash if `disposal` trashes.
+///
+/// Every rename is written to a journal first. If a move fails, or a previous
+/// run was interrupted, the journal is replayed backwards to restore the
+/// original directories and metadata.
+pub fn reorganize(
+    dest: &Path,
+    layout: &Layout,
+    disposal: Disposal,
+) -> anyhow::Result<Reorganization> {
+    let mut store = Store::open(dest);
+    if dest.join(JOURNAL_FILENAME).exists() {
+        warn!("Rolling back an interrupted reorganization");
+        rollback(dest, &mut store, disposal)?;
+    }
+
+    let mut journal = Journal::create(dest)?;
+    match move_all(dest, layout, disposal, &mut store, &mut journal) {
+        Ok(reorganization) => {
+            journal.finish()?;
+            Ok(reorganization)
+        }
+        Err(e) => {
+            drop(journal);
+            rollback(dest, &mut store, disposal)
+                .context("Failed to roll back the reorganization")?;
+            Err(e.context("Reorganization failed; all changes were rolled back"))
+        }
+    }
+}
+
+fn move_all(
+    dest: &Path,
+    layout: &Layout,
+    disposal: Disposal,
+    store: &mut Store,
+    journal: &mut Journal,
+) -> anyhow::Result<Reorganization> {
+    let mut reorganization = Reorganization::default();
+
+    let mut staged = Vec::new();
+    // Songs that are not moved keep their directories, even where these are
+    // missing.
+    let mut taken = TakenDirs::default();
+    for (id, entry) in store.entries() {
+        let old = entry.dir(&id).to_owned();
+        if entry.title.is_empty() || !dest.join(&old).is_dir() {
+            taken.insert(&old);
+            reorganization.skipped.push(id);
+            continue;
+        }
+        if layout.dir_name(&Fields::entry(&id, &entry)) == old {
+            continue;
+        }
+        let staging = format!("{STAGING_DIRNAME}/{id}");
+        journal.rename(&id, &old, &staging)?;
+        remove_empty_parents(dest, &old, disposal);
+        staged.push((id, entry, old, staging));
+    }
+
+    for (id, entry, old, staging) in staged {
+        let new = layout.unique_dir_name(&Fields::entry(&id, &entry), dest, &taken);
+        journal.rename(&id, &staging, &new)?;
+        store.insert(
+            &id,
+            &Entry {
+                dir: (new != id).then(|| new.clone()),
+                ..entry
+            },
+        )?;
+        reorganization.moved.push((id, old, new));
+    }
+    Ok(reorganization)
+}
+
+/// Undoes the renames recorded in the journal in `dest`, newest first, and
+/// restores the original directories in the metadata store.
+fn rollback(dest: &Path, store: &mut Store, disposal: Disposal) -> anyhow::Result<()> {
+    let path = dest.join(JOURNAL_FILENAME);
+    let journal =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let steps: Vec<_> = journal
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            Some((fields.next()?, fields.next()?, fields.next()?))
+        })
+        .collect();
+
+    for (_, from, to) in steps.iter().rev() {
+        let (from_path, to_path) = (dest.join(from), dest.join(to));
+        // The last step may have been recorded without being carried out.
+        if to_path.exists() && !from_path.exists() {
+            rename(&to_path, &from_path)?;
+            remove_empty_parents(dest, to, disposal);
+        }
+    }
+
+    let mut original_dirs = HashMap::new();
+    for (id, from, _) in &steps {
+        original_dirs.entry(*id).or_insert(*from);
+    }
+    for (id, dir) in original_dirs {
+        if let Some(entry) = store.get(id) {
+            let dir = (dir != id).then(|| dir.to_owned());
+            store.insert(id, &Entry { dir, ..entry })?;
+        }
+    }
+
+    let _ = fs::remove_dir(dest.join(STAGING_DIRNAME));
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+/// Write-ahead log of the renames of a reorganization. Each line holds the
+/// song ID and the old and new directory, separated by tabs.
+struct Journal {
+    dest: PathBuf,
+    file: fs::File,
+}
+
+impl Journal {
+    fn create(dest: &Path) -> anyhow::Result<Self> {
+        let file = fs::File::create(dest.join(JOURNAL_FILENAME))?;
+        Ok(Self {
+            dest: dest.to_owned(),
+            file,
+        })
+    }
+
+    fn rename(&mut self, id: &str, from: &str, to: &str) -> anyhow::Result<()> {
+        writeln!(self.file, "{id}\t{from}\t{to}")?;
+        self.file.sync_data()?;
+        rename(&self.dest.join(from), &self.dest.join(to))
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        let _ = fs::remove_dir(self.dest.join(STAGING_DIRNAME));
+        fs::remove_file(self.dest.join(JOURNAL_FILENAME))?;
+        Ok(())
+    }
+}
+
+fn rename(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(from, to)
+        .with_context(|| format!("Failed to move {} to {}", from.display(), to.display()))
+}
+
+/// Removes the parent directories of `dir` inside `dest` that have become
+/// empty. Those left with only clutter are moved into the trash if `disposal`
+/// trashes, and kept otherwise.
+fn remove_empty_parents(dest: &Path, dir: &str, disposal: Disposal) {
+    let mut parent = Path::new(dir).parent();
+    while let Some(p) = parent.filter(|p| !p.as_os_str().is_empty()) {
+        let path = dest.join(p);
+        let removed = if disposal == Disposal::Trash && holds_only_clutter(&path) {
+            disposal.dispose(dest, &path, p).is_ok()
+        } else {
+            fs::remove_dir(&path).is_ok()
+        };
+        if !removed {
+            break;
+        }
+        parent = p.parent();
+    }
+}
+
+/// Returns whether the directory `path` holds files, all of which are clutter.
+fn holds_only_clutter(path: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(path) else {
+        return false;
+    };
+    let mut entries = entries.peekable();
+    entries.peek().is_some()
+        && entries.all(|entry| {
+            entry.is_ok_and(|entry| {
+                entry.file_type().is_ok_and(|file_type| file_type.is_file())
+                    && CLUTTER_FILENAMES.contains(&entry.file_name().to_string_lossy().as_ref())
+            })
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn entry(title: &str, dir: Option<&str>) -> Entry {
+        Entry {
+            downloaded_at: Utc::now(),
+            title: title.to_owned(),
+            artist: String::from("artist"),
+            user_id: String::from("user"),
+            dir: dir.map(str::to_owned),
+            ..Default::default()
+        }
+    }
+
+    fn add_song(dest: &Path, id: &str, entry: Entry) {
+        let dir = dest.join(entry.dir(id));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("chart.ksh"), id).unwrap();
+        Store::open(dest).insert(id, &entry).unwrap();
+    }
+
+    #[test]
+    fn reorganize_to_template_and_back() {
+        let dest = tempdir().unwrap();
+        add_song(dest.path(), "a", entry("Outbreak", None));
+        add_song(dest.path(), "b", entry("Turing Love", None));
+        add_song(dest.path(), "c", entry("", None));
+
+        let layout: Layout = "{artist}/{title}".parse().unwrap();
+        let reorganization = reorganize(dest.path(), &layout, Disposal::Delete).unwrap();
+
+        assert_eq!(reorganization.moved.len(), 2);
+        assert_eq!(reorganization.skipped, ["c"]);
+        assert!(dest.path().join("artist/Outbreak/chart.ksh").exists());
+        assert!(!dest.path().join("a").exists());
+        assert!(!dest.path().join(JOURNAL_FILENAME).exists());
+        assert!(!dest.path().join(STAGING_DIRNAME).exists());
+        let store = Store::open_read_only(dest.path());
+        assert_eq!(
+            store.get("b").unwrap().dir.as_deref(),
+            Some("artist/Turing Love")
+        );
+
+        reorganize(dest.path(), &Layout::Id, Disposal::Delete).unwrap();
+        assert_eq!(
+            fs::read_to_string(dest.path().join("a/chart.ksh")).unwrap(),
+            "a"
+        );
+        assert!(!dest.path().join("artist").exists());
+        assert_eq!(
+            Store::open_read_only(dest.path()).get("b").unwrap().dir,
+            None
+        );
+    }
+
+    #[test]
+    fn trash_folders_left_with_clutter() {
+        let dest = tempdir().unwrap();
+        add_song(dest.path(), "a", entry("Outbreak", Some("artist/Outbreak")));
+        add_song(
+            dest.path(),
+            "b",
+            entry("Turing Love", Some("other/Turing Love")),
+        );
+        fs::write(dest.path().join("artist/.DS_Store"), "").unwrap();
+        fs::create_dir(dest.path().join("other/desktop.ini")).unwrap();
+
+        // Clutter is only ever trashed, never deleted.
+        let layout: Layout = "{title}".parse().unwrap();
+        reorganize(dest.path(), &layout, Disposal::Delete).unwrap();
+        assert!(dest.path().join("Outbreak/chart.ksh").exists());
+        assert!(dest.path().join("artist/.DS_Store").exists());
+
+        add_song(dest.path(), "c", entry("Jump", Some("artist/Jump")));
+        add_song(dest.path(), "d", entry("Rise", Some("other/Rise")));
+        reorganize(dest.path(), &Layout::Id, Disposal::Trash).unwrap();
+        // A folder named like clutter is no clutter.
+        assert!(dest.path().join("other/desktop.ini").is_dir());
+        assert!(dest.path().join("a/chart.ksh").exists());
+        assert!(!dest.path().join("artist").exists());
+        let batch = fs::read_dir(dest.path().join(".trash"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        assert!(batch.join("artist/.DS_Store").exists());
+    }
+
+    #[test]
+    fn interrupted_reorganization_is_rolled_back() {
+        let dest = tempdir().unwrap();
+        add_song(dest.path(), "a", entry("Outbreak", Some("artist/Outbreak")));
+
+        // Simulate a run that staged the song and crashed before the next move.
+        fs::create_dir(dest.path().join(STAGING_DIRNAME)).unwrap();
+        fs::rename(
+            dest.path().join("artist/Outbreak"),
+            dest.path().join(STAGING_DIRNAME).join("a"),
+        )
+        .unwrap();
+        fs::write(
+            dest.path().join(JOURNAL_FILENAME),
+            "a\tartist/Outbreak\t.reorganize/a\na\t.reorganize/a\ta\n",
+        )
+        .unwrap();
+
+        let layout: Layout = "{artist}/{title}".parse().unwrap();
+        let reorganization = reorganize(dest.path(), &layout, Disposal::Delete).unwrap();
+
+        assert!(reorganization.moved.is_empty());
+        assert!(dest.path().join("artist/Outbreak/chart.ksh").exists());
+        assert!(!dest.path().join(STAGING_DIRNAME).exists());
+        assert!(!dest.path().join(JOURNAL_FILENAME).exists());
+    }
+}
@@ -0,0 +1,241 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::ensure;
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::Utc;
+use hmac::Hmac;
+use hmac::Mac;
+use serde::Deserialize;
+use sha2::Digest;
+use sha2::Sha256;
+use url::Url;
+
+use crate::remote::uri_encode;
+use crate::remote::Remote;
+
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Settings of the `[s3]` table of the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    /// Endpoint of the S3-compatible service, e.g.
+    /// `https://s3.eu-west-1.amazonaws.com` or `http://minio.local:9000`.
+    pub endpoint: String,
+
+    pub bucket: String,
+
+    #[serde(default)]
+    pub region: Option<String>,
+
+    /// Prepended to the object keys, e.g. `nautica/`.
+    #[serde(default)]
+    pub prefix: String,
+
+    /// Falls back to the `AWS_ACCESS_KEY_ID` environment variable.
+    #[serde(default)]
+    pub access_key_id: Option<String>,
+
+    /// Falls back to the `AWS_SECRET_ACCESS_KEY` environment variable.
+    #[serde(default)]
+    pub secret_access_key: Option<String>,
+
+    /// Keep the local copy of uploaded songs instead of removing it.
+    #[serde(default)]
+    pub keep_local: bool,
+}
+
+/// A bucket of an S3-compatible object storage, addressed path-style
+/// (`<endpoint>/<bucket>/<key>`), which every such service supports.
+#[derive(Clone)]
+pub struct Bucket {
+    endpoint: Url,
+    name: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+impl fmt::Debug for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Leave the secret out of logs.
+        f.debug_struct("Bucket")
+            .field("endpoint", &self.endpoint.as_str())
+            .field("name", &self.name)
+            .field("region", &self.region)
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Bucket {
+    pub fn new(config: &S3Config) -> anyhow::Result<Self> {
+        let credential = |value: &Option<String>, var| {
+            value
+                .clone()
+                .or_else(|| std::env::var(var).ok())
+                .with_context(|| format!("No S3 credentials; set {var} or configure them in [s3]"))
+        };
+        Ok(Self {
+            endpoint: Url::parse(&config.endpoint)
+                .with_context(|| format!("Invalid S3 endpoint: {}", config.endpoint))?,
+            name: config.bucket.clone(),
+            region: config
+                .region
+                .clone()
+                .unwrap_or_else(|| String::from(DEFAULT_REGION)),
+            access_key_id: credential(&config.access_key_id, "AWS_ACCESS_KEY_ID")?,
+            secret_access_key: credential(&config.secret_access_key, "AWS_SECRET_ACCESS_KEY")?,
+        })
+    }
+
+    /// Uploads `body` as the object `key`.
+    pub fn put_object(&self, key: &str, body: Vec<u8>) -> anyhow::Result<()> {
+        let path = format!(
+            "{}/{}/{}",
+            self.endpoint.path().trim_end_matches('/'),
+            uri_encode(&self.name),
+            uri_encode(key)
+        );
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let authorization = self.authorization("PUT", &url, &body, Utc::now());
+        let resp = attohttpc::put(url.as_str())
+            .header("Authorization", authorization.header)
+            .header("x-amz-content-sha256", authorization.payload_hash)
+            .header("x-amz-date", authorization.date)
+            .bytes(body)
+            .send()?;
+        ensure!(
+            resp.is_success(),
+            "Failed to upload {key}: {} {}",
+            resp.status(),
+            resp.text().unwrap_or_default()
+        );
+        Ok(())
+    }
+
+    /// Signs a request with AWS Signature Version 4.
+    fn authorization(
+        &self,
+        method: &str,
+        url: &Url,
+        body: &[u8],
+        now: DateTime<Utc>,
+    ) -> Authorization {
+        let date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let day = &date[..8];
+        let payload_hash = hex::encode(Sha256::digest(body));
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{method}\n{}\n{}\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{date}\n\n{signed_headers}\n{payload_hash}",
+            url.path(),
+            url.query().unwrap_or_default()
+        );
+        let scope = format!("{day}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{date}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request))
+        );
+        let key = [day, &self.region, "s3", "aws4_request"].iter().fold(
+            format!("AWS4{}", self.secret_access_key).into_bytes(),
+            |key, part| hmac(&key, part.as_bytes()),
+        );
+        let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+        Authorization {
+            header: format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                self.access_key_id
+            ),
+            payload_hash,
+            date,
+        }
+    }
+}
+
+/// Headers that authenticate a request.
+struct Authorization {
+    header: String,
+    payload_hash: String,
+    date: String,
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl Remote for Bucket {
+    fn upload(&self, dir: &str, files: &[PathBuf]) -> anyhow::Result<()> {
+        for file in files {
+            let name = file.file_name().unwrap_or_default().to_string_lossy();
+            self.put_object(&format!("{dir}/{name}"), fs::read(file)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+    use httpmock::prelude::*;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn config(endpoint: String) -> S3Config {
+        S3Config {
+            endpoint,
+            bucket: String::from("charts"),
+            region: None,
+            prefix: String::from("nautica/"),
+            access_key_id: Some(String::from("AKIDEXAMPLE")),
+            secret_access_key: Some(String::from("secret")),
+            keep_local: false,
+        }
+    }
+
+    #[test]
+    fn sign_request() {
+        let bucket = Bucket::new(&config(String::from("http://localhost:9000"))).unwrap();
+        let url = Url::parse("http://localhost:9000/charts/nautica/a/chart%20EXH.ksh").unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        let authorization = bucket.authorization("PUT", &url, b"chart", now);
+        assert_eq!(authorization.date, "20240102T030405Z");
+        assert_eq!(
+            authorization.header,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20240102/us-east-1/s3/aws4_request, \
+             SignedHeaders=host;x-amz-content-sha256;x-amz-date, \
+             Signature=3c246ecb0d835f10c24d22e47a5310eba6acbe7f26948e20edbe16e229fd0999"
+        );
+        assert_eq!(uri_encode("a b/曲.ogg"), "a%20b/%E6%9B%B2.ogg");
+    }
+
+    #[test]
+    fn upload_objects() {
+        let server = MockServer::start();
+        let put = server.mock(|when, then| {
+            when.method(PUT)
+                .path("/charts/nautica/a/%E6%9B%B2.ksh")
+                .header_exists("authorization")
+                .header_exists("x-amz-date")
+                .body("chart");
+            then.status(200);
+        });
+
+        let dir = tempdir().unwrap();
+        let chart = dir.path().join("曲.ksh");
+        fs::write(&chart, "chart").unwrap();
+        let bucket = Bucket::new(&config(server.base_url())).unwrap();
+        bucket.upload("nautica/a", &[chart]).unwrap();
+        put.assert();
+    }
+}
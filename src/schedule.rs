@@ -0,0 +1,176 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::Duration;
+use chrono::Local;
+use chrono::NaiveDateTime;
+use chrono::TimeZone;
+use chrono::Timelike;
+
+/// A five-field cron expression: minute, hour, day of month, month, and day of
+/// week.
+///
+/// Each field accepts `*`, numbers, ranges (`1-5`), lists (`1,3`), and steps
+/// (`*/15`, `0-30/10`). Day of week 0 and 7 are both Sunday.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    /// Returns the first time strictly after `after` that matches the schedule.
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut t = after.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        // Every schedule matches at least once within a leap-year cycle.
+        let limit = t + Duration::days(366 * 4 + 1);
+        while t < limit {
+            if !self.matches_day(&t) {
+                t = t.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if bit(self.hours, t.hour()) && bit(self.minutes, t.minute()) {
+                if let Some(local) = Local.from_local_datetime(&t).earliest() {
+                    return Some(local);
+                }
+            }
+            t += Duration::minutes(1);
+        }
+        None
+    }
+
+    fn matches_day(&self, t: &NaiveDateTime) -> bool {
+        if !bit(self.months, t.month()) {
+            return false;
+        }
+        let day = bit(self.days, t.day());
+        let weekday = bit(self.weekdays, t.weekday().num_days_from_sunday());
+        // Like cron, when both fields are restricted either one may match.
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+        ensure!(
+            fields.len() == 5,
+            "Cron expression must have 5 fields, got {}: {s:?}",
+            fields.len()
+        );
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        if bit(weekdays, 7) {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+fn bit(set: u64, n: u32) -> bool {
+    set & (1 << n) != 0
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let mut set = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse()?),
+            None => (item, 1),
+        };
+        ensure!(step > 0, "Step must be positive: {item:?}");
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (start.parse()?, end.parse()?),
+                // `5/10` means from 5 to the maximum in steps of 10.
+                None if item.contains('/') => (range.parse()?, max),
+                None => {
+                    let n = range.parse()?;
+                    (n, n)
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            bail!("{item:?} is out of range {min}-{max}");
+        }
+        for n in (start..=end).step_by(step) {
+            set |= 1 << n;
+        }
+    }
+    if set == 0 {
+        return Err(anyhow!("Empty cron field: {field:?}"));
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn local(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    #[test]
+    fn next_after() {
+        let nightly: Schedule = "0 4 * * *".parse().unwrap();
+        assert_eq!(
+            nightly.next_after(local(2023, 9, 10, 3, 59)),
+            Some(local(2023, 9, 10, 4, 0))
+        );
+        assert_eq!(
+            nightly.next_after(local(2023, 9, 10, 4, 0)),
+            Some(local(2023, 9, 11, 4, 0))
+        );
+
+        let quarterly: Schedule = "*/15 9-17 * * 1-5".parse().unwrap();
+        // 2023-09-09 is a Saturday.
+        assert_eq!(
+            quarterly.next_after(local(2023, 9, 9, 12, 0)),
+            Some(local(2023, 9, 11, 9, 0))
+        );
+        assert_eq!(
+            quarterly.next_after(local(2023, 9, 11, 9, 1)),
+            Some(local(2023, 9, 11, 9, 15))
+        );
+
+        let sundays: Schedule = "30 2 * * 7".parse().unwrap();
+        assert_eq!(
+            sundays.next_after(local(2023, 9, 11, 0, 0)),
+            Some(local(2023, 9, 17, 2, 30))
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!("0 4 * *".parse::<Schedule>().is_err());
+        assert!("60 4 * * *".parse::<Schedule>().is_err());
+        assert!("0 4 0 * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+        assert!("a * * * *".parse::<Schedule>().is_err());
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::ksh;
+use crate::ksh::Header;
+use crate::notify::Notifier;
+use crate::store::Entry;
+use crate::store::Store;
+use crate::DownloadReport;
+use crate::Song;
+
+const INDEX_FILENAME: &str = "search.idx";
+
+/// Kana pairs read as one syllable, checked before [`KANA`].
+const KANA_PAIRS: &[(&str, &str)] = &[
+    ("きゃ", "kya"),
+    ("きゅ", "kyu"),
+    ("きょ", "kyo"),
+    ("しゃ", "sha"),
+    ("しゅ", "shu"),
+    ("しょ", "sho"),
+    ("しぇ", "she"),
+    ("ちゃ", "cha"),
+    ("ちゅ", "chu"),
+    ("ちょ", "cho"),
+    ("ちぇ", "che"),
+    ("にゃ", "nya"),
+    ("にゅ", "nyu"),
+    ("にょ", "nyo"),
+    ("ひゃ", "hya"),
+    ("ひゅ", "hyu"),
+    ("ひょ", "hyo"),
+    ("みゃ", "mya"),
+    ("みゅ", "myu"),
+    ("みょ", "myo"),
+    ("りゃ", "rya"),
+    ("りゅ", "ryu"),
+    ("りょ", "ryo"),
+    ("ぎゃ", "gya"),
+    ("ぎゅ", "gyu"),
+    ("ぎょ", "gyo"),
+    ("じゃ", "ja"),
+    ("じゅ", "ju"),
+    ("じょ", "jo"),
+    ("じぇ", "je"),
+    ("びゃ", "bya"),
+    ("びゅ", "byu"),
+    ("びょ", "byo"),
+    ("ぴゃ", "pya"),
+    ("ぴゅ", "pyu"),
+    ("ぴょ", "pyo"),
+    ("ふぁ", "fa"),
+    ("ふぃ", "fi"),
+    ("ふぇ", "fe"),
+    ("ふぉ", "fo"),
+    ("てぃ", "ti"),
+    ("でぃ", "di"),
+    ("うぃ", "wi"),
+    ("うぇ", "we"),
+    ("ゔぁ", "va"),
+    ("ゔぃ", "vi"),
+    ("ゔぇ", "ve"),
+    ("ゔぉ", "vo"),
+];
+
+/// Hepburn romanization of single hiragana.
+const KANA: &[(char, &str)] = &[
+    ('あ', "a"),
+    ('い', "i"),
+    ('う', "u"),
+    ('え', "e"),
+    ('お', "o"),
+    ('か', "ka"),
+    ('き', "ki"),
+    ('く', "ku"),
+    ('け', "ke"),
+    ('こ', "ko"),
+    ('さ', "sa"),
+    ('し', "shi"),
+    ('す', "su"),
+    ('せ', "se"),
+    ('そ', "so"),
+    ('た', "ta"),
+    ('ち', "chi"),
+    ('つ', "tsu"),
+    ('て', "te"),
+    ('と', "to"),
+    ('な', "na"),
+    ('に', "ni"),
+    ('ぬ', "nu"),
+    ('ね', "ne"),
+    ('の', "no"),
+    ('は', "ha"),
+    ('ひ', "hi"),
+    ('ふ', "fu"),
+    ('へ', "he"),
+    ('ほ', "ho"),
+    ('ま', "ma"),
+    ('み', "mi"),
+    ('む', "mu"),
+    ('め', "me"),
+    ('も', "mo"),
+    ('や', "ya"),
+    ('ゆ', "yu"),
+    ('よ', "yo"),
+    ('ら', "ra"),
+    ('り', "ri"),
+    ('る', "ru"),
+    ('れ', "re"),
+    ('ろ', "ro"),
+    ('わ', "wa"),
+    ('を', "wo"),
+    ('ん', "n"),
+    ('が', "ga"),
+    ('ぎ', "gi"),
+    ('ぐ', "gu"),
+    ('げ', "ge"),
+    ('ご', "go"),
+    ('ざ', "za"),
+    ('じ', "ji"),
+    ('ず', "zu"),
+    ('ぜ', "ze"),
+    ('ぞ', "zo"),
+    ('だ', "da"),
+    ('ぢ', "ji"),
+    ('づ', "zu"),
+    ('で', "de"),
+    ('ど', "do"),
+    ('ば', "ba"),
+    ('び', "bi"),
+    ('ぶ', "bu"),
+    ('べ', "be"),
+    ('ぼ', "bo"),
+    ('ぱ', "pa"),
+    ('ぴ', "pi"),
+    ('ぷ', "pu"),
+    ('ぺ', "pe"),
+    ('ぽ', "po"),
+    ('ぁ', "a"),
+    ('ぃ', "i"),
+    ('ぅ', "u"),
+    ('ぇ', "e"),
+    ('ぉ', "o"),
+    ('ゃ', "ya"),
+    ('ゅ', "yu"),
+    ('ょ', "yo"),
+    ('ゔ', "vu"),
+];
+
+/// Folds text for matching: lowercases, turns full-width ASCII into
+/// half-width, and katakana into hiragana.
+pub fn normalize(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xfee0).unwrap_or(c),
+            '\u{3000}' => ' ',
+            '\u{30a1}'..='\u{30f6}' => char::from_u32(c as u32 - 0x60).unwrap_or(c),
+            _ => c,
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Romanizes the hiragana in normalized `text`, leaving other characters as
+/// they are, e.g. `ちゅーりんぐらぶ` becomes `chu-ringurabu`.
+pub fn romanize(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut romaji = String::new();
+    let mut double = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let pair: String = chars[i..chars.len().min(i + 2)].iter().collect();
+        let (syllable, len) = match KANA_PAIRS.iter().find(|(kana, _)| *kana == pair) {
+            Some((_, syllable)) => (Some(*syllable), 2),
+            None => (
+                KANA.iter()
+                    .find(|(kana, _)| *kana == chars[i])
+                    .map(|(_, syllable)| *syllable),
+                1,
+            ),
+        };
+        match (chars[i], syllable) {
+            ('っ', _) => double = true,
+            ('ー', _) => romaji.push('-'),
+            (_, Some(syllable)) => {
+                if double {
+                    romaji.push_str(&syllable[..1]);
+                }
+                romaji.push_str(syllable);
+                double = false;
+            }
+            (c, None) => {
+                romaji.push(c);
+                double = false;
+            }
+        }
+        i += len;
+    }
+    romaji
+}
+
+/// A song in the search index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Document {
+    pub id: String,
+    pub title: String,
+    pub artist: String,
+    pub uploader: String,
+
+    /// Normalized and romanized text of the searchable fields.
+    text: String,
+}
+
+impl Document {
+    /// Indexes the song `id`, reading the effectors and file names from its
+    /// directory in `dest`.
+    fn new(dest: &Path, id: &str, entry: &Entry) -> Self {
+        let dir = dest.join(entry.dir(id));
+        let mut fields = vec![
+            entry.title.clone(),
+            entry.artist.clone(),
+            entry.uploader().to_owned(),
+        ];
+        for chart in ksh::charts(&dir).unwrap_or_default() {
+            if let Some(effector) = Header::read(&chart)
+                .ok()
+                .and_then(|h| h.get("effect").map(str::to_owned))
+            {
+                fields.push(effector);
+            }
+        }
+        if let Ok(files) = fs::read_dir(&dir) {
+            fields
+                .extend(files.filter_map(|file| {
+                    Some(file.ok()?.file_name().to_string_lossy().into_owned())
+                }));
+        }
+        let normalized = normalize(&fields.join("\n"));
+        let romaji = romanize(&normalized);
+        let text = [normalized, romaji.replace('-', ""), romaji]
+            .join("\n")
+            .replace('\t', " ");
+        Self {
+            id: id.to_owned(),
+            title: entry.title.clone(),
+            artist: entry.artist.clone(),
+            uploader: entry.uploader().to_owned(),
+            text,
+        }
+    }
+
+    fn matches(&self, words: &[String]) -> bool {
+        words.iter().all(|word| self.text.contains(word.as_str()))
+    }
+
+    fn to_line(&self) -> String {
+        [
+            &self.id,
+            &self.title,
+            &self.artist,
+            &self.uploader,
+            &self.text,
+        ]
+        .map(|field| field.replace(['\t', '\n'], " "))
+        .join("\t")
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(5, '\t').map(str::to_owned);
+        Some(Self {
+            id: fields.next()?,
+            title: fields.next()?,
+            artist: fields.next()?,
+            uploader: fields.next()?,
+            text: fields.next()?,
+        })
+    }
+}
+
+/// Search index of the local library, kept in `<dest>/search.idx` with one
+/// song per line.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    documents: BTreeMap<String, Document>,
+}
+
+impl SearchIndex {
+    /// Loads the index of `dest`, building it first if it does not exist.
+    pub fn open(dest: &Path) -> anyhow::Result<Self> {
+        let path = dest.join(INDEX_FILENAME);
+        if !path.exists() {
+            return Self::build(dest);
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let documents = content
+            .lines()
+            .filter_map(Document::from_line)
+            .map(|doc| (doc.id.clone(), doc))
+            .collect();
+        Ok(Self { documents })
+    }
+
+    /// Indexes every song in `dest` and saves the index.
+    pub fn build(dest: &Path) -> anyhow::Result<Self> {
+        let documents = Store::open_read_only(dest)
+            .entries()
+            .into_iter()
+            .map(|(id, entry)| (id.clone(), Document::new(dest, &id, &entry)))
+            .collect();
+        let index = Self { documents };
+        index.save(dest)?;
+        Ok(index)
+    }
+
+    /// Adds or refreshes the songs `ids` and saves the index.
+    pub fn update<'a>(
+        &mut self,
+        dest: &Path,
+        ids: impl IntoIterator<Item = &'a str>,
+    ) -> anyhow::Result<()> {
+        let store = Store::open_read_only(dest);
+        for id in ids {
+            match store.get(id).filter(Entry::is_kept) {
+                Some(entry) => {
+                    self.documents
+                        .insert(id.to_owned(), Document::new(dest, id, &entry));
+                }
+                None => {
+                    self.documents.remove(id);
+                }
+            }
+        }
+        self.save(dest)
+    }
+
+    fn save(&self, dest: &Path) -> anyhow::Result<()> {
+        let path = dest.join(INDEX_FILENAME);
+        let tmp = dest.join(format!("{INDEX_FILENAME}.tmp"));
+        let content: String = self
+            .documents
+            .values()
+            .map(|doc| doc.to_line() + "\n")
+            .collect();
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Returns the songs whose title, artist, uploader, effectors, or file
+    /// names contain every word of `query`. Kana match regardless of
+    /// hiragana or katakana, and romaji match kana.
+    pub fn find(&self, query: &str) -> Vec<&Document> {
+        let words: Vec<_> = normalize(query)
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect();
+        self.documents
+            .values()
+            .filter(|doc| doc.matches(&words))
+            .collect()
+    }
+}
+
+/// Adds each downloaded song to the search index, and the duplicates found
+/// after each sync.
+#[derive(Debug)]
+pub struct SearchIndexNotifier {
+    dest: PathBuf,
+}
+
+impl SearchIndexNotifier {
+    pub fn new(dest: PathBuf) -> Self {
+        Self { dest }
+    }
+
+    fn update<'a>(&self, ids: impl IntoIterator<Item = &'a str>) -> anyhow::Result<()> {
+        // Opening a missing index builds it from the whole library.
+        let mut index = SearchIndex::open(&self.dest)?;
+        index.update(&self.dest, ids)
+    }
+}
+
+impl Notifier for SearchIndexNotifier {
+    // Indexes the song while its files are still local, since uploading may
+    // remove them.
+    fn song_downloaded(&self, song: &Song, _path: &Path) -> anyhow::Result<()> {
+        self.update([song.id.as_str()])
+    }
+
+    fn run_finished(&self, report: &DownloadReport) -> anyhow::Result<()> {
+        if report.duplicates.is_empty() {
+            return Ok(());
+        }
+        self.update(report.duplicates.iter().map(|song| song.id.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn normalize_and_romanize() {
+        assert_eq!(normalize("ＲＧ＋Ｉｃｅ"), "rg+ice");
+        assert_eq!(normalize("チューリングラブ"), "ちゅーりんぐらぶ");
+        assert_eq!(
+            romanize("ちゅーりんぐらぶ feat.sou"),
+            "chu-ringurabu feat.sou"
+        );
+        assert_eq!(romanize("がっこう"), "gakkou");
+        assert_eq!(romanize("しゃっふる"), "shaffuru");
+    }
+
+    #[test]
+    fn build_and_find() {
+        let dest = tempdir().unwrap();
+        let mut store = Store::open(dest.path());
+        for (id, title, artist) in [
+            ("5441d590", "Outbreak", "RG+Ice"),
+            ("89b54d80", "チューリングラブ feat.Sou", "ナナヲアカリ"),
+        ] {
+            let entry = Entry {
+                downloaded_at: Utc::now(),
+                title: title.to_owned(),
+                artist: artist.to_owned(),
+                user_id: String::from("user"),
+                user_name: Some(String::from("Ixiot")),
+                ..Default::default()
+            };
+            store.insert(id, &entry).unwrap();
+        }
+        fs::create_dir(dest.path().join("5441d590")).unwrap();
+        fs::write(
+            dest.path().join("5441d590/Exhaust.ksh"),
+            "title=Outbreak\neffect=Someone Else\n--\n",
+        )
+        .unwrap();
+
+        let index = SearchIndex::open(dest.path()).unwrap();
+        let ids = |query| {
+            index
+                .find(query)
+                .iter()
+                .map(|doc| doc.id.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids("outbreak"), ["5441d590"]);
+        assert_eq!(ids("someone"), ["5441d590"]);
+        assert_eq!(ids("exhaust.ksh"), ["5441d590"]);
+        assert_eq!(ids("ちゅーりんぐ"), ["89b54d80"]);
+        assert_eq!(ids("churingu nanawo"), ["89b54d80"]);
+        assert_eq!(ids("ixiot").len(), 2);
+        assert!(ids("missing").is_empty());
+
+        // The saved index is read back without the store.
+        fs::remove_file(dest.path().join("meta.json")).unwrap();
+        let index = SearchIndex::open(dest.path()).unwrap();
+        assert_eq!(index.find("rg+ice").len(), 1);
+    }
+}
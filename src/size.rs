@@ -0,0 +1,70 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::ensure;
+
+const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+/// A number of bytes, parsed from and displayed with decimal units (e.g.
+/// `500MB`, `1.5 GB`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: f64 = number.parse().map_err(|_| anyhow!("Invalid size: {s:?}"))?;
+        let unit = unit.trim();
+        let exp = UNITS
+            .iter()
+            .position(|u| u.eq_ignore_ascii_case(unit) || (unit.is_empty() && *u == "B"))
+            .ok_or_else(|| anyhow!("Unknown size unit {unit:?}, expected one of {UNITS:?}"))?;
+        let bytes = number * 1000f64.powi(exp as i32);
+        ensure!(bytes < u64::MAX as f64, "Size is too large: {s:?}");
+        Ok(Self(bytes as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut size = self.0 as f64;
+        let mut unit = 0;
+        while size >= 1000.0 && unit < UNITS.len() - 1 {
+            size /= 1000.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{} B", self.0)
+        } else {
+            write!(f, "{size:.1} {}", UNITS[unit])
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_size() {
+        assert_eq!("512".parse::<ByteSize>().unwrap(), ByteSize(512));
+        assert_eq!("5GB".parse::<ByteSize>().unwrap(), ByteSize(5_000_000_000));
+        assert_eq!("1.5 mb".parse::<ByteSize>().unwrap(), ByteSize(1_500_000));
+        assert!("GB".parse::<ByteSize>().is_err());
+        assert!("5 GiB".parse::<ByteSize>().is_err());
+        assert!("-1GB".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn display_size() {
+        assert_eq!(ByteSize(999).to_string(), "999 B");
+        assert_eq!(ByteSize(1_234_567).to_string(), "1.2 MB");
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::ensure;
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::dedup;
+use crate::store::Entry;
+use crate::store::Store;
+
+/// Version of the state file format.
+const VERSION: u32 = 1;
+
+/// Metadata of a library, exported to bring another machine's library in
+/// step with it.
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    version: u32,
+    exported_at: DateTime<Utc>,
+
+    /// Entries of all songs by ID, including the ones not kept, so that the
+    /// other machine does not request them again either.
+    songs: BTreeMap<String, Entry>,
+}
+
+/// Outcome of importing a state file.
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// Songs whose files were already in the library, e.g. copied over USB,
+    /// and are now recorded.
+    pub adopted: Vec<String>,
+
+    /// Songs recorded as not kept, e.g. removed from the server or
+    /// duplicates.
+    pub skipped: Vec<String>,
+
+    /// Songs whose files are missing, or differ from the exported ones. They
+    /// are left for the next sync to download.
+    pub missing: Vec<String>,
+
+    /// Number of songs the library already had.
+    pub known: usize,
+}
+
+/// Writes the metadata of the library `dest` to `output`. Returns the number
+/// of songs exported.
+pub fn export(dest: &Path, output: &Path) -> anyhow::Result<usize> {
+    let store = Store::open_read_only(dest);
+    let state = State {
+        version: VERSION,
+        exported_at: Utc::now(),
+        songs: store.all_entries().into_iter().collect(),
+    };
+    fs::write(output, serde_json::to_string_pretty(&state)?)
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+    Ok(state.songs.len())
+}
+
+/// Records the songs of the state file `input` in the library `dest`. Songs
+/// the library already has are left as they are. Exported songs whose files
+/// are in the library under the same directory are adopted once their
+/// content matches, so copies need no download.
+pub fn import(dest: &Path, input: &Path) -> anyhow::Result<ImportReport> {
+    let content =
+        fs::read_to_string(input).with_context(|| format!("Failed to read {}", input.display()))?;
+    let state: State = serde_json::from_str(&content)
+        .with_context(|| format!("Invalid state file {}", input.display()))?;
+    ensure!(
+        state.version <= VERSION,
+        "The state file is from a newer version (format {})",
+        state.version
+    );
+
+    let mut store = Store::open(dest);
+    let mut report = ImportReport::default();
+    for (id, entry) in state.songs {
+        if store.contains(&id) {
+            report.known += 1;
+            continue;
+        }
+        if !entry.is_kept() {
+            store.insert(&id, &entry)?;
+            report.skipped.push(id);
+            continue;
+        }
+        let dir = dest.join(entry.dir(&id));
+        if !dir.is_dir() {
+            report.missing.push(id);
+            continue;
+        }
+        let fingerprint = dedup::fingerprint(&dir).unwrap_or_default();
+        if entry.fingerprint.is_some() && fingerprint != entry.fingerprint {
+            warn!(id, dir = %dir.display(), "Files differ from the exported song");
+            report.missing.push(id);
+            continue;
+        }
+        info!(id, title = entry.title, "Adopted a copied song");
+        store.insert(&id, &entry)?;
+        report.adopted.push(id);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::Song;
+
+    fn entry(id: &str) -> Entry {
+        let song: Song = serde_json::from_value(json!({
+            "id": id,
+            "user_id": "user",
+            "title": id,
+            "artist": "RG+Ice",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+        }))
+        .unwrap();
+        Entry::new(&song, id)
+    }
+
+    fn write_song(dest: &Path, id: &str, notes: &str) -> Option<String> {
+        let dir = dest.join(id);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("chart.ksh"), format!("title={id}\n--\n{notes}\n")).unwrap();
+        dedup::fingerprint(&dir).unwrap()
+    }
+
+    #[test]
+    fn export_and_import_state() {
+        let desktop = tempdir().unwrap();
+        let mut store = Store::open(desktop.path());
+        for id in ["copied", "changed", "missing", "known"] {
+            let mut entry = entry(id);
+            entry.fingerprint = write_song(desktop.path(), id, "0000|00|--");
+            store.insert(id, &entry).unwrap();
+        }
+        let mut removed = entry("removed");
+        removed.removed = true;
+        store.insert("removed", &removed).unwrap();
+        drop(store);
+        let state = desktop.path().join("state.json");
+        assert_eq!(export(desktop.path(), &state).unwrap(), 5);
+
+        let cab = tempdir().unwrap();
+        write_song(cab.path(), "copied", "0000|00|--");
+        write_song(cab.path(), "changed", "1000|00|--");
+        Store::open(cab.path())
+            .insert("known", &entry("known"))
+            .unwrap();
+
+        let report = import(cab.path(), &state).unwrap();
+        assert_eq!(report.adopted, ["copied"]);
+        assert_eq!(report.skipped, ["removed"]);
+        assert_eq!(report.missing, ["changed", "missing"]);
+        assert_eq!(report.known, 1);
+
+        let store = Store::open_read_only(cab.path());
+        assert!(store.contains("copied"));
+        assert!(store.contains("removed"));
+        assert!(!store.contains("missing"));
+        assert!(store.get("known").unwrap().fingerprint.is_none());
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::size::ByteSize;
+use crate::store::Store;
+
+/// Output formats of the statistics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Plain text for the terminal.
+    #[default]
+    Text,
+
+    /// Markdown, e.g. to publish a leaderboard of uploaders.
+    Md,
+
+    /// JSON for other tools.
+    Json,
+}
+
+/// Summary of the local library.
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    /// Number of downloaded songs.
+    pub songs: usize,
+
+    /// Total size of the song directories in bytes.
+    pub bytes: u64,
+
+    /// Breakdown per uploader.
+    pub per_uploader: BTreeMap<String, UploaderStats>,
+
+    /// Number of charts per level.
+    pub per_level: BTreeMap<u8, usize>,
+
+    /// Number of charts per effector, for the charts that credit one.
+    pub per_effector: BTreeMap<String, usize>,
+
+    /// Number of songs downloaded per month, keyed by `YYYY-MM`.
+    pub per_month: BTreeMap<String, usize>,
+}
+
+/// Songs of one uploader in the local library.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct UploaderStats {
+    /// Number of downloaded songs.
+    pub songs: usize,
+
+    /// Total size of the song directories in bytes.
+    pub bytes: u64,
+
+    /// Average level of the charts, if the songs have any.
+    pub average_level: Option<f64>,
+
+    /// The most recently uploaded of the downloaded songs.
+    pub last_upload: Option<LastUpload>,
+}
+
+/// A song and when it was uploaded.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LastUpload {
+    pub id: String,
+    pub title: String,
+    pub uploaded_at: DateTime<Utc>,
+}
+
+impl Stats {
+    /// Computes statistics from the metadata store and song directories in
+    /// `dest`.
+    pub fn collect(dest: &Path) -> anyhow::Result<Self> {
+        let store = Store::open_read_only(dest);
+        let mut stats = Self::default();
+        // Sums and counts of the levels per uploader, for the averages.
+        let mut levels: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        for (id, entry) in store.entries() {
+            let bytes = dir_size(&dest.join(entry.dir(&id)))?;
+            stats.songs += 1;
+            stats.bytes += bytes;
+            let uploader = stats
+                .per_uploader
+                .entry(entry.uploader().to_owned())
+                .or_default();
+            uploader.songs += 1;
+            uploader.bytes += bytes;
+            if let Some(uploaded_at) = entry.uploaded_at {
+                if uploader
+                    .last_upload
+                    .as_ref()
+                    .is_none_or(|last| last.uploaded_at < uploaded_at)
+                {
+                    uploader.last_upload = Some(LastUpload {
+                        id: id.clone(),
+                        title: entry.title.clone(),
+                        uploaded_at,
+                    });
+                }
+            }
+            let (sum, count) = levels.entry(entry.uploader().to_owned()).or_default();
+            *sum += entry
+                .levels
+                .iter()
+                .map(|&level| u64::from(level))
+                .sum::<u64>();
+            *count += entry.levels.len() as u64;
+            for level in &entry.levels {
+                *stats.per_level.entry(*level).or_default() += 1;
+            }
+            for effector in entry.charts.iter().filter_map(|c| c.effector.as_ref()) {
+                *stats.per_effector.entry(effector.clone()).or_default() += 1;
+            }
+            *stats
+                .per_month
+                .entry(entry.downloaded_at.format("%Y-%m").to_string())
+                .or_default() += 1;
+        }
+        for (name, (sum, count)) in levels {
+            if let Some(uploader) = stats.per_uploader.get_mut(&name) {
+                uploader.average_level = (count > 0).then(|| sum as f64 / count as f64);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Returns the uploaders with the most songs first, ties broken by name.
+    pub fn leaderboard(&self) -> Vec<(&String, &UploaderStats)> {
+        let mut leaderboard: Vec<_> = self.per_uploader.iter().collect();
+        leaderboard.sort_by(|a, b| b.1.songs.cmp(&a.1.songs).then(a.0.cmp(b.0)));
+        leaderboard
+    }
+
+    /// Renders the statistics as Markdown, with a leaderboard of the
+    /// uploaders.
+    pub fn to_markdown(&self) -> String {
+        let mut md = String::from("## Library stats\n\n");
+        let _ = writeln!(md, "{} songs, {}", self.songs, ByteSize(self.bytes));
+
+        md.push_str("\n### Most prolific uploaders\n\n");
+        md.push_str("| # | Uploader | Songs | Size | Avg. level | Last upload |\n");
+        md.push_str("|---:|---|---:|---:|---:|---|\n");
+        for (rank, (name, uploader)) in self.leaderboard().into_iter().enumerate() {
+            let average_level = uploader
+                .average_level
+                .map(|level| format!("{level:.1}"))
+                .unwrap_or_default();
+            let last_upload = uploader
+                .last_upload
+                .as_ref()
+                .map(|last| {
+                    format!(
+                        "{} ({})",
+                        escape_cell(&last.title),
+                        last.uploaded_at.format("%Y-%m-%d")
+                    )
+                })
+                .unwrap_or_default();
+            let _ = writeln!(
+                md,
+                "| {} | {} | {} | {} | {average_level} | {last_upload} |",
+                rank + 1,
+                escape_cell(name),
+                uploader.songs,
+                ByteSize(uploader.bytes),
+            );
+        }
+
+        md.push_str("\n### Charts per level\n\n| Level | Charts |\n|---:|---:|\n");
+        for (level, count) in &self.per_level {
+            let _ = writeln!(md, "| {level} | {count} |");
+        }
+        md
+    }
+}
+
+/// Escapes `text` for a cell of a Markdown table.
+fn escape_cell(text: &str) -> String {
+    text.replace('|', "\\|")
+}
+
+/// Returns the total size of the files in `dir`, or 0 if it does not exist.
+pub(crate) fn dir_size(dir: &Path) -> anyhow::Result<u64> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+    use chrono::Utc;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::store::Entry;
+    use crate::Chart;
+
+    fn entry(user_name: &str, levels: &[u8], month: u32) -> Entry {
+        let date = Utc.with_ymd_and_hms(2023, month, 1, 0, 0, 0).unwrap();
+        Entry {
+            downloaded_at: date,
+            title: String::from("title"),
+            artist: String::from("artist"),
+            user_id: String::from("user"),
+            user_name: Some(user_name.to_owned()),
+            levels: levels.to_vec(),
+            charts: levels
+                .iter()
+                .map(|&level| Chart {
+                    difficulty: 4,
+                    level,
+                    effector: Some(user_name.to_owned()),
+                    video_link: None,
+                })
+                .collect(),
+            uploaded_at: Some(date),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn collect_stats() {
+        let dest = tempdir().unwrap();
+        let mut store = Store::open(dest.path());
+        store.insert("a", &entry("alice", &[5, 12, 16], 8)).unwrap();
+        store.insert("b", &entry("alice", &[16, 18], 9)).unwrap();
+        store.insert("c", &entry("bob", &[12], 9)).unwrap();
+        fs::create_dir(dest.path().join("a")).unwrap();
+        fs::write(dest.path().join("a").join("chart.ksh"), [0; 100]).unwrap();
+        fs::create_dir(dest.path().join("b")).unwrap();
+        fs::write(dest.path().join("b").join("chart.ksh"), [0; 20]).unwrap();
+
+        let stats = Stats::collect(dest.path()).unwrap();
+
+        assert_eq!(stats.songs, 3);
+        assert_eq!(stats.bytes, 120);
+        assert_eq!(stats.per_uploader.len(), 2);
+        let alice = &stats.per_uploader["alice"];
+        assert_eq!(alice.songs, 2);
+        assert_eq!(alice.bytes, 120);
+        assert_eq!(alice.average_level, Some(13.4));
+        let last_upload = alice.last_upload.as_ref().unwrap();
+        assert_eq!(
+            (last_upload.id.as_str(), last_upload.title.as_str()),
+            ("b", "title")
+        );
+        assert_eq!(stats.per_uploader["bob"].songs, 1);
+        assert_eq!(
+            stats
+                .leaderboard()
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            ["alice", "bob"]
+        );
+        assert_eq!(
+            stats.per_level,
+            BTreeMap::from([(5, 1), (12, 2), (16, 2), (18, 1)])
+        );
+        assert_eq!(
+            stats.per_effector,
+            BTreeMap::from([(String::from("alice"), 5), (String::from("bob"), 1)])
+        );
+        assert_eq!(
+            stats.per_month,
+            BTreeMap::from([(String::from("2023-08"), 1), (String::from("2023-09"), 2)])
+        );
+
+        let md = stats.to_markdown();
+        assert!(md.contains("| 1 | alice | 2 | 120 B | 13.4 | title (2023-09-01) |\n"));
+        assert!(md.contains("| 2 | bob | 1 | 0 B | 12.0 | title (2023-09-01) |\n"));
+        assert!(md.contains("| 16 | 2 |\n"));
+    }
+}
@@ -0,0 +1,687 @@
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::bail;
+use anyhow::ensure;
+use chrono::DateTime;
+use chrono::Utc;
+use pickledb::PickleDb;
+use pickledb::PickleDbDumpPolicy;
+use pickledb::SerializationMethod;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::audio;
+use crate::dedup;
+use crate::http::Validators;
+use crate::ksh;
+use crate::ksh::Bpm;
+use crate::ksh::Header;
+use crate::layout::TakenDirs;
+use crate::search::normalize;
+use crate::Chart;
+use crate::Song;
+
+const DB_FILENAME: &str = "meta.json";
+
+const LOCK_FILENAME: &str = ".lock";
+
+/// Takes an exclusive lock on the library in `dest`, held until the returned
+/// file is dropped. Syncs and the commands that change the library hold it,
+/// so that none of them overwrites the metadata the others write.
+pub fn lock(dest: &Path) -> anyhow::Result<fs::File> {
+    let file = fs::File::create(dest.join(LOCK_FILENAME))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(fs::TryLockError::WouldBlock) => {
+            bail!(
+                "Another sync or command is already changing {}",
+                dest.display()
+            )
+        }
+        Err(fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Metadata about a downloaded song.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "EntryRepr")]
+pub struct Entry {
+    pub downloaded_at: DateTime<Utc>,
+    pub title: String,
+    pub artist: String,
+    pub user_id: String,
+    pub user_name: Option<String>,
+    pub levels: Vec<u8>,
+
+    /// Charts of the song, as listed by the server or read from the chart
+    /// files.
+    pub charts: Vec<Chart>,
+
+    /// Illustrator of the jacket, from the chart files.
+    pub illustrator: Option<String>,
+
+    /// Title in the chart files, if it differs from the one the server
+    /// listed, e.g. after a rename, a typo, or mojibake.
+    pub chart_title: Option<String>,
+
+    /// Artist in the chart files, if it differs from the one the server
+    /// listed.
+    pub chart_artist: Option<String>,
+
+    pub uploaded_at: Option<DateTime<Utc>>,
+
+    /// When the server last listed the song as updated, if recorded.
+    pub updated_at: Option<DateTime<Utc>>,
+
+    /// Integrated loudness of the song's music in LUFS, once analyzed.
+    pub loudness: Option<f64>,
+
+    /// BPM shown in song select, from the chart header.
+    pub bpm: Option<Bpm>,
+
+    /// Length of the song's music in seconds.
+    pub duration: Option<f64>,
+
+    /// Why the song's music may not play, e.g. a header without a sample
+    /// rate, if checking it after download found a problem.
+    pub audio_problem: Option<String>,
+
+    /// Hash of the chart bodies and audio; see [`dedup::fingerprint`].
+    pub fingerprint: Option<String>,
+
+    /// Validators of the song's archive as downloaded, so that it is only
+    /// downloaded again if it changed.
+    pub validators: Option<Validators>,
+
+    /// ID of the local song with the same content, if this song was not kept
+    /// because of it.
+    pub duplicate_of: Option<String>,
+
+    /// Keys of the song's files in remote storage, once uploaded.
+    pub remote_keys: Vec<String>,
+
+    /// Whether the song was downloaded because the account liked it, so that
+    /// it is removed again once unliked.
+    pub favorite: bool,
+
+    /// Tags added locally with the tag command, e.g. `stamina`.
+    pub tags: Vec<String>,
+
+    /// Whether the song was starred locally, which keeps it from being
+    /// removed by favorites syncs or uploads.
+    pub starred: bool,
+
+    /// Whether the server no longer has the song, so that it is not
+    /// requested again. No files of it are in the library.
+    pub removed: bool,
+
+    /// Why the song failed validation after download, if it did. Its files
+    /// are kept in quarantine instead of the library until it is released.
+    pub quarantined: Option<String>,
+
+    /// Directory of the song relative to the library, if it is not named
+    /// after the song ID.
+    pub dir: Option<String>,
+}
+
+impl Entry {
+    /// Creates an entry for `song` downloaded into `dir`.
+    pub fn new(song: &Song, dir: &str) -> Self {
+        let mut charts = song.charts.clone();
+        charts.sort_by_key(|chart| chart.difficulty);
+        Self {
+            downloaded_at: Utc::now(),
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            user_id: song.user_id.clone(),
+            user_name: song.user.as_ref().map(|user| user.name.clone()),
+            levels: song.charts.iter().map(|chart| chart.level).collect(),
+            charts,
+            illustrator: None,
+            chart_title: None,
+            chart_artist: None,
+            uploaded_at: Some(song.uploaded_at),
+            updated_at: Some(song.updated_at),
+            loudness: None,
+            bpm: None,
+            duration: None,
+            audio_problem: None,
+            fingerprint: None,
+            validators: None,
+            duplicate_of: None,
+            remote_keys: Vec::new(),
+            favorite: false,
+            tags: Vec::new(),
+            starred: false,
+            removed: false,
+            quarantined: None,
+            dir: (dir != song.id).then(|| dir.to_owned()),
+        }
+    }
+
+    /// Reads the song info of songs downloaded into `dest` by older versions,
+    /// which stored no BPM, duration, charts, or illustrator yet.
+    pub fn backfill_song_info(&mut self, dest: &Path, id: &str) {
+        if self.bpm.is_none() && self.duration.is_none()
+            || self.charts.is_empty()
+            || self.illustrator.is_none()
+        {
+            self.read_song_info(&dest.join(self.dir(id)));
+        }
+    }
+
+    /// Fills in the BPM, duration, and illustrator from the charts and music
+    /// in `dir`, as well as the charts if the server did not list them. The
+    /// title and artist of the charts are recorded where they differ from the
+    /// listed ones. The headers of the music are checked, and any problem
+    /// recorded.
+    pub fn read_song_info(&mut self, dir: &Path) {
+        let headers: Vec<_> = ksh::charts(dir)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|chart| Header::read(chart).ok())
+            .collect();
+        if let Some(header) = headers.first() {
+            self.bpm = header.bpm();
+        }
+        self.illustrator = headers
+            .iter()
+            .find_map(|header| header.get("illustrator").filter(|name| !name.is_empty()))
+            .map(str::to_owned);
+        let chart_field = |key, listed: &str| {
+            headers
+                .first()
+                .and_then(|header| header.get(key))
+                .filter(|value| differs(value, listed))
+                .map(str::to_owned)
+        };
+        self.chart_title = chart_field("title", &self.title);
+        self.chart_artist = chart_field("artist", &self.artist);
+        if self.charts.is_empty() {
+            self.charts = headers
+                .iter()
+                .filter_map(|header| {
+                    Some(Chart {
+                        difficulty: header.difficulty_index()? + 1,
+                        level: header.level()?,
+                        effector: header.get("effect").map(str::to_owned),
+                        video_link: None,
+                    })
+                })
+                .collect();
+            self.charts.sort_by_key(|chart| chart.difficulty);
+            if self.levels.is_empty() {
+                self.levels = self.charts.iter().map(|chart| chart.level).collect();
+            }
+        }
+        // Checking the headers flags music that will not play, e.g. broken
+        // re-encodes, before it is found out mid-play.
+        (self.duration, self.audio_problem) = match ksh::music_file(dir) {
+            Ok(music) => match audio::probe(&music) {
+                Ok(info) => (info.map(|info| info.duration), None),
+                Err(e) => (None, Some(format!("{}: {e}", music.display()))),
+            },
+            Err(e) => (None, Some(e.to_string())),
+        };
+    }
+
+    /// Returns whether the title or artist in the chart files differs from
+    /// the one the server listed.
+    pub fn is_mismatched(&self) -> bool {
+        self.chart_title.is_some() || self.chart_artist.is_some()
+    }
+
+    /// Returns the directory of the song with ID `id`, relative to the
+    /// library.
+    pub fn dir<'a>(&'a self, id: &'a str) -> &'a str {
+        self.dir.as_deref().unwrap_or(id)
+    }
+
+    /// Returns whether the song's files are in the library, i.e. it was
+    /// neither left out as a duplicate, removed from the server, nor
+    /// quarantined.
+    pub fn is_kept(&self) -> bool {
+        self.duplicate_of.is_none() && !self.removed && self.quarantined.is_none()
+    }
+
+    /// Returns whether the song has the local tag `tag` (case-insensitive).
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+    }
+
+    /// Adds or removes a local tag, keeping the tags sorted.
+    pub fn apply(&mut self, change: &TagChange) {
+        match change {
+            TagChange::Add(tag) => {
+                if !self.has_tag(tag) {
+                    self.tags.push(tag.clone());
+                    self.tags.sort();
+                }
+            }
+            TagChange::Remove(tag) => self.tags.retain(|t| !t.eq_ignore_ascii_case(tag)),
+        }
+    }
+
+    /// Name of the uploader, falling back to the user ID.
+    pub fn uploader(&self) -> &str {
+        match &self.user_name {
+            Some(name) => name,
+            None if self.user_id.is_empty() => "(unknown)",
+            None => &self.user_id,
+        }
+    }
+}
+
+/// A change to the local tags of a song, parsed from `+tag` or `-tag`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagChange {
+    Add(String),
+    Remove(String),
+}
+
+impl FromStr for TagChange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (add, tag) = match s.split_at_checked(1) {
+            Some(("+", tag)) => (true, tag.trim()),
+            Some(("-", tag)) => (false, tag.trim()),
+            _ => bail!("Expected +TAG or -TAG, got {s:?}"),
+        };
+        ensure!(
+            !tag.is_empty() && !tag.contains(char::is_whitespace),
+            "Invalid tag {tag:?}"
+        );
+        let tag = tag.to_owned();
+        Ok(if add {
+            Self::Add(tag)
+        } else {
+            Self::Remove(tag)
+        })
+    }
+}
+
+/// Returns whether `value` from a chart file differs from the `listed` one,
+/// ignoring case, full-width forms, and surrounding whitespace. Nothing
+/// differs from an empty value, which older versions recorded for titles.
+fn differs(value: &str, listed: &str) -> bool {
+    let (value, listed) = (value.trim(), listed.trim());
+    !value.is_empty() && !listed.is_empty() && normalize(value) != normalize(listed)
+}
+
+// Only lives while an entry is deserialized, so its size does not matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EntryRepr {
+    /// Older versions only recorded when the song was downloaded.
+    Legacy(DateTime<Utc>),
+    Full {
+        downloaded_at: DateTime<Utc>,
+        #[serde(default)]
+        title: String,
+        #[serde(default)]
+        artist: String,
+        #[serde(default)]
+        user_id: String,
+        #[serde(default)]
+        user_name: Option<String>,
+        #[serde(default)]
+        levels: Vec<u8>,
+        #[serde(default)]
+        charts: Vec<Chart>,
+        #[serde(default)]
+        illustrator: Option<String>,
+        #[serde(default)]
+        chart_title: Option<String>,
+        #[serde(default)]
+        chart_artist: Option<String>,
+        #[serde(default)]
+        uploaded_at: Option<DateTime<Utc>>,
+        #[serde(default)]
+        updated_at: Option<DateTime<Utc>>,
+        #[serde(default)]
+        loudness: Option<f64>,
+        #[serde(default)]
+        bpm: Option<Bpm>,
+        #[serde(default)]
+        duration: Option<f64>,
+        #[serde(default)]
+        audio_problem: Option<String>,
+        #[serde(default)]
+        fingerprint: Option<String>,
+        #[serde(default)]
+        validators: Option<Validators>,
+        #[serde(default)]
+        duplicate_of: Option<String>,
+        #[serde(default)]
+        remote_keys: Vec<String>,
+        #[serde(default)]
+        favorite: bool,
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(default)]
+        starred: bool,
+        #[serde(default)]
+        removed: bool,
+        #[serde(default)]
+        quarantined: Option<String>,
+        #[serde(default)]
+        dir: Option<String>,
+    },
+}
+
+impl From<EntryRepr> for Entry {
+    fn from(repr: EntryRepr) -> Self {
+        match repr {
+            EntryRepr::Legacy(downloaded_at) => Self {
+                downloaded_at,
+                title: String::new(),
+                artist: String::new(),
+                user_id: String::new(),
+                user_name: None,
+                levels: Vec::new(),
+                charts: Vec::new(),
+                illustrator: None,
+                chart_title: None,
+                chart_artist: None,
+                uploaded_at: None,
+                updated_at: None,
+                loudness: None,
+                bpm: None,
+                duration: None,
+                audio_problem: None,
+                fingerprint: None,
+                validators: None,
+                duplicate_of: None,
+                remote_keys: Vec::new(),
+                favorite: false,
+                tags: Vec::new(),
+                starred: false,
+                removed: false,
+                quarantined: None,
+                dir: None,
+            },
+            EntryRepr::Full {
+                downloaded_at,
+                title,
+                artist,
+                user_id,
+                user_name,
+                levels,
+                charts,
+                illustrator,
+                chart_title,
+                chart_artist,
+                uploaded_at,
+                updated_at,
+                loudness,
+                bpm,
+                duration,
+                audio_problem,
+                fingerprint,
+                validators,
+                duplicate_of,
+                remote_keys,
+                favorite,
+                tags,
+                starred,
+                removed,
+                quarantined,
+                dir,
+            } => Self {
+                downloaded_at,
+                title,
+                artist,
+                user_id,
+                user_name,
+                levels,
+                charts,
+                illustrator,
+                chart_title,
+                chart_artist,
+                uploaded_at,
+                updated_at,
+                loudness,
+                bpm,
+                duration,
+                audio_problem,
+                fingerprint,
+                validators,
+                duplicate_of,
+                remote_keys,
+                favorite,
+                tags,
+                starred,
+                removed,
+                quarantined,
+                dir,
+            },
+        }
+    }
+}
+
+/// Metadata store of downloaded songs, keyed by song ID.
+pub struct Store {
+    db: PickleDb,
+    path: PathBuf,
+}
+
+impl Store {
+    /// Opens the store in `dest`, creating an empty one if it does not exist.
+    ///
+    /// Every change is written at once. Dropping the store writes nothing,
+    /// so it never overwrites the changes made through another store
+    /// meanwhile.
+    pub fn open(dest: &Path) -> Self {
+        let path = dest.join(DB_FILENAME);
+        let db = PickleDb::load_json(&path, PickleDbDumpPolicy::DumpUponRequest)
+            .unwrap_or_else(|_| PickleDb::new_json(&path, PickleDbDumpPolicy::DumpUponRequest));
+        Self { db, path }
+    }
+
+    /// Reads the store again, picking up the changes written through another
+    /// store since this one was opened.
+    pub fn reload(&mut self) {
+        if let Ok(db) = PickleDb::load_json(&self.path, PickleDbDumpPolicy::DumpUponRequest) {
+            self.db = db;
+        }
+    }
+
+    /// Opens the store in `dest` without ever writing back to it.
+    pub fn open_read_only(dest: &Path) -> Self {
+        let path = dest.join(DB_FILENAME);
+        let db = PickleDb::load_read_only(&path, SerializationMethod::Json).unwrap_or_else(|_| {
+            PickleDb::new(
+                &path,
+                PickleDbDumpPolicy::NeverDump,
+                SerializationMethod::Json,
+            )
+        });
+        Self { db, path }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.db.exists(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Entry> {
+        self.db.get(id)
+    }
+
+    pub fn insert(&mut self, id: &str, entry: &Entry) -> anyhow::Result<()> {
+        self.db.set(id, entry)?;
+        self.db.dump()?;
+        Ok(())
+    }
+
+    /// Waits until the written changes are on disk, e.g. before removing
+    /// files that only the store records the whereabouts of.
+    pub fn sync(&self) -> anyhow::Result<()> {
+        File::open(&self.path)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Removes the entry of song `id`. Returns `false` if there was none.
+    pub fn remove(&mut self, id: &str) -> anyhow::Result<bool> {
+        let removed = self.db.rem(id)?;
+        self.db.dump()?;
+        Ok(removed)
+    }
+
+    /// Returns all entries sorted by song ID, leaving out the songs that were
+    /// not kept.
+    pub fn entries(&self) -> Vec<(String, Entry)> {
+        let mut entries: Vec<_> = self
+            .db
+            .iter()
+            .filter_map(|kv| Some((kv.get_key().to_owned(), kv.get_value::<Entry>()?)))
+            .filter(|(_, entry)| entry.is_kept())
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// Returns all entries sorted by song ID, including the songs that were
+    /// not kept.
+    pub fn all_entries(&self) -> Vec<(String, Entry)> {
+        let mut entries: Vec<_> = self
+            .db
+            .iter()
+            .filter_map(|kv| Some((kv.get_key().to_owned(), kv.get_value::<Entry>()?)))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// Returns the directories recorded for all songs, for naming new songs.
+    pub fn taken_dirs(&self) -> TakenDirs {
+        self.all_entries()
+            .iter()
+            .map(|(id, entry)| entry.dir(id))
+            .collect()
+    }
+
+    /// Resolves a song by ID, ID prefix, or case-insensitive title substring.
+    pub fn resolve(&self, query: &str) -> anyhow::Result<(String, Entry)> {
+        if let Some(entry) = self.get(query) {
+            return Ok((query.to_owned(), entry));
+        }
+        let query_lower = query.to_lowercase();
+        let mut matches: Vec<_> = self
+            .entries()
+            .into_iter()
+            .filter(|(id, entry)| {
+                id.starts_with(query) || entry.title.to_lowercase().contains(&query_lower)
+            })
+            .collect();
+        match matches.len() {
+            0 => bail!("No song matches {query:?}"),
+            1 => Ok(matches.remove(0)),
+            _ => {
+                let candidates: Vec<_> = matches
+                    .iter()
+                    .map(|(id, entry)| format!("{id} ({} / {})", entry.title, entry.artist))
+                    .collect();
+                bail!(
+                    "{query:?} matches multiple songs:\n  {}",
+                    candidates.join("\n  ")
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn entry(title: &str) -> Entry {
+        Entry {
+            downloaded_at: Utc::now(),
+            title: title.to_owned(),
+            artist: String::from("artist"),
+            user_id: String::from("user"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn read_charts_of_older_download() {
+        let dest = tempdir().unwrap();
+        std::fs::write(
+            dest.path().join("exh.ksh"),
+            "title=t\nartist=a\neffect=Ixiot\ndifficulty=extended\nlevel=16\nt=190\n--\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dest.path().join("nov.ksh"),
+            "title=t\nartist=a\nillustrator=yoshimo\ndifficulty=light\nlevel=5\nt=190\n--\n",
+        )
+        .unwrap();
+        let mut entry = entry("t");
+        entry.read_song_info(dest.path());
+        let charts: Vec<_> = entry.charts.iter().map(Chart::to_string).collect();
+        assert_eq!(charts, ["NOV5", "EXH16"]);
+        assert_eq!(entry.charts[1].effector.as_deref(), Some("Ixiot"));
+        assert_eq!(entry.levels, [5, 16]);
+        assert_eq!(entry.illustrator.as_deref(), Some("yoshimo"));
+    }
+
+    #[test]
+    fn find_mismatched_names() {
+        let dest = tempdir().unwrap();
+        let chart = dest.path().join("exh.ksh");
+        std::fs::write(&chart, "title=Ｔ \nartist=artist\n--\n").unwrap();
+        let mut entry = entry("t");
+        entry.read_song_info(dest.path());
+        assert!(!entry.is_mismatched());
+
+        std::fs::write(&chart, "title=t (typo\nartist=\n--\n").unwrap();
+        entry.read_song_info(dest.path());
+        assert_eq!(entry.chart_title.as_deref(), Some("t (typo"));
+        assert_eq!(entry.chart_artist, None);
+        assert!(entry.is_mismatched());
+    }
+
+    #[test]
+    fn tag_song() {
+        let change = |s: &str| s.parse::<TagChange>().unwrap();
+        let mut entry = entry("t");
+        entry.apply(&change("+stamina"));
+        entry.apply(&change("+Favorite"));
+        entry.apply(&change("+STAMINA"));
+        assert_eq!(entry.tags, ["Favorite", "stamina"]);
+        assert!(entry.has_tag("favorite"));
+        entry.apply(&change("-favorite"));
+        assert_eq!(entry.tags, ["stamina"]);
+
+        assert!("stamina".parse::<TagChange>().is_err());
+        assert!("+".parse::<TagChange>().is_err());
+        assert!("+two words".parse::<TagChange>().is_err());
+    }
+
+    #[test]
+    fn resolve_song() {
+        let dest = tempdir().unwrap();
+        let mut store = Store::open(dest.path());
+        store.insert("5441d590", &entry("Outbreak")).unwrap();
+        store.insert("89b54d80", &entry("Turing Love")).unwrap();
+        store
+            .insert("89b5ffff", &entry("Outbreak (Remix)"))
+            .unwrap();
+
+        assert_eq!(store.resolve("5441d590").unwrap().0, "5441d590");
+        assert_eq!(store.resolve("5441").unwrap().0, "5441d590");
+        assert_eq!(store.resolve("turing").unwrap().0, "89b54d80");
+        assert!(store.resolve("outbreak").is_err());
+        assert!(store.resolve("89b5").is_err());
+        assert!(store.resolve("missing").is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use std::env;
+use std::fmt;
+use std::io;
+use std::io::IsTerminal;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+/// Whether output is colored, decided once at startup.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// When to color the output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Color a terminal unless the `NO_COLOR` environment variable is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Decides whether to color the output, for the styles here as well as
+    /// the log.
+    pub fn init(self) -> bool {
+        let enabled = self.is_enabled();
+        ENABLED.store(enabled, Ordering::Relaxed);
+        enabled
+    }
+
+    fn is_enabled(self) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => {
+                env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                    && env::var_os("TERM").is_none_or(|term| term != "dumb")
+                    && io::stdout().is_terminal()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Style {
+    Success,
+    Skip,
+    Fail,
+}
+
+impl Style {
+    fn ansi_code(self) -> &'static str {
+        match self {
+            Self::Success => "32",
+            Self::Skip => "33",
+            Self::Fail => "31",
+        }
+    }
+}
+
+/// A value displayed in a style, or plain if output is not colored.
+pub struct Styled<T> {
+    style: Style,
+    value: T,
+}
+
+impl<T: fmt::Display> Styled<T> {
+    fn fmt_colored(&self, f: &mut fmt::Formatter<'_>, colored: bool) -> fmt::Result {
+        if colored {
+            write!(f, "\x1b[{}m{}\x1b[0m", self.style.ansi_code(), self.value)
+        } else {
+            self.value.fmt(f)
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Styled<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_colored(f, ENABLED.load(Ordering::Relaxed))
+    }
+}
+
+/// Styles a line about something that was done, e.g. a downloaded song.
+pub fn success<T: fmt::Display>(value: T) -> Styled<T> {
+    Styled {
+        style: Style::Success,
+        value,
+    }
+}
+
+/// Styles a line about something that was left alone or had nothing to do.
+pub fn skip<T: fmt::Display>(value: T) -> Styled<T> {
+    Styled {
+        style: Style::Skip,
+        value,
+    }
+}
+
+/// Styles a line about something that went wrong.
+pub fn fail<T: fmt::Display>(value: T) -> Styled<T> {
+    Styled {
+        style: Style::Fail,
+        value,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Renders `styled` as it would be with `choice`, without touching the
+    /// choice other tests share.
+    fn render<T: fmt::Display>(styled: Styled<T>, choice: ColorChoice) -> String {
+        struct Rendered<T>(Styled<T>, bool);
+        impl<T: fmt::Display> fmt::Display for Rendered<T> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt_colored(f, self.1)
+            }
+        }
+        Rendered(styled, choice.is_enabled()).to_string()
+    }
+
+    #[test]
+    fn color_when_forced_on() {
+        assert_eq!(
+            render(success("3 songs downloaded"), ColorChoice::Always),
+            "\x1b[32m3 songs downloaded\x1b[0m"
+        );
+        assert_eq!(
+            render(fail("- 123"), ColorChoice::Always),
+            "\x1b[31m- 123\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn plain_when_forced_off() {
+        assert_eq!(
+            render(success("3 songs downloaded"), ColorChoice::Never),
+            "3 songs downloaded"
+        );
+        assert_eq!(render(fail("- 123"), ColorChoice::Never), "- 123");
+    }
+
+    #[test]
+    fn plain_when_not_a_terminal() {
+        // Test output is captured unless run with --nocapture.
+        if io::stdout().is_terminal() {
+            return;
+        }
+        assert_eq!(render(fail("- 123"), ColorChoice::Auto), "- 123");
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write;
+
+use crate::Song;
+
+/// Bands the songs are grouped into by their highest level, from the
+/// hardest.
+const LEVEL_BANDS: [(u8, u8); 6] = [(20, 20), (19, 19), (18, 18), (16, 17), (11, 15), (1, 10)];
+
+/// Songs downloaded in a run, grouped per uploader and per level band, to
+/// tell what is new at a glance.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Number of songs in the summary.
+    pub songs: usize,
+
+    /// Songs per uploader name, as `Title / Artist [levels]` lines.
+    pub per_uploader: BTreeMap<String, Vec<String>>,
+
+    /// Songs per level band, from the hardest. Songs without charts are left
+    /// out.
+    pub per_level_band: Vec<(String, Vec<String>)>,
+}
+
+impl Summary {
+    /// Groups `songs`, usually the ones downloaded in a run.
+    pub fn new(songs: &[Song]) -> Self {
+        let mut summary = Self {
+            songs: songs.len(),
+            ..Default::default()
+        };
+        let mut bands: Vec<_> = LEVEL_BANDS.iter().map(|_| Vec::new()).collect();
+        for song in songs {
+            let line = line(song);
+            let uploader = song.user.as_ref().map_or(&song.user_id, |user| &user.name);
+            summary
+                .per_uploader
+                .entry(uploader.clone())
+                .or_default()
+                .push(line.clone());
+            let Some(level) = song.charts.iter().map(|chart| chart.level).max() else {
+                continue;
+            };
+            if let Some(i) = LEVEL_BANDS
+                .iter()
+                .position(|(min, max)| (*min..=*max).contains(&level))
+            {
+                bands[i].push(line);
+            }
+        }
+        summary.per_level_band = LEVEL_BANDS
+            .iter()
+            .zip(bands)
+            .filter(|(_, songs)| !songs.is_empty())
+            .map(|((min, max), songs)| (band_name(*min, *max), songs))
+            .collect();
+        summary
+    }
+
+    /// Renders the summary as Markdown, e.g. to post to a chat.
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = format!("## What's new: {} songs\n", self.songs);
+        write_markdown_groups(&mut markdown, "By uploader", &self.per_uploader);
+        write_markdown_groups(
+            &mut markdown,
+            "By level",
+            self.per_level_band
+                .iter()
+                .map(|(band, songs)| (band, songs)),
+        );
+        markdown
+    }
+}
+
+fn write_markdown_groups<'a>(
+    markdown: &mut String,
+    title: &str,
+    groups: impl IntoIterator<Item = (&'a String, &'a Vec<String>)>,
+) {
+    let _ = write!(markdown, "\n### {title}\n");
+    for (name, songs) in groups {
+        let _ = write!(markdown, "\n**{name}** ({})\n", songs.len());
+        for song in songs {
+            let _ = writeln!(markdown, "- {song}");
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "What's new by uploader:")?;
+        for (uploader, songs) in &self.per_uploader {
+            writeln!(f, "  {uploader} ({})", songs.len())?;
+            for song in songs {
+                writeln!(f, "    {song}")?;
+            }
+        }
+        writeln!(f, "What's new by level:")?;
+        for (band, songs) in &self.per_level_band {
+            writeln!(f, "  {band} ({})", songs.len())?;
+            for song in songs {
+                writeln!(f, "    {song}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn line(song: &Song) -> String {
+    let levels: Vec<_> = song.charts.iter().map(|c| c.level.to_string()).collect();
+    if levels.is_empty() {
+        format!("{} / {}", song.title, song.artist)
+    } else {
+        format!("{} / {} [{}]", song.title, song.artist, levels.join(" "))
+    }
+}
+
+fn band_name(min: u8, max: u8) -> String {
+    if min == max {
+        format!("Level {min}")
+    } else {
+        format!("Level {min}-{max}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn song(id: &str, title: &str, user: &str, levels: &[u8]) -> Song {
+        let charts: Vec<_> = levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| json!({ "difficulty": i + 1, "level": level }))
+            .collect();
+        serde_json::from_value(json!({
+            "id": id,
+            "user_id": user,
+            "title": title,
+            "artist": "RG+Ice",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+            "user": { "id": user, "name": user.to_uppercase() },
+            "charts": charts,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn group_songs() {
+        let summary = Summary::new(&[
+            song("5441d590", "Outbreak", "ixiot", &[5, 12, 16, 18]),
+            song("89b54d80", "Turing Love", "ixiot", &[6, 13, 17]),
+            song("9e523640", "Easy", "rg", &[1, 5]),
+            song("a0000000", "No charts", "rg", &[]),
+        ]);
+        assert_eq!(summary.songs, 4);
+        assert_eq!(summary.per_uploader["IXIOT"].len(), 2);
+        assert_eq!(
+            summary.per_uploader["RG"],
+            ["Easy / RG+Ice [1 5]", "No charts / RG+Ice"]
+        );
+        let bands: Vec<_> = summary
+            .per_level_band
+            .iter()
+            .map(|(band, songs)| (band.as_str(), songs.len()))
+            .collect();
+        assert_eq!(
+            bands,
+            [("Level 18", 1), ("Level 16-17", 1), ("Level 1-10", 1)]
+        );
+
+        let markdown = summary.to_markdown();
+        assert!(markdown.starts_with("## What's new: 4 songs\n"));
+        assert!(markdown.contains("\n**IXIOT** (2)\n- Outbreak / RG+Ice [5 12 16 18]\n"));
+        assert!(markdown.contains("\n### By level\n\n**Level 18** (1)\n"));
+    }
+}
@@ -0,0 +1,201 @@
+use std::env;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use tracing::warn;
+
+/// How often signals received by the handlers are passed on.
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Sends `state` (e.g. `READY=1`) to the service manager. Returns `false` if
+/// not running under systemd or another manager implementing `sd_notify`.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    send(&path, state)?;
+    Ok(true)
+}
+
+/// Sends `state` to the service manager listening on the socket `path`.
+#[cfg(unix)]
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_path: &OsStr, _state: &str) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Sends `state` to the service manager, logging failures.
+pub fn notify_or_warn(state: &str) {
+    if let Err(e) = notify(state) {
+        warn!(error = %e, state, "Failed to notify the service manager");
+    }
+}
+
+/// Watchdog of the service manager, pinged as work makes progress so that a
+/// sync that hangs is restarted.
+#[derive(Debug)]
+pub struct Watchdog {
+    socket: OsString,
+    interval: Duration,
+
+    /// When the watchdog was last pinged.
+    pinged: Mutex<Option<Instant>>,
+}
+
+impl Watchdog {
+    /// Returns the watchdog of this process, if the service manager watches
+    /// it.
+    pub fn from_env() -> Option<Self> {
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        if let Ok(pid) = env::var("WATCHDOG_PID") {
+            if pid.parse() != Ok(std::process::id()) {
+                return None;
+            }
+        }
+        let socket = env::var_os("NOTIFY_SOCKET")?;
+        (usec > 0).then(|| Self::new(socket, Duration::from_micros(usec)))
+    }
+
+    /// Creates a watchdog listening on `socket` that expects a ping every
+    /// `interval`.
+    pub fn new(socket: impl Into<OsString>, interval: Duration) -> Self {
+        Self {
+            socket: socket.into(),
+            interval,
+            pinged: Mutex::new(None),
+        }
+    }
+
+    /// Pings the watchdog, unless it was pinged within half the interval it
+    /// expects.
+    pub fn ping(&self) {
+        let mut pinged = self.pinged.lock().unwrap();
+        if pinged.is_some_and(|pinged| pinged.elapsed() < self.interval / 2) {
+            return;
+        }
+        *pinged = Some(Instant::now());
+        if let Err(e) = send(&self.socket, "WATCHDOG=1") {
+            warn!(error = %e, "Failed to ping the watchdog");
+        }
+    }
+}
+
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether SIGTERM was received, i.e. the service manager stopped
+/// this process, once [`handle_signals`] handles it.
+pub fn terminated() -> bool {
+    TERMINATE.load(Ordering::Relaxed)
+}
+
+/// Sets `stop` on SIGTERM, and on SIGHUP as well as the returned flag, which
+/// asks for the configuration to be reloaded once the current sync stops.
+#[cfg(unix)]
+pub fn handle_signals(stop: Arc<AtomicBool>) -> io::Result<Arc<AtomicBool>> {
+    extern "C" fn on_signal(signal: libc::c_int) {
+        // Only touches atomics, which is async-signal-safe.
+        match signal {
+            libc::SIGTERM => TERMINATE.store(true, Ordering::Relaxed),
+            _ => RELOAD.store(true, Ordering::Relaxed),
+        }
+    }
+
+    for signal in [libc::SIGTERM, libc::SIGHUP] {
+        // SAFETY: `on_signal` only stores to atomics.
+        let previous =
+            unsafe { libc::signal(signal, on_signal as *const () as libc::sighandler_t) };
+        if previous == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let reload = Arc::new(AtomicBool::new(false));
+    let reload_requested = Arc::clone(&reload);
+    thread::spawn(move || loop {
+        if TERMINATE.load(Ordering::Relaxed) {
+            stop.store(true, Ordering::Relaxed);
+        }
+        if RELOAD.swap(false, Ordering::Relaxed) {
+            reload_requested.store(true, Ordering::Relaxed);
+            stop.store(true, Ordering::Relaxed);
+        }
+        thread::sleep(SIGNAL_POLL_INTERVAL);
+    });
+    Ok(reload)
+}
+
+#[cfg(not(unix))]
+pub fn handle_signals(_stop: Arc<AtomicBool>) -> io::Result<Arc<AtomicBool>> {
+    Ok(Arc::new(AtomicBool::new(false)))
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::os::unix::net::UnixDatagram;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn notify_socket() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &path);
+        assert!(notify("READY=1").unwrap());
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(!notify("READY=1").unwrap());
+
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[test]
+    fn ping_watchdog_at_most_every_half_interval() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let watchdog = Watchdog::new(&path, Duration::from_millis(200));
+        watchdog.ping();
+        watchdog.ping();
+        thread::sleep(Duration::from_millis(100));
+        watchdog.ping();
+
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+        assert_eq!(socket.recv(&mut buf).unwrap(), len);
+        assert!(socket.recv(&mut buf).is_err());
+    }
+}
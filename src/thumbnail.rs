@@ -0,0 +1,257 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::ensure;
+use anyhow::Context;
+use tracing::warn;
+
+use crate::jackets;
+use crate::jackets::JacketCache;
+use crate::notify::find_jacket;
+use crate::notify::Notifier;
+use crate::store::Store;
+use crate::transcode::FFMPEG;
+use crate::Song;
+
+const THUMBNAILS_DIRNAME: &str = ".thumbnails";
+
+/// Width and height thumbnails fit in when none is set.
+pub const DEFAULT_SIZE: u32 = 256;
+
+/// Small WebP copies of the jackets of songs, stored as
+/// `<dest>/.thumbnails/<id>.webp`, so that notifications and browsers need
+/// not decode full-size images again and again. They are made with ffmpeg.
+#[derive(Debug, Clone)]
+pub struct ThumbnailCache {
+    dir: PathBuf,
+    program: String,
+    size: u32,
+}
+
+/// Outcome of generating the thumbnails of a library.
+#[derive(Debug, Default)]
+pub struct ThumbnailReport {
+    /// Number of thumbnails made in this run.
+    pub generated: usize,
+
+    /// Number of songs whose thumbnail was newer than their jacket.
+    pub fresh: usize,
+
+    /// Number of songs without a jacket.
+    pub missing: usize,
+
+    /// IDs of the songs whose jacket is not a valid image.
+    pub invalid: Vec<String>,
+
+    /// IDs of the songs whose thumbnail failed to be made.
+    pub failed: Vec<String>,
+}
+
+impl ThumbnailCache {
+    pub fn new(dest: &Path) -> Self {
+        Self {
+            dir: dest.join(THUMBNAILS_DIRNAME),
+            program: String::from(FFMPEG),
+            size: DEFAULT_SIZE,
+        }
+    }
+
+    /// Sets the ffmpeg executable to run.
+    pub fn program(mut self, program: impl Into<String>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Sets the width and height thumbnails fit in, keeping the aspect ratio.
+    pub fn size(mut self, size: u32) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Returns the thumbnail of the song `id`, if one was made.
+    pub fn get(&self, id: &str) -> Option<PathBuf> {
+        Some(self.path(id)).filter(|path| path.is_file())
+    }
+
+    /// Checks the jacket of the song `id` and makes its thumbnail, unless the
+    /// one already made is newer than the jacket. Returns the path of the
+    /// thumbnail.
+    pub fn generate(&self, id: &str, jacket: &Path) -> anyhow::Result<PathBuf> {
+        if !self.is_fresh(id, jacket) {
+            check_jacket(jacket)?;
+            self.make(id, jacket)?;
+        }
+        Ok(self.path(id))
+    }
+
+    /// Makes the thumbnails of the songs in the library `dest` from the
+    /// jackets in their directories, or the cached jackets of songs that
+    /// have none.
+    pub fn generate_library(&self, dest: &Path) -> ThumbnailReport {
+        let jacket_cache = JacketCache::new(dest);
+        let mut report = ThumbnailReport::default();
+        for (id, entry) in Store::open_read_only(dest).entries() {
+            let jacket = find_jacket(&dest.join(entry.dir(&id))).or_else(|| jacket_cache.get(&id));
+            let Some(jacket) = jacket else {
+                report.missing += 1;
+                continue;
+            };
+            if self.is_fresh(&id, &jacket) {
+                report.fresh += 1;
+                continue;
+            }
+            if let Err(e) = check_jacket(&jacket) {
+                warn!(id, error = format!("{e:#}"), "Invalid jacket");
+                report.invalid.push(id);
+                continue;
+            }
+            match self.make(&id, &jacket) {
+                Ok(()) => report.generated += 1,
+                Err(e) => {
+                    warn!(id, error = %e, "Failed to make thumbnail");
+                    report.failed.push(id);
+                }
+            }
+        }
+        report
+    }
+
+    fn make(&self, id: &str, jacket: &Path) -> anyhow::Result<()> {
+        let path = self.path(id);
+        fs::create_dir_all(&self.dir)?;
+        // Written under a temporary name so that readers never see a partial
+        // thumbnail.
+        let tmp = self.dir.join(format!("{id}.tmp"));
+        let status = Command::new(&self.program)
+            .args(["-nostdin", "-loglevel", "error", "-y", "-i"])
+            .arg(jacket)
+            .arg("-vf")
+            .arg(format!(
+                "scale={0}:{0}:force_original_aspect_ratio=decrease",
+                self.size
+            ))
+            .args(["-frames:v", "1", "-f", "webp"])
+            .arg(&tmp)
+            .stdin(Stdio::null())
+            .status()
+            .with_context(|| format!("Failed to run {}", self.program));
+        let result = status.and_then(|status| {
+            ensure!(
+                status.success(),
+                "{} exited with {status} for {}",
+                self.program,
+                jacket.display()
+            );
+            Ok(fs::rename(&tmp, &path)?)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.webp"))
+    }
+
+    fn is_fresh(&self, id: &str, jacket: &Path) -> bool {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        match (modified(&self.path(id)), modified(jacket)) {
+            (Some(thumbnail), Some(jacket)) => thumbnail >= jacket,
+            _ => false,
+        }
+    }
+}
+
+fn check_jacket(jacket: &Path) -> anyhow::Result<()> {
+    let bytes = fs::read(jacket).with_context(|| format!("Failed to read {}", jacket.display()))?;
+    jackets::check(&bytes).with_context(|| format!("Invalid jacket {}", jacket.display()))?;
+    Ok(())
+}
+
+/// Checks the jacket of each downloaded song and makes its thumbnail.
+#[derive(Debug)]
+pub struct ThumbnailNotifier {
+    cache: ThumbnailCache,
+}
+
+impl ThumbnailNotifier {
+    pub fn new(cache: ThumbnailCache) -> Self {
+        Self { cache }
+    }
+}
+
+impl Notifier for ThumbnailNotifier {
+    fn song_downloaded(&self, song: &Song, path: &Path) -> anyhow::Result<()> {
+        if let Some(jacket) = find_jacket(path) {
+            self.cache.generate(&song.id, &jacket)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::store::Entry;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\x02\0\0\0\x02\0\x08\x06\0\0\0\0\0\0\0\0\0\0\0IEND\xae\x42\x60\x82";
+
+    #[cfg(unix)]
+    #[test]
+    fn generate_thumbnails() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dest = tempdir().unwrap();
+        // Writes its arguments to the output file, the last argument.
+        let ffmpeg = dest.path().join("ffmpeg");
+        fs::write(
+            &ffmpeg,
+            "#!/bin/sh\nfor out; do :; done\necho \"$@\" > \"$out\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&ffmpeg, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut store = Store::open(dest.path());
+        for id in ["a", "broken", "bare"] {
+            let song: Song = serde_json::from_value(json!({
+                "id": id,
+                "user_id": "user",
+                "title": id,
+                "artist": "RG+Ice",
+                "uploaded_at": "2023-09-01 00:00:00",
+                "updated_at": "2023-09-01 00:00:00",
+            }))
+            .unwrap();
+            store.insert(id, &Entry::new(&song, id)).unwrap();
+            fs::create_dir(dest.path().join(id)).unwrap();
+        }
+        drop(store);
+        fs::write(dest.path().join("a/jacket.png"), PNG).unwrap();
+        fs::write(dest.path().join("broken/jacket.png"), &PNG[..20]).unwrap();
+
+        let cache = ThumbnailCache::new(dest.path())
+            .program(ffmpeg.to_string_lossy())
+            .size(128);
+        let report = cache.generate_library(dest.path());
+        assert_eq!(report.generated, 1);
+        assert_eq!(report.missing, 1);
+        assert_eq!(report.invalid, ["broken"]);
+        assert!(report.failed.is_empty());
+
+        let thumbnail = cache.get("a").unwrap();
+        assert_eq!(thumbnail, dest.path().join(".thumbnails/a.webp"));
+        let args = fs::read_to_string(&thumbnail).unwrap();
+        assert!(args.contains("scale=128:128:force_original_aspect_ratio=decrease"));
+        assert_eq!(cache.get("broken"), None);
+
+        // Thumbnails newer than their jacket are kept.
+        assert_eq!(cache.generate_library(dest.path()).fresh, 1);
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::ensure;
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::ksh;
+use crate::notify::Notifier;
+use crate::Song;
+
+pub(crate) const FFMPEG: &str = "ffmpeg";
+
+/// Vorbis quality used when none is configured, about 192 kbit/s.
+pub const DEFAULT_QUALITY: u8 = 6;
+
+/// Transcoding settings from the configuration file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TranscodeConfig {
+    /// Vorbis quality from 0 to 10.
+    pub quality: Option<u8>,
+
+    /// Keep the WAV files after transcoding.
+    pub keep_originals: bool,
+}
+
+/// Outcome of transcoding a song directory.
+#[derive(Debug, Default)]
+pub struct Transcoded {
+    /// WAV files converted to OGG.
+    pub files: usize,
+
+    /// Bytes freed by removing the WAV files.
+    pub bytes_saved: u64,
+}
+
+/// Converts the WAV audio of songs to OGG Vorbis with ffmpeg and points the
+/// charts at the new files.
+#[derive(Debug, Clone)]
+pub struct Transcoder {
+    program: String,
+    quality: u8,
+    keep_originals: bool,
+}
+
+impl Default for Transcoder {
+    fn default() -> Self {
+        Self {
+            program: String::from(FFMPEG),
+            quality: DEFAULT_QUALITY,
+            keep_originals: false,
+        }
+    }
+}
+
+impl Transcoder {
+    /// Sets the ffmpeg executable to run.
+    pub fn program(mut self, program: impl Into<String>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Sets the Vorbis quality, from 0 to 10.
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.quality = quality.min(10);
+        self
+    }
+
+    pub fn keep_originals(mut self, keep_originals: bool) -> Self {
+        self.keep_originals = keep_originals;
+        self
+    }
+
+    /// Transcodes the WAV files in `dir` and rewrites the chart references to
+    /// them.
+    ///
+    /// WAV files whose OGG name is already taken are left alone. All files
+    /// are converted before any chart is touched, so a failed conversion
+    /// leaves the song as it was.
+    pub fn transcode_dir(&self, dir: &Path) -> anyhow::Result<Transcoded> {
+        let mut converted = Vec::new();
+        for wav in wav_files(dir)? {
+            let ogg = wav.with_extension("ogg");
+            if ogg.exists() {
+                continue;
+            }
+            if let Err(e) = self.convert(&wav, &ogg) {
+                let _ = fs::remove_file(&ogg);
+                for (_, ogg) in &converted {
+                    let _ = fs::remove_file(ogg);
+                }
+                return Err(e);
+            }
+            converted.push((wav, ogg));
+        }
+        if converted.is_empty() {
+            return Ok(Transcoded::default());
+        }
+
+        let renames: Vec<_> = converted
+            .iter()
+            .map(|(wav, ogg)| (file_name(wav), file_name(ogg)))
+            .collect();
+        for chart in ksh::charts(dir)? {
+            ksh::rewrite(&chart, |content| replace_references(content, &renames))?;
+        }
+
+        let mut transcoded = Transcoded {
+            files: converted.len(),
+            ..Default::default()
+        };
+        if !self.keep_originals {
+            for (wav, _) in &converted {
+                transcoded.bytes_saved += fs::metadata(wav)?.len();
+                fs::remove_file(wav)?;
+            }
+        }
+        Ok(transcoded)
+    }
+
+    fn convert(&self, wav: &Path, ogg: &Path) -> anyhow::Result<()> {
+        let status = Command::new(&self.program)
+            .args(["-nostdin", "-loglevel", "error", "-i"])
+            .arg(wav)
+            .args(["-vn", "-c:a", "libvorbis", "-q:a"])
+            .arg(self.quality.to_string())
+            .arg(ogg)
+            .stdin(Stdio::null())
+            .status()
+            .with_context(|| format!("Failed to run {}", self.program))?;
+        ensure!(
+            status.success(),
+            "{} exited with {status} for {}",
+            self.program,
+            wav.display()
+        );
+        Ok(())
+    }
+}
+
+fn wav_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+/// Replaces each whole file name `from` in `content` with `to`. A name only
+/// matches where it is not part of a longer name, so `1.wav` leaves
+/// `11.wav` alone.
+fn replace_references(content: &str, renames: &[(String, String)]) -> String {
+    let is_name_char = |c: char| c.is_alphanumeric() || "_-.+()[]".contains(c);
+    let mut content = content.to_owned();
+    for (from, to) in renames {
+        let mut replaced = String::with_capacity(content.len());
+        let mut rest = content.as_str();
+        while let Some(i) = rest.find(from.as_str()) {
+            let before = rest[..i].chars().next_back();
+            let after = rest[i + from.len()..].chars().next();
+            replaced.push_str(&rest[..i]);
+            if before.is_some_and(is_name_char) || after.is_some_and(is_name_char) {
+                replaced.push_str(from);
+            } else {
+                replaced.push_str(to);
+            }
+            rest = &rest[i + from.len()..];
+        }
+        replaced.push_str(rest);
+        content = replaced;
+    }
+    content
+}
+
+/// Transcodes the WAV audio of each downloaded song.
+#[derive(Debug, Default)]
+pub struct TranscodeNotifier {
+    transcoder: Transcoder,
+}
+
+impl TranscodeNotifier {
+    pub fn new(transcoder: Transcoder) -> Self {
+        Self { transcoder }
+    }
+}
+
+impl Notifier for TranscodeNotifier {
+    fn song_downloaded(&self, _song: &Song, path: &Path) -> anyhow::Result<()> {
+        self.transcoder.transcode_dir(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn replace_whole_names() {
+        let renames = [(String::from("1.wav"), String::from("1.ogg"))];
+        assert_eq!(
+            replace_references("m=1.wav;11.wav\nfx-l_se=1.wav;50\nx1.wav", &renames),
+            "m=1.ogg;11.wav\nfx-l_se=1.ogg;50\nx1.wav"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn transcode_with_fake_ffmpeg() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let ffmpeg = dir.path().join("ffmpeg");
+        fs::write(
+            &ffmpeg,
+            "#!/bin/sh\nwhile [ $# -gt 1 ]; do [ \"$1\" = -i ] && in=$2; shift; done\ncp \"$in\" \"$1\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&ffmpeg, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let song = dir.path().join("song");
+        fs::create_dir(&song).unwrap();
+        fs::write(song.join("song.wav"), "music").unwrap();
+        fs::write(song.join("kick.WAV"), "kick").unwrap();
+        fs::write(song.join("chart.ksh"), "m=song.wav\n--\nfx-l_se=kick.WAV\n").unwrap();
+
+        let transcoder = Transcoder::default().program(ffmpeg.to_string_lossy());
+        let transcoded = transcoder.transcode_dir(&song).unwrap();
+        assert_eq!(transcoded.files, 2);
+        assert_eq!(transcoded.bytes_saved, 9);
+        assert_eq!(
+            fs::read_to_string(song.join("chart.ksh")).unwrap(),
+            "m=song.ogg\n--\nfx-l_se=kick.ogg\n"
+        );
+        assert_eq!(fs::read_to_string(song.join("song.ogg")).unwrap(), "music");
+        assert!(!song.join("song.wav").exists());
+
+        let transcoder = Transcoder::default().program("false");
+        fs::write(song.join("other.wav"), "other").unwrap();
+        assert!(transcoder.transcode_dir(&song).is_err());
+        assert!(song.join("other.wav").exists());
+        assert!(!song.join("other.ogg").exists());
+    }
+}
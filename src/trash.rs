@@ -0,0 +1,131 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::NaiveDateTime;
+use chrono::Utc;
+
+const TRASH_DIRNAME: &str = ".trash";
+
+/// Format of the names of the batches in `.trash`, one per second files were
+/// trashed in.
+const BATCH_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Time trashed files are kept for when none is configured.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How files that are no longer wanted are disposed of.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Disposal {
+    /// Delete them for good.
+    #[default]
+    Delete,
+
+    /// Move them into `<dest>/.trash`, where they are kept for a while in
+    /// case they were removed by mistake.
+    Trash,
+}
+
+impl Disposal {
+    /// Disposes of the file or directory `path` of the library `dest`, which
+    /// is trashed under `name`, its path relative to the library.
+    pub fn dispose(self, dest: &Path, path: &Path, name: &Path) -> anyhow::Result<()> {
+        match self {
+            Self::Delete if path.is_dir() => fs::remove_dir_all(path)?,
+            Self::Delete => fs::remove_file(path)?,
+            Self::Trash => {
+                put(dest, path, name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Moves the file or directory `path` of the library `dest` into the batch
+/// of the current second in `.trash`, as `name`. Returns where it was moved.
+pub fn put(dest: &Path, path: &Path, name: &Path) -> anyhow::Result<PathBuf> {
+    let batch = dest
+        .join(TRASH_DIRNAME)
+        .join(Utc::now().format(BATCH_FORMAT).to_string());
+    let mut target = batch.join(name);
+    let mut n = 1;
+    while target.exists() {
+        n += 1;
+        let mut file_name = name.file_name().unwrap_or_default().to_owned();
+        file_name.push(format!(" ({n})"));
+        target.set_file_name(file_name);
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(path, &target)
+        .with_context(|| format!("Failed to move {} to the trash", path.display()))?;
+    Ok(target)
+}
+
+/// Deletes the batches in the trash of `dest` older than `retention`.
+/// Returns the number of batches deleted.
+pub fn purge(dest: &Path, retention: Duration) -> anyhow::Result<usize> {
+    let root = dest.join(TRASH_DIRNAME);
+    if !root.is_dir() {
+        return Ok(0);
+    }
+    let now = Utc::now();
+    let mut purged = 0;
+    for entry in fs::read_dir(&root)? {
+        let entry = entry?;
+        let Some(trashed_at) = batch_time(&entry.file_name().to_string_lossy()) else {
+            continue;
+        };
+        if (now - trashed_at).to_std().unwrap_or_default() > retention {
+            fs::remove_dir_all(entry.path())?;
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+fn batch_time(name: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(name, BATCH_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn trash_and_purge() {
+        let dest = tempdir().unwrap();
+        for _ in 0..2 {
+            let song_dir = dest.path().join("RG+Ice/Outbreak");
+            fs::create_dir_all(&song_dir).unwrap();
+            fs::write(song_dir.join("exh.ksh"), "title=Outbreak").unwrap();
+            Disposal::Trash
+                .dispose(dest.path(), &song_dir, Path::new("RG+Ice/Outbreak"))
+                .unwrap();
+            assert!(!song_dir.exists());
+        }
+        let batches: Vec<_> = fs::read_dir(dest.path().join(TRASH_DIRNAME))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        let trashed = batches
+            .iter()
+            .map(|batch| fs::read_dir(batch.join("RG+Ice")).unwrap().count())
+            .sum::<usize>();
+        assert_eq!(trashed, 2);
+
+        let old = dest.path().join(TRASH_DIRNAME).join("20230901T000000Z");
+        fs::create_dir(&old).unwrap();
+        assert_eq!(purge(dest.path(), DEFAULT_RETENTION).unwrap(), 1);
+        assert!(!old.exists());
+        assert!(batches.iter().all(|batch| batch.exists()));
+    }
+}
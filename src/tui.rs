@@ -0,0 +1,397 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use nautica_downloader_rs::store;
+use nautica_downloader_rs::store::Store;
+use nautica_downloader_rs::Song;
+use ratatui::crossterm::event;
+use ratatui::crossterm::event::Event;
+use ratatui::crossterm::event::KeyCode;
+use ratatui::crossterm::event::KeyEventKind;
+use ratatui::layout::Constraint;
+use ratatui::layout::Layout;
+use ratatui::style::Modifier;
+use ratatui::style::Style;
+use ratatui::text::Line;
+use ratatui::widgets::Block;
+use ratatui::widgets::List;
+use ratatui::widgets::ListItem;
+use ratatui::widgets::ListState;
+use ratatui::widgets::Paragraph;
+use ratatui::DefaultTerminal;
+use ratatui::Frame;
+
+/// Number of songs fetched each time the end of the list is reached.
+const PAGE_SIZE: usize = 50;
+
+const HELP: &str = "↑↓ move  space queue  s star  / search  [ ] min level  { } max level  \
+                    L load all  enter download  q quit";
+
+/// Browser over the remote catalog that lets the user queue songs for
+/// download.
+struct App<'a> {
+    catalog: Box<dyn Iterator<Item = anyhow::Result<Song>> + 'a>,
+    exhausted: bool,
+    songs: Vec<Song>,
+    dest: &'a Path,
+    /// Snapshot of the library, read again after each change.
+    store: Store,
+    queued: HashSet<String>,
+    starred: HashSet<String>,
+    query: String,
+    searching: bool,
+    min_level: u8,
+    max_level: u8,
+    state: ListState,
+    status: String,
+}
+
+impl<'a> App<'a> {
+    fn new(catalog: impl Iterator<Item = anyhow::Result<Song>> + 'a, dest: &'a Path) -> Self {
+        let store = Store::open_read_only(dest);
+        let starred = store
+            .entries()
+            .into_iter()
+            .filter(|(_, entry)| entry.starred)
+            .map(|(id, _)| id)
+            .collect();
+        Self {
+            catalog: Box::new(catalog),
+            exhausted: false,
+            songs: Vec::new(),
+            dest,
+            store,
+            queued: HashSet::new(),
+            starred,
+            query: String::new(),
+            searching: false,
+            min_level: 1,
+            max_level: 20,
+            state: ListState::default().with_selected(Some(0)),
+            status: String::new(),
+        }
+    }
+
+    fn load_more(&mut self, count: usize) {
+        for _ in 0..count {
+            match self.catalog.next() {
+                Some(Ok(song)) => self.songs.push(song),
+                Some(Err(e)) => {
+                    self.status = format!("Failed to load songs: {e}");
+                    self.exhausted = true;
+                    return;
+                }
+                None => {
+                    self.exhausted = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn matches(&self, song: &Song) -> bool {
+        let query = self.query.to_lowercase();
+        let text_matches = song.title.to_lowercase().contains(&query)
+            || song.artist.to_lowercase().contains(&query);
+        let level_matches = song.charts.is_empty()
+            || song
+                .charts
+                .iter()
+                .any(|chart| (self.min_level..=self.max_level).contains(&chart.level));
+        text_matches && level_matches
+    }
+
+    fn visible(&self) -> Vec<usize> {
+        (0..self.songs.len())
+            .filter(|&i| self.matches(&self.songs[i]))
+            .collect()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let visible = self.visible();
+        let [header, body, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let search = if self.searching {
+            format!("/{}_", self.query)
+        } else if self.query.is_empty() {
+            String::from("(no search)")
+        } else {
+            format!("/{}", self.query)
+        };
+        frame.render_widget(
+            Paragraph::new(format!(
+                "{search}  level {}-{}  {} loaded{}  {} queued",
+                self.min_level,
+                self.max_level,
+                self.songs.len(),
+                if self.exhausted { "" } else { "+" },
+                self.queued.len(),
+            )),
+            header,
+        );
+
+        let items: Vec<_> = visible
+            .iter()
+            .map(|&i| {
+                let song = &self.songs[i];
+                let mark = if self.queued.contains(&song.id) {
+                    "[+]"
+                } else if self.store.contains(&song.id) {
+                    "[✓]"
+                } else {
+                    "[ ]"
+                };
+                let star = if self.starred.contains(&song.id) {
+                    "★ "
+                } else {
+                    ""
+                };
+                let levels: Vec<_> = song.charts.iter().map(|c| c.level.to_string()).collect();
+                ListItem::new(Line::from(format!(
+                    "{mark} {star}{} / {}  ({})",
+                    song.title,
+                    song.artist,
+                    levels.join(" ")
+                )))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title("Nautica"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, body, &mut self.state);
+
+        let footer_text = if self.status.is_empty() {
+            HELP
+        } else {
+            &self.status
+        };
+        frame.render_widget(Paragraph::new(footer_text), footer);
+    }
+
+    /// Handles a key press. Returns `Some(download)` when the browser should
+    /// close.
+    fn on_key(&mut self, code: KeyCode) -> Option<bool> {
+        self.status.clear();
+        if self.searching {
+            match code {
+                KeyCode::Enter | KeyCode::Esc => self.searching = false,
+                KeyCode::Backspace => {
+                    self.query.pop();
+                }
+                KeyCode::Char(c) => self.query.push(c),
+                _ => {}
+            }
+            self.state.select(Some(0));
+            return None;
+        }
+
+        let visible = self.visible();
+        let selected = self.state.selected().unwrap_or(0);
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return Some(false),
+            KeyCode::Enter => return Some(true),
+            KeyCode::Down | KeyCode::Char('j') if selected + 1 < visible.len() => {
+                self.state.select(Some(selected + 1));
+            }
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.state.select(Some(selected.saturating_sub(1)));
+            }
+            KeyCode::Char(' ') => {
+                if let Some(&i) = visible.get(selected) {
+                    let song = &self.songs[i];
+                    if self.store.contains(&song.id) {
+                        self.status = String::from("Already downloaded");
+                    } else if !self.queued.remove(&song.id) {
+                        self.queued.insert(song.id.clone());
+                    }
+                }
+            }
+            KeyCode::Char('s') => {
+                if let Some(&i) = visible.get(selected) {
+                    let id = self.songs[i].id.clone();
+                    if let Err(e) = self.toggle_star(&id) {
+                        self.status = format!("Failed to star the song: {e}");
+                    }
+                }
+            }
+            KeyCode::Char('/') => {
+                self.searching = true;
+            }
+            KeyCode::Char('[') => self.min_level = self.min_level.saturating_sub(1).max(1),
+            KeyCode::Char(']') => self.min_level = (self.min_level + 1).min(self.max_level),
+            KeyCode::Char('{') => self.max_level = (self.max_level - 1).max(self.min_level),
+            KeyCode::Char('}') => self.max_level = (self.max_level + 1).min(20),
+            KeyCode::Char('L') => self.load_more(usize::MAX),
+            _ => {}
+        }
+        None
+    }
+
+    /// Stars a downloaded song locally, or removes its star.
+    ///
+    /// The store is opened under the library lock for each change, so that
+    /// the entries a sync recorded since the browser opened are kept.
+    fn toggle_star(&mut self, id: &str) -> anyhow::Result<()> {
+        let _lock = store::lock(self.dest)?;
+        let mut store = Store::open(self.dest);
+        let Some(mut entry) = store.get(id) else {
+            self.status = String::from("Only downloaded songs can be starred");
+            return Ok(());
+        };
+        entry.starred = !self.starred.contains(id);
+        store.insert(id, &entry)?;
+        if entry.starred {
+            self.starred.insert(id.to_owned());
+        } else {
+            self.starred.remove(id);
+        }
+        self.store = Store::open_read_only(self.dest);
+        Ok(())
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> anyhow::Result<bool> {
+        self.load_more(PAGE_SIZE);
+        loop {
+            // Fetch more songs when the selection reaches the end of the list.
+            let visible = self.visible().len();
+            if !self.exhausted && self.state.selected().unwrap_or(0) + 1 >= visible {
+                self.load_more(PAGE_SIZE);
+            }
+            let visible = self.visible().len();
+            if self.state.selected().unwrap_or(0) >= visible {
+                self.state.select(Some(visible.saturating_sub(1)));
+            }
+
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                if let Some(download) = self.on_key(key.code) {
+                    return Ok(download);
+                }
+            }
+        }
+    }
+}
+
+/// Opens the interactive browser and returns the songs queued for download,
+/// in catalog order. Songs starred in the browser are saved to the library
+/// `dest`.
+pub fn browse(
+    catalog: impl Iterator<Item = anyhow::Result<Song>>,
+    dest: &Path,
+) -> anyhow::Result<Vec<Song>> {
+    let mut app = App::new(catalog, dest);
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+
+    if !result? {
+        return Ok(Vec::new());
+    }
+    Ok(app
+        .songs
+        .into_iter()
+        .filter(|song| app.queued.contains(&song.id))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use nautica_downloader_rs::store::Entry;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn song(id: &str, level: u8) -> Song {
+        serde_json::from_value(json!({
+            "id": id,
+            "user_id": "user",
+            "title": format!("title of {id}"),
+            "artist": "artist",
+            "uploaded_at": "2023-09-01 00:00:00",
+            "updated_at": "2023-09-01 00:00:00",
+            "charts": [{ "difficulty": 4, "level": level }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn queue_search_and_star() {
+        let dest = tempdir().unwrap();
+        Store::open(dest.path())
+            .insert("b", &Entry::new(&song("b", 12), "b"))
+            .unwrap();
+        let catalog = vec![Ok(song("a", 18)), Ok(song("b", 12)), Ok(song("c", 5))];
+        let mut app = App::new(catalog.into_iter(), dest.path());
+        app.load_more(PAGE_SIZE);
+        assert!(app.exhausted);
+
+        // Queue a, then try b, which is already downloaded.
+        assert_eq!(app.on_key(KeyCode::Char(' ')), None);
+        app.on_key(KeyCode::Down);
+        app.on_key(KeyCode::Char(' '));
+        assert_eq!(app.status, "Already downloaded");
+        assert_eq!(app.queued, HashSet::from([String::from("a")]));
+
+        // Only downloaded songs can be starred.
+        app.on_key(KeyCode::Char('s'));
+        assert!(app.starred.contains("b"));
+        app.on_key(KeyCode::Up);
+        app.on_key(KeyCode::Char('s'));
+        assert!(!app.starred.contains("a"));
+        assert_eq!(app.status, "Only downloaded songs can be starred");
+
+        for c in "/of c".chars() {
+            app.on_key(KeyCode::Char(c));
+        }
+        app.on_key(KeyCode::Enter);
+        assert_eq!(app.visible(), [2]);
+        app.query.clear();
+        for _ in 0..6 {
+            app.on_key(KeyCode::Char('{'));
+        }
+        assert_eq!(app.max_level, 14);
+        assert_eq!(app.visible(), [1, 2]);
+
+        assert_eq!(app.on_key(KeyCode::Enter), Some(true));
+        drop(app);
+        assert!(Store::open_read_only(dest.path()).get("b").unwrap().starred);
+    }
+
+    #[test]
+    fn star_keeps_entries_synced_meanwhile() {
+        let dest = tempdir().unwrap();
+        Store::open(dest.path())
+            .insert("a", &Entry::new(&song("a", 18), "a"))
+            .unwrap();
+        let mut app = App::new(vec![Ok(song("a", 18))].into_iter(), dest.path());
+        app.load_more(PAGE_SIZE);
+
+        // A sync records another song after the browser opened.
+        Store::open(dest.path())
+            .insert("b", &Entry::new(&song("b", 12), "b"))
+            .unwrap();
+        app.on_key(KeyCode::Char('s'));
+
+        let store = Store::open_read_only(dest.path());
+        assert!(store.get("a").unwrap().starred);
+        assert!(store.contains("b"));
+
+        // Starring waits for a running sync.
+        let sync = store::lock(dest.path()).unwrap();
+        app.on_key(KeyCode::Char('s'));
+        assert!(app.status.starts_with("Failed to star the song"));
+        assert!(app.starred.contains("a"));
+        drop(sync);
+        app.on_key(KeyCode::Char('s'));
+        assert!(!Store::open_read_only(dest.path()).get("a").unwrap().starred);
+    }
+}
@@ -0,0 +1,291 @@
+use std::env;
+use std::env::consts::EXE_SUFFIX;
+use std::fs;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use attohttpc::header;
+use attohttpc::Session;
+use ring::signature;
+use serde::Deserialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+const LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/reiyw/nautica-downloader-rs/releases/latest";
+
+/// Release asset listing the SHA-256 checksums of the other assets, in the
+/// format of `sha256sum`.
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+
+/// Release asset with the Ed25519 signature of [`CHECKSUMS_ASSET`].
+const SIGNATURE_ASSET: &str = "SHA256SUMS.sig";
+
+/// Hex-encoded Ed25519 public key the checksums of releases are signed with,
+/// given when building a release.
+const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("NAUTICA_RELEASE_PUBLIC_KEY");
+
+/// A release on GitHub.
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+impl Release {
+    /// Returns the version of the release, e.g. `0.2.0` for the tag `v0.2.0`.
+    pub fn version(&self) -> &str {
+        self.tag_name.trim_start_matches('v')
+    }
+
+    fn asset(&self, name: &str) -> anyhow::Result<&Asset> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .with_context(|| format!("Release {} has no {name}", self.tag_name))
+    }
+}
+
+/// Name of the release asset with the binary for the running platform.
+pub fn asset_name() -> String {
+    format!("{}-{}{EXE_SUFFIX}", env!("CARGO_PKG_NAME"), env!("TARGET"))
+}
+
+/// Returns whether `version` is newer than the running version.
+pub fn is_newer(version: &str) -> bool {
+    parse_version(version) > parse_version(env!("CARGO_PKG_VERSION"))
+}
+
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .split(['.', '-', '+'])
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+/// Fetches releases from GitHub and replaces the running binary with the
+/// latest one.
+#[derive(Debug)]
+pub struct Updater {
+    latest_release_url: String,
+    public_key: Option<Vec<u8>>,
+    sess: Session,
+}
+
+impl Default for Updater {
+    fn default() -> Self {
+        let mut sess = Session::new();
+        // The GitHub API rejects requests without a user agent.
+        sess.header(
+            header::USER_AGENT,
+            concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+        );
+        Self {
+            latest_release_url: String::from(LATEST_RELEASE_URL),
+            public_key: RELEASE_PUBLIC_KEY.and_then(|key| hex::decode(key).ok()),
+            sess,
+        }
+    }
+}
+
+impl Updater {
+    /// Uses another endpoint for the latest release, e.g. of a fork.
+    pub fn latest_release_url(mut self, url: String) -> Self {
+        self.latest_release_url = url;
+        self
+    }
+
+    /// Verifies releases with another Ed25519 public key, e.g. of a fork.
+    pub fn public_key(mut self, key: Vec<u8>) -> Self {
+        self.public_key = Some(key);
+        self
+    }
+
+    pub fn latest(&self) -> anyhow::Result<Release> {
+        let resp = self
+            .sess
+            .get(&self.latest_release_url)
+            .header(header::ACCEPT, "application/vnd.github+json")
+            .send()?
+            .error_for_status()
+            .context("Failed to look up the latest release")?;
+        Ok(resp.json_utf8()?)
+    }
+
+    /// Downloads the binary for the running platform from `release`, checking
+    /// it against the checksum published with the release. The checksums
+    /// have to be signed with the release key, so that a release whose
+    /// assets were replaced is not installed.
+    pub fn download(&self, release: &Release) -> anyhow::Result<Vec<u8>> {
+        let Some(public_key) = &self.public_key else {
+            bail!("This build has no release key to verify updates with");
+        };
+        let name = asset_name();
+        let asset = release.asset(&name)?;
+        let checksums = self.fetch(release.asset(CHECKSUMS_ASSET)?)?;
+        let sig = self.fetch(release.asset(SIGNATURE_ASSET)?)?;
+        signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(&checksums, &sig)
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "{CHECKSUMS_ASSET} of {} is not signed by the release key",
+                    release.tag_name
+                )
+            })?;
+        let checksums = String::from_utf8_lossy(&checksums);
+        let expected = checksums
+            .lines()
+            .filter_map(|line| line.split_once(char::is_whitespace))
+            .find(|(_, file)| file.trim().trim_start_matches('*') == name)
+            .map(|(checksum, _)| checksum.to_ascii_lowercase())
+            .with_context(|| format!("{CHECKSUMS_ASSET} has no checksum for {name}"))?;
+        let binary = self.fetch(asset)?;
+        let actual = hex::encode(Sha256::digest(&binary));
+        ensure!(
+            actual == expected,
+            "Checksum mismatch for {name}: expected {expected}, got {actual}"
+        );
+        Ok(binary)
+    }
+
+    fn fetch(&self, asset: &Asset) -> anyhow::Result<Vec<u8>> {
+        let resp = self
+            .sess
+            .get(&asset.browser_download_url)
+            .send()?
+            .error_for_status()
+            .with_context(|| format!("Failed to download {}", asset.name))?;
+        Ok(resp.bytes()?)
+    }
+}
+
+/// Replaces the running executable with `binary`.
+pub fn replace_current_exe(binary: &[u8]) -> anyhow::Result<()> {
+    let exe = env::current_exe()?;
+    replace_exe(&exe.canonicalize().unwrap_or(exe), binary)
+}
+
+/// Replaces the executable at `exe` with `binary`. The new binary is written
+/// next to it first so that a failed write never leaves a broken executable.
+fn replace_exe(exe: &Path, binary: &[u8]) -> anyhow::Result<()> {
+    let new = exe.with_extension("new");
+    fs::write(&new, binary).with_context(|| format!("Failed to write {}", new.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&new, fs::Permissions::from_mode(0o755))?;
+    }
+    // Windows cannot overwrite a running executable but can rename it.
+    let old = exe.with_extension("old");
+    if cfg!(windows) {
+        let _ = fs::remove_file(&old);
+        fs::rename(exe, &old)?;
+    }
+    if let Err(e) = fs::rename(&new, exe) {
+        if cfg!(windows) {
+            let _ = fs::rename(&old, exe);
+        }
+        bail!("Failed to replace {}: {e}", exe.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use httpmock::MockServer;
+    use ring::rand::SystemRandom;
+    use ring::signature::Ed25519KeyPair;
+    use ring::signature::KeyPair;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn compare_versions() {
+        assert!(is_newer("999.0.0"));
+        assert!(!is_newer(env!("CARGO_PKG_VERSION")));
+        assert!(!is_newer("0.0.1"));
+        assert!(parse_version("0.10.0") > parse_version("0.9.1"));
+    }
+
+    #[test]
+    fn download_and_verify() {
+        let server = MockServer::start();
+        let name = asset_name();
+        server.mock(|when, then| {
+            when.path("/latest").header_exists("user-agent");
+            then.status(200).json_body(json!({
+                "tag_name": "v999.0.0",
+                "assets": [
+                    { "name": name, "browser_download_url": server.url("/bin") },
+                    { "name": "SHA256SUMS", "browser_download_url": server.url("/sums") },
+                    { "name": "SHA256SUMS.sig", "browser_download_url": server.url("/sig") },
+                ],
+            }));
+        });
+        server.mock(|when, then| {
+            when.path("/bin");
+            then.status(200).body("binary");
+        });
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let checksums = format!(
+            "{}  other\n{}  {name}\n",
+            "0".repeat(64),
+            hex::encode(Sha256::digest("binary"))
+        );
+        let mut sums = server.mock(|when, then| {
+            when.path("/sums");
+            then.status(200).body(&checksums);
+        });
+        let mut sig = server.mock(|when, then| {
+            when.path("/sig");
+            then.status(200).body(key.sign(checksums.as_bytes()));
+        });
+
+        let updater = Updater::default()
+            .latest_release_url(server.url("/latest"))
+            .public_key(key.public_key().as_ref().to_vec());
+        let release = updater.latest().unwrap();
+        assert_eq!(release.version(), "999.0.0");
+        assert_eq!(updater.download(&release).unwrap(), b"binary");
+
+        // Checksums that do not match the signature are rejected.
+        sums.delete();
+        let tampered = format!("{}  {name}\n", "0".repeat(64));
+        server.mock(|when, then| {
+            when.path("/sums");
+            then.status(200).body(&tampered);
+        });
+        let err = updater.download(&release).unwrap_err();
+        assert!(err.to_string().contains("not signed"));
+
+        // Signed checksums are still checked against the binary.
+        sig.delete();
+        server.mock(|when, then| {
+            when.path("/sig");
+            then.status(200).body(key.sign(tampered.as_bytes()));
+        });
+        let err = updater.download(&release).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"));
+    }
+
+    #[test]
+    fn replace_executable() {
+        let dir = tempdir().unwrap();
+        let exe = dir.path().join("nautica-downloader-rs");
+        fs::write(&exe, "old").unwrap();
+        replace_exe(&exe, b"new").unwrap();
+        assert_eq!(fs::read(&exe).unwrap(), b"new");
+        assert!(!dir.path().join("nautica-downloader-rs.new").exists());
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use tracing::warn;
+
+/// Name of the file in the library caching the names of uploaders.
+const UPLOADERS_FILENAME: &str = ".uploaders.json";
+
+/// Display names of uploaders by user ID, cached in the library so that
+/// directories grouped by uploader need the server to look up each uploader
+/// only once.
+#[derive(Debug, Default)]
+pub struct UploaderNames {
+    path: PathBuf,
+    names: BTreeMap<String, String>,
+}
+
+impl UploaderNames {
+    /// Opens the cache of the library `dest`. A broken cache is started
+    /// over, since the names can be looked up again.
+    pub fn open(dest: &Path) -> Self {
+        let path = dest.join(UPLOADERS_FILENAME);
+        let names = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| match serde_json::from_str(&content) {
+                Ok(names) => Some(names),
+                Err(e) => {
+                    warn!(error = %e, path = %path.display(), "Ignoring broken uploader names");
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self { path, names }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&str> {
+        self.names.get(id).map(String::as_str)
+    }
+
+    /// Records the name of the uploader `id`, saving the cache if it
+    /// changed.
+    pub fn insert(&mut self, id: &str, name: &str) -> anyhow::Result<()> {
+        if self.get(id) == Some(name) {
+            return Ok(());
+        }
+        self.names.insert(id.to_owned(), name.to_owned());
+        fs::write(&self.path, serde_json::to_string_pretty(&self.names)?)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn cache_names() {
+        let dest = tempdir().unwrap();
+        let mut names = UploaderNames::open(dest.path());
+        assert_eq!(names.get("1"), None);
+        names.insert("1", "Ixiot").unwrap();
+        names.insert("2", "RG+Ice").unwrap();
+
+        let names = UploaderNames::open(dest.path());
+        assert_eq!(names.get("1"), Some("Ixiot"));
+        assert_eq!(names.get("2"), Some("RG+Ice"));
+
+        fs::write(dest.path().join(UPLOADERS_FILENAME), "{").unwrap();
+        assert_eq!(UploaderNames::open(dest.path()).get("1"), None);
+    }
+}
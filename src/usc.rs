@@ -0,0 +1,401 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use anyhow::bail;
+use anyhow::Context;
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+
+use crate::ksh;
+use crate::ksh::Header;
+use crate::notify::Notifier;
+use crate::Song;
+
+/// Names of the difficulty slots as USC shows them, by difficulty index.
+pub(crate) const DIFFICULTIES: [(&str, &str); 4] = [
+    ("Novice", "NOV"),
+    ("Advanced", "ADV"),
+    ("Exhaust", "EXH"),
+    ("Infinite", "INF"),
+];
+
+/// Columns that every known version of the `Charts` table has. Other columns
+/// are only written when the database has them.
+const REQUIRED_CHART_COLUMNS: [&str; 3] = ["path", "folderid", "level"];
+
+/// The song database (`maps.db`) of unnamed_sdvx_clone (USC).
+///
+/// The schema has changed between USC versions, so the columns of the
+/// `Charts` table are looked up when opening and only those present are
+/// filled in.
+pub struct MapDatabase {
+    conn: Connection,
+    chart_columns: HashSet<String>,
+}
+
+impl MapDatabase {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            bail!("USC database not found: {}", path.display());
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open USC database {}", path.display()))?;
+        let chart_columns = columns(&conn, "Charts")?;
+        for column in REQUIRED_CHART_COLUMNS {
+            if !chart_columns.contains(column) {
+                bail!("Unsupported USC database: Charts table has no {column} column");
+            }
+        }
+        if !columns(&conn, "Folders")?.contains("path") {
+            bail!("Unsupported USC database: Folders table has no path column");
+        }
+        Ok(Self {
+            conn,
+            chart_columns,
+        })
+    }
+
+    /// Inserts the charts in the song directory `dir`, replacing any entries
+    /// USC had for it. Returns the number of charts inserted.
+    pub fn refresh(&mut self, dir: &Path) -> anyhow::Result<usize> {
+        let dir = dir
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {}", dir.display()))?;
+        let dir_str = dir.to_string_lossy();
+
+        // USC only plays KSH charts.
+        let charts: Vec<_> = ksh::charts(&dir)?
+            .into_iter()
+            .filter(|chart| !ksh::is_kson(chart))
+            .collect();
+        let tx = self.conn.transaction()?;
+        let folder_id = match folder_id(&tx, &dir_str)? {
+            Some(id) => {
+                tx.execute("DELETE FROM Charts WHERE folderid = ?1", [id])?;
+                id
+            }
+            None => {
+                tx.execute("INSERT INTO Folders (path) VALUES (?1)", [&dir_str])?;
+                tx.last_insert_rowid()
+            }
+        };
+
+        for chart in &charts {
+            let header = Header::read(chart)?;
+            let values: Vec<_> = chart_values(chart, &dir, &header, folder_id)?
+                .into_iter()
+                .filter(|(column, _)| self.chart_columns.contains(*column))
+                .collect();
+            let sql = format!(
+                "INSERT INTO Charts ({}) VALUES ({})",
+                values
+                    .iter()
+                    .map(|(column, _)| *column)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                vec!["?"; values.len()].join(", ")
+            );
+            tx.execute(
+                &sql,
+                rusqlite::params_from_iter(values.into_iter().map(|(_, value)| value)),
+            )?;
+        }
+        tx.commit()?;
+        Ok(charts.len())
+    }
+
+    /// Makes the collection `name` consist of exactly the songs in `dirs`,
+    /// adding songs that are not in the database yet. Returns the number of
+    /// songs in the collection.
+    pub fn set_collection(&mut self, name: &str, dirs: &[PathBuf]) -> anyhow::Result<usize> {
+        self.check_collections_table()?;
+        let mut folder_ids = Vec::new();
+        for dir in dirs {
+            let path = dir
+                .canonicalize()
+                .with_context(|| format!("Failed to resolve {}", dir.display()))?;
+            let id = match folder_id(&self.conn, &path.to_string_lossy())? {
+                Some(id) => id,
+                None => {
+                    self.refresh(&path)?;
+                    folder_id(&self.conn, &path.to_string_lossy())?
+                        .context("Song missing from the USC database after adding it")?
+                }
+            };
+            folder_ids.push(id);
+        }
+
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM Collections WHERE collection = ?1", [name])?;
+        for id in &folder_ids {
+            tx.execute(
+                "INSERT OR IGNORE INTO Collections (collection, folderid) VALUES (?1, ?2)",
+                rusqlite::params![name, id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(folder_ids.len())
+    }
+
+    /// Deletes the collection `name`. The songs themselves are kept.
+    pub fn delete_collection(&mut self, name: &str) -> anyhow::Result<()> {
+        self.check_collections_table()?;
+        self.conn
+            .execute("DELETE FROM Collections WHERE collection = ?1", [name])?;
+        Ok(())
+    }
+
+    fn check_collections_table(&self) -> anyhow::Result<()> {
+        let columns = columns(&self.conn, "Collections")?;
+        if !columns.contains("collection") || !columns.contains("folderid") {
+            bail!("Unsupported USC database: unexpected Collections table");
+        }
+        Ok(())
+    }
+}
+
+fn folder_id(conn: &Connection, path: &str) -> anyhow::Result<Option<i64>> {
+    Ok(conn
+        .query_row("SELECT rowid FROM Folders WHERE path = ?1", [path], |row| {
+            row.get(0)
+        })
+        .optional()?)
+}
+
+fn columns(conn: &Connection, table: &str) -> anyhow::Result<HashSet<String>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<HashSet<_>, _>>()?;
+    if columns.is_empty() {
+        bail!("Unsupported USC database: no {table} table");
+    }
+    Ok(columns)
+}
+
+/// Values of a row of the `Charts` table, for every column known to any USC
+/// version.
+fn chart_values(
+    chart: &Path,
+    dir: &Path,
+    header: &Header,
+    folder_id: i64,
+) -> anyhow::Result<Vec<(&'static str, Value)>> {
+    let text = |key: &str| Value::from(header.get(key).unwrap_or_default().to_owned());
+    let int = |key: &str| {
+        Value::from(
+            header
+                .get(key)
+                .and_then(|v| v.parse::<i64>().ok())
+                .unwrap_or(0),
+        )
+    };
+    let path_in_dir = |key: &str| match header.get(key) {
+        Some(file) if !file.is_empty() => {
+            // Preview audio may list alternatives separated by `;`.
+            let file = file.split(';').next().unwrap_or(file);
+            Value::from(dir.join(file).to_string_lossy().into_owned())
+        }
+        _ => Value::Null,
+    };
+    let diff_index = header.difficulty_index().unwrap_or(3);
+    let (diff_name, diff_shortname) = DIFFICULTIES[usize::from(diff_index)];
+
+    let bytes = fs::read(chart)?;
+    let mtime = fs::metadata(chart)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+
+    Ok(vec![
+        ("title", text("title")),
+        ("artist", text("artist")),
+        ("title_translit", text("title_translit")),
+        ("artist_translit", text("artist_translit")),
+        ("jacket_path", path_in_dir("jacket")),
+        ("effector", text("effect")),
+        ("illustrator", text("illustrator")),
+        ("diff_name", Value::from(diff_name.to_owned())),
+        ("diff_shortname", Value::from(diff_shortname.to_owned())),
+        ("path", Value::from(chart.to_string_lossy().into_owned())),
+        ("bpm", text("t")),
+        ("diff_index", Value::from(i64::from(diff_index))),
+        ("level", Value::from(i64::from(header.level().unwrap_or(0)))),
+        (
+            "hash",
+            Value::from(sha1_smol::Sha1::from(&bytes).digest().to_string()),
+        ),
+        ("preview_file", path_in_dir("m")),
+        ("preview_offset", int("po")),
+        ("preview_length", int("plength")),
+        ("lwt", Value::from(mtime)),
+        ("folderid", Value::from(folder_id)),
+        ("custom_offset", Value::from(0)),
+    ])
+}
+
+/// Adds each downloaded song to a USC song database so it shows up in game
+/// without a full rescan.
+#[derive(Debug)]
+pub struct UscNotifier {
+    db: PathBuf,
+}
+
+impl UscNotifier {
+    pub fn new(db: PathBuf) -> Self {
+        Self { db }
+    }
+}
+
+impl Notifier for UscNotifier {
+    fn song_downloaded(&self, _song: &Song, path: &Path) -> anyhow::Result<()> {
+        MapDatabase::open(&self.db)?.refresh(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    const CHARTS_TABLE: &str = "CREATE TABLE Charts (title TEXT, artist TEXT, \
+        title_translit TEXT, artist_translit TEXT, jacket_path TEXT, effector TEXT, \
+        illustrator TEXT, diff_name TEXT, diff_shortname TEXT, path TEXT, bpm TEXT, \
+        diff_index INTEGER, level INTEGER, hash TEXT, preview_file TEXT, \
+        preview_offset INTEGER, preview_length INTEGER, lwt INTEGER, folderid INTEGER, \
+        custom_offset INTEGER, rowid INTEGER PRIMARY KEY)";
+
+    fn song_dir(dest: &Path) -> PathBuf {
+        let dir = dest.join("song");
+        fs::create_dir(&dir).unwrap();
+        fs::write(
+            dir.join("exh.ksh"),
+            "\u{feff}title=Outbreak\nartist=RG+Ice\neffect=Ixiot\njacket=jacket.png\n\
+             difficulty=extended\nlevel=16\nt=190\nm=song.ogg;song_f.ogg\npo=1000\n--\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("nov.ksh"),
+            "title=Outbreak\ndifficulty=light\nlevel=5\n--\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn refresh_song() {
+        let dest = tempdir().unwrap();
+        let db_path = dest.path().join("maps.db");
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(&format!(
+            "CREATE TABLE Folders (path TEXT, rowid INTEGER PRIMARY KEY); {CHARTS_TABLE};"
+        ))
+        .unwrap();
+        let dir = song_dir(dest.path());
+
+        let mut db = MapDatabase::open(&db_path).unwrap();
+        assert_eq!(db.refresh(&dir).unwrap(), 2);
+        // Refreshing replaces the charts instead of adding them again.
+        assert_eq!(db.refresh(&dir).unwrap(), 2);
+
+        let charts: Vec<(String, String, i64, String)> = conn
+            .prepare("SELECT title, diff_shortname, level, preview_file FROM Charts ORDER BY level")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3).unwrap_or_default(),
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(charts.len(), 2);
+        assert_eq!(charts[0].1, "NOV");
+        assert_eq!(
+            charts[1],
+            (
+                String::from("Outbreak"),
+                String::from("EXH"),
+                16,
+                dir.canonicalize()
+                    .unwrap()
+                    .join("song.ogg")
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        );
+        let folders: i64 = conn
+            .query_row("SELECT COUNT(*) FROM Folders", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(folders, 1);
+    }
+
+    #[test]
+    fn set_collection() {
+        let dest = tempdir().unwrap();
+        let db_path = dest.path().join("maps.db");
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(&format!(
+            "CREATE TABLE Folders (path TEXT, rowid INTEGER PRIMARY KEY); {CHARTS_TABLE};
+             CREATE TABLE Collections (collection TEXT, folderid INTEGER, PRIMARY KEY (collection, folderid));"
+        ))
+        .unwrap();
+        let dir = song_dir(dest.path());
+        let other = dest.path().join("other");
+        fs::create_dir(&other).unwrap();
+
+        let mut db = MapDatabase::open(&db_path).unwrap();
+        db.refresh(&dir).unwrap();
+        assert_eq!(
+            db.set_collection("Lv18+", &[dir.clone(), other]).unwrap(),
+            2
+        );
+        assert_eq!(db.set_collection("Lv18+", &[dir]).unwrap(), 1);
+        let count = |conn: &Connection| -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM Collections", [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(count(&conn), 1);
+        db.delete_collection("Lv18+").unwrap();
+        assert_eq!(count(&conn), 0);
+    }
+
+    #[test]
+    fn older_schema_without_optional_columns() {
+        let dest = tempdir().unwrap();
+        let db_path = dest.path().join("maps.db");
+        Connection::open(&db_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE Folders (path TEXT, rowid INTEGER PRIMARY KEY);
+                 CREATE TABLE Charts (title TEXT, path TEXT, level INTEGER, folderid INTEGER, rowid INTEGER PRIMARY KEY);",
+            )
+            .unwrap();
+        let dir = song_dir(dest.path());
+        assert_eq!(
+            MapDatabase::open(&db_path).unwrap().refresh(&dir).unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn unsupported_schema() {
+        let dest = tempdir().unwrap();
+        let db_path = dest.path().join("maps.db");
+        Connection::open(&db_path)
+            .unwrap()
+            .execute_batch("CREATE TABLE Folders (path TEXT);")
+            .unwrap();
+        assert!(MapDatabase::open(&db_path).is_err());
+        assert!(MapDatabase::open(&dest.path().join("missing.db")).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::ensure;
+use anyhow::Context;
+
+use crate::notify::shell;
+use crate::notify::Notifier;
+use crate::Song;
+
+const VIDEO_LINKS_FILENAME: &str = "video_links.txt";
+
+/// Returns the distinct background video links of the song's charts, in chart
+/// order.
+pub fn video_links(song: &Song) -> Vec<&str> {
+    let mut links: Vec<&str> = Vec::new();
+    for link in song.charts.iter().filter_map(|c| c.video_link.as_deref()) {
+        let link = link.trim();
+        if !link.is_empty() && !links.contains(&link) {
+            links.push(link);
+        }
+    }
+    links
+}
+
+/// Records the background video links of each downloaded song in
+/// `video_links.txt` in the song directory, and optionally fetches them for
+/// offline play.
+///
+/// The fetcher is a shell command run in the song directory for each link,
+/// which it receives in the `NAUTICA_VIDEO_URL` environment variable, e.g.
+/// `yt-dlp "$NAUTICA_VIDEO_URL"`.
+#[derive(Debug, Default)]
+pub struct VideoNotifier {
+    fetcher: Option<String>,
+}
+
+impl VideoNotifier {
+    pub fn new(fetcher: Option<String>) -> Self {
+        Self { fetcher }
+    }
+}
+
+impl Notifier for VideoNotifier {
+    fn song_downloaded(&self, song: &Song, path: &Path) -> anyhow::Result<()> {
+        let links = video_links(song);
+        if links.is_empty() {
+            return Ok(());
+        }
+        let content: String = links.iter().map(|link| format!("{link}\n")).collect();
+        fs::write(path.join(VIDEO_LINKS_FILENAME), content)?;
+
+        let Some(fetcher) = &self.fetcher else {
+            return Ok(());
+        };
+        for link in links {
+            let status = shell(fetcher)
+                .current_dir(path)
+                .env("NAUTICA_SONG_ID", &song.id)
+                .env("NAUTICA_VIDEO_URL", link)
+                .status()
+                .with_context(|| format!("Failed to run video fetcher {fetcher:?}"))?;
+            ensure!(
+                status.success(),
+                "Video fetcher {fetcher:?} exited with {status} for {link}"
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn song() -> Song {
+        serde_json::from_value(json!({
+            "id": "5441d590",
+            "user_id": "user",
+            "title": "Outbreak",
+            "artist": "RG+Ice",
+            "uploaded_at": "2023-09-07 05:56:46",
+            "updated_at": "2023-09-07 15:05:04",
+            "charts": [
+                {"difficulty": 1, "level": 12, "video_link": "https://youtu.be/a"},
+                {"difficulty": 2, "level": 16, "video_link": null},
+                {"difficulty": 3, "level": 18, "video_link": "https://youtu.be/a"},
+                {"difficulty": 4, "level": 19, "video_link": "https://youtu.be/b"},
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn distinct_links() {
+        assert_eq!(
+            video_links(&song()),
+            ["https://youtu.be/a", "https://youtu.be/b"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn record_and_fetch_links() {
+        let dir = tempdir().unwrap();
+
+        VideoNotifier::new(None)
+            .song_downloaded(&song(), dir.path())
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join(VIDEO_LINKS_FILENAME)).unwrap(),
+            "https://youtu.be/a\nhttps://youtu.be/b\n"
+        );
+
+        VideoNotifier::new(Some(String::from(
+            "echo \"$NAUTICA_VIDEO_URL\" >> fetched.txt",
+        )))
+        .song_downloaded(&song(), dir.path())
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("fetched.txt")).unwrap(),
+            "https://youtu.be/a\nhttps://youtu.be/b\n"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::layout::sanitize;
+use crate::layout::Fields;
+use crate::layout::Layout;
+use crate::notify::Notifier;
+use crate::store::Store;
+use crate::DownloadReport;
+use crate::Song;
+
+const VIEWS_DIRNAME: &str = "views";
+
+/// Rebuilds the symlink trees in `<dest>/views`, returning the number of links
+/// created.
+///
+/// Songs are linked as `Artist - Title [5441d590]` under `by-level/<level>`,
+/// `by-artist/<artist>`, and `by-uploader/<uploader>`. Links are relative, so
+/// the library can be moved. The previous views are replaced as a whole.
+pub fn generate(dest: &Path) -> anyhow::Result<usize> {
+    let views = dest.join(VIEWS_DIRNAME);
+    let staging = dest.join(format!("{VIEWS_DIRNAME}.new"));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+
+    let mut links = 0;
+    for (id, entry) in Store::open_read_only(dest).entries() {
+        let dir = entry.dir(&id);
+        if !dest.join(dir).is_dir() {
+            continue;
+        }
+        let name = Layout::Readable.dir_name(&Fields::entry(&id, &entry));
+        let levels: BTreeSet<_> = entry.levels.iter().collect();
+        let groups = levels
+            .into_iter()
+            .map(|level| ("by-level", level.to_string()))
+            .chain([
+                ("by-artist", sanitize(&entry.artist)),
+                ("by-uploader", sanitize(entry.uploader())),
+            ]);
+        for (view, group) in groups {
+            let parent = staging.join(view).join(group);
+            fs::create_dir_all(&parent)?;
+            // Links sit three levels below the library root.
+            let target: PathBuf = ["..", "..", ".."].iter().collect::<PathBuf>().join(dir);
+            symlink_dir(&target, &parent.join(&name))?;
+            links += 1;
+        }
+    }
+
+    if views.exists() {
+        fs::remove_dir_all(&views)?;
+    }
+    if staging.exists() {
+        fs::rename(&staging, &views)?;
+    }
+    Ok(links)
+}
+
+#[cfg(unix)]
+fn symlink_dir(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink_dir(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_dir(target, link)
+}
+
+/// Regenerates the symlink views after each sync that downloaded songs.
+#[derive(Debug)]
+pub struct ViewsNotifier {
+    dest: PathBuf,
+}
+
+impl ViewsNotifier {
+    pub fn new(dest: PathBuf) -> Self {
+        Self { dest }
+    }
+}
+
+impl Notifier for ViewsNotifier {
+    fn song_downloaded(&self, _song: &Song, _path: &Path) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn run_finished(&self, report: &DownloadReport) -> anyhow::Result<()> {
+        if !report.downloaded.is_empty() {
+            generate(&self.dest)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use chrono::Utc;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::store::Entry;
+
+    #[test]
+    fn generate_views() {
+        let dest = tempdir().unwrap();
+        let mut store = Store::open(dest.path());
+        for (id, dir, artist) in [
+            ("5441d590-4d43", None, "RG+Ice"),
+            ("89b54d80-4e6d", Some("Sou/Turing Love"), "Sou"),
+        ] {
+            let entry = Entry {
+                downloaded_at: Utc::now(),
+                title: String::from("Song"),
+                artist: artist.to_owned(),
+                user_id: String::from("u1"),
+                user_name: Some(String::from("Ixiot")),
+                levels: vec![16, 18, 18],
+                dir: dir.map(str::to_owned),
+                ..Default::default()
+            };
+            fs::create_dir_all(dest.path().join(entry.dir(id))).unwrap();
+            fs::write(dest.path().join(entry.dir(id)).join("chart.ksh"), id).unwrap();
+            store.insert(id, &entry).unwrap();
+        }
+
+        assert_eq!(generate(dest.path()).unwrap(), 8);
+        // Regenerating replaces the old views.
+        assert_eq!(generate(dest.path()).unwrap(), 8);
+
+        let views = dest.path().join(VIEWS_DIRNAME);
+        assert_eq!(
+            fs::read_to_string(views.join("by-level/18/RG+Ice - Song [5441d590]/chart.ksh"))
+                .unwrap(),
+            "5441d590-4d43"
+        );
+        assert_eq!(
+            fs::read_to_string(views.join("by-artist/Sou/Sou - Song [89b54d80]/chart.ksh"))
+                .unwrap(),
+            "89b54d80-4e6d"
+        );
+        assert_eq!(
+            fs::read_dir(views.join("by-uploader/Ixiot"))
+                .unwrap()
+                .count(),
+            2
+        );
+        assert!(!dest.path().join("views.new").exists());
+    }
+}